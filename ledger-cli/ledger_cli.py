@@ -88,7 +88,7 @@ def main():
             format_json(client.get_identity())
         elif action == "inbox":
             resp = client.list_messages("inbox")
-            messages = resp.get("data", [])
+            messages = (resp.get("data") or {}).get("messages", [])
             if not messages:
                 print("  No messages in inbox.")
             else:
@@ -98,7 +98,7 @@ def main():
                     print(f"     From: {m.get('from_id', '?')}  |  {m.get('id', '')[:8]}...")
         elif action == "sent":
             resp = client.list_messages("sent")
-            messages = resp.get("data", [])
+            messages = (resp.get("data") or {}).get("messages", [])
             if not messages:
                 print("  No sent messages.")
             else:
@@ -108,7 +108,8 @@ def main():
             to = input("  To (ledger:... or email): ").strip()
             subject = input("  Subject: ").strip()
             body = input("  Body: ").strip()
-            mode = input("  Mode (auto/p2p_only/gmail_only) [auto]: ").strip() or "auto"
+            # Blank uses the configured delivery_mode setting
+            mode = input("  Mode (auto/p2p_only/gmail_only) [default]: ").strip() or None
             resp = client.send_message(to, subject, body, mode)
             if resp.get("success"):
                 print(f"  ✅ Sent via {resp['data'].get('delivery_method', '?')}")
@@ -49,9 +49,13 @@ class LedgerClient:
         path = f"/api/messages?folder={folder}" if folder else "/api/messages"
         return self.get(path)
 
-    def send_message(self, to: str, subject: str, body: str, mode: str = "auto") -> dict:
+    def send_message(self, to: str, subject: str, body: str, mode: Optional[str] = None,
+                     cc: Optional[list[str]] = None, bcc: Optional[list[str]] = None,
+                     content_type: str = "text", read_receipt: bool = False) -> dict:
         return self.post("/api/messages", {
-            "to": to, "subject": subject, "body": body, "mode": mode
+            "to": to, "subject": subject, "body": body, "mode": mode,
+            "content_type": content_type, "cc": cc or [], "bcc": bcc or [],
+            "read_receipt": read_receipt,
         })
 
     def delete_message(self, msg_id: str) -> dict:
@@ -80,9 +84,11 @@ class LedgerClient:
     def fetch_gmail(self) -> dict:
         return self.post("/api/gmail/fetch")
 
-    def add_contact(self, ledger_id: str, public_key: str,
+    def add_contact(self, ledger_id: str, public_key: str = None,
                     display_name: str = None, gmail_address: str = None) -> dict:
-        data = {"ledger_id": ledger_id, "public_key": public_key}
+        # Keys are derived from a Ledger ID, so public_key is only needed for others
+        data = {"ledger_id": ledger_id}
+        if public_key: data["public_key"] = public_key
         if display_name: data["display_name"] = display_name
         if gmail_address: data["gmail_address"] = gmail_address
         return self.post("/api/contacts", data)
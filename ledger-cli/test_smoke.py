@@ -53,8 +53,8 @@ def main():
     resp = client.list_messages()
     test("GET /api/messages returns success",
          resp.get("success") == True)
-    test("Messages response has data array",
-         isinstance(resp.get("data"), list))
+    test("Messages response has a messages array",
+         isinstance((resp.get("data") or {}).get("messages"), list))
 
     # Test 4: Settings endpoint
     resp = client.get_settings()
@@ -0,0 +1,145 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use crate::models::message::*;
+
+use super::super::AppState;
+
+/// Routes under `/api` reachable without a token. Google's redirect to the
+/// OAuth callback can't carry a bearer header, so the callback is protected by
+/// its `state` parameter instead. Starting the flow does need the token, or
+/// anyone could link their own Gmail account; a client with a token follows
+/// the returned redirect itself.
+const PUBLIC_PATHS: &[&str] = &[
+    "/api/health",
+    "/api/gmail/oauth/callback",
+];
+
+/// Prefix of the WebSocket subprotocol that carries the token on `/api/ws`.
+/// Browsers can't set headers on a WebSocket, so a client offers
+/// `bearer.<api_token>` next to `ledger` in `Sec-WebSocket-Protocol` instead.
+const WS_TOKEN_PROTOCOL_PREFIX: &str = "bearer.";
+
+/// Require `Authorization: Bearer <api_token>` on `/api/*` and `/metrics`
+/// when a token is configured, or the token subprotocol on `/api/ws`
+pub async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let expected = req.app_data::<web::Data<AppState>>()
+        .and_then(|state| state.api_token.clone());
+
+    // The percent-decoded path, as routing sees it; the raw one would let
+    // `/%61pi/…` reach `/api/…` without a token
+    let path = req.match_info().as_str();
+    let protected = (path.starts_with("/api/") || path == "/metrics") && !PUBLIC_PATHS.contains(&path);
+    if let (Some(expected), true) = (expected, protected) {
+        let presented = req.headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .or_else(|| if path == "/api/ws" { ws_protocol_token(&req) } else { None });
+        if !presented.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+            let response = HttpResponse::Unauthorized()
+                .insert_header((actix_web::http::header::WWW_AUTHENTICATE, "Bearer"))
+                .json(ApiResponse::<()>::err("Missing or invalid API token"));
+            return Ok(req.into_response(response));
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+/// The token a WebSocket handshake offers as a `bearer.<token>` subprotocol
+fn ws_protocol_token(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())?
+        .split(',')
+        .find_map(|protocol| protocol.trim().strip_prefix(WS_TOKEN_PROTOCOL_PREFIX))
+}
+
+/// Compare without short-circuiting so response timing doesn't leak the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{get, http::StatusCode, App};
+    use std::sync::Arc;
+
+    #[get("/api/messages")]
+    async fn messages() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[get("/api/ws")]
+    async fn ws() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_token_required_on_encoded_paths() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_auth");
+        let mut state = web::Data::into_inner(state);
+        Arc::get_mut(&mut state).unwrap().api_token = Some("secret".into());
+        let app = init_service(
+            App::new()
+                .wrap(actix_web::middleware::from_fn(require_token))
+                .app_data(web::Data::from(state))
+                .service(messages)
+                .service(crate::api::health::metrics),
+        ).await;
+
+        for uri in ["/api/messages", "/%61pi/messages", "/api/%6Dessages", "/metrics", "/%6Detrics"] {
+            let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+        let authorized = TestRequest::get().uri("/%61pi/messages")
+            .insert_header((actix_web::http::header::AUTHORIZATION, "Bearer secret"));
+        let resp = call_service(&app, authorized.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_token_in_subprotocol() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_auth_ws");
+        let mut state = web::Data::into_inner(state);
+        Arc::get_mut(&mut state).unwrap().api_token = Some("secret".into());
+        let app = init_service(
+            App::new()
+                .wrap(actix_web::middleware::from_fn(require_token))
+                .app_data(web::Data::from(state))
+                .service(messages)
+                .service(ws),
+        ).await;
+        let offering = |uri: &str, protocols: &str| TestRequest::get().uri(uri)
+            .insert_header((actix_web::http::header::SEC_WEBSOCKET_PROTOCOL, protocols.to_string()))
+            .to_request();
+
+        let resp = call_service(&app, offering("/api/ws", "ledger, bearer.secret")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for protocols in ["ledger", "ledger, bearer.wrong", "bearer."] {
+            let resp = call_service(&app, offering("/api/ws", protocols)).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", protocols);
+        }
+        // Only the WebSocket takes it there
+        let resp = call_service(&app, offering("/api/messages", "ledger, bearer.secret")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
@@ -0,0 +1,52 @@
+use actix_web::{web, HttpResponse, get, post, delete};
+use crate::crypto::keys::LedgerIdentity;
+use crate::models::message::*;
+use crate::p2p::node::P2PCommand;
+
+use super::super::AppState;
+
+#[get("/api/blocklist")]
+pub async fn list_blocked(state: web::Data<AppState>) -> HttpResponse {
+    match state.db.get_blocklist() {
+        Ok(blocked) => HttpResponse::Ok().json(ApiResponse::ok(blocked)),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Block a Ledger ID: its envelopes are rejected and its peer is disconnected
+#[post("/api/blocklist")]
+pub async fn block(
+    state: web::Data<AppState>,
+    body: web::Json<BlockRequest>,
+) -> HttpResponse {
+    let peer_id = match LedgerIdentity::peer_id_from_ledger_id(&body.ledger_id) {
+        Ok(peer_id) => peer_id,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Invalid Ledger ID: {}", e)));
+        }
+    };
+
+    if let Err(e) = state.db.block(&body.ledger_id, &peer_id.to_string()) {
+        return super::error_response(&e);
+    }
+    let _ = state.p2p_tx.send(P2PCommand::DisconnectPeer { peer_id, response_tx: None }).await;
+
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "ledger_id": body.ledger_id,
+        "peer_id": peer_id.to_string(),
+        "status": "blocked"
+    })))
+}
+
+#[delete("/api/blocklist/{ledger_id}")]
+pub async fn unblock(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let ledger_id = path.into_inner();
+    match state.db.unblock(&ledger_id) {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok("Unblocked")),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Ledger ID is not blocked")),
+        Err(e) => super::error_response(&e),
+    }
+}
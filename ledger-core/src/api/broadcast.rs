@@ -0,0 +1,40 @@
+use actix_web::{web, HttpResponse, post};
+use crate::error::LedgerError;
+use crate::models::message::*;
+use crate::p2p::bulletin::Bulletin;
+use crate::p2p::node::P2PCommand;
+use tokio::sync::mpsc;
+
+use super::super::AppState;
+
+/// Publish a signed public bulletin to every node on the announce topic.
+/// Our own copy is filed in the Broadcast folder.
+#[post("/api/broadcast")]
+pub async fn broadcast(
+    state: web::Data<AppState>,
+    body: web::Json<BroadcastRequest>,
+) -> HttpResponse {
+    if body.subject.trim().is_empty() && body.body.trim().is_empty() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Bulletin is empty"));
+    }
+
+    let bulletin = Bulletin::new(&state.identity, &body.subject, &body.body);
+    let (tx, mut rx) = mpsc::channel(1);
+    let _ = state.p2p_tx.send(P2PCommand::PublishBulletin {
+        bulletin: bulletin.clone(),
+        response_tx: tx,
+    }).await;
+
+    match rx.recv().await {
+        Some(Ok(())) => {}
+        Some(Err(e)) => return super::error_response(&LedgerError::network(e)),
+        None => return HttpResponse::InternalServerError().json(ApiResponse::<()>::err("No response")),
+    }
+
+    let mut msg = bulletin.to_message();
+    msg.is_read = true;
+    if let Err(e) = state.db.insert_message(&msg) {
+        return super::error_response(&e);
+    }
+    HttpResponse::Ok().json(ApiResponse::ok(msg))
+}
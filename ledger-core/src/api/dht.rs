@@ -0,0 +1,171 @@
+use actix_web::{web, HttpResponse, post};
+use crate::crypto::envelope;
+use crate::dht;
+use crate::models::message::*;
+
+use super::super::AppState;
+
+/// Pull messages left for us in the DHT while we were offline
+#[post("/api/dht/sync")]
+pub async fn sync_dht(state: web::Data<AppState>) -> HttpResponse {
+    let mut envelopes = match dht::store::retrieve_from_dht(&state.p2p_tx, &state.identity.encryption_secret).await {
+        Ok(Some(envelopes)) => envelopes,
+        Ok(None) => vec![],
+        Err(e) => return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e)),
+    };
+    // Contacts who haven't seen a rotation yet still leave mail under the old key.
+    // Only the current identity can sign acknowledgements, so senders keep
+    // republishing these until the mailbox drops them.
+    let now = chrono::Utc::now().timestamp();
+    for retired in state.identity.retired_keys.iter().filter(|key| key.is_active(now)) {
+        match dht::store::retrieve_from_dht(&state.p2p_tx, &retired.encryption_secret).await {
+            Ok(found) => envelopes.extend(found.unwrap_or_default()),
+            Err(e) => tracing::warn!("Failed to check the DHT mailbox of retired {}: {}", retired.ledger_id, e),
+        }
+    }
+
+    let (fetched, retrieved) = match store_envelopes(&state, &envelopes) {
+        Ok(stored) => stored,
+        Err(e) => return super::error_response(&e),
+    };
+
+    if !retrieved.is_empty() {
+        if let Err(e) = dht::store::publish_retrieval_ack(&state.p2p_tx, &state.identity, retrieved).await {
+            tracing::warn!("Failed to acknowledge DHT retrieval: {}", e);
+        }
+    }
+
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "fetched": fetched,
+    })))
+}
+
+/// Store the envelopes from our DHT mailbox, returning how many were new and
+/// the ids of those we hold, so their senders can stop republishing them.
+/// Mail from blocked senders, or refused by `receive_policy`, is dropped but
+/// still acknowledged.
+fn store_envelopes(state: &AppState, envelopes: &[EncryptedEnvelope]) -> crate::error::Result<(usize, Vec<String>)> {
+    let mut fetched = 0;
+    let mut retrieved = Vec::new();
+    for env in envelopes {
+        // Skip mail we already pulled on an earlier sync. Ids are readable in the
+        // mailbox, so one stored from another sender is no sign we have this
+        // envelope: leave it unacknowledged for its sender to retry.
+        if let Some(stored) = state.db.get_message(&env.id)? {
+            if stored.from_id == env.from_ledger_id {
+                retrieved.push(env.id.clone());
+            } else {
+                tracing::warn!("Not acknowledging DHT envelope {} from {}: id taken by {}", env.id, env.from_ledger_id, stored.from_id);
+            }
+            continue;
+        }
+        if let Some(reason) = state.db.sender_rejection(&env.from_ledger_id)
+            .or_else(|| crate::p2p::node::legacy_rejection(env))
+        {
+            tracing::info!("Dropping DHT envelope {} from {}: {}", env.id, env.from_ledger_id, reason);
+            retrieved.push(env.id.clone());
+            continue;
+        }
+
+        let plaintext = match envelope::decrypt_envelope(&state.identity, env) {
+            Ok(p) => p,
+            Err(e) => {
+                state.metrics.decrypt_failures.inc();
+                tracing::warn!("Failed to decrypt DHT envelope {}: {}", env.id, e);
+                continue;
+            }
+        };
+
+        let msg = Message::from_envelope(env, state.identity.ledger_id.clone(), plaintext);
+        match state.db.insert_message(&msg) {
+            Ok(()) => {
+                fetched += 1;
+                retrieved.push(env.id.clone());
+                state.metrics.messages_received.inc();
+                let _ = state.events.send(MessageEvent::NewMessage { id: msg.id.clone() });
+            }
+            Err(e) => tracing::error!("Failed to store DHT message: {}", e),
+        }
+    }
+    Ok((fetched, retrieved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::LedgerIdentity;
+
+    #[test]
+    fn test_blocked_and_refused_senders_dropped() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_dht_sender_rejection");
+        let seal = |sender: &LedgerIdentity| envelope::encrypt_message(
+            sender,
+            &state.identity.ledger_id,
+            &state.identity.encryption_public_bytes(),
+            &OutgoingContent::text("Hi", "Through the DHT"),
+        ).unwrap();
+        let friend = LedgerIdentity::generate().unwrap();
+        let blocked = LedgerIdentity::generate().unwrap();
+        state.db.block(&blocked.ledger_id, &blocked.libp2p_keypair().unwrap().public().to_peer_id().to_string()).unwrap();
+
+        let envelopes = vec![seal(&friend), seal(&blocked)];
+        let (fetched, retrieved) = store_envelopes(&state, &envelopes).unwrap();
+        assert_eq!(fetched, 1);
+        assert_eq!(retrieved.len(), 2);
+        assert!(state.db.get_message(&envelopes[0].id).unwrap().is_some());
+        assert!(state.db.get_message(&envelopes[1].id).unwrap().is_none());
+
+        state.db.set_setting("receive_policy", "contacts_only").unwrap();
+        let stranger = [seal(&friend)];
+        assert_eq!(store_envelopes(&state, &stranger).unwrap().0, 0);
+        assert!(state.db.get_message(&stranger[0].id).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_legacy_envelope_dropped() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_dht_legacy");
+        let sender = LedgerIdentity::generate().unwrap();
+        let mut env = envelope::encrypt_message(
+            &sender,
+            &state.identity.ledger_id,
+            &state.identity.encryption_public_bytes(),
+            &OutgoingContent::text("Hi", "Through the DHT"),
+        ).unwrap();
+        env.version = envelope::ENVELOPE_VERSION_LEGACY;
+
+        let (fetched, retrieved) = store_envelopes(&state, std::slice::from_ref(&env)).unwrap();
+        assert_eq!(fetched, 0);
+        assert_eq!(retrieved, vec![env.id.clone()]);
+        assert!(state.db.get_message(&env.id).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_id_taken_by_another_sender_not_acknowledged() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_dht_id_squatting");
+        let sender = LedgerIdentity::generate().unwrap();
+        let squatter = LedgerIdentity::generate().unwrap();
+        let env = envelope::encrypt_message(
+            &sender,
+            &state.identity.ledger_id,
+            &state.identity.encryption_public_bytes(),
+            &OutgoingContent::text("Hi", "Through the DHT"),
+        ).unwrap();
+
+        let mut squatted = Message::new(squatter.ledger_id.clone(), state.identity.ledger_id.clone(), "Hi".into(), "Mine".into());
+        squatted.id = env.id.clone();
+        state.db.insert_message(&squatted).unwrap();
+        let envelopes = [env];
+        assert_eq!(store_envelopes(&state, &envelopes).unwrap(), (0, vec![]));
+
+        // Once it really is stored, a later sync acknowledges it
+        state.db.delete_message(&envelopes[0].id).unwrap();
+        assert_eq!(store_envelopes(&state, &envelopes).unwrap().0, 1);
+        assert_eq!(store_envelopes(&state, &envelopes).unwrap(), (0, vec![envelopes[0].id.clone()]));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
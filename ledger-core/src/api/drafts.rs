@@ -0,0 +1,107 @@
+use actix_web::{web, HttpResponse, get, post, put, delete};
+use crate::fallback::router;
+use crate::models::message::*;
+
+use super::super::AppState;
+use super::messages::{deliver_and_store, resolve_mode};
+
+/// Save a draft under `id`, creating it or overwriting the previous save
+fn save_draft(state: &AppState, id: String, draft: &DraftRequest) -> HttpResponse {
+    match state.db.get_message(&id) {
+        Ok(Some(existing)) if existing.folder != Folder::Drafts => {
+            return HttpResponse::Conflict().json(ApiResponse::<()>::err("Message is not a draft"));
+        }
+        Err(e) => return super::error_response(&e),
+        _ => {}
+    }
+
+    let mut msg = Message::new(
+        state.identity.ledger_id.clone(),
+        draft.to.clone(),
+        draft.subject.clone(),
+        draft.body.clone(),
+    );
+    msg.id = id;
+    msg.folder = Folder::Drafts;
+    msg.is_read = true;
+    msg.content_type = draft.content_type;
+
+    match state.db.insert_message(&msg) {
+        Ok(()) => HttpResponse::Ok().json(ApiResponse::ok(msg)),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[get("/api/drafts")]
+pub async fn list_drafts(state: web::Data<AppState>) -> HttpResponse {
+    match state.db.query_messages(&MessageFilter::folder("drafts"), u32::MAX, 0) {
+        Ok(drafts) => HttpResponse::Ok().json(ApiResponse::ok(drafts)),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[post("/api/drafts")]
+pub async fn create_draft(
+    state: web::Data<AppState>,
+    body: web::Json<DraftRequest>,
+) -> HttpResponse {
+    save_draft(&state, uuid::Uuid::new_v4().to_string(), &body)
+}
+
+/// Idempotent on `id`, so clients can autosave by repeating the same PUT
+#[put("/api/drafts/{id}")]
+pub async fn update_draft(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<DraftRequest>,
+) -> HttpResponse {
+    save_draft(&state, path.into_inner(), &body)
+}
+
+#[delete("/api/drafts/{id}")]
+pub async fn delete_draft(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    match state.db.get_message(&id) {
+        Ok(Some(msg)) if msg.folder == Folder::Drafts => match state.db.delete_message(&id) {
+            Ok(_) => HttpResponse::Ok().json(ApiResponse::ok("Deleted")),
+            Err(e) => super::error_response(&e),
+        },
+        Ok(_) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Draft not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Send a draft through the normal routing path; on success it moves to Sent
+#[post("/api/drafts/{id}/send")]
+pub async fn send_draft(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let draft = match state.db.get_message(&id) {
+        Ok(Some(msg)) if msg.folder == Folder::Drafts => msg,
+        Ok(_) => return HttpResponse::NotFound().json(ApiResponse::<()>::err("Draft not found")),
+        Err(e) => return super::error_response(&e),
+    };
+    if draft.to_id.is_empty() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Draft has no recipient"));
+    }
+    let recipients = match router::parse_recipients(&state.db, &Recipients::single(draft.to_id)) {
+        Ok(recipients) => recipients,
+        Err(e) => return super::error_response(&e),
+    };
+
+    let mode = match resolve_mode(query.get("mode").map(|s| s.as_str())) {
+        Ok(mode) => mode,
+        Err(response) => return response,
+    };
+    let content = OutgoingContent {
+        content_type: draft.content_type,
+        ..OutgoingContent::text(&draft.subject, &draft.body)
+    };
+    deliver_and_store(&state, draft.id, &recipients, &content, mode).await
+}
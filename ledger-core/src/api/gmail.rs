@@ -1,17 +1,27 @@
-use actix_web::{web, HttpResponse, get, post};
+use actix_web::{web, HttpRequest, HttpResponse, get, post};
+use serde::Deserialize;
 use crate::models::message::*;
-use crate::gmail::{smtp_client, imap_client};
+use crate::crypto::keys::LedgerIdentity;
+use crate::gmail::{smtp_client, imap_client, oauth, spam};
+use crate::store::db::Database;
 
 use super::super::AppState;
 
+/// Messages fetched from IMAP at a time while draining the INBOX
+const FETCH_PAGE_SIZE: usize = 50;
+
 #[get("/api/gmail/config")]
 pub async fn get_gmail_config(state: web::Data<AppState>) -> HttpResponse {
     let email = state.db.get_setting("gmail_email").ok().flatten();
-    let configured = email.is_some();
+    let config = state.db.gmail_config().ok().flatten();
+    let auth = config.as_ref().map(|c| {
+        if c.refresh_token.is_some() || c.access_token.is_some() { "oauth2" } else { "app_password" }
+    });
 
     HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
-        "configured": configured,
+        "configured": config.is_some(),
         "email": email,
+        "auth": auth,
     })))
 }
 
@@ -21,10 +31,13 @@ pub async fn set_gmail_config(
     body: web::Json<GmailConfig>,
 ) -> HttpResponse {
     if let Err(e) = state.db.set_setting("gmail_email", &body.email) {
-        return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string()));
+        return super::error_response(&e);
     }
-    if let Err(e) = state.db.set_setting("gmail_app_password", &body.app_password) {
-        return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string()));
+    // OAuth2-only setups leave the app password blank
+    if !body.app_password.is_empty() {
+        if let Err(e) = state.db.set_setting("gmail_app_password", &body.app_password) {
+            return super::error_response(&e);
+        }
     }
     if let Some(ref host) = body.imap_host {
         let _ = state.db.set_setting("gmail_imap_host", host);
@@ -32,49 +45,143 @@ pub async fn set_gmail_config(
     if let Some(ref host) = body.smtp_host {
         let _ = state.db.set_setting("gmail_smtp_host", host);
     }
+    if let Some(ref client_id) = body.oauth_client_id {
+        let _ = state.db.set_setting("gmail_oauth_client_id", client_id);
+    }
+    if let Some(ref client_secret) = body.oauth_client_secret {
+        let _ = state.db.set_setting("gmail_oauth_client_secret", client_secret);
+    }
 
+    state.gmail_configured.notify_one();
     HttpResponse::Ok().json(ApiResponse::ok("Gmail configured"))
 }
 
 #[post("/api/gmail/fetch")]
 pub async fn fetch_gmail(state: web::Data<AppState>) -> HttpResponse {
-    let email = match state.db.get_setting("gmail_email") {
-        Ok(Some(e)) => e,
-        _ => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Gmail not configured")),
-    };
-    let app_password = match state.db.get_setting("gmail_app_password") {
-        Ok(Some(p)) => p,
-        _ => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Gmail not configured")),
+    let config = match oauth::fresh_config(&state.db).await {
+        Ok(Some(config)) => config,
+        Ok(None) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Gmail not configured")),
+        Err(e) => return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
     };
 
-    let config = GmailConfig {
-        email,
-        app_password,
-        imap_host: state.db.get_setting("gmail_imap_host").ok().flatten(),
-        smtp_host: None,
+    let db = state.db.clone();
+    let mut cursor = match db.imap_cursor(&config.email) {
+        Ok(cursor) => cursor,
+        Err(e) => return super::error_response(&e),
     };
 
-    // Run IMAP fetch in a blocking task (it uses synchronous I/O)
-    let db = state.db.clone();
-    let result = tokio::task::spawn_blocking(move || {
-        imap_client::fetch_messages(&config, 20)
+    let mut imported = Vec::new();
+    let fetched = imap_client::fetch_messages(&config, &mut cursor, FETCH_PAGE_SIZE, |msg| {
+        match store_fetched(&db, &state.identity, msg) {
+            Ok(stored) => {
+                imported.extend(msg.imap_uid);
+                if stored {
+                    state.metrics.messages_received.inc();
+                    let _ = state.events.send(MessageEvent::NewMessage { id: msg.id.clone() });
+                }
+                true
+            }
+            Err(e) => {
+                tracing::error!("Failed to store Gmail message: {}", e);
+                false
+            }
+        }
     }).await;
+    // Whatever was stored before a failure stays imported
+    if let Err(e) = db.set_imap_cursor(&cursor) {
+        tracing::error!("Failed to save Gmail import position: {}", e);
+    }
+    mark_imported_seen(&db, &cursor, imported).await;
+
+    match fetched {
+        Ok(messages) => HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+            "fetched": messages.len(),
+            "messages": messages,
+        }))),
+        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+    }
+}
+
+/// Store a fetched email, first decrypting it if it is a Ledger fallback and
+/// otherwise filing it in Spam if it scores too high. Returns false for mail
+/// already imported, a fallback whose envelope already arrived over P2P or the
+/// DHT, or one from a sender that is blocked or refused by `receive_policy`.
+pub(crate) fn store_fetched(db: &Database, identity: &LedgerIdentity, msg: &mut Message) -> crate::error::Result<bool> {
+    if let Some(uid) = msg.imap_uid {
+        if db.has_imap_uid(uid)? {
+            return Ok(false);
+        }
+    }
+    imap_client::open_fallback(msg, identity);
+    if msg.signature_status == SignatureStatus::Valid {
+        if db.get_message(&msg.id)?.is_some() {
+            return Ok(false);
+        }
+        if let Some(reason) = db.sender_rejection(&msg.from_id) {
+            tracing::info!("Dropping Gmail fallback {} from {}: {}", msg.id, msg.from_id, reason);
+            return Ok(false);
+        }
+        // A signed Ledger envelope isn't scored like other email
+        msg.spam_score = None;
+    }
+    spam::classify(db, msg)?;
+    db.insert_message(msg)?;
+    Ok(true)
+}
 
-    match result {
-        Ok(Ok(messages)) => {
-            let count = messages.len();
-            for msg in &messages {
-                if let Err(e) = db.insert_message(msg) {
-                    tracing::error!("Failed to store Gmail message: {}", e);
+/// Store a batch of mail forwarded by IMAP IDLE, saving the import position
+/// after each message stored, and return the UIDs imported. The first message
+/// that fails to store comes back with everything after it, to go in front of
+/// the next batch: the position never passes mail that isn't stored, and a
+/// passing failure doesn't hold it back for the rest of the session.
+pub(crate) fn store_idle_batch(
+    db: &Database,
+    identity: &LedgerIdentity,
+    events: &tokio::sync::broadcast::Sender<MessageEvent>,
+    metrics: &crate::metrics::Metrics,
+    batch: Vec<(Message, ImapCursor)>,
+) -> (Vec<u32>, Vec<(Message, ImapCursor)>) {
+    let mut imported = Vec::new();
+    let mut batch = batch.into_iter();
+    while let Some((msg, cursor)) = batch.next() {
+        // Opening a fallback rewrites it, so a retry starts from the original
+        let mut opened = msg.clone();
+        match store_fetched(db, identity, &mut opened) {
+            Ok(stored) => {
+                imported.extend(opened.imap_uid);
+                if stored {
+                    metrics.messages_received.inc();
+                    let _ = events.send(MessageEvent::NewMessage { id: opened.id.clone() });
                 }
+                if let Err(e) = db.set_imap_cursor(&cursor) {
+                    tracing::error!("Failed to save Gmail import position: {}", e);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to store Gmail message: {}", e);
+                return (imported, std::iter::once((msg, cursor)).chain(batch).collect());
             }
-            HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
-                "fetched": count,
-                "messages": messages,
-            })))
         }
-        Ok(Err(e)) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+    }
+    (imported, Vec::new())
+}
+
+/// With the `gmail_mark_seen` setting on, flag imported mail `\Seen` on the
+/// server so it stops showing as unread in other Gmail clients
+pub(crate) async fn mark_imported_seen(db: &Database, cursor: &ImapCursor, uids: Vec<u32>) {
+    if uids.is_empty() || db.get_setting("gmail_mark_seen").ok().flatten().as_deref() != Some("true") {
+        return;
+    }
+    let config = match oauth::fresh_config(db).await {
+        Ok(Some(config)) => config,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!("Failed to load Gmail config: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = imap_client::mark_seen(&config, cursor.uid_validity, &uids).await {
+        tracing::warn!("Failed to mark Gmail messages seen: {}", e);
     }
 }
 
@@ -83,34 +190,50 @@ pub async fn send_gmail(
     state: web::Data<AppState>,
     body: web::Json<GmailSendRequest>,
 ) -> HttpResponse {
-    let email = match state.db.get_setting("gmail_email") {
-        Ok(Some(e)) => e,
-        _ => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Gmail not configured")),
-    };
-    let app_password = match state.db.get_setting("gmail_app_password") {
-        Ok(Some(p)) => p,
-        _ => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Gmail not configured")),
+    let config = match oauth::fresh_config(&state.db).await {
+        Ok(Some(config)) => config,
+        Ok(None) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Gmail not configured")),
+        Err(e) => return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
     };
 
-    let config = GmailConfig {
-        email: email.clone(),
-        app_password,
-        imap_host: None,
-        smtp_host: state.db.get_setting("gmail_smtp_host").ok().flatten(),
+    let recipients = Recipients {
+        to: vec![body.to.clone()],
+        cc: body.cc.clone(),
+        bcc: body.bcc.clone(),
     };
+    let mut msg = Message::new(
+        config.email.clone(),
+        recipients.joined(),
+        body.subject.clone(),
+        body.body.clone(),
+    );
+    msg.folder = Folder::Sent;
+    msg.delivery_method = DeliveryMethod::Gmail;
+    msg.content_type = body.content_type;
 
-    match smtp_client::send_email(&config, &body.to, &body.subject, &body.body).await {
+    for outgoing in &body.attachments {
+        let data = match base64::Engine::decode(&base64::engine::general_purpose::STANDARD, &outgoing.data) {
+            Ok(data) => data,
+            Err(e) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err(
+                format!("Invalid base64 in attachment {}: {}", outgoing.filename, e),
+            )),
+        };
+        msg.attachments.push(Attachment {
+            id: uuid::Uuid::new_v4().to_string(),
+            message_id: msg.id.clone(),
+            filename: outgoing.filename.clone(),
+            mime_type: outgoing.mime_type.clone().unwrap_or_else(|| "application/octet-stream".into()),
+            size: data.len() as i64,
+            data,
+            ..Default::default()
+        });
+    }
+
+    let started = std::time::Instant::now();
+    match smtp_client::send_email(&config, &recipients, &body.subject, &body.body, body.content_type, &msg.attachments, body.in_reply_to.as_deref()).await {
         Ok(()) => {
+            state.metrics.record_sent("gmail", started.elapsed());
             // Store in sent folder
-            let msg = Message::new(
-                email,
-                body.to.clone(),
-                body.subject.clone(),
-                body.body.clone(),
-            );
-            let mut msg = msg;
-            msg.folder = Folder::Sent;
-            msg.delivery_method = DeliveryMethod::Gmail;
             let _ = state.db.insert_message(&msg);
 
             HttpResponse::Ok().json(ApiResponse::ok("Email sent"))
@@ -118,3 +241,163 @@ pub async fn send_gmail(
         Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
     }
 }
+
+/// Query string Google appends when redirecting back to the callback
+#[derive(Debug, Deserialize)]
+pub struct OAuthCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Callback URL registered with Google, derived from how this node was reached
+fn oauth_redirect_uri(req: &HttpRequest) -> String {
+    let conn = req.connection_info();
+    format!("{}://{}/api/gmail/oauth/callback", conn.scheme(), conn.host())
+}
+
+#[get("/api/gmail/oauth/start")]
+pub async fn oauth_start(state: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let client_id = match state.db.get_setting("gmail_oauth_client_id") {
+        Ok(Some(id)) => id,
+        _ => return HttpResponse::BadRequest().json(ApiResponse::<()>::err("OAuth client ID not configured")),
+    };
+
+    // Random state ties the callback to this request (CSRF protection)
+    let csrf_state = uuid::Uuid::new_v4().to_string();
+    if let Err(e) = state.db.set_setting("gmail_oauth_state", &csrf_state) {
+        return super::error_response(&e);
+    }
+
+    match oauth::authorization_url(&client_id, &oauth_redirect_uri(&req), &csrf_state) {
+        Ok(url) => HttpResponse::Found()
+            .insert_header((actix_web::http::header::LOCATION, url))
+            .finish(),
+        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+    }
+}
+
+#[get("/api/gmail/oauth/callback")]
+pub async fn oauth_callback(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<OAuthCallbackQuery>,
+) -> HttpResponse {
+    if let Some(ref error) = query.error {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Authorization denied: {}", error)));
+    }
+
+    let expected = state.db.get_setting("gmail_oauth_state").ok().flatten().filter(|s| !s.is_empty());
+    if expected.is_none() || query.state != expected {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Invalid OAuth state"));
+    }
+    let _ = state.db.set_setting("gmail_oauth_state", "");
+
+    let Some(ref code) = query.code else {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Missing authorization code"));
+    };
+    let (Ok(Some(client_id)), Ok(Some(client_secret))) = (
+        state.db.get_setting("gmail_oauth_client_id"),
+        state.db.get_setting("gmail_oauth_client_secret"),
+    ) else {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("OAuth client not configured"));
+    };
+
+    let proxy = match state.db.tor_proxy() {
+        Ok(proxy) => proxy,
+        Err(e) => return super::error_response(&e),
+    };
+    let redirect_uri = oauth_redirect_uri(&req);
+    let tokens = match oauth::exchange_code(&client_id, &client_secret, code, &redirect_uri, proxy.as_deref()).await {
+        Ok(tokens) => tokens,
+        Err(e) => return HttpResponse::BadGateway().json(ApiResponse::<()>::err(e.to_string())),
+    };
+    if let Err(e) = oauth::store_tokens(&state.db, &tokens) {
+        return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string()));
+    }
+
+    tracing::info!("Gmail OAuth2 authorization complete");
+    state.gmail_configured.notify_one();
+    HttpResponse::Ok().json(ApiResponse::ok("Gmail authorized"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fallback(sender: &LedgerIdentity, recipient: &LedgerIdentity) -> Message {
+        let envelope = crate::crypto::envelope::encrypt_message(
+            sender, &recipient.ledger_id, &recipient.encryption_public_bytes(), &OutgoingContent::text("Hi", "Over Gmail"),
+        ).unwrap();
+        let payload = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, serde_json::to_vec(&envelope).unwrap());
+        let raw = format!(
+            "From: sender@example.com\r\nSubject: {}\r\n\r\n{}",
+            smtp_client::FALLBACK_SUBJECT,
+            smtp_client::fallback_body(&payload).replace('\n', "\r\n"),
+        );
+        imap_client::parse_message(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_fallback_from_blocked_or_refused_sender_dropped() {
+        let dir = std::env::temp_dir().join("ledger_test_gmail_sender_rejection");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+        let local = LedgerIdentity::generate().unwrap();
+        let sender = LedgerIdentity::generate().unwrap();
+
+        db.block(&sender.ledger_id, &sender.libp2p_keypair().unwrap().public().to_peer_id().to_string()).unwrap();
+        let mut msg = fallback(&sender, &local);
+        assert!(!store_fetched(&db, &local, &mut msg).unwrap());
+        assert!(db.get_message(&msg.id).unwrap().is_none());
+
+        db.unblock(&sender.ledger_id).unwrap();
+        db.set_setting("receive_policy", "contacts_only").unwrap();
+        let mut msg = fallback(&sender, &local);
+        assert!(!store_fetched(&db, &local, &mut msg).unwrap());
+
+        // The policy is about Ledger senders; ordinary email still arrives
+        let raw = b"From: friend@example.com\r\nSubject: Lunch\r\n\r\nNoon?\r\n";
+        let mut plain = imap_client::parse_message(raw).unwrap();
+        assert!(store_fetched(&db, &local, &mut plain).unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_idle_batch_retries_after_a_failed_store() {
+        let dir = std::env::temp_dir().join("ledger_test_gmail_idle_retry");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+        let local = LedgerIdentity::generate().unwrap();
+        let (events, _) = tokio::sync::broadcast::channel(8);
+        let metrics = crate::metrics::Metrics::new();
+        let fetched = |uid: u32| {
+            let raw = format!("From: friend@example.com\r\nSubject: Mail {}\r\n\r\nHello\r\n", uid);
+            let mut msg = imap_client::parse_message(raw.as_bytes()).unwrap();
+            msg.imap_uid = Some(uid);
+            let cursor = ImapCursor { account: "me@gmail.com".into(), uid_validity: 1, last_uid: uid };
+            (msg, cursor)
+        };
+        let position = |db: &Database| db.imap_cursor("me@gmail.com").unwrap().last_uid;
+
+        // The store fails once, at the second message
+        rusqlite::Connection::open(dir.join("ledger.db")).unwrap()
+            .execute_batch("CREATE TRIGGER full BEFORE INSERT ON messages WHEN NEW.subject = 'Mail 2' BEGIN SELECT RAISE(FAIL, 'disk full'); END;")
+            .unwrap();
+        let (imported, retry) = store_idle_batch(&db, &local, &events, &metrics, vec![fetched(1), fetched(2), fetched(3)]);
+        assert_eq!(imported, [1]);
+        assert_eq!(retry.iter().map(|(msg, _)| msg.imap_uid.unwrap()).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(position(&db), 1);
+
+        // With the failure gone, the next batch picks up where it stopped
+        rusqlite::Connection::open(dir.join("ledger.db")).unwrap().execute_batch("DROP TRIGGER full;").unwrap();
+        let batch = retry.into_iter().chain([fetched(4)]).collect();
+        let (imported, retry) = store_idle_batch(&db, &local, &events, &metrics, batch);
+        assert_eq!(imported, [2, 3, 4]);
+        assert!(retry.is_empty());
+        assert_eq!(position(&db), 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
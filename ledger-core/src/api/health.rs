@@ -0,0 +1,54 @@
+use actix_web::{web, HttpResponse, get};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::p2p::node::P2PCommand;
+
+use super::super::AppState;
+
+/// How long the health check waits for a DB connection before reporting it unavailable
+const DB_PING_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for the swarm loop to report its status
+const P2P_STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Liveness/readiness check. 503 if the database can't be reached promptly.
+#[get("/api/health")]
+pub async fn health(state: web::Data<AppState>) -> HttpResponse {
+    let db = state.db.clone();
+    let db_ok = web::block(move || db.ping(DB_PING_TIMEOUT)).await.unwrap_or(false);
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let _ = state.p2p_tx.send(P2PCommand::GetStatus { response_tx: tx }).await;
+    let p2p = tokio::time::timeout(P2P_STATUS_TIMEOUT, rx.recv()).await.ok().flatten();
+
+    let status = match (db_ok, &p2p) {
+        (false, _) => "unavailable",
+        (true, None) => "degraded",
+        (true, Some(_)) => "ok",
+    };
+    let body = serde_json::json!({
+        "status": status,
+        "db": db_ok,
+        "p2p_listeners": p2p.as_ref().map(|s| s.listeners.len()).unwrap_or(0),
+        "connected_peers": p2p.as_ref().map(|s| s.connected_peers).unwrap_or(0),
+        "nat_status": p2p.as_ref().map(|s| s.nat_status).unwrap_or_default(),
+        "public_address": p2p.as_ref().and_then(|s| s.public_address.clone()),
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+    });
+
+    if db_ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Prometheus scrape endpoint
+#[get("/metrics")]
+pub async fn metrics(state: web::Data<AppState>) -> HttpResponse {
+    match state.metrics.encode() {
+        Ok(text) => HttpResponse::Ok()
+            .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+            .body(text),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -1,14 +1,306 @@
-use actix_web::{web, HttpResponse, get};
-use crate::models::message::{ApiResponse, IdentityInfo};
+use actix_web::{web, HttpRequest, HttpResponse, get, post};
+use qrcode::QrCode;
+use crate::crypto::card::ContactCard;
+use crate::crypto::keys::{LedgerIdentity, DEFAULT_RETIRED_KEY_DAYS};
+use crate::error::LedgerError;
+use crate::models::message::{ApiResponse, IdentityInfo, ImportIdentityRequest, RecoverIdentityRequest, RotateIdentityRequest};
 
 use super::super::AppState;
 
 #[get("/api/identity")]
 pub async fn get_identity(state: web::Data<AppState>) -> HttpResponse {
-    let info = IdentityInfo {
-        ledger_id: state.identity.ledger_id.clone(),
-        public_key: bs58::encode(state.identity.public_key_bytes()).into_string(),
-        peer_id: state.peer_id.to_string(),
+    HttpResponse::Ok().json(ApiResponse::ok(identity_info(&state.identity, state.peer_id)))
+}
+
+/// Public parts of an identity, as `GET /api/identity` and `ledger-core identity` report them
+pub(crate) fn identity_info(identity: &LedgerIdentity, peer_id: libp2p::PeerId) -> IdentityInfo {
+    IdentityInfo {
+        ledger_id: identity.ledger_id.clone(),
+        public_key: bs58::encode(identity.public_key_bytes()).into_string(),
+        encryption_public_key: base64::Engine::encode(
+            &base64::engine::general_purpose::STANDARD,
+            identity.encryption_public_bytes(),
+        ),
+        peer_id: peer_id.to_string(),
+    }
+}
+
+/// This node's signed contact card, as JSON and in compact form for a QR code.
+/// Carries the `display_name` setting and the configured Gmail address.
+#[get("/api/identity/card")]
+pub async fn get_card(state: web::Data<AppState>) -> HttpResponse {
+    let card = match own_card(&state) {
+        Ok(card) => card,
+        Err(e) => return super::error_response(&e),
+    };
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "compact": card.to_compact(),
+        "card": card,
+    })))
+}
+
+/// The compact contact card as a QR code, PNG unless `?format=svg`. Scanning
+/// it and posting the text to `/api/contacts/import-card` adds this node.
+#[get("/api/identity/qr")]
+pub async fn get_qr(
+    state: web::Data<AppState>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let format = query.get("format").map(String::as_str).unwrap_or("png");
+    if !matches!(format, "png" | "svg") {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown QR format: {}", format)));
+    }
+    let code = match own_card(&state).and_then(|card| qr_code(&card.to_compact())) {
+        Ok(code) => code,
+        Err(e) => return super::error_response(&e),
+    };
+    if format == "svg" {
+        return HttpResponse::Ok().content_type("image/svg+xml").body(qr_svg(&code));
+    }
+    match qr_png(&code) {
+        Ok(png) => HttpResponse::Ok().content_type("image/png").body(png),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+fn own_card(state: &AppState) -> crate::error::Result<ContactCard> {
+    let display_name = state.db.get_setting("display_name")?;
+    let gmail_address = state.db.get_setting("gmail_email")?;
+    Ok(ContactCard::new(&state.identity, display_name.as_deref(), gmail_address.as_deref()))
+}
+
+/// Pixels per QR module, and modules of blank margin scanners need around the code
+const QR_MODULE_PIXELS: usize = 8;
+const QR_QUIET_ZONE: usize = 4;
+
+fn qr_code(text: &str) -> crate::error::Result<QrCode> {
+    QrCode::new(text).map_err(|e| LedgerError::invalid(format!("Contact card doesn't fit in a QR code: {}", e)))
+}
+
+fn qr_svg(code: &QrCode) -> String {
+    code.render::<qrcode::render::svg::Color>()
+        .module_dimensions(QR_MODULE_PIXELS as u32, QR_MODULE_PIXELS as u32)
+        .build()
+}
+
+/// Black modules on white, as an 8-bit grayscale PNG
+fn qr_png(code: &QrCode) -> crate::error::Result<Vec<u8>> {
+    let modules = code.width();
+    let side = (modules + 2 * QR_QUIET_ZONE) * QR_MODULE_PIXELS;
+    let mut pixels = vec![0xff_u8; side * side];
+    for (index, color) in code.to_colors().into_iter().enumerate() {
+        if color != qrcode::Color::Dark {
+            continue;
+        }
+        let left = (index % modules + QR_QUIET_ZONE) * QR_MODULE_PIXELS;
+        let top = (index / modules + QR_QUIET_ZONE) * QR_MODULE_PIXELS;
+        for y in top..top + QR_MODULE_PIXELS {
+            pixels[y * side + left..y * side + left + QR_MODULE_PIXELS].fill(0);
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| LedgerError::Io(std::io::Error::other(e)))?;
+    Ok(png)
+}
+
+/// The 24-word recovery phrase for the current identity
+#[get("/api/identity/mnemonic")]
+pub async fn get_mnemonic(state: web::Data<AppState>) -> HttpResponse {
+    let phrase = state.identity.to_mnemonic();
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "words": words,
+    })))
+}
+
+/// Rewrite `identity.key` from a recovery phrase. Replacing a different
+/// identity needs `?force=true`. Takes effect on restart.
+#[post("/api/identity/recover")]
+pub async fn recover_identity(
+    state: web::Data<AppState>,
+    body: web::Json<RecoverIdentityRequest>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let identity = match LedgerIdentity::from_mnemonic(&body.mnemonic) {
+        Ok(i) => i,
+        Err(e) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err(e.to_string())),
+    };
+    if let Err(conflict) = check_replace(&state, &identity, &query) {
+        return conflict;
+    }
+
+    if let Err(e) = identity.save(&state.data_dir, key_passphrase(&state, body.passphrase.as_deref())) {
+        return super::error_response(&e);
+    }
+    tracing::info!("Identity recovered as {}; restart to use it", identity.ledger_id);
+
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "ledger_id": identity.ledger_id,
+        "restart_required": true,
+    })))
+}
+
+/// Write a newly generated identity to `identity.key`, keeping the current
+/// encryption key for `retired_key_days` so mail sent to the old Ledger ID
+/// still decrypts meanwhile. Both are encrypted under the request's
+/// passphrase, else the node's. Takes effect on restart.
+#[post("/api/identity/rotate")]
+pub async fn rotate_identity(
+    state: web::Data<AppState>,
+    body: web::Json<RotateIdentityRequest>,
+) -> HttpResponse {
+    let days = match state.db.get_setting("retired_key_days") {
+        Ok(days) => days.and_then(|d| d.parse().ok()).unwrap_or(DEFAULT_RETIRED_KEY_DAYS),
+        Err(e) => return super::error_response(&e),
     };
-    HttpResponse::Ok().json(ApiResponse::ok(info))
+    let identity = match LedgerIdentity::generate() {
+        Ok(i) => i,
+        Err(e) => return super::error_response(&e),
+    };
+
+    // Retire first: a failed save then leaves the old identity in place, still usable.
+    // The retired key is sealed under the passphrase the new identity.key gets,
+    // which is the one the node restarts with.
+    let passphrase = key_passphrase(&state, body.passphrase.as_deref());
+    let now = chrono::Utc::now().timestamp();
+    let retired = state.identity.retire(now + days * 24 * 60 * 60);
+    if let Err(e) = state.db.retire_key(&retired, now, passphrase) {
+        return super::error_response(&e);
+    }
+    if let Err(e) = identity.save(&state.data_dir, passphrase) {
+        return super::error_response(&e);
+    }
+    tracing::info!("Identity rotated from {} to {}; restart to use it", retired.ledger_id, identity.ledger_id);
+
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "ledger_id": identity.ledger_id,
+        "mnemonic": identity.to_mnemonic(),
+        "retired_ledger_id": retired.ledger_id,
+        "retired_until": retired.expires_at,
+        "restart_required": true,
+    })))
+}
+
+/// Header carrying the export passphrase, so it stays out of URLs
+const EXPORT_PASSPHRASE_HEADER: &str = "X-Export-Passphrase";
+
+/// Download the identity sealed under the passphrase in `X-Export-Passphrase`
+#[get("/api/identity/export")]
+pub async fn export_identity(state: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let passphrase = req.headers()
+        .get(EXPORT_PASSPHRASE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    match state.identity.export(passphrase) {
+        Ok(export) => HttpResponse::Ok()
+            .insert_header(actix_web::http::header::ContentDisposition::attachment("ledger-identity.json"))
+            .json(export),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Rewrite `identity.key` from an export. Replacing a different identity
+/// needs `?force=true`. Takes effect on restart.
+#[post("/api/identity/import")]
+pub async fn import_identity(
+    state: web::Data<AppState>,
+    body: web::Json<ImportIdentityRequest>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let identity = match LedgerIdentity::from_export(&body.blob, &body.passphrase) {
+        Ok(i) => i,
+        Err(e) => return super::error_response(&e),
+    };
+
+    if let Err(conflict) = check_replace(&state, &identity, &query) {
+        return conflict;
+    }
+
+    if let Err(e) = identity.save(&state.data_dir, key_passphrase(&state, body.key_passphrase.as_deref())) {
+        return super::error_response(&e);
+    }
+    tracing::info!("Identity imported as {}; restart to use it", identity.ledger_id);
+
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "ledger_id": identity.ledger_id,
+        "restart_required": true,
+    })))
+}
+
+/// Refuse to replace this node's identity with a different one unless the
+/// request passes `force=true`
+fn check_replace(
+    state: &AppState,
+    identity: &LedgerIdentity,
+    query: &std::collections::HashMap<String, String>,
+) -> Result<(), HttpResponse> {
+    let force = query.get("force").map(|v| v == "true").unwrap_or(false);
+    if identity.ledger_id != state.identity.ledger_id && !force {
+        return Err(HttpResponse::Conflict().json(ApiResponse::<()>::err(format!(
+            "This node already has identity {}; pass force=true to replace it",
+            state.identity.ledger_id
+        ))));
+    }
+    Ok(())
+}
+
+/// The passphrase to write a new `identity.key` under: the request's, else
+/// the one the node's own key is under, so it never drops to plaintext
+fn key_passphrase<'a>(state: &'a AppState, requested: Option<&'a str>) -> Option<&'a str> {
+    requested.filter(|p| !p.is_empty()).or(state.key_passphrase.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, App};
+    use actix_web::test::{call_service, init_service, TestRequest};
+
+    #[test]
+    fn test_qr_png_and_svg() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let compact = ContactCard::new(&identity, Some("Alice"), None).to_compact();
+        let code = qr_code(&compact).unwrap();
+
+        let png = qr_png(&code).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let side = ((code.width() + 2 * QR_QUIET_ZONE) * QR_MODULE_PIXELS) as u32;
+        assert_eq!((reader.info().width, reader.info().height), (side, side));
+
+        assert!(qr_svg(&code).starts_with("<?xml"));
+        assert!(matches!(qr_code(&"x".repeat(8000)), Err(LedgerError::InvalidInput(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_recover_needs_force_to_replace_another_identity() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_recover_force");
+        let app = init_service(App::new().app_data(state.clone()).service(recover_identity)).await;
+        let other = LedgerIdentity::generate().unwrap();
+
+        let req = TestRequest::post().uri("/api/identity/recover")
+            .set_json(serde_json::json!({ "mnemonic": other.to_mnemonic() }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::CONFLICT);
+        assert!(!dir.join("identity.key").exists());
+
+        // The node's own phrase needs no force
+        let req = TestRequest::post().uri("/api/identity/recover")
+            .set_json(serde_json::json!({ "mnemonic": state.identity.to_mnemonic() }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = TestRequest::post().uri("/api/identity/recover?force=true")
+            .set_json(serde_json::json!({ "mnemonic": other.to_mnemonic() }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,18 @@
+use actix_web::{web, HttpResponse, post};
+use crate::models::message::ApiResponse;
+
+use super::super::AppState;
+
+/// Compact the database. Requests arriving meanwhile wait for it to finish.
+#[post("/api/maintenance/vacuum")]
+pub async fn vacuum(state: web::Data<AppState>) -> HttpResponse {
+    let db = state.db.clone();
+    match web::block(move || db.vacuum()).await {
+        Ok(Ok(reclaimed)) => HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+            "reclaimed_bytes": reclaimed,
+            "size_bytes": state.db.size_on_disk(),
+        }))),
+        Ok(Err(e)) => super::error_response(&e),
+        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+    }
+}
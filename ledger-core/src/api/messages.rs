@@ -1,19 +1,85 @@
-use actix_web::{web, HttpResponse, get, post, delete};
+use actix_web::{web, HttpResponse, get, post, put, delete};
+use crate::crypto::attachment;
+use crate::error::LedgerError;
 use crate::models::message::*;
 use crate::fallback::router;
+use crate::store::{db::Database, mbox};
 
 use super::super::AppState;
 
+/// Page size for `GET /api/messages` when `limit` is omitted
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// Messages read from the database per chunk of an mbox export
+const EXPORT_PAGE_SIZE: u32 = 100;
+
+/// Largest mbox or .eml file `POST /api/messages/import` reads
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Response header listing the correlation ID of each recipient's delivery
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
 #[get("/api/messages")]
 pub async fn list_messages(
     state: web::Data<AppState>,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
-    let folder = query.get("folder").map(|s| s.as_str());
-    match state.db.get_messages(folder) {
-        Ok(messages) => HttpResponse::Ok().json(ApiResponse::ok(messages)),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+    let limit = query.get("limit")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = query.get("offset").and_then(|v| v.parse::<u32>().ok()).unwrap_or(0);
+    let filter = match message_filter(&query) {
+        Ok(filter) => filter,
+        Err(e) => return super::error_response(&e),
+    };
+
+    // Fetch one extra row to learn whether another page exists
+    match state.db.query_messages(&filter, limit + 1, offset) {
+        Ok(mut messages) => {
+            let has_more = messages.len() > limit as usize;
+            messages.truncate(limit as usize);
+            HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+                "messages": messages,
+                "has_more": has_more,
+                "next_offset": has_more.then(|| offset.saturating_add(limit)),
+            })))
+        }
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// The filters of a `GET /api/messages` query. `after` and `before` are Unix
+/// timestamps; anything else is a bad request.
+fn message_filter(query: &std::collections::HashMap<String, String>) -> Result<MessageFilter, LedgerError> {
+    let timestamp = |name: &str| {
+        query.get(name)
+            .map(|v| v.parse::<i64>().map_err(|_| LedgerError::invalid(format!("{} must be a Unix timestamp: {}", name, v))))
+            .transpose()
+    };
+    Ok(MessageFilter {
+        folder: query.get("folder").cloned(),
+        starred_only: query.get("starred").is_some_and(|v| v == "true"),
+        unread_only: query.get("unread").is_some_and(|v| v == "true"),
+        from: query.get("from").filter(|v| !v.is_empty()).cloned(),
+        after: timestamp("after")?,
+        before: timestamp("before")?,
+    })
+}
+
+/// Longest message id accepted in a path
+const MAX_MESSAGE_ID_LEN: usize = 256;
+
+/// Our own ids are UUIDs, but peers pick envelope and bulletin ids and drafts
+/// can be saved under any id, so take anything bounded and printable. The
+/// rest is a bad request rather than a lookup that can only miss.
+fn message_id(path: web::Path<String>) -> Result<String, LedgerError> {
+    let id = path.into_inner();
+    if id.is_empty() || id.len() > MAX_MESSAGE_ID_LEN || id.chars().any(char::is_control) {
+        return Err(LedgerError::invalid(format!("Invalid message id: {:?}", id)));
     }
+    Ok(id)
 }
 
 #[get("/api/messages/{id}")]
@@ -21,83 +87,869 @@ pub async fn get_message(
     state: web::Data<AppState>,
     path: web::Path<String>,
 ) -> HttpResponse {
-    let id = path.into_inner();
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
     match state.db.get_message(&id) {
-        Ok(Some(msg)) => {
+        Ok(Some(mut msg)) => {
             let _ = state.db.mark_read(&id);
+            if !msg.is_read && msg.read_receipt_requested && sends_read_receipts(&state) {
+                let (identity, db, p2p_tx, opened) = (state.identity.clone(), state.db.clone(), state.p2p_tx.clone(), msg.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = router::send_read_receipt(&identity, &db, &p2p_tx, &opened).await {
+                        tracing::info!("Could not send read receipt for {}: {}", opened.id, e);
+                    }
+                });
+            }
+            msg.attachments = state.db.get_attachments(&id).unwrap_or_default();
+            msg.read_receipts = state.db.get_read_receipts(&id).unwrap_or_default();
             HttpResponse::Ok().json(ApiResponse::ok(msg))
         }
         Ok(None) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+        Err(e) => super::error_response(&e),
     }
 }
 
+/// Whether the `encrypt_subject` setting keeps subjects out of envelope hints
+fn encrypts_subjects(state: &AppState) -> bool {
+    state.db.get_setting("encrypt_subject").ok().flatten().as_deref() == Some("true")
+}
+
+/// Whether the `send_read_receipts` setting lets us answer receipt requests
+fn sends_read_receipts(state: &AppState) -> bool {
+    state.db.get_setting("send_read_receipts").ok().flatten().as_deref() != Some("false")
+}
+
 #[post("/api/messages")]
 pub async fn send_message(
     state: web::Data<AppState>,
     body: web::Json<SendMessageRequest>,
 ) -> HttpResponse {
-    let mode = body.mode.as_deref().unwrap_or("auto");
-
-    // Route through fallback logic
-    let result = router::route_message(
-        &state.identity,
-        &state.db,
-        &state.p2p_tx,
-        &body.to,
-        &body.subject,
-        &body.body,
-        mode,
-    ).await;
-
-    let (delivery_method, success) = match result {
-        router::DeliveryResult::P2pDirect => (DeliveryMethod::P2p, true),
-        router::DeliveryResult::DhtStored => (DeliveryMethod::P2p, true),
-        router::DeliveryResult::GmailFallback => (DeliveryMethod::Fallback, true),
-        router::DeliveryResult::GmailDirect => (DeliveryMethod::Gmail, true),
-        router::DeliveryResult::Failed(ref e) => {
-            tracing::warn!("Delivery failed: {}", e);
-            (DeliveryMethod::P2p, false)
-        }
+    let recipients = match router::parse_recipients(&state.db, &Recipients {
+        to: body.to.to_vec(),
+        cc: body.cc.clone(),
+        bcc: body.bcc.clone(),
+    }) {
+        Ok(recipients) => recipients,
+        Err(e) => return super::error_response(&e),
+    };
+    let mode = match resolve_mode(body.mode.as_deref()) {
+        Ok(mode) => mode,
+        Err(response) => return response,
+    };
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let attachments = match outgoing_attachments(&message_id, &body.attachments) {
+        Ok(attachments) => attachments,
+        Err(e) => return super::error_response(&e),
+    };
+
+    let content = OutgoingContent {
+        subject: body.subject.clone(),
+        body: body.body.clone(),
+        content_type: body.content_type,
+        read_receipt_requested: body.read_receipt,
+        attachments,
+        ..Default::default()
+    };
+
+    deliver_and_store(&state, message_id, &recipients, &content, mode).await
+}
+
+/// Decode the files of a send request
+fn outgoing_attachments(message_id: &str, files: &[OutgoingAttachment]) -> crate::error::Result<Vec<Attachment>> {
+    files.iter()
+        .map(|file| {
+            let data = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, &file.data)
+                .map_err(|e| LedgerError::invalid(format!("Invalid base64 in attachment {}: {}", file.filename, e)))?;
+            if data.len() as u64 > attachment::MAX_ATTACHMENT_BYTES {
+                return Err(LedgerError::invalid(format!(
+                    "Attachment {} is {} bytes; the limit is {}", file.filename, data.len(), attachment::MAX_ATTACHMENT_BYTES,
+                )));
+            }
+            let mime_type = file.mime_type.clone().unwrap_or_else(|| "application/octet-stream".into());
+            Ok(Attachment::outgoing(message_id, file.filename.clone(), mime_type, data))
+        })
+        .collect()
+}
+
+/// Which delivery path a message to one recipient would take, without
+/// encrypting or sending anything
+#[post("/api/messages/preview")]
+pub async fn preview_message(
+    state: web::Data<AppState>,
+    body: web::Json<PreviewRequest>,
+) -> HttpResponse {
+    let to = match router::expand_alias(&state.db, &body.to).and_then(|to| router::parse_recipient(&to)) {
+        Ok(to) => to.to_string(),
+        Err(e) => return super::error_response(&e),
+    };
+    let mode = match resolve_mode(body.mode.as_deref()) {
+        Ok(mode) => mode,
+        Err(response) => return response,
     };
+    let preview = router::preview_route(&state.db, &state.p2p_tx, &to, mode).await;
+    HttpResponse::Ok().json(ApiResponse::ok(preview))
+}
+
+/// The delivery mode a send asked for, if any. Without one each recipient
+/// gets their contact's preference, else the `delivery_mode` setting (see
+/// `router::effective_mode`). An unknown requested mode is a 400.
+pub(crate) fn resolve_mode(requested: Option<&str>) -> Result<Option<DeliveryMode>, HttpResponse> {
+    requested.map(|requested| DeliveryMode::parse(requested).ok_or_else(|| {
+        HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown delivery mode: {}", requested)))
+    })).transpose()
+}
 
-    if !success {
-        if let router::DeliveryResult::Failed(e) = result {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e));
+/// Route a message to every recipient and record it in Sent under `message_id`.
+/// Storing replaces any existing row with that id, which is how a sent draft
+/// leaves Drafts.
+///
+/// Plain email recipients in to and cc share one email; every other recipient
+/// gets their own copy, so BCC addresses never reach anyone else. The
+/// returned message lists how each recipient's copy went in `deliveries`.
+/// The request only fails if no recipient could be reached; otherwise
+/// the failed ones are reported in `error` next to the stored message.
+pub(crate) async fn deliver_and_store(
+    state: &AppState,
+    message_id: String,
+    recipients: &Recipients,
+    content: &OutgoingContent,
+    mode: Option<DeliveryMode>,
+) -> HttpResponse {
+    if recipients.all().is_empty() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("No recipients"));
+    }
+
+    let delivery = deliver(state, message_id, recipients, content, mode).await;
+    let failures: Vec<String> = delivery.results.iter()
+        .filter_map(|(to, outcome)| match outcome.result {
+            router::DeliveryResult::Failed(ref e) => Some(format!("{}: {}", to, e)),
+            _ => None,
+        })
+        .collect();
+    // One per recipient, in order, to grep the delivery logs for
+    let request_ids: Vec<&str> = delivery.results.iter()
+        .map(|(_, outcome)| outcome.request_id.as_str())
+        .collect();
+    let request_ids = (REQUEST_ID_HEADER, request_ids.join(", "));
+    match delivery.message {
+        Some(msg) => HttpResponse::Ok().insert_header(request_ids).json(ApiResponse {
+            success: true,
+            data: Some(msg),
+            error: (!failures.is_empty()).then(|| failures.join("; ")),
+        }),
+        None => HttpResponse::InternalServerError()
+            .insert_header(request_ids)
+            .json(ApiResponse::<()>::err(failures.join("; "))),
+    }
+}
+
+/// What became of a send
+pub(crate) struct Delivery {
+    /// The stored Sent copy; `None` if no recipient could be reached
+    pub message: Option<Message>,
+    /// Each recipient address with how their copy went
+    pub results: Vec<(String, router::RouteOutcome)>,
+}
+
+/// Route a message to every recipient and, if any copy got out (or was
+/// queued), store it in Sent. The core of `deliver_and_store`, without HTTP.
+pub(crate) async fn deliver(
+    state: &AppState,
+    message_id: String,
+    recipients: &Recipients,
+    content: &OutgoingContent,
+    mode: Option<DeliveryMode>,
+) -> Delivery {
+    let content = &OutgoingContent { encrypt_subject: encrypts_subjects(state), ..content.clone() };
+    // Ledger recipients in to and cc can share one envelope; BCC always gets its own
+    let group = router::seal_group(&state.identity, &state.db, recipients, content, mode);
+    let content = &OutgoingContent { group, ..content.clone() };
+
+    // Plain email recipients in to and cc share one Gmail message
+    let started = std::time::Instant::now();
+    let emailed = router::route_email_group(&state.db, recipients, content, mode).await;
+    if let Some((_, ref outcome)) = emailed {
+        record_sent(state, &outcome.result, started);
+    }
+
+    let addresses = recipients.all();
+    let mut results = Vec::new();
+    for to in &addresses {
+        if let Some((ref covered, ref outcome)) = emailed {
+            if covered.iter().any(|c| c == to) {
+                results.push((to.to_string(), outcome.clone()));
+                continue;
+            }
         }
+        // Route through fallback logic
+        let started = std::time::Instant::now();
+        let outcome = router::route_message(
+            &state.identity,
+            &state.db,
+            &state.p2p_tx,
+            &message_id,
+            to,
+            content,
+            mode,
+        ).await;
+        record_sent(state, &outcome.result, started);
+        results.push((to.to_string(), outcome));
     }
 
+    let deliveries: Vec<RecipientDelivery> = results.iter()
+        .map(|(to, outcome)| RecipientDelivery { recipient: to.clone(), method: outcome.result.method() })
+        .collect();
+    let Some(delivery_method) = overall_method(&deliveries) else {
+        return Delivery { message: None, results };
+    };
+    let delivered: Vec<&router::DeliveryResult> = results.iter()
+        .map(|(_, outcome)| &outcome.result)
+        .filter(|result| !matches!(result, router::DeliveryResult::Failed(_)))
+        .collect();
+    // Waiting in the DHT or the outbox until a recipient comes online
+    let delivery_status = if delivered.iter().any(|r| matches!(r, router::DeliveryResult::DhtStored | router::DeliveryResult::Queued)) {
+        DeliveryStatus::Pending
+    } else {
+        DeliveryStatus::Delivered
+    };
+
     // Store in sent folder
     let msg = Message {
-        id: uuid::Uuid::new_v4().to_string(),
+        id: message_id,
         from_id: state.identity.ledger_id.clone(),
-        to_id: body.to.clone(),
-        subject: body.subject.clone(),
-        body: body.body.clone(),
+        to_id: recipients.joined(),
+        subject: content.subject.clone(),
+        body: content.body.clone(),
         timestamp: chrono::Utc::now().timestamp(),
         delivery_method,
         is_read: true,
         folder: Folder::Sent,
         signature: None,
-        encrypted: body.to.starts_with("ledger:"),
+        signature_status: SignatureStatus::Unsigned,
+        encrypted: addresses.iter().all(|to| to.starts_with("ledger:")),
+        delivery_status,
+        content_type: content.content_type,
+        is_starred: false,
+        email_message_id: None,
+        imap_uid: None,
+        attachments: content.attachments.clone(),
+        read_receipt_requested: false,
+        read_receipts: vec![],
+        spam_score: None,
+        deliveries,
     };
 
     if let Err(e) = state.db.insert_message(&msg) {
         tracing::error!("Failed to store sent message: {}", e);
     }
 
-    HttpResponse::Ok().json(ApiResponse::ok(msg))
+    Delivery { message: Some(msg), results }
+}
+
+/// Count a copy that got out under its method
+fn record_sent(state: &AppState, result: &router::DeliveryResult, started: std::time::Instant) {
+    let method = match result {
+        router::DeliveryResult::Failed(_) => None,
+        // Counted when the outbox finally gets it through
+        router::DeliveryResult::Queued => None,
+        router::DeliveryResult::DhtStored => Some("dht"),
+        router::DeliveryResult::GmailFallback => Some("fallback"),
+        router::DeliveryResult::GmailDirect => Some("gmail"),
+        router::DeliveryResult::P2pDirect => Some("p2p"),
+    };
+    if let Some(method) = method {
+        state.metrics.record_sent(method, started.elapsed());
+    }
+}
+
+/// The method the Sent copy is filed under: Gmail or fallback only when every
+/// copy that got out went through Gmail, else P2P. `None` if none got out.
+/// `deliveries` has the method for each recipient.
+fn overall_method(deliveries: &[RecipientDelivery]) -> Option<DeliveryMethod> {
+    let methods: Vec<RecipientMethod> = deliveries.iter()
+        .map(|delivery| delivery.method)
+        .filter(|&method| method != RecipientMethod::Failed)
+        .collect();
+    if methods.is_empty() {
+        None
+    } else if methods.iter().all(|&method| method == RecipientMethod::Gmail) {
+        Some(DeliveryMethod::Gmail)
+    } else if methods.iter().all(|&method| matches!(method, RecipientMethod::Gmail | RecipientMethod::Fallback)) {
+        Some(DeliveryMethod::Fallback)
+    } else {
+        Some(DeliveryMethod::P2p)
+    }
 }
 
 #[delete("/api/messages/{id}")]
 pub async fn delete_message(
     state: web::Data<AppState>,
     path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
-    let id = path.into_inner();
-    match state.db.delete_message(&id) {
-        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok("Deleted")),
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    let permanent = query.get("permanent").is_some_and(|v| v == "true");
+
+    if permanent {
+        return match state.db.delete_message(&id) {
+            Ok(true) => HttpResponse::Ok().json(ApiResponse::ok("Deleted")),
+            Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
+            Err(e) => super::error_response(&e),
+        };
+    }
+
+    match state.db.trash_message(&id) {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok("Moved to trash")),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found or already in trash")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[post("/api/messages/{id}/restore")]
+pub async fn restore_message(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    match state.db.restore_message(&id) {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok("Restored")),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not in trash")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Move a message out of Spam into the inbox and never file its sender's
+/// mail as spam again
+#[post("/api/messages/{id}/not-spam")]
+pub async fn not_spam(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    let msg = match state.db.get_message(&id) {
+        Ok(Some(msg)) if msg.folder == Folder::Spam => msg,
+        Ok(_) => return HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not in spam")),
+        Err(e) => return super::error_response(&e),
+    };
+    let sender = crate::gmail::spam::sender_address(&msg.from_id);
+    if let Err(e) = state.db.allow_sender(&sender) {
+        return super::error_response(&e);
+    }
+    match state.db.move_message(&id, &Folder::Inbox) {
+        Ok(_) => HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+            "folder": Folder::Inbox,
+            "allowed_sender": sender,
+        }))),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[put("/api/messages/{id}/folder")]
+pub async fn move_message(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<MoveMessageRequest>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    let Some(folder) = Folder::parse(&body.folder) else {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown folder: {}", body.folder)));
+    };
+    match state.db.move_message(&id, &folder) {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok(folder)),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Most ids one bulk request may name, so a single transaction stays short
+const MAX_BULK_IDS: usize = 1000;
+
+/// Delete or move many messages in one transaction. Unknown ids don't fail
+/// the request; they are listed in `not_found`.
+#[post("/api/messages/bulk")]
+pub async fn bulk_messages(
+    state: web::Data<AppState>,
+    body: web::Json<BulkMessagesRequest>,
+) -> HttpResponse {
+    if body.ids.len() > MAX_BULK_IDS {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("At most {} ids per request", MAX_BULK_IDS)));
+    }
+    let result = match body.action {
+        BulkAction::Delete => state.db.bulk_delete(&body.ids, body.permanent),
+        BulkAction::Move => {
+            let Some(name) = body.folder.as_deref() else {
+                return HttpResponse::BadRequest().json(ApiResponse::<()>::err("move needs a folder"));
+            };
+            let Some(folder) = Folder::parse(name) else {
+                return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown folder: {}", name)));
+            };
+            state.db.bulk_move(&body.ids, &folder)
+        }
+    };
+    match result {
+        Ok(result) => HttpResponse::Ok().json(ApiResponse::ok(result)),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[put("/api/messages/{id}/read")]
+pub async fn set_read(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<SetReadRequest>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    match state.db.set_read(&id, body.read) {
+        Ok(true) => unread_counts_response(&state),
         Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[put("/api/messages/{id}/star")]
+pub async fn set_starred(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<SetStarredRequest>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    match state.db.set_starred(&id, body.starred) {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok(body.starred)),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[post("/api/messages/mark-all-read")]
+pub async fn mark_all_read(
+    state: web::Data<AppState>,
+    body: web::Json<MarkAllReadRequest>,
+) -> HttpResponse {
+    let Some(folder) = Folder::parse(&body.folder) else {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown folder: {}", body.folder)));
+    };
+    match state.db.mark_all_read(&folder) {
+        Ok(_) => unread_counts_response(&state),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[get("/api/messages/counts")]
+pub async fn message_counts(state: web::Data<AppState>) -> HttpResponse {
+    unread_counts_response(&state)
+}
+
+/// Current unread counts per folder plus the total, for folder badges
+fn unread_counts_response(state: &AppState) -> HttpResponse {
+    match state.db.unread_counts() {
+        Ok(counts) => HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+            "total": counts.values().sum::<i64>(),
+            "unread": counts,
+        }))),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[get("/api/messages/{id}/attachments")]
+pub async fn list_attachments(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    match state.db.get_message(&id) {
+        Ok(Some(_)) => match state.db.get_attachments(&id) {
+            Ok(attachments) => HttpResponse::Ok().json(ApiResponse::ok(attachments)),
+            Err(e) => super::error_response(&e),
+        },
+        Ok(None) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// A message as an RFC 822 `.eml` file
+#[get("/api/messages/{id}/eml")]
+pub async fn export_eml(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = match message_id(path) {
+        Ok(id) => id,
+        Err(e) => return super::error_response(&e),
+    };
+    match state.db.get_message(&id) {
+        Ok(Some(msg)) => HttpResponse::Ok()
+            .content_type("message/rfc822")
+            .insert_header(actix_web::http::header::ContentDisposition::attachment(format!("{}.eml", id)))
+            .body(mbox::to_eml(&msg)),
+        Ok(None) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Message not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Every message in `folder` (all of them if omitted) as one mbox file,
+/// newest first, read from the database a page at a time as it is sent
+#[get("/api/messages/export")]
+pub async fn export_mbox(
+    state: web::Data<AppState>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let filter = MessageFilter { folder: query.get("folder").cloned(), ..Default::default() };
+    let filename = format!("{}.mbox", filter.folder.as_deref().unwrap_or("ledger"));
+    let db = state.db.clone();
+
+    let pages = futures::stream::unfold(Some(0u32), move |offset| {
+        let db = db.clone();
+        let filter = filter.clone();
+        async move {
+            let offset = offset?;
+            match db.query_messages(&filter, EXPORT_PAGE_SIZE, offset) {
+                Ok(page) if page.is_empty() => None,
+                Ok(page) => {
+                    let next = (page.len() == EXPORT_PAGE_SIZE as usize).then_some(offset + EXPORT_PAGE_SIZE);
+                    let chunk: String = page.iter().map(mbox::to_mbox_entry).collect();
+                    Some((Ok(web::Bytes::from(chunk)), next))
+                }
+                Err(e) => {
+                    tracing::error!("mbox export failed: {}", e);
+                    Some((Err(actix_web::error::ErrorInternalServerError(e)), None))
+                }
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/mbox")
+        .insert_header(actix_web::http::header::ContentDisposition::attachment(filename))
+        .streaming(pages)
+}
+
+/// Store the mail in an uploaded mbox file or single `.eml` in `folder`
+/// (inbox by default). Messages whose `Message-ID` is already stored are
+/// skipped, as are ones that don't parse.
+#[post("/api/messages/import")]
+pub async fn import_messages(
+    state: web::Data<AppState>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let folder = match query.get("folder").map(|f| Folder::parse(f).ok_or(f)) {
+        None => Folder::Inbox,
+        Some(Ok(folder)) => folder,
+        Some(Err(f)) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown folder: {}", f))),
+    };
+
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = futures::StreamExt::next(&mut payload).await {
+        match chunk {
+            Ok(chunk) if data.len() + chunk.len() <= MAX_IMPORT_BYTES => data.extend_from_slice(&chunk),
+            Ok(_) => {
+                return HttpResponse::PayloadTooLarge()
+                    .json(ApiResponse::<()>::err(format!("Imports are limited to {} bytes", MAX_IMPORT_BYTES)));
+            }
+            Err(e) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err(e.to_string())),
+        }
+    }
+    let raw = if mbox::is_mbox(&data) { mbox::split_mbox(&data) } else { vec![data.to_vec()] };
+
+    let db = state.db.clone();
+    match web::block(move || import_raw(&db, raw, folder)).await {
+        Ok(Ok((imported, skipped))) => HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+            "imported": imported,
+            "skipped": skipped,
+        }))),
+        Ok(Err(e)) => super::error_response(&e),
         Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
     }
 }
+
+/// Parse and store raw RFC 822 messages, returning how many were imported and skipped
+fn import_raw(db: &Database, raw: Vec<Vec<u8>>, folder: Folder) -> crate::error::Result<(usize, usize)> {
+    let (mut imported, mut skipped) = (0, 0);
+    for raw in raw {
+        let Some(mut msg) = crate::gmail::imap_client::parse_message(&raw) else {
+            skipped += 1;
+            continue;
+        };
+        if let Some(ref email_message_id) = msg.email_message_id {
+            // Our own export of a message we still hold counts as a duplicate too
+            let exported = mbox::exported_message_id(email_message_id)
+                .map(|id| db.get_message(id).map(|m| m.is_some()))
+                .transpose()?
+                .unwrap_or(false);
+            if exported || db.has_email_message_id(email_message_id)? {
+                skipped += 1;
+                continue;
+            }
+        }
+
+        let date = mailparse::parse_headers(&raw).ok()
+            .and_then(|(headers, _)| headers.iter()
+                .find(|h| h.get_key().eq_ignore_ascii_case("date"))
+                .and_then(|h| mailparse::dateparse(&h.get_value()).ok()));
+        if let Some(date) = date {
+            msg.timestamp = date;
+        }
+        msg.folder = folder.clone();
+        msg.delivery_method = DeliveryMethod::Gmail;
+        msg.encrypted = false;
+        msg.spam_score = None;
+        db.insert_message(&msg)?;
+        imported += 1;
+    }
+    Ok((imported, skipped))
+}
+
+#[get("/api/messages/{id}/attachments/{aid}")]
+pub async fn download_attachment(
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, aid) = path.into_inner();
+    let mut attachment = match state.db.get_attachment(&id, &aid) {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return HttpResponse::NotFound().json(ApiResponse::<()>::err("Attachment not found")),
+        Err(e) => return super::error_response(&e),
+    };
+    // Sent over P2P: fetch it from the sender the first time it's opened
+    if attachment.pending {
+        match fetch_pending_attachment(&state, &attachment).await {
+            Ok(data) => attachment.data = data,
+            Err(e) => return super::error_response(&e),
+        }
+    }
+    HttpResponse::Ok()
+        .content_type(attachment.mime_type.as_str())
+        .insert_header(actix_web::http::header::ContentDisposition::attachment(&attachment.filename))
+        .body(attachment.data)
+}
+
+/// Fetch a pending attachment from its message's sender and keep it
+async fn fetch_pending_attachment(state: &AppState, attachment: &Attachment) -> crate::error::Result<Vec<u8>> {
+    let msg = state.db.get_message(&attachment.message_id)?
+        .ok_or_else(|| LedgerError::not_found(format!("Message {}", attachment.message_id)))?;
+    let data = router::fetch_attachment(&state.db, &state.p2p_tx, &msg.from_id, attachment).await?;
+    state.db.store_attachment_data(&attachment.message_id, &attachment.id, &data)?;
+    tracing::info!("Fetched attachment {} ({} bytes) from {}", attachment.filename, data.len(), msg.from_id);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+
+    use crate::api::test_state;
+
+    #[actix_web::test]
+    async fn test_message_routes_take_non_uuid_ids_and_distinguish_bad_id_missing_and_failure() {
+        let (state, dir) = test_state("ledger_test_api_message_status");
+        let msg = Message::new("ledger:a".into(), "ledger:b".into(), "Hi".into(), "Body".into());
+        state.db.insert_message(&msg).unwrap();
+        // Not a UUID: peers choose envelope and bulletin ids, so these must
+        // still resolve rather than be rejected as bad input
+        let mut chosen = Message::new("ledger:c".into(), "ledger:b".into(), "Hi".into(), "Body".into());
+        chosen.id = "bulletin/2026.1".into();
+        state.db.insert_message(&chosen).unwrap();
+        let app = test::init_service(
+            App::new().app_data(state.clone()).service(get_message).service(delete_message).service(set_read),
+        ).await;
+
+        let missing = uuid::Uuid::new_v4().to_string();
+        let read = serde_json::json!({ "read": true });
+        let cases = [
+            (test::TestRequest::get().uri(&format!("/api/messages/{}", msg.id)), StatusCode::OK),
+            (test::TestRequest::get().uri("/api/messages/bulletin%2F2026.1"), StatusCode::OK),
+            (test::TestRequest::get().uri("/api/messages/bad%0Aid"), StatusCode::BAD_REQUEST),
+            (test::TestRequest::get().uri(&format!("/api/messages/{}", "x".repeat(MAX_MESSAGE_ID_LEN + 1))), StatusCode::BAD_REQUEST),
+            (test::TestRequest::get().uri(&format!("/api/messages/{}", missing)), StatusCode::NOT_FOUND),
+            (test::TestRequest::put().uri("/api/messages/bad%0Aid/read").set_json(&read), StatusCode::BAD_REQUEST),
+            (test::TestRequest::put().uri(&format!("/api/messages/{}/read", missing)).set_json(&read), StatusCode::NOT_FOUND),
+            (test::TestRequest::put().uri(&format!("/api/messages/{}/read", msg.id)).set_json(&read), StatusCode::OK),
+            (test::TestRequest::delete().uri("/api/messages/bad%0Aid"), StatusCode::BAD_REQUEST),
+            (test::TestRequest::delete().uri("/api/messages/bulletin%2F2026.1?permanent=true"), StatusCode::OK),
+            (test::TestRequest::delete().uri(&format!("/api/messages/{}?permanent=true", missing)), StatusCode::NOT_FOUND),
+            (test::TestRequest::delete().uri(&format!("/api/messages/{}?permanent=true", msg.id)), StatusCode::OK),
+        ];
+        for (request, expected) in cases {
+            let request = request.to_request();
+            let uri = request.uri().to_string();
+            let resp = test::call_service(&app, request).await;
+            assert_eq!(resp.status(), expected, "{}", uri);
+        }
+
+        // A broken database is our fault, not the caller's
+        rusqlite::Connection::open(dir.join("ledger.db")).unwrap()
+            .execute_batch("DROP TABLE messages;").unwrap();
+        for request in [
+            test::TestRequest::get().uri(&format!("/api/messages/{}", missing)),
+            test::TestRequest::put().uri(&format!("/api/messages/{}/read", missing)).set_json(&read),
+            test::TestRequest::delete().uri(&format!("/api/messages/{}", missing)),
+        ] {
+            let resp = test::call_service(&app, request.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_list_pages_up_to_the_largest_offset() {
+        let (state, dir) = test_state("ledger_test_api_message_offset");
+        for i in 0..3 {
+            let msg = Message::new("ledger:a".into(), "ledger:b".into(), format!("Hi {}", i), "Body".into());
+            state.db.insert_message(&msg).unwrap();
+        }
+        let app = test::init_service(App::new().app_data(state.clone()).service(list_messages)).await;
+
+        let page: serde_json::Value = test::call_and_read_body_json(
+            &app, test::TestRequest::get().uri("/api/messages?limit=2").to_request(),
+        ).await;
+        assert_eq!(page["data"]["next_offset"], 2);
+
+        let last = format!("/api/messages?offset={}", u32::MAX);
+        let page: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&last).to_request()).await;
+        assert_eq!(page["data"]["has_more"], false);
+        assert!(page["data"]["next_offset"].is_null());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_mark_all_read_rejects_unknown_folder() {
+        let (state, dir) = test_state("ledger_test_api_mark_all_read");
+        let msg = Message::new("ledger:a".into(), "ledger:b".into(), "Hi".into(), "Body".into());
+        state.db.insert_message(&msg).unwrap();
+        let app = test::init_service(App::new().app_data(state.clone()).service(mark_all_read)).await;
+
+        let mark = |folder: &str| test::TestRequest::post().uri("/api/messages/mark-all-read")
+            .set_json(serde_json::json!({ "folder": folder }))
+            .to_request();
+        let resp = test::call_service(&app, mark("inboxx")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!state.db.get_message(&msg.id).unwrap().unwrap().is_read);
+
+        let resp = test::call_service(&app, mark("inbox")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(state.db.get_message(&msg.id).unwrap().unwrap().is_read);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_overall_method() {
+        let deliveries = |methods: &[RecipientMethod]| -> Vec<RecipientDelivery> {
+            methods.iter()
+                .map(|&method| RecipientDelivery { recipient: "someone".into(), method })
+                .collect()
+        };
+        use RecipientMethod::*;
+        assert_eq!(overall_method(&deliveries(&[Failed, Failed])), None);
+        assert_eq!(overall_method(&deliveries(&[Gmail, Failed, Gmail])), Some(DeliveryMethod::Gmail));
+        assert_eq!(overall_method(&deliveries(&[Gmail, Fallback])), Some(DeliveryMethod::Fallback));
+        assert_eq!(overall_method(&deliveries(&[Gmail, P2p])), Some(DeliveryMethod::P2p));
+        assert_eq!(overall_method(&deliveries(&[Fallback, Queued])), Some(DeliveryMethod::P2p));
+    }
+
+    #[actix_web::test]
+    async fn test_send_returns_a_request_id_per_recipient() {
+        let (state, dir) = test_state("ledger_test_api_send_request_id");
+        let app = test::init_service(App::new().app_data(state.clone()).service(send_message)).await;
+
+        // Gmail isn't configured, so every copy fails without touching the network
+        let req = test::TestRequest::post().uri("/api/messages")
+            .set_json(serde_json::json!({
+                "to": ["a@example.com", "b@example.com"], "bcc": ["c@example.com"],
+                "subject": "Hi", "body": "Body", "mode": "gmail_only",
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let ids = resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        let ids: Vec<&str> = ids.split(", ").collect();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.iter().all(|id| id.len() == 8));
+        // To and cc share one email; BCC gets its own
+        assert!(ids[0] == ids[1] && ids[1] != ids[2]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_import_mbox_skips_duplicates() {
+        let (state, dir) = test_state("ledger_test_api_import");
+        let app = test::init_service(App::new().app_data(state.clone()).service(import_messages)).await;
+        let mbox = "From a@example.com Thu Jan  1 00:00:00 1970\n\
+            Message-ID: <one@example.com>\nDate: Mon, 2 Jan 2023 10:00:00 +0000\nFrom: a@example.com\nSubject: One\n\nFirst\n\n\
+            From a@example.com Thu Jan  1 00:00:00 1970\n\
+            Message-ID: <one@example.com>\nFrom: a@example.com\nSubject: One again\n\nSame\n";
+
+        let import = |uri: &str| test::TestRequest::post().uri(uri).set_payload(mbox).to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, import("/api/messages/import?folder=archive")).await;
+        assert_eq!((resp["data"]["imported"].as_u64(), resp["data"]["skipped"].as_u64()), (Some(1), Some(1)));
+
+        let stored = state.db.query_messages(&MessageFilter::folder("archive"), 10, 0).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!((stored[0].subject.as_str(), stored[0].timestamp), ("One", 1672653600));
+        assert_eq!(stored[0].delivery_method, DeliveryMethod::Gmail);
+
+        // Importing the same file again adds nothing
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, import("/api/messages/import")).await;
+        assert_eq!(resp["data"]["imported"].as_u64(), Some(0));
+
+        let resp = test::call_service(&app, import("/api/messages/import?folder=nowhere")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_export_eml_and_mbox() {
+        let (state, dir) = test_state("ledger_test_api_export");
+        for subject in ["First", "Second"] {
+            let msg = Message::new("ledger:a".into(), "ledger:b".into(), subject.into(), "Body".into());
+            state.db.insert_message(&msg).unwrap();
+        }
+        let mut sent = Message::new("ledger:b".into(), "ledger:a".into(), "Elsewhere".into(), "Body".into());
+        sent.folder = Folder::Sent;
+        state.db.insert_message(&sent).unwrap();
+        let app = test::init_service(
+            App::new().app_data(state.clone()).service(export_mbox).service(export_eml),
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/messages/export?folder=inbox").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mbox = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(mbox.matches("\nFrom ").count() + mbox.starts_with("From ") as usize, 2);
+        assert!(mbox.contains("Subject: First") && !mbox.contains("Subject: Elsewhere"));
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/api/messages/{}/eml", sent.id)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let eml = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(eml.contains("Subject: Elsewhere\r\n"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,5 +1,80 @@
+pub mod auth;
+pub mod health;
 pub mod identity;
 pub mod messages;
+pub mod drafts;
+pub mod outbox;
 pub mod peers;
+pub mod blocklist;
 pub mod gmail;
 pub mod settings;
+pub mod dht;
+pub mod broadcast;
+pub mod maintenance;
+pub mod ws;
+
+use actix_web::{http::StatusCode, HttpResponse};
+
+use crate::error::LedgerError;
+use crate::models::message::ApiResponse;
+
+/// HTTP status for a failure from the layers below the API
+pub fn status_for(e: &LedgerError) -> StatusCode {
+    match e {
+        LedgerError::NotFound(_) => StatusCode::NOT_FOUND,
+        LedgerError::InvalidInput(_) | LedgerError::Config(_) => StatusCode::BAD_REQUEST,
+        LedgerError::Network(_) => StatusCode::BAD_GATEWAY,
+        LedgerError::Crypto(_)
+        | LedgerError::Db(_)
+        | LedgerError::Pool(_)
+        | LedgerError::Io(_)
+        | LedgerError::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Error response with the status `e` calls for
+pub fn error_response(e: &LedgerError) -> HttpResponse {
+    HttpResponse::build(status_for(e)).json(ApiResponse::<()>::err(e.to_string()))
+}
+
+/// App state over a fresh database in a temp directory named `name`, for handler tests
+#[cfg(test)]
+pub(crate) fn test_state(name: &str) -> (actix_web::web::Data<crate::AppState>, std::path::PathBuf) {
+    use std::sync::Arc;
+
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    let identity = Arc::new(crate::crypto::keys::LedgerIdentity::generate().unwrap());
+    let (p2p_tx, _) = tokio::sync::mpsc::channel(1);
+    let state = crate::AppState {
+        peer_id: identity.libp2p_keypair().unwrap().public().to_peer_id(),
+        identity,
+        db: Arc::new(crate::store::db::Database::open(&dir).unwrap()),
+        p2p_tx,
+        data_dir: dir.clone(),
+        key_passphrase: None,
+        events: tokio::sync::broadcast::channel(8).0,
+        started_at: std::time::Instant::now(),
+        metrics: Arc::new(crate::metrics::Metrics::new()),
+        api_token: None,
+        online: Default::default(),
+        gmail_configured: Default::default(),
+    };
+    (actix_web::web::Data::new(state), dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::LedgerIdentity;
+
+    #[test]
+    fn test_status_follows_error_kind() {
+        assert_eq!(status_for(&LedgerError::not_found("Message x")), StatusCode::NOT_FOUND);
+        // A malformed Ledger ID is the caller's mistake, not ours
+        let bad_id = LedgerIdentity::pubkey_from_ledger_id("ledger:0OIl").unwrap_err();
+        assert_eq!(status_for(&bad_id), StatusCode::BAD_REQUEST);
+        let db = LedgerError::from(rusqlite::Error::QueryReturnedNoRows);
+        assert_eq!(status_for(&db), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,13 @@
+use actix_web::{web, HttpResponse, get};
+use crate::models::message::*;
+
+use super::super::AppState;
+
+/// Envelopes waiting for a delivery retry, soonest first
+#[get("/api/outbox")]
+pub async fn list_outbox(state: web::Data<AppState>) -> HttpResponse {
+    match state.db.get_outbox() {
+        Ok(entries) => HttpResponse::Ok().json(ApiResponse::ok(entries)),
+        Err(e) => super::error_response(&e),
+    }
+}
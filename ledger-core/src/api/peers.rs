@@ -1,4 +1,4 @@
-use actix_web::{web, HttpResponse, get, post};
+use actix_web::{web, HttpResponse, get, post, delete};
 use crate::models::message::*;
 use crate::p2p::node::P2PCommand;
 use tokio::sync::mpsc;
@@ -38,10 +38,39 @@ pub async fn connect_peer(
         Some(Ok(peer_id)) => {
             HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
                 "peer_id": peer_id.to_string(),
-                "status": "connecting"
+                "status": "connected"
             })))
         }
         Some(Err(e)) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e)),
         None => HttpResponse::InternalServerError().json(ApiResponse::<()>::err("No response")),
     }
 }
+
+/// Drop every connection to a peer. It may reconnect unless it is also blocked.
+#[delete("/api/peers/{peer_id}")]
+pub async fn disconnect_peer(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let peer_id = match path.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Invalid peer ID: {}", e)));
+        }
+    };
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let _ = state.p2p_tx.send(P2PCommand::DisconnectPeer {
+        peer_id,
+        response_tx: Some(tx),
+    }).await;
+
+    match rx.recv().await {
+        Some(true) => HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+            "peer_id": peer_id.to_string(),
+            "status": "disconnected"
+        }))),
+        Some(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Peer not connected")),
+        None => HttpResponse::InternalServerError().json(ApiResponse::<()>::err("No response")),
+    }
+}
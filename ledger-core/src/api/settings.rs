@@ -1,41 +1,103 @@
-use actix_web::{web, HttpResponse, get, put};
+use actix_web::{web, HttpResponse, get, put, delete};
+use crate::crypto::card::ContactCard;
+use crate::crypto::keys::LedgerIdentity;
 use crate::models::message::*;
+use crate::p2p::codec::MAX_MESSAGE_BYTES_CEILING;
+use crate::p2p::node::MAX_REPLAY_WINDOW_HOURS;
 
 use super::super::AppState;
 
-#[get("/api/settings")]
-pub async fn get_settings(state: web::Data<AppState>) -> HttpResponse {
+/// Credentials and keys, which can be written through the API but are never
+/// sent back: whoever reads `tor_onion_key` can impersonate our onion address
+const SECRET_SETTINGS: &[&str] = &[
+    "api_token",
+    "gmail_app_password",
+    "gmail_oauth_client_secret",
+    "gmail_oauth_state",
+    "gmail_access_token",
+    "gmail_refresh_token",
+    "tor_control_password",
+    "tor_onion_key",
+];
+
+/// The settings a client may read
+fn settings_response(state: &AppState) -> HttpResponse {
     match state.db.get_all_settings() {
-        Ok(settings) => HttpResponse::Ok().json(ApiResponse::ok(settings)),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+        Ok(mut settings) => {
+            settings.retain(|key, _| !SECRET_SETTINGS.contains(&key.as_str()));
+            HttpResponse::Ok().json(ApiResponse::ok(settings))
+        }
+        Err(e) => super::error_response(&e),
     }
 }
 
+#[get("/api/settings")]
+pub async fn get_settings(state: web::Data<AppState>) -> HttpResponse {
+    settings_response(&state)
+}
+
 #[put("/api/settings")]
 pub async fn update_settings(
     state: web::Data<AppState>,
     body: web::Json<Settings>,
 ) -> HttpResponse {
+    let bad_request = |msg: String| HttpResponse::BadRequest().json(ApiResponse::<()>::err(msg));
+
+    // Check every field before writing any, so a rejected request changes nothing
     if let Some(ref mode) = body.delivery_mode {
-        if let Err(e) = state.db.set_setting("delivery_mode", mode) {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string()));
+        if DeliveryMode::parse(mode).is_none() {
+            return bad_request(format!("Unknown delivery mode: {}", mode));
         }
     }
-    if let Some(tor) = body.tor_enabled {
-        if let Err(e) = state.db.set_setting("tor_enabled", &tor.to_string()) {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string()));
+    if let Some(ref addr) = body.relay_addr {
+        if !addr.is_empty() && addr.parse::<libp2p::Multiaddr>().is_err() {
+            return bad_request("Invalid relay_addr multiaddr".into());
         }
     }
-    if let Some(ttl) = body.dht_ttl_hours {
-        if let Err(e) = state.db.set_setting("dht_ttl_hours", &ttl.to_string()) {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string()));
+    if body.replay_window_hours.is_some_and(|h| !(1..=MAX_REPLAY_WINDOW_HOURS).contains(&h)) {
+        return bad_request(format!("replay_window_hours must be between 1 and {}", MAX_REPLAY_WINDOW_HOURS));
+    }
+    if body.max_message_bytes.is_some_and(|max| !(1..=MAX_MESSAGE_BYTES_CEILING).contains(&max)) {
+        return bad_request(format!("max_message_bytes must be between 1 and {}", MAX_MESSAGE_BYTES_CEILING));
+    }
+    if let Some(ref policy) = body.receive_policy {
+        if ReceivePolicy::parse(policy).is_none() {
+            return bad_request(format!("Unknown receive policy: {}", policy));
         }
     }
 
-    match state.db.get_all_settings() {
-        Ok(settings) => HttpResponse::Ok().json(ApiResponse::ok(settings)),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+    let changes: Vec<(&str, Option<String>)> = vec![
+        ("delivery_mode", body.delivery_mode.clone()),
+        ("tor_enabled", body.tor_enabled.map(|v| v.to_string())),
+        ("tor_socks_addr", body.tor_socks_addr.clone()),
+        ("tor_onion_service", body.tor_onion_service.map(|v| v.to_string())),
+        ("tor_control_addr", body.tor_control_addr.clone()),
+        ("tor_control_password", body.tor_control_password.clone()),
+        ("relay_addr", body.relay_addr.clone()),
+        ("bootstrap_nodes", body.bootstrap_nodes.clone()),
+        ("dht_ttl_hours", body.dht_ttl_hours.map(|v| v.to_string())),
+        ("replay_window_hours", body.replay_window_hours.map(|v| v.to_string())),
+        ("max_message_bytes", body.max_message_bytes.map(|v| v.to_string())),
+        ("peer_rate_limit", body.peer_rate_limit.map(|v| v.to_string())),
+        ("send_read_receipts", body.send_read_receipts.map(|v| v.to_string())),
+        ("receive_policy", body.receive_policy.clone()),
+        ("nightly_vacuum", body.nightly_vacuum.map(|v| v.to_string())),
+        ("gmail_send_rate", body.gmail_send_rate.map(|v| v.to_string())),
+        ("gmail_mark_seen", body.gmail_mark_seen.map(|v| v.to_string())),
+        ("encrypt_subject", body.encrypt_subject.map(|v| v.to_string())),
+        ("spam_threshold", body.spam_threshold.map(|v| v.to_string())),
+        ("retired_key_days", body.retired_key_days.map(|v| v.to_string())),
+        ("display_name", body.display_name.clone()),
+        ("api_token", body.api_token.clone()),
+    ];
+    let changes: Vec<(&str, String)> = changes.into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect();
+    if let Err(e) = state.db.set_settings(&changes) {
+        return super::error_response(&e);
     }
+
+    settings_response(&state)
 }
 
 /// Contacts API (bonus — needed for P2P to work)
@@ -43,17 +105,348 @@ pub async fn update_settings(
 pub async fn list_contacts(state: web::Data<AppState>) -> HttpResponse {
     match state.db.get_contacts() {
         Ok(contacts) => HttpResponse::Ok().json(ApiResponse::ok(contacts)),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+        Err(e) => super::error_response(&e),
     }
 }
 
 #[actix_web::post("/api/contacts")]
 pub async fn add_contact(
     state: web::Data<AppState>,
-    body: web::Json<Contact>,
+    body: web::Json<AddContactRequest>,
 ) -> HttpResponse {
-    match state.db.upsert_contact(&body) {
+    let body = body.into_inner();
+    let alias = match body.alias.as_deref().map(parse_alias).transpose() {
+        Ok(alias) => alias,
+        Err(response) => return response,
+    };
+    let preferred_delivery = match body.preferred_delivery.as_deref().map(parse_delivery).transpose() {
+        Ok(mode) => mode,
+        Err(response) => return response,
+    };
+    let mut contact = Contact {
+        ledger_id: body.ledger_id,
+        public_key: body.public_key.unwrap_or_default(),
+        encryption_public_key: body.encryption_public_key,
+        display_name: body.display_name,
+        gmail_address: body.gmail_address,
+        verified: false,
+        alias,
+        preferred_delivery,
+    };
+
+    // A Ledger ID encodes the Ed25519 key, and the X25519 key follows from it.
+    // Keys sent along must agree: a substituted encryption key would have mail
+    // sealed to someone else while the safety number still matched.
+    if contact.ledger_id.starts_with("ledger:") {
+        let derived = LedgerIdentity::pubkey_from_ledger_id(&contact.ledger_id)
+            .and_then(|ed_public| {
+                let x25519 = LedgerIdentity::x25519_public_from_ed25519(&ed_public)?;
+                Ok((ed_public, x25519))
+            });
+        let (ed_public, x25519) = match derived {
+            Ok(keys) => keys,
+            Err(e) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Invalid Ledger ID: {}", e))),
+        };
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let supplied_ed = Some(contact.public_key.as_str()).filter(|k| !k.is_empty())
+            .map(|k| bs58::decode(k).into_vec().ok());
+        if supplied_ed.is_some_and(|k| k.as_deref() != Some(&ed_public[..])) {
+            return HttpResponse::BadRequest().json(ApiResponse::<()>::err("public_key doesn't match the Ledger ID"));
+        }
+        let supplied_x25519 = contact.encryption_public_key.as_deref()
+            .map(|k| base64::Engine::decode(&b64, k).ok());
+        if supplied_x25519.is_some_and(|k| k.as_deref() != Some(&x25519[..])) {
+            return HttpResponse::BadRequest().json(ApiResponse::<()>::err("encryption_public_key doesn't match the Ledger ID"));
+        }
+        contact.public_key = bs58::encode(ed_public).into_string();
+        contact.encryption_public_key = Some(base64::Engine::encode(&b64, x25519));
+    } else if contact.public_key.is_empty() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()>::err("public_key is required for non-Ledger contacts"));
+    }
+
+    // Re-adding a contact keeps its verification unless the keys changed
+    match state.db.get_contact(&contact.ledger_id) {
+        Ok(Some(existing)) => {
+            contact.verified = existing.verified
+                && existing.public_key == contact.public_key
+                && existing.encryption_public_key == contact.encryption_public_key;
+            if contact.alias.is_none() {
+                contact.alias = existing.alias;
+            }
+            if contact.preferred_delivery.is_none() {
+                contact.preferred_delivery = existing.preferred_delivery;
+            }
+        }
+        Ok(None) => {}
+        Err(e) => return super::error_response(&e),
+    }
+
+    match state.db.upsert_contact(&contact) {
         Ok(()) => HttpResponse::Ok().json(ApiResponse::ok("Contact added")),
-        Err(e) => HttpResponse::InternalServerError().json(ApiResponse::<()>::err(e.to_string())),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Add or refresh a contact from a card, after checking it is signed by the
+/// identity it describes. An existing contact keeps its alias and verification.
+///
+/// With `?verified=true` the contact is marked verified: a card scanned in
+/// person from the contact's own QR code is as good as comparing safety numbers.
+#[actix_web::post("/api/contacts/import-card")]
+pub async fn import_card(
+    state: web::Data<AppState>,
+    body: web::Json<ImportCardRequest>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let card = match body.into_inner() {
+        ImportCardRequest::Card(card) => Ok(card),
+        ImportCardRequest::Compact { card } => ContactCard::from_compact(&card),
+    };
+    let card = match card.and_then(|card| card.verify().map(|()| card)) {
+        Ok(card) => card,
+        Err(e) => return HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Invalid contact card: {}", e))),
+    };
+
+    let mut contact = card.to_contact();
+    match state.db.get_contact(&contact.ledger_id) {
+        Ok(Some(existing)) => {
+            contact.verified = existing.verified
+                && existing.encryption_public_key == contact.encryption_public_key;
+            contact.alias = existing.alias;
+            contact.preferred_delivery = existing.preferred_delivery;
+        }
+        Ok(None) => {}
+        Err(e) => return super::error_response(&e),
+    }
+
+    if query.get("verified").is_some_and(|v| v == "true") {
+        contact.verified = true;
+    }
+
+    if let Err(e) = state.db.upsert_contact(&contact) {
+        return super::error_response(&e);
+    }
+    HttpResponse::Ok().json(ApiResponse::ok(contact))
+}
+
+#[get("/api/contacts/{ledger_id}")]
+pub async fn get_contact(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match state.db.get_contact(&path.into_inner()) {
+        Ok(Some(contact)) => HttpResponse::Ok().json(ApiResponse::ok(contact)),
+        Ok(None) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Contact not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+#[put("/api/contacts/{ledger_id}")]
+pub async fn update_contact(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<UpdateContactRequest>,
+) -> HttpResponse {
+    let ledger_id = path.into_inner();
+    // An empty alias clears it, like the other fields
+    let alias = match body.alias.as_deref() {
+        Some(alias) if !alias.trim().is_empty() => match parse_alias(alias) {
+            Ok(alias) => Some(alias),
+            Err(response) => return response,
+        },
+        Some(_) => Some(String::new()),
+        None => None,
+    };
+    // Likewise an empty mode goes back to the `delivery_mode` setting
+    if let Some(mode) = body.preferred_delivery.as_deref().filter(|mode| !mode.is_empty()) {
+        if let Err(response) = parse_delivery(mode) {
+            return response;
+        }
+    }
+    let updated = state.db.update_contact(
+        &ledger_id,
+        body.display_name.as_deref(),
+        body.gmail_address.as_deref(),
+        body.verified,
+        alias.as_deref(),
+        body.preferred_delivery.as_deref(),
+    );
+    match updated {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json(ApiResponse::<()>::err("Contact not found")),
+        Err(e) => return super::error_response(&e),
+    }
+
+    match state.db.get_contact(&ledger_id) {
+        Ok(Some(contact)) => HttpResponse::Ok().json(ApiResponse::ok(contact)),
+        Ok(None) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Contact not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// Safety number to compare with a contact out of band before marking them verified
+#[get("/api/contacts/{ledger_id}/safety-number")]
+pub async fn contact_safety_number(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let contact = match state.db.get_contact(&path.into_inner()) {
+        Ok(Some(contact)) => contact,
+        Ok(None) => return HttpResponse::NotFound().json(ApiResponse::<()>::err("Contact not found")),
+        Err(e) => return super::error_response(&e),
+    };
+    let pubkey = match LedgerIdentity::pubkey_from_ledger_id(&contact.ledger_id) {
+        Ok(pubkey) => pubkey,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(ApiResponse::<()>::err("Safety numbers are only available for Ledger ID contacts"));
+        }
+    };
+
+    HttpResponse::Ok().json(ApiResponse::ok(serde_json::json!({
+        "ledger_id": contact.ledger_id,
+        "safety_number": state.identity.safety_number(&pubkey),
+        "verified": contact.verified,
+    })))
+}
+
+/// Remove a contact; their messages stay where they are
+#[delete("/api/contacts/{ledger_id}")]
+pub async fn delete_contact(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match state.db.delete_contact(&path.into_inner()) {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::ok("Contact deleted")),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()>::err("Contact not found")),
+        Err(e) => super::error_response(&e),
+    }
+}
+
+/// An alias from a request in its stored form, or a 400 saying what's allowed
+fn parse_alias(alias: &str) -> Result<String, HttpResponse> {
+    Contact::normalize_alias(alias).ok_or_else(|| HttpResponse::BadRequest().json(ApiResponse::<()>::err(
+        format!("Invalid alias {:?}: use up to 32 letters, digits, '.', '_' or '-'", alias),
+    )))
+}
+
+/// A delivery mode from a request, or a 400 naming the unknown one
+fn parse_delivery(mode: &str) -> Result<DeliveryMode, HttpResponse> {
+    DeliveryMode::parse(mode).ok_or_else(|| {
+        HttpResponse::BadRequest().json(ApiResponse::<()>::err(format!("Unknown delivery mode: {}", mode)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{http::StatusCode, App};
+
+    #[actix_web::test]
+    async fn test_settings_never_return_secrets() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_settings_secrets");
+        for key in SECRET_SETTINGS {
+            state.db.set_setting(key, "secret").unwrap();
+        }
+        let app = init_service(App::new().app_data(state.clone()).service(get_settings).service(update_settings)).await;
+
+        let update = serde_json::json!({ "tor_control_password": "hunter2", "api_token": "token" });
+        for request in [TestRequest::get().uri("/api/settings"), TestRequest::put().uri("/api/settings").set_json(update)] {
+            let body: serde_json::Value = call_and_read_body_json(&app, request.to_request()).await;
+            let settings = &body["data"];
+            assert_eq!(settings["delivery_mode"], "auto");
+            for key in SECRET_SETTINGS {
+                assert!(settings.get(key).is_none(), "{} was returned", key);
+            }
+        }
+        assert_eq!(state.db.get_setting("tor_control_password").unwrap().as_deref(), Some("hunter2"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_update_gmail_mark_seen() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_settings_mark_seen");
+        let app = init_service(App::new().app_data(state.clone()).service(update_settings)).await;
+
+        let update = serde_json::json!({ "gmail_mark_seen": true });
+        let body: serde_json::Value = call_and_read_body_json(
+            &app,
+            TestRequest::put().uri("/api/settings").set_json(update).to_request(),
+        ).await;
+        assert_eq!(body["data"]["gmail_mark_seen"], "true");
+        assert_eq!(state.db.get_setting("gmail_mark_seen").unwrap().as_deref(), Some("true"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_rejected_update_changes_nothing() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_settings_rejected");
+        let app = init_service(App::new().app_data(state.clone()).service(update_settings)).await;
+        let before = state.db.get_all_settings().unwrap();
+
+        for bad in [
+            serde_json::json!({ "delivery_mode": "p2p_only", "tor_enabled": true, "relay_addr": "not a multiaddr" }),
+            serde_json::json!({ "delivery_mode": "p2p_only", "max_message_bytes": 0 }),
+            serde_json::json!({ "delivery_mode": "p2p_only", "max_message_bytes": u64::MAX }),
+            serde_json::json!({ "tor_enabled": true, "receive_policy": "nobody" }),
+        ] {
+            let resp = call_service(&app, TestRequest::put().uri("/api/settings").set_json(&bad).to_request()).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST, "{}", bad);
+            assert_eq!(state.db.get_all_settings().unwrap(), before, "{}", bad);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_replay_window_bounded() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_settings_replay_window");
+        let app = init_service(App::new().app_data(state.clone()).service(update_settings)).await;
+
+        for (hours, expected) in [
+            (0, StatusCode::BAD_REQUEST),
+            (MAX_REPLAY_WINDOW_HOURS + 1, StatusCode::BAD_REQUEST),
+            (i64::MAX as u64, StatusCode::BAD_REQUEST),
+            (MAX_REPLAY_WINDOW_HOURS, StatusCode::OK),
+        ] {
+            let update = serde_json::json!({ "replay_window_hours": hours });
+            let resp = call_service(&app, TestRequest::put().uri("/api/settings").set_json(update).to_request()).await;
+            assert_eq!(resp.status(), expected, "{}", hours);
+        }
+        assert_eq!(
+            state.db.get_setting("replay_window_hours").unwrap(),
+            Some(MAX_REPLAY_WINDOW_HOURS.to_string()),
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn test_add_contact_keys_follow_the_ledger_id() {
+        let (state, dir) = crate::api::test_state("ledger_test_api_contact_keys");
+        let app = init_service(App::new().app_data(state.clone()).service(add_contact)).await;
+        let contact = LedgerIdentity::generate().unwrap();
+        let other = LedgerIdentity::generate().unwrap();
+        let b64 = |key: Vec<u8>| base64::Engine::encode(&base64::engine::general_purpose::STANDARD, key);
+
+        let substituted = serde_json::json!({
+            "ledger_id": contact.ledger_id,
+            "encryption_public_key": b64(other.encryption_public_bytes()),
+        });
+        let resp = call_service(&app, TestRequest::post().uri("/api/contacts").set_json(substituted).to_request()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        assert!(state.db.get_contact(&contact.ledger_id).unwrap().is_none());
+
+        for key in [None, Some(b64(contact.encryption_public_bytes()))] {
+            let body = serde_json::json!({ "ledger_id": contact.ledger_id, "encryption_public_key": key });
+            let resp = call_service(&app, TestRequest::post().uri("/api/contacts").set_json(body).to_request()).await;
+            assert!(resp.status().is_success());
+            let stored = state.db.get_contact(&contact.ledger_id).unwrap().unwrap();
+            assert_eq!(stored.encryption_public_key, Some(b64(contact.encryption_public_bytes())));
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
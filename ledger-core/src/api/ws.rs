@@ -0,0 +1,103 @@
+use actix_web::{web, HttpRequest, HttpResponse, get};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use crate::models::message::MessageEvent;
+
+use super::super::AppState;
+
+/// How often idle WebSocket clients are pinged
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Subprotocol accepted when a client offers it. A browser passing the API
+/// token as a `bearer.<token>` subprotocol offers this too, since the server
+/// has to pick one of the offered subprotocols and must never echo the token.
+const WS_PROTOCOL: &str = "ledger";
+
+/// Push `MessageEvent`s to the client as JSON text frames, starting with a
+/// `presence` event for each contact already online
+#[get("/api/ws")]
+pub async fn events_ws(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (mut response, mut session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    if offers_protocol(&req) {
+        response.headers_mut().insert(
+            actix_web::http::header::SEC_WEBSOCKET_PROTOCOL,
+            actix_web::http::header::HeaderValue::from_static(WS_PROTOCOL),
+        );
+    }
+    let mut events = state.events.subscribe();
+    // Subscribed first, so a change between the two shows up as an event
+    let online: Vec<MessageEvent> = state.online.online().into_iter()
+        .map(|ledger_id| MessageEvent::Presence { ledger_id, online: true })
+        .collect();
+
+    actix_web::rt::spawn(async move {
+        for event in &online {
+            if send_event(&mut session, event).await.is_err() {
+                return;
+            }
+        }
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if session.ping(b"").await.is_err() {
+                        break;
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if send_event(&mut session, &event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("WebSocket client lagged, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Close(reason))) => {
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break,
+                },
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+/// Whether the handshake offers the `ledger` subprotocol
+fn offers_protocol(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|protocols| protocols.split(',').any(|protocol| protocol.trim() == WS_PROTOCOL))
+}
+
+/// Send one event as a JSON text frame. Only a closed session is an error;
+/// an event that won't serialize is logged and skipped.
+async fn send_event(session: &mut actix_ws::Session, event: &MessageEvent) -> Result<(), actix_ws::Closed> {
+    match serde_json::to_string(event) {
+        Ok(json) => session.text(json).await,
+        Err(e) => {
+            tracing::error!("Failed to serialize event: {}", e);
+            Ok(())
+        }
+    }
+}
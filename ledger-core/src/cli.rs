@@ -0,0 +1,381 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::api;
+use crate::config::FileConfig;
+use crate::fallback::router;
+use crate::metrics::Metrics;
+use crate::models::message::{ContentType, DeliveryMode, OutgoingContent, Recipients};
+use crate::p2p::node::P2PCommand;
+use crate::p2p::presence::OnlineContacts;
+use crate::p2p::protocol::{Protocols, DEFAULT_NETWORK_ID};
+use crate::AppState;
+
+const DEFAULT_P2P_PORT: u16 = 9420;
+const DEFAULT_API_PORT: u16 = 8420;
+
+/// Argument ids of `ServeArgs`, as flattened into `Cli`
+const SERVE_ARG_IDS: &[&str] = &["port", "bind", "tls_cert", "tls_key", "api_token"];
+
+/// Ledger Core — Decentralized Encrypted Mail Engine
+#[derive(Parser, Debug)]
+#[command(name = "ledger-core", version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub node: NodeArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Options for `serve` when it runs as the default, i.e. without a subcommand
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+impl Cli {
+    /// Parse the command line into the node options and the command to run,
+    /// exiting with a usage error as clap would
+    pub fn node_and_command() -> (NodeArgs, Command) {
+        let matches = Cli::command().get_matches();
+        Cli::resolve(&matches).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Serve options given before the subcommand apply to `serve`, under any
+    /// given after it, and are an error before any other subcommand
+    fn resolve(matches: &ArgMatches) -> Result<(NodeArgs, Command), clap::Error> {
+        let cli = Cli::from_arg_matches(matches)?;
+        let command = match cli.command {
+            None => Command::Serve(cli.serve),
+            Some(Command::Serve(args)) => Command::Serve(args.or(cli.serve)),
+            Some(command) => {
+                let given: Vec<String> = SERVE_ARG_IDS.iter()
+                    .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+                    .map(|id| format!("--{}", id.replace('_', "-")))
+                    .collect();
+                if !given.is_empty() {
+                    return Err(Cli::command().error(
+                        ErrorKind::ArgumentConflict,
+                        format!("{} only apply to `serve`", given.join(", ")),
+                    ));
+                }
+                command
+            }
+        };
+        Ok((cli.node, command))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the node and REST API (the default)
+    Serve(ServeArgs),
+    /// Send one message, print how each recipient was reached, and exit
+    Send(SendArgs),
+    /// Print this node's Ledger ID, public keys and peer ID
+    Identity,
+    /// Start the node, wait for connections and print the connected peers
+    Peers(PeersArgs),
+}
+
+/// Options every command that opens the node shares
+#[derive(Args, Debug)]
+pub struct NodeArgs {
+    /// libp2p swarm port [default: 9420]
+    #[arg(long, global = true)]
+    pub p2p_port: Option<u16>,
+
+    /// Data directory, holding the identity, database and an optional `ledger.toml`
+    #[arg(long, global = true)]
+    pub data_dir: Option<String>,
+
+    /// Write logs to `ledger.log` in this directory instead of the terminal
+    #[arg(long, global = true)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Passphrase protecting the identity key file (empty keeps it unencrypted)
+    #[arg(long, global = true, env = "LEDGER_PASSPHRASE", hide_env_values = true)]
+    pub passphrase: Option<String>,
+
+    /// Encrypt the database with SQLCipher under --passphrase (an existing database is converted)
+    #[arg(long, global = true)]
+    pub encrypt_db: bool,
+
+    /// Kademlia bootstrap node, e.g. /ip4/1.2.3.4/tcp/9420/p2p/12D3Koo... (repeatable)
+    #[arg(long = "bootstrap", global = true)]
+    pub bootstrap: Vec<libp2p::Multiaddr>,
+
+    /// Network to join; nodes only talk to peers on the same one [default: mainnet]
+    #[arg(long, global = true)]
+    pub network_id: Option<String>,
+
+    /// Options from `ledger.toml`, filled in by `load_config`
+    #[arg(skip)]
+    pub config: FileConfig,
+}
+
+impl NodeArgs {
+    /// `--data-dir`, or `ledger` under the platform's local data directory
+    pub fn data_dir(&self) -> PathBuf {
+        match self.data_dir {
+            Some(ref dir) => PathBuf::from(dir),
+            None => dirs::data_local_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("ledger"),
+        }
+    }
+
+    /// Read `ledger.toml` from the data directory, using its values for flags not given
+    pub fn load_config(&mut self) -> crate::error::Result<()> {
+        let config = FileConfig::load(&self.data_dir())?;
+        self.p2p_port = self.p2p_port.or(config.p2p_port);
+        self.log_dir = self.log_dir.take().or_else(|| config.log_dir.clone());
+        if self.bootstrap.is_empty() {
+            self.bootstrap = config.bootstrap.clone();
+        }
+        self.network_id = self.network_id.take().or_else(|| config.network_id.clone());
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn p2p_port(&self) -> u16 {
+        self.p2p_port.unwrap_or(DEFAULT_P2P_PORT)
+    }
+
+    /// Protocol names for `--network-id`
+    pub fn protocols(&self) -> crate::error::Result<Protocols> {
+        Protocols::for_network(self.network_id.as_deref().unwrap_or(DEFAULT_NETWORK_ID))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// REST API port [default: 8420]
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Address the REST API binds to [default: 127.0.0.1]
+    #[arg(long)]
+    pub bind: Option<std::net::IpAddr>,
+
+    /// PEM certificate chain; serves the API over HTTPS together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Require `Authorization: Bearer <token>` on the REST API (overrides the `api_token` setting)
+    #[arg(long, env = "LEDGER_API_TOKEN", hide_env_values = true)]
+    pub api_token: Option<String>,
+}
+
+impl ServeArgs {
+    /// These options, with any not given taken from `other`
+    fn or(self, other: ServeArgs) -> Self {
+        Self {
+            port: self.port.or(other.port),
+            bind: self.bind.or(other.bind),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            api_token: self.api_token.or(other.api_token),
+        }
+    }
+
+    /// Use `ledger.toml` values for flags not given
+    pub fn apply_config(&mut self, config: &FileConfig) {
+        self.port = self.port.or(config.port);
+        self.bind = self.bind.or(config.bind);
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_API_PORT)
+    }
+
+    pub fn bind(&self) -> std::net::IpAddr {
+        self.bind.unwrap_or(std::net::Ipv4Addr::LOCALHOST.into())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SendArgs {
+    /// Recipient Ledger ID or email address (repeatable)
+    #[arg(long, required = true)]
+    pub to: Vec<String>,
+
+    /// Copy recipient (repeatable)
+    #[arg(long)]
+    pub cc: Vec<String>,
+
+    /// Blind copy recipient, hidden from everyone else (repeatable)
+    #[arg(long)]
+    pub bcc: Vec<String>,
+
+    #[arg(long, default_value = "")]
+    pub subject: String,
+
+    /// Message body; `-` reads it from stdin
+    #[arg(long)]
+    pub body: String,
+
+    /// Send the body as HTML
+    #[arg(long)]
+    pub html: bool,
+
+    /// "p2p_only", "gmail_only" or "auto"; when omitted, each recipient's
+    /// contact preference and then the `delivery_mode` setting
+    #[arg(long)]
+    pub mode: Option<String>,
+
+    /// Ask Ledger recipients for a read receipt
+    #[arg(long)]
+    pub read_receipt: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct PeersArgs {
+    /// Seconds to wait for connections before listing
+    #[arg(long, default_value_t = 10)]
+    pub wait: u64,
+}
+
+/// Start just the P2P node, without the REST API or background workers
+async fn start_headless(node: &NodeArgs) -> Result<AppState, Box<dyn std::error::Error>> {
+    let (data_dir, identity) = crate::open_identity(node)?;
+    let db = crate::open_database(node, &data_dir)?;
+    let identity = crate::add_retired_keys(node, identity, &db)?;
+    let (events, _) = broadcast::channel(16);
+    let metrics = Arc::new(Metrics::new());
+    let online = Arc::new(OnlineContacts::default());
+    let (p2p_tx, peer_id) = crate::start_p2p(node, identity.clone(), db.clone(), events.clone(), metrics.clone(), online.clone()).await?;
+
+    Ok(AppState {
+        identity,
+        db,
+        p2p_tx,
+        peer_id,
+        data_dir,
+        key_passphrase: node.passphrase.clone().filter(|p| !p.is_empty()),
+        events,
+        started_at: std::time::Instant::now(),
+        metrics,
+        api_token: None,
+        online,
+        gmail_configured: Default::default(),
+    })
+}
+
+/// `ledger-core send`: route one message, report each recipient, then shut
+/// the node down as `serve` does, so DHT puts leave before it exits.
+/// Envelopes that only made it into the outbox go out the next time `serve` runs.
+pub async fn send(node: &NodeArgs, args: SendArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let body = if args.body == "-" {
+        let mut body = String::new();
+        std::io::stdin().read_to_string(&mut body)?;
+        body
+    } else {
+        args.body
+    };
+    let content = OutgoingContent {
+        subject: args.subject,
+        body,
+        content_type: if args.html { ContentType::Html } else { ContentType::Text },
+        read_receipt_requested: args.read_receipt,
+        ..Default::default()
+    };
+
+    let mode = args.mode.as_deref()
+        .map(|mode| DeliveryMode::parse(mode).ok_or_else(|| format!("Unknown delivery mode: {}", mode)))
+        .transpose()?;
+
+    let state = start_headless(node).await?;
+    let sent = deliver(&state, &Recipients { to: args.to, cc: args.cc, bcc: args.bcc }, &content, mode).await;
+    // Even a failed send may have left envelopes in the DHT to flush
+    crate::shut_down(&state.p2p_tx, &state.db).await?;
+    sent
+}
+
+async fn deliver(
+    state: &AppState,
+    recipients: &Recipients,
+    content: &OutgoingContent,
+    mode: Option<DeliveryMode>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let recipients = router::parse_recipients(&state.db, recipients)?;
+    let delivery = api::messages::deliver(state, uuid::Uuid::new_v4().to_string(), &recipients, content, mode).await;
+    for (to, outcome) in &delivery.results {
+        println!("{}: {} [request {}]", to, outcome.result, outcome.request_id);
+    }
+    match delivery.message {
+        Some(msg) => {
+            println!("Stored in Sent as {}", msg.id);
+            Ok(())
+        }
+        None => Err("No recipient could be reached".into()),
+    }
+}
+
+/// `ledger-core identity`: print the identity as JSON, without starting the
+/// node. Unlike the other commands it never creates one.
+pub fn identity(node: &NodeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let identity = crate::crypto::keys::LedgerIdentity::load(&node.data_dir(), node.passphrase.as_deref())?;
+    let peer_id = identity.libp2p_keypair()?.public().to_peer_id();
+    let info = api::identity::identity_info(&identity, peer_id);
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+/// `ledger-core peers`: run the node for a while and print who it connected to
+pub async fn peers(node: &NodeArgs, args: PeersArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let state = start_headless(node).await?;
+    tokio::time::sleep(Duration::from_secs(args.wait)).await;
+
+    let (tx, mut rx) = mpsc::channel(1);
+    state.p2p_tx.send(P2PCommand::GetPeers { response_tx: tx }).await?;
+    let peers = rx.recv().await.unwrap_or_default();
+    crate::shut_down(&state.p2p_tx, &state.db).await?;
+    println!("{}", serde_json::to_string_pretty(&peers)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str]) -> Result<(NodeArgs, Command), clap::Error> {
+        let matches = Cli::command().try_get_matches_from(std::iter::once("ledger-core").chain(args.iter().copied()))?;
+        Cli::resolve(&matches)
+    }
+
+    #[test]
+    fn test_serve_options_before_serve_apply() {
+        for args in [&["--port", "9999"][..], &["--port", "9999", "serve"], &["--port", "1", "serve", "--port", "9999"]] {
+            match resolve(args).unwrap().1 {
+                Command::Serve(serve) => assert_eq!(serve.port(), 9999, "{:?}", args),
+                other => panic!("{:?} resolved to {:?}", args, other),
+            }
+        }
+        let (_, command) = resolve(&["--bind", "0.0.0.0", "serve", "--port", "9999"]).unwrap();
+        assert!(matches!(command, Command::Serve(ref serve) if serve.port() == 9999 && serve.bind().is_unspecified()));
+    }
+
+    #[test]
+    fn test_serve_options_rejected_before_other_commands() {
+        let err = resolve(&["--port", "9999", "--bind", "0.0.0.0", "--data-dir", "/tmp/x", "identity"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
+        assert!(err.to_string().contains("--port, --bind"));
+        assert!(resolve(&["--api-token", "t", "peers"]).is_err());
+
+        // Options every command shares are fine anywhere
+        let (node, command) = resolve(&["--data-dir", "/tmp/x", "identity"]).unwrap();
+        assert!(matches!(command, Command::Identity));
+        assert_eq!(node.data_dir(), PathBuf::from("/tmp/x"));
+    }
+}
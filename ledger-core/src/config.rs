@@ -0,0 +1,116 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{LedgerError, Result};
+use crate::models::message::DeliveryMode;
+use crate::store::db::Database;
+
+/// Name of the config file looked for in the data directory
+pub const CONFIG_FILE: &str = "ledger.toml";
+
+/// Options read from `ledger.toml`. Command-line flags take precedence; the
+/// delivery, Tor and Gmail options are written to the stored settings at startup.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub port: Option<u16>,
+    pub p2p_port: Option<u16>,
+    pub bind: Option<IpAddr>,
+    #[serde(default)]
+    pub bootstrap: Vec<libp2p::Multiaddr>,
+    pub delivery_mode: Option<String>,
+    pub tor_enabled: Option<bool>,
+    pub tor_socks_addr: Option<String>,
+    pub tor_onion_service: Option<bool>,
+    pub tor_control_addr: Option<String>,
+    pub gmail_mark_seen: Option<bool>,
+    /// Write logs to `ledger.log` here instead of the terminal
+    pub log_dir: Option<PathBuf>,
+    /// Network to join when `--network-id` isn't given
+    pub network_id: Option<String>,
+}
+
+impl FileConfig {
+    /// Read `ledger.toml` from `data_dir`; no file means no overrides
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(CONFIG_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text)
+                .map_err(|e| LedgerError::config(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text).map_err(LedgerError::config)?;
+        if let Some(ref mode) = config.delivery_mode {
+            if DeliveryMode::parse(mode).is_none() {
+                return Err(LedgerError::config(format!("Unknown delivery mode: {}", mode)));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Store the file's delivery, Tor and Gmail options as settings
+    pub fn apply_settings(&self, db: &Database) -> Result<()> {
+        if let Some(ref mode) = self.delivery_mode {
+            db.set_setting("delivery_mode", mode)?;
+        }
+        if let Some(tor) = self.tor_enabled {
+            db.set_setting("tor_enabled", &tor.to_string())?;
+        }
+        if let Some(ref addr) = self.tor_socks_addr {
+            db.set_setting("tor_socks_addr", addr)?;
+        }
+        if let Some(onion) = self.tor_onion_service {
+            db.set_setting("tor_onion_service", &onion.to_string())?;
+        }
+        if let Some(ref addr) = self.tor_control_addr {
+            db.set_setting("tor_control_addr", addr)?;
+        }
+        if let Some(mark_seen) = self.gmail_mark_seen {
+            db.set_setting("gmail_mark_seen", &mark_seen.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = FileConfig::parse(r#"
+            port = 8500
+            bind = "0.0.0.0"
+            bootstrap = ["/ip4/192.0.2.1/tcp/9420"]
+            delivery_mode = "p2p_only"
+            tor_enabled = true
+            tor_onion_service = true
+            gmail_mark_seen = true
+            network_id = "testnet"
+        "#).unwrap();
+        assert_eq!(config.port, Some(8500));
+        assert_eq!(config.p2p_port, None);
+        assert_eq!(config.bind, Some("0.0.0.0".parse().unwrap()));
+        assert_eq!(config.bootstrap.len(), 1);
+        assert_eq!(config.tor_onion_service, Some(true));
+        assert_eq!(config.gmail_mark_seen, Some(true));
+        assert_eq!(config.network_id.as_deref(), Some("testnet"));
+
+        // Typos shouldn't be silently ignored
+        assert!(FileConfig::parse("prot = 8500").is_err());
+        assert!(FileConfig::parse(r#"delivery_mode = "fast""#).is_err());
+    }
+
+    #[test]
+    fn test_missing_file_is_empty() {
+        let dir = std::env::temp_dir().join("ledger_test_config_missing");
+        let config = FileConfig::load(&dir).unwrap();
+        assert!(config.port.is_none() && config.bootstrap.is_empty());
+    }
+}
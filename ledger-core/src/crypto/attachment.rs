@@ -0,0 +1,106 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::error::{LedgerError, Result};
+
+/// Plaintext bytes in each chunk of an attachment sent over P2P
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest attachment sent or fetched over P2P. Downloads are held in memory,
+/// so a sender can't make us fetch more than this by claiming a bigger size.
+pub const MAX_ATTACHMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+const NONCE_LEN: usize = 24;
+
+/// Hex SHA-256 of an attachment's contents, which is how envelopes and
+/// chunk requests refer to it
+pub fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Hex SHA-256 of a transfer key. Chunk requests carry it, since copies of the
+/// same contents can be under different keys, and the holder has to seal with
+/// the one the requester has.
+pub fn key_id(key: &[u8]) -> String {
+    hex::encode(Sha256::digest(key))
+}
+
+/// A random key for an attachment's chunks, sealed into the envelopes that
+/// reference it
+pub fn new_transfer_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// How many chunks an attachment of `size` bytes is sent in, if that fits a `u32`
+pub fn chunk_count(size: u64) -> Option<u32> {
+    u32::try_from(size.div_ceil(CHUNK_SIZE as u64)).ok()
+}
+
+/// Encrypt chunk `index` of the attachment hashing to `sha256`, returning the
+/// nonce followed by the ciphertext. The hash and index are associated data,
+/// so a chunk can't be passed off as another attachment's or moved.
+pub fn seal_chunk(key: &[u8], sha256: &str, index: u32, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher(key)?
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad: &chunk_aad(sha256, index) })
+        .map_err(|e| LedgerError::crypto(format!("Encryption error: {}", e)))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypt a chunk sealed by `seal_chunk`
+pub fn open_chunk(key: &[u8], sha256: &str, index: u32, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(LedgerError::crypto("Chunk is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher(key)?
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &chunk_aad(sha256, index) })
+        .map_err(|e| LedgerError::crypto(format!("Decryption error: {}", e)))
+}
+
+fn cipher(key: &[u8]) -> Result<XChaCha20Poly1305> {
+    XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|e| LedgerError::crypto(format!("Cipher init error: {}", e)))
+}
+
+fn chunk_aad(sha256: &str, index: u32) -> Vec<u8> {
+    let mut aad = sha256.as_bytes().to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_roundtrip_is_bound_to_hash_and_index() {
+        let key = new_transfer_key();
+        let hash = content_hash(b"hello");
+        let sealed = seal_chunk(&key, &hash, 3, b"hello").unwrap();
+
+        assert_eq!(open_chunk(&key, &hash, 3, &sealed).unwrap(), b"hello");
+        assert!(open_chunk(&key, &hash, 4, &sealed).is_err());
+        assert!(open_chunk(&key, &content_hash(b"other"), 3, &sealed).is_err());
+        assert!(open_chunk(&new_transfer_key(), &hash, 3, &sealed).is_err());
+        assert!(open_chunk(&key, &hash, 3, &sealed[..10]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_count() {
+        assert_eq!(chunk_count(0), Some(0));
+        assert_eq!(chunk_count(1), Some(1));
+        assert_eq!(chunk_count(CHUNK_SIZE as u64), Some(1));
+        assert_eq!(chunk_count(CHUNK_SIZE as u64 + 1), Some(2));
+        assert_eq!(chunk_count(u64::MAX), None);
+    }
+}
@@ -0,0 +1,151 @@
+use base64::{Engine as _, engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD}};
+use serde::{Deserialize, Serialize};
+
+use super::keys::LedgerIdentity;
+use crate::models::message::Contact;
+
+/// Prefix of a card's compact form, so a scanned QR code is recognisable
+const COMPACT_PREFIX: &str = "ledger-card:";
+
+/// Contact details for sharing, signed by the identity they describe
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ContactCard {
+    pub ledger_id: String,
+    /// Base64 X25519 key to encrypt to; must be the one the Ledger ID implies
+    pub encryption_pubkey: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub gmail_address: Option<String>,
+    /// Base64 Ed25519 signature by the Ledger ID's key over every other field
+    pub signature: String,
+}
+
+impl ContactCard {
+    /// Build and sign this node's card. Empty names and addresses are left out.
+    pub fn new(identity: &LedgerIdentity, display_name: Option<&str>, gmail_address: Option<&str>) -> Self {
+        let present = |value: Option<&str>| value.map(str::trim).filter(|v| !v.is_empty()).map(String::from);
+        let mut card = Self {
+            ledger_id: identity.ledger_id.clone(),
+            encryption_pubkey: BASE64.encode(identity.encryption_public_bytes()),
+            display_name: present(display_name),
+            gmail_address: present(gmail_address),
+            signature: String::new(),
+        };
+        card.signature = BASE64.encode(identity.sign(&card.signing_payload()));
+        card
+    }
+
+    /// Check the card is signed by the Ledger ID it names and carries that
+    /// identity's encryption key
+    pub fn verify(&self) -> Result<(), String> {
+        let pubkey = LedgerIdentity::pubkey_from_ledger_id(&self.ledger_id)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        let expected_key = LedgerIdentity::x25519_public_from_ed25519(&pubkey)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        if BASE64.decode(&self.encryption_pubkey).ok().as_deref() != Some(&expected_key[..]) {
+            return Err("encryption key doesn't match the Ledger ID".into());
+        }
+
+        let signature = BASE64.decode(&self.signature)
+            .map_err(|e| format!("invalid signature encoding: {}", e))?;
+        match LedgerIdentity::verify(&pubkey, &self.signing_payload(), &signature) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("bad signature".into()),
+            Err(e) => Err(format!("bad signature: {}", e)),
+        }
+    }
+
+    /// `ledger-card:` and the card's JSON as unpadded URL-safe base64, short
+    /// enough for a QR code
+    pub fn to_compact(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        format!("{}{}", COMPACT_PREFIX, URL_SAFE_NO_PAD.encode(json))
+    }
+
+    /// Decode a card's compact form. The card still needs verifying.
+    pub fn from_compact(compact: &str) -> Result<Self, String> {
+        let encoded = compact.trim().strip_prefix(COMPACT_PREFIX)
+            .ok_or_else(|| format!("card must start with {:?}", COMPACT_PREFIX))?;
+        let json = URL_SAFE_NO_PAD.decode(encoded).map_err(|e| format!("invalid card encoding: {}", e))?;
+        serde_json::from_slice(&json).map_err(|e| format!("invalid card: {}", e))
+    }
+
+    /// The card as a contact, unverified and without an alias or delivery preference
+    pub fn to_contact(&self) -> Contact {
+        let public_key = LedgerIdentity::pubkey_from_ledger_id(&self.ledger_id)
+            .map(|pubkey| bs58::encode(pubkey).into_string())
+            .unwrap_or_default();
+        Contact {
+            ledger_id: self.ledger_id.clone(),
+            public_key,
+            encryption_public_key: Some(self.encryption_pubkey.clone()),
+            display_name: self.display_name.clone(),
+            gmail_address: self.gmail_address.clone(),
+            verified: false,
+            alias: None,
+            preferred_delivery: None,
+        }
+    }
+
+    /// Domain tag followed by each field length-prefixed, as for bulletins
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut out = b"ledger-card".to_vec();
+        let fields = [
+            self.ledger_id.as_str(),
+            self.encryption_pubkey.as_str(),
+            self.display_name.as_deref().unwrap_or_default(),
+            self.gmail_address.as_deref().unwrap_or_default(),
+        ];
+        for field in fields {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_card_verifies_and_round_trips_compact() {
+        let alice = LedgerIdentity::generate().unwrap();
+        let card = ContactCard::new(&alice, Some("Alice"), Some(""));
+        assert_eq!(card.gmail_address, None);
+        assert!(card.verify().is_ok());
+
+        let compact = card.to_compact();
+        assert!(compact.starts_with("ledger-card:"));
+        assert_eq!(ContactCard::from_compact(&compact).unwrap(), card);
+        assert!(ContactCard::from_compact("ledger-card:!!").is_err());
+
+        let contact = card.to_contact();
+        assert_eq!(contact.public_key, bs58::encode(alice.public_key_bytes()).into_string());
+        assert!(!contact.verified);
+    }
+
+    #[test]
+    fn test_card_rejects_tampering_and_mismatched_keys() {
+        let alice = LedgerIdentity::generate().unwrap();
+        let mallory = LedgerIdentity::generate().unwrap();
+        let card = ContactCard::new(&alice, Some("Alice"), None);
+
+        let mut renamed = card.clone();
+        renamed.display_name = Some("Bob".into());
+        assert_eq!(renamed.verify(), Err("bad signature".into()));
+
+        // Mallory's key under Alice's Ledger ID is caught before the signature
+        let mut swapped = card.clone();
+        swapped.encryption_pubkey = BASE64.encode(mallory.encryption_public_bytes());
+        assert!(swapped.verify().unwrap_err().contains("doesn't match"));
+
+        // Nor can Mallory pass off a card from their own key as Alice's
+        let mut forged = ContactCard::new(&mallory, Some("Alice"), None);
+        forged.ledger_id = alice.ledger_id.clone();
+        forged.encryption_pubkey = card.encryption_pubkey.clone();
+        assert!(forged.verify().is_err());
+    }
+}
@@ -1,23 +1,239 @@
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
-    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
 };
+use ed25519_dalek::VerifyingKey;
 use hkdf::Hkdf;
 use rand::RngCore;
 use sha2::Sha256;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 
+use super::key_cache::VerifyingKeyCache;
 use super::keys::LedgerIdentity;
-use crate::models::message::EncryptedEnvelope;
+use crate::error::{LedgerError, Result};
+use crate::models::message::{Attachment, AttachmentRef, EncryptedEnvelope, OutgoingContent, WrappedKey};
+
+/// What a decrypted envelope says
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenedEnvelope {
+    /// The sealed subject, or for envelopes without one the `subject_hint`
+    pub subject: String,
+    pub body: String,
+    /// Files to fetch from the sender over P2P
+    pub attachments: Vec<AttachmentRef>,
+}
+
+/// Original envelopes: only the ciphertext is signed and no associated data is bound
+pub const ENVELOPE_VERSION_LEGACY: u8 = 0;
+/// Every envelope field is signed and sender/recipient/timestamp are AEAD associated data
+pub const ENVELOPE_VERSION_V1: u8 = 1;
+/// Version 1 plus a signed `content_type`
+pub const ENVELOPE_VERSION_V2: u8 = 2;
+/// Version 2 plus a signed `read_receipt_requested`
+pub const ENVELOPE_VERSION_V3: u8 = 3;
+/// Version 3 for several recipients: the body is encrypted once under a random
+/// content key, which is wrapped for each recipient in the signed `recipients`
+pub const ENVELOPE_VERSION_GROUP: u8 = 4;
+/// Version 4 sealed with XChaCha20-Poly1305 and 24-byte nonces. An empty
+/// `recipients` means a single recipient, as in version 3.
+pub const ENVELOPE_VERSION: u8 = 5;
+/// Version 5 with the subject sealed in front of the body, length-prefixed,
+/// and an empty `subject_hint`. Only sent with `encrypt_subject` on, as nodes
+/// from before it can't open it.
+pub const ENVELOPE_VERSION_SEALED_SUBJECT: u8 = 6;
+/// Version 6 with a length-prefixed JSON list of `AttachmentRef`s between the
+/// subject and the body. `subject_hint` is still set unless `encrypt_subject`
+/// is on. Only sent for messages with attachments.
+pub const ENVELOPE_VERSION_ATTACHMENTS: u8 = 7;
+
+/// HKDF info for the key a single recipient's body is encrypted under
+const MESSAGE_KEY_INFO: &[u8] = b"ledger-message-key";
+/// HKDF info for the key a group envelope's content key is wrapped under
+const GROUP_WRAP_INFO: &[u8] = b"ledger-group-wrap";
+
+/// AEAD an envelope's body and wrapped keys are sealed with
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeCipher {
+    /// ChaCha20-Poly1305 with 12-byte nonces, up to version 4
+    ChaCha20,
+    /// XChaCha20-Poly1305 with 24-byte nonces, long enough that random ones never repeat
+    XChaCha20,
+}
+
+impl EnvelopeCipher {
+    fn for_version(version: u8) -> Self {
+        if version >= ENVELOPE_VERSION {
+            EnvelopeCipher::XChaCha20
+        } else {
+            EnvelopeCipher::ChaCha20
+        }
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            EnvelopeCipher::ChaCha20 => 12,
+            EnvelopeCipher::XChaCha20 => 24,
+        }
+    }
+
+    fn random_nonce(self) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_len()];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    fn encrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload<'_, '_>) -> Result<Vec<u8>> {
+        self.check_nonce(nonce)?;
+        let key = Key::from_slice(key);
+        match self {
+            EnvelopeCipher::ChaCha20 => ChaCha20Poly1305::new(key).encrypt(Nonce::from_slice(nonce), payload),
+            EnvelopeCipher::XChaCha20 => XChaCha20Poly1305::new(key).encrypt(XNonce::from_slice(nonce), payload),
+        }
+        .map_err(|e| LedgerError::crypto(format!("Encryption error: {}", e)))
+    }
+
+    fn decrypt(self, key: &[u8; 32], nonce: &[u8], payload: Payload<'_, '_>) -> Result<Vec<u8>> {
+        self.check_nonce(nonce)?;
+        let key = Key::from_slice(key);
+        match self {
+            EnvelopeCipher::ChaCha20 => ChaCha20Poly1305::new(key).decrypt(Nonce::from_slice(nonce), payload),
+            EnvelopeCipher::XChaCha20 => XChaCha20Poly1305::new(key).decrypt(XNonce::from_slice(nonce), payload),
+        }
+        .map_err(|e| LedgerError::crypto(format!("Decryption error: {}", e)))
+    }
+
+    /// A nonce of the wrong size is an error rather than a panic in `from_slice`
+    fn check_nonce(self, nonce: &[u8]) -> Result<()> {
+        if nonce.len() != self.nonce_len() {
+            return Err(LedgerError::crypto(format!(
+                "Expected a {}-byte nonce, got {} bytes", self.nonce_len(), nonce.len(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Canonical bytes signed for a versioned envelope: a domain tag followed by
+/// each field length-prefixed, so no two envelopes share an encoding
+fn signing_payload(envelope: &EncryptedEnvelope) -> Vec<u8> {
+    let mut out = b"ledger-envelope".to_vec();
+    out.push(envelope.version);
+    for field in [
+        &envelope.id,
+        &envelope.from_ledger_id,
+        &envelope.to_ledger_id,
+        &envelope.ephemeral_pubkey,
+        &envelope.nonce,
+        &envelope.encrypted_body,
+    ] {
+        out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        out.extend_from_slice(field.as_bytes());
+    }
+    out.extend_from_slice(&envelope.timestamp.to_be_bytes());
+    out.extend_from_slice(&(envelope.subject_hint.len() as u32).to_be_bytes());
+    out.extend_from_slice(envelope.subject_hint.as_bytes());
+    if envelope.version >= ENVELOPE_VERSION_V2 {
+        let content_type = envelope.content_type.to_string();
+        out.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+        out.extend_from_slice(content_type.as_bytes());
+    }
+    if envelope.version >= ENVELOPE_VERSION_V3 {
+        out.push(envelope.read_receipt_requested as u8);
+    }
+    if envelope.version >= ENVELOPE_VERSION_GROUP {
+        out.extend_from_slice(&(envelope.recipients.len() as u32).to_be_bytes());
+        for entry in &envelope.recipients {
+            for field in [&entry.ledger_id, &entry.wrapped_key] {
+                out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                out.extend_from_slice(field.as_bytes());
+            }
+        }
+    }
+    out
+}
+
+/// Whether the body is sealed under a content key wrapped for each recipient
+fn is_group(envelope: &EncryptedEnvelope) -> bool {
+    envelope.version == ENVELOPE_VERSION_GROUP
+        || (envelope.version >= ENVELOPE_VERSION && !envelope.recipients.is_empty())
+}
+
+/// Associated data binding an envelope to its sender, recipient and send time,
+/// so a captured envelope can't be redirected to another node
+fn envelope_aad(from_ledger_id: &str, to_ledger_id: &str, timestamp: i64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(from_ledger_id.len() + to_ledger_id.len() + 8);
+    aad.extend_from_slice(from_ledger_id.as_bytes());
+    aad.extend_from_slice(to_ledger_id.as_bytes());
+    aad.extend_from_slice(&timestamp.to_be_bytes());
+    aad
+}
+
+/// The version, subject hint and plaintext of an envelope carrying `content`
+fn seal_content(content: &OutgoingContent) -> Result<(u8, String, Vec<u8>)> {
+    let attachments: Vec<AttachmentRef> = content.attachments.iter().filter_map(Attachment::to_ref).collect();
+    if attachments.is_empty() && !content.encrypt_subject {
+        return Ok((ENVELOPE_VERSION, content.subject.clone(), content.body.as_bytes().to_vec()));
+    }
+    let subject_hint = if content.encrypt_subject { String::new() } else { content.subject.clone() };
+    let mut plaintext = Vec::with_capacity(4 + content.subject.len() + content.body.len());
+    push_prefixed(&mut plaintext, content.subject.as_bytes());
+    let version = if attachments.is_empty() {
+        ENVELOPE_VERSION_SEALED_SUBJECT
+    } else {
+        push_prefixed(&mut plaintext, &serde_json::to_vec(&attachments)?);
+        ENVELOPE_VERSION_ATTACHMENTS
+    };
+    plaintext.extend_from_slice(content.body.as_bytes());
+    Ok((version, subject_hint, plaintext))
+}
+
+/// Split a decrypted plaintext into subject, attachments and body
+fn open_content(envelope: &EncryptedEnvelope, plaintext: Vec<u8>) -> Result<OpenedEnvelope> {
+    if envelope.version < ENVELOPE_VERSION_SEALED_SUBJECT {
+        return Ok(OpenedEnvelope {
+            subject: envelope.subject_hint.clone(),
+            body: String::from_utf8(plaintext).map_err(LedgerError::crypto)?,
+            attachments: vec![],
+        });
+    }
+    let (subject, rest) = take_prefixed(&plaintext, "subject")?;
+    let (attachments, body) = if envelope.version >= ENVELOPE_VERSION_ATTACHMENTS {
+        let (manifest, body) = take_prefixed(rest, "attachment list")?;
+        (serde_json::from_slice(manifest)?, body)
+    } else {
+        (vec![], rest)
+    };
+    Ok(OpenedEnvelope {
+        subject: String::from_utf8(subject.to_vec()).map_err(LedgerError::crypto)?,
+        body: String::from_utf8(body.to_vec()).map_err(LedgerError::crypto)?,
+        attachments,
+    })
+}
+
+fn push_prefixed(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    out.extend_from_slice(field);
+}
+
+/// Split a `push_prefixed` field off the front of `data`
+fn take_prefixed<'a>(data: &'a [u8], what: &str) -> Result<(&'a [u8], &'a [u8])> {
+    let truncated = || LedgerError::crypto(format!("Sealed {} is truncated", what));
+    let (len, rest) = data.split_first_chunk::<4>().ok_or_else(truncated)?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    Ok(rest.split_at(len))
+}
 
 /// Encrypt a message for a recipient
 pub fn encrypt_message(
     sender: &LedgerIdentity,
+    recipient_ledger_id: &str,
     recipient_encryption_pubkey: &[u8],
-    subject: &str,
-    plaintext: &str,
-) -> Result<EncryptedEnvelope, Box<dyn std::error::Error>> {
+    content: &OutgoingContent,
+) -> Result<EncryptedEnvelope> {
     // Generate ephemeral X25519 keypair for this message
     let ephemeral_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
     let ephemeral_public = X25519PublicKey::from(&ephemeral_secret);
@@ -25,125 +241,411 @@ pub fn encrypt_message(
     // Perform DH with recipient's public key
     let recipient_pubkey = X25519PublicKey::from(
         <[u8; 32]>::try_from(recipient_encryption_pubkey)
-            .map_err(|_| "Invalid recipient public key length")?
+            .map_err(|_| LedgerError::crypto("Invalid recipient public key length"))?
     );
     let shared_secret = ephemeral_secret.diffie_hellman(&recipient_pubkey);
 
     // Derive symmetric key via HKDF
-    let hk = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
-    let mut sym_key = [0u8; 32];
-    hk.expand(b"ledger-message-key", &mut sym_key)
-        .map_err(|e| format!("HKDF error: {}", e))?;
-
-    // Generate random nonce
-    let mut nonce_bytes = [0u8; 12];
-    rand::rngs::OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-
-    // Encrypt with ChaCha20-Poly1305
-    let cipher = ChaCha20Poly1305::new_from_slice(&sym_key)
-        .map_err(|e| format!("Cipher init error: {}", e))?;
-    let ciphertext = cipher.encrypt(nonce, plaintext.as_bytes())
-        .map_err(|e| format!("Encryption error: {}", e))?;
-
-    // Sign the ciphertext with sender's Ed25519 key
-    let signature = sender.sign(&ciphertext);
-
-    let envelope = EncryptedEnvelope {
+    let sym_key = derive_key(shared_secret.as_bytes(), MESSAGE_KEY_INFO)?;
+
+    // Encrypt with XChaCha20-Poly1305 under a random nonce
+    let (version, subject_hint, plaintext) = seal_content(content)?;
+    let cipher = EnvelopeCipher::for_version(version);
+    let nonce = cipher.random_nonce();
+    let timestamp = chrono::Utc::now().timestamp();
+    let aad = envelope_aad(&sender.ledger_id, recipient_ledger_id, timestamp);
+    let ciphertext = cipher.encrypt(&sym_key, &nonce, Payload { msg: &plaintext, aad: &aad })?;
+
+    let mut envelope = EncryptedEnvelope {
+        version,
+        id: uuid::Uuid::new_v4().to_string(),
+        from_ledger_id: sender.ledger_id.clone(),
+        to_ledger_id: recipient_ledger_id.to_string(),
+        ephemeral_pubkey: BASE64.encode(ephemeral_public.as_bytes()),
+        encrypted_body: BASE64.encode(&ciphertext),
+        nonce: BASE64.encode(&nonce),
+        signature: String::new(),
+        timestamp,
+        subject_hint,
+        content_type: content.content_type,
+        read_receipt_requested: content.read_receipt_requested,
+        recipients: vec![],
+    };
+
+    // Sign the whole envelope with sender's Ed25519 key
+    let signature = sender.sign(&signing_payload(&envelope));
+    envelope.signature = BASE64.encode(&signature);
+
+    Ok(envelope)
+}
+
+/// Encrypt a message once for several recipients, each given as their Ledger
+/// ID and X25519 encryption key. Every recipient can read the whole list.
+pub fn encrypt_group_message(
+    sender: &LedgerIdentity,
+    recipients: &[(String, Vec<u8>)],
+    content: &OutgoingContent,
+) -> Result<EncryptedEnvelope> {
+    if recipients.is_empty() {
+        return Err(LedgerError::invalid("A group message needs at least one recipient"));
+    }
+
+    // One ephemeral key for the envelope; each recipient's DH gives their own wrapping key
+    let ephemeral_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let ephemeral_public = X25519PublicKey::from(&ephemeral_secret);
+    let (version, subject_hint, plaintext) = seal_content(content)?;
+    let cipher = EnvelopeCipher::for_version(version);
+
+    let mut content_key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut content_key);
+
+    let wrapped = recipients.iter()
+        .map(|(ledger_id, encryption_pubkey)| {
+            let pubkey = X25519PublicKey::from(
+                <[u8; 32]>::try_from(encryption_pubkey.as_slice())
+                    .map_err(|_| LedgerError::crypto(format!("Invalid public key length for {}", ledger_id)))?
+            );
+            let wrap_key = derive_key(ephemeral_secret.diffie_hellman(&pubkey).as_bytes(), GROUP_WRAP_INFO)?;
+            let nonce = cipher.random_nonce();
+            // The recipient's ID as associated data stops an entry being relabelled
+            let sealed = cipher.encrypt(&wrap_key, &nonce, Payload { msg: &content_key, aad: ledger_id.as_bytes() })?;
+            let mut blob = nonce;
+            blob.extend_from_slice(&sealed);
+            Ok(WrappedKey { ledger_id: ledger_id.clone(), wrapped_key: BASE64.encode(blob) })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let to_ledger_id = recipients.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>().join(", ");
+    let nonce = cipher.random_nonce();
+    let timestamp = chrono::Utc::now().timestamp();
+    let aad = envelope_aad(&sender.ledger_id, &to_ledger_id, timestamp);
+    let ciphertext = cipher.encrypt(&content_key, &nonce, Payload { msg: &plaintext, aad: &aad })?;
+
+    let mut envelope = EncryptedEnvelope {
+        version,
         id: uuid::Uuid::new_v4().to_string(),
         from_ledger_id: sender.ledger_id.clone(),
-        to_ledger_id: String::new(), // filled by caller
+        to_ledger_id,
         ephemeral_pubkey: BASE64.encode(ephemeral_public.as_bytes()),
         encrypted_body: BASE64.encode(&ciphertext),
-        nonce: BASE64.encode(&nonce_bytes),
-        signature: BASE64.encode(&signature),
-        timestamp: chrono::Utc::now().timestamp(),
-        subject_hint: subject.to_string(),
+        nonce: BASE64.encode(&nonce),
+        signature: String::new(),
+        timestamp,
+        subject_hint,
+        content_type: content.content_type,
+        read_receipt_requested: content.read_receipt_requested,
+        recipients: wrapped,
     };
+    envelope.signature = BASE64.encode(sender.sign(&signing_payload(&envelope)));
 
     Ok(envelope)
 }
 
-/// Decrypt a received envelope
+/// Decrypt a received envelope, falling back to the keys of identities we
+/// rotated away from for mail still addressed to them
 pub fn decrypt_envelope(
     recipient: &LedgerIdentity,
     envelope: &EncryptedEnvelope,
-) -> Result<String, Box<dyn std::error::Error>> {
+) -> Result<OpenedEnvelope> {
+    let sender_key = LedgerIdentity::verifying_key_from_ledger_id(&envelope.from_ledger_id)?;
+    open_envelope(recipient, envelope, &sender_key)
+}
+
+/// `decrypt_envelope` with the sender's key from `keys`, for the node's
+/// stream of inbound envelopes
+pub fn decrypt_envelope_cached(
+    recipient: &LedgerIdentity,
+    envelope: &EncryptedEnvelope,
+    keys: &mut VerifyingKeyCache,
+) -> Result<OpenedEnvelope> {
+    let sender_key = keys.get(&envelope.from_ledger_id)?;
+    open_envelope(recipient, envelope, &sender_key)
+}
+
+fn open_envelope(
+    recipient: &LedgerIdentity,
+    envelope: &EncryptedEnvelope,
+    sender_key: &VerifyingKey,
+) -> Result<OpenedEnvelope> {
+    let current = [&recipient.encryption_secret, &recipient.legacy_encryption_secret];
+    let error = match decrypt_as(&recipient.ledger_id, &current, envelope, sender_key) {
+        Ok(plaintext) => return Ok(plaintext),
+        Err(e) => e,
+    };
+    let now = chrono::Utc::now().timestamp();
+    recipient.retired_keys.iter()
+        .filter(|key| key.is_active(now))
+        .find_map(|key| decrypt_as(&key.ledger_id, &[&key.encryption_secret], envelope, sender_key).ok())
+        .ok_or(error)
+}
+
+/// Decrypt an envelope as `ledger_id`, trying each of its X25519 `secrets`,
+/// once it checks out as signed by `sender_key`
+fn decrypt_as(
+    ledger_id: &str,
+    secrets: &[&StaticSecret],
+    envelope: &EncryptedEnvelope,
+    sender_key: &VerifyingKey,
+) -> Result<OpenedEnvelope> {
     // Decode ephemeral public key
     let ephemeral_bytes = BASE64.decode(&envelope.ephemeral_pubkey)?;
     let ephemeral_pubkey = X25519PublicKey::from(
         <[u8; 32]>::try_from(ephemeral_bytes.as_slice())
-            .map_err(|_| "Invalid ephemeral public key")?
+            .map_err(|_| LedgerError::crypto("Invalid ephemeral public key"))?
     );
 
-    // Perform DH with our secret key
-    let shared_secret = recipient.encryption_secret.diffie_hellman(&ephemeral_pubkey);
-
-    // Derive symmetric key
-    let hk = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
-    let mut sym_key = [0u8; 32];
-    hk.expand(b"ledger-message-key", &mut sym_key)
-        .map_err(|e| format!("HKDF error: {}", e))?;
-
     // Decode nonce and ciphertext
-    let nonce_bytes = BASE64.decode(&envelope.nonce)?;
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    let nonce = BASE64.decode(&envelope.nonce)?;
     let ciphertext = BASE64.decode(&envelope.encrypted_body)?;
 
-    // Verify signature
-    let sender_pubkey = LedgerIdentity::pubkey_from_ledger_id(&envelope.from_ledger_id)?;
+    // Verify signature over whatever the envelope version covers
+    let (signed, aad) = match envelope.version {
+        ENVELOPE_VERSION_LEGACY => (ciphertext.clone(), Vec::new()),
+        // Addressed to the whole group; our wrapped key shows we are in it
+        ENVELOPE_VERSION_GROUP..=ENVELOPE_VERSION_ATTACHMENTS if is_group(envelope) => (
+            signing_payload(envelope),
+            envelope_aad(&envelope.from_ledger_id, &envelope.to_ledger_id, envelope.timestamp),
+        ),
+        ENVELOPE_VERSION_V1..=ENVELOPE_VERSION_ATTACHMENTS => (
+            signing_payload(envelope),
+            // Authenticate the envelope as addressed to us
+            envelope_aad(&envelope.from_ledger_id, ledger_id, envelope.timestamp),
+        ),
+        v => return Err(LedgerError::crypto(format!("Unsupported envelope version: {}", v))),
+    };
     let signature_bytes = BASE64.decode(&envelope.signature)?;
-    let valid = LedgerIdentity::verify(&sender_pubkey, &ciphertext, &signature_bytes)?;
+    let valid = LedgerIdentity::verify_with_key(sender_key, &signed, &signature_bytes)?;
     if !valid {
-        return Err("Signature verification failed".into());
+        return Err(LedgerError::crypto("Signature verification failed"));
+    }
+
+    let cipher = EnvelopeCipher::for_version(envelope.version);
+    let payload = || Payload { msg: ciphertext.as_slice(), aad: &aad };
+    let plaintext = if is_group(envelope) {
+        let content_key = unwrap_content_key(ledger_id, secrets, envelope, &ephemeral_pubkey, cipher)?;
+        cipher.decrypt(&content_key, &nonce, payload())?
+    } else {
+        // Later secrets, like the pre-standard X25519 key, only for mail sent to them
+        open_with_any(secrets, |secret| open_dh(secret, &ephemeral_pubkey, MESSAGE_KEY_INFO, cipher, &nonce, payload()))?
+    };
+
+    open_content(envelope, plaintext)
+}
+
+/// Find our entry in a group envelope and unwrap the content key from it
+fn unwrap_content_key(
+    ledger_id: &str,
+    secrets: &[&StaticSecret],
+    envelope: &EncryptedEnvelope,
+    ephemeral_pubkey: &X25519PublicKey,
+    cipher: EnvelopeCipher,
+) -> Result<[u8; 32]> {
+    let entry = envelope.recipients.iter()
+        .find(|entry| entry.ledger_id == ledger_id)
+        .ok_or_else(|| LedgerError::crypto("Not a recipient of this group message"))?;
+    let blob = BASE64.decode(&entry.wrapped_key)?;
+    if blob.len() < cipher.nonce_len() {
+        return Err(LedgerError::crypto("Wrapped key too short"));
     }
+    let (nonce, sealed) = blob.split_at(cipher.nonce_len());
+    let payload = || Payload { msg: sealed, aad: entry.ledger_id.as_bytes() };
 
-    // Decrypt
-    let cipher = ChaCha20Poly1305::new_from_slice(&sym_key)
-        .map_err(|e| format!("Cipher init error: {}", e))?;
-    let plaintext = cipher.decrypt(nonce, ciphertext.as_slice())
-        .map_err(|e| format!("Decryption error: {}", e))?;
+    let key = open_with_any(secrets, |secret| open_dh(secret, ephemeral_pubkey, GROUP_WRAP_INFO, cipher, nonce, payload()))?;
+    <[u8; 32]>::try_from(key.as_slice()).map_err(|_| LedgerError::crypto("Invalid content key length"))
+}
 
-    String::from_utf8(plaintext).map_err(|e| e.into())
+/// The first of `secrets` that `open` succeeds with, else the first one's error
+fn open_with_any(
+    secrets: &[&StaticSecret],
+    open: impl Fn(&StaticSecret) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let mut first_error = None;
+    for secret in secrets {
+        match open(secret) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.unwrap_or_else(|| LedgerError::crypto("No key to decrypt with")))
+}
+
+/// DH with `secret`, derive the key for `info` and open the AEAD payload
+fn open_dh(
+    secret: &StaticSecret,
+    ephemeral_pubkey: &X25519PublicKey,
+    info: &[u8],
+    cipher: EnvelopeCipher,
+    nonce: &[u8],
+    payload: Payload<'_, '_>,
+) -> Result<Vec<u8>> {
+    let shared_secret = secret.diffie_hellman(ephemeral_pubkey);
+    let sym_key = derive_key(shared_secret.as_bytes(), info)?;
+    cipher.decrypt(&sym_key, nonce, payload)
+}
+
+/// Expand a DH shared secret into a 32-byte key via HKDF-SHA256
+fn derive_key(shared_secret: &[u8], info: &[u8]) -> Result<[u8; 32]> {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut key = [0u8; 32];
+    hk.expand(info, &mut key)
+        .map_err(|e| LedgerError::crypto(format!("HKDF error: {}", e)))?;
+    Ok(key)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::message::ContentType;
 
     #[test]
     fn test_encrypt_decrypt_roundtrip() {
         let sender = LedgerIdentity::generate().unwrap();
         let recipient = LedgerIdentity::generate().unwrap();
 
-        let mut envelope = encrypt_message(
+        let envelope = encrypt_message(
             &sender,
+            &recipient.ledger_id,
             &recipient.encryption_public_bytes(),
-            "Test Subject",
-            "Hello, this is a secret message!",
+            &OutgoingContent::text("Test Subject", "Hello, this is a secret message!"),
         ).unwrap();
 
-        envelope.to_ledger_id = recipient.ledger_id.clone();
-
-        let decrypted = decrypt_envelope(&recipient, &envelope).unwrap();
+        let decrypted = decrypt_envelope(&recipient, &envelope).unwrap().body;
         assert_eq!(decrypted, "Hello, this is a secret message!");
     }
 
+    #[test]
+    fn test_sealed_subject_roundtrip() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let content = OutgoingContent { encrypt_subject: true, ..OutgoingContent::text("Quarterly numbers", "See attached") };
+
+        let envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &content,
+        ).unwrap();
+        assert_eq!(envelope.version, ENVELOPE_VERSION_SEALED_SUBJECT);
+        assert_eq!(envelope.subject_hint, "");
+
+        let opened = decrypt_envelope(&recipient, &envelope).unwrap();
+        assert_eq!(opened, OpenedEnvelope {
+            subject: "Quarterly numbers".into(),
+            body: "See attached".into(),
+            attachments: vec![],
+        });
+
+        // Group envelopes seal it the same way
+        let group = encrypt_group_message(
+            &sender,
+            &[(recipient.ledger_id.clone(), recipient.encryption_public_bytes())],
+            &content,
+        ).unwrap();
+        assert_eq!(group.subject_hint, "");
+        assert_eq!(decrypt_envelope(&recipient, &group).unwrap().subject, "Quarterly numbers");
+
+        // Downgrading the version to have the prefix read as body breaks the signature
+        let mut downgraded = envelope.clone();
+        downgraded.version = ENVELOPE_VERSION;
+        assert!(decrypt_envelope(&recipient, &downgraded).is_err());
+    }
+
+    #[test]
+    fn test_attachment_refs_roundtrip() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let file = Attachment::outgoing("m1", "notes.txt".into(), "text/plain".into(), b"hello".to_vec());
+        let content = OutgoingContent { attachments: vec![file.clone()], ..OutgoingContent::text("Notes", "Attached") };
+
+        let envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &content,
+        ).unwrap();
+        assert_eq!(envelope.version, ENVELOPE_VERSION_ATTACHMENTS);
+        // Without encrypt_subject the hint is still there for older clients' lists
+        assert_eq!(envelope.subject_hint, "Notes");
+
+        let opened = decrypt_envelope(&recipient, &envelope).unwrap();
+        assert_eq!((opened.subject.as_str(), opened.body.as_str()), ("Notes", "Attached"));
+        assert_eq!(opened.attachments, vec![file.to_ref().unwrap()]);
+        assert!(!envelope.encrypted_body.contains("notes.txt"));
+    }
+
+    #[test]
+    fn test_envelopes_use_24_byte_nonces() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        let envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &OutgoingContent::text("Test", "Secret message"),
+        ).unwrap();
+        assert_eq!(envelope.version, ENVELOPE_VERSION);
+        assert_eq!(BASE64.decode(&envelope.nonce).unwrap().len(), 24);
+
+        // A short nonce is refused rather than panicking
+        let short = EnvelopeCipher::XChaCha20.decrypt(&[0u8; 32], &[0u8; 12], Payload { msg: b"x", aad: b"" });
+        assert!(short.is_err());
+    }
+
+    #[test]
+    fn test_version_3_envelope_still_decrypts() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        // Sealed the way version 3 nodes did: ChaCha20-Poly1305 with a 12-byte nonce
+        let ephemeral_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient.encryption_public);
+        let sym_key = derive_key(shared_secret.as_bytes(), MESSAGE_KEY_INFO).unwrap();
+        let nonce = EnvelopeCipher::ChaCha20.random_nonce();
+        let aad = envelope_aad(&sender.ledger_id, &recipient.ledger_id, 1_700_000_000);
+        let ciphertext = EnvelopeCipher::ChaCha20
+            .encrypt(&sym_key, &nonce, Payload { msg: b"from an older node", aad: &aad })
+            .unwrap();
+
+        let mut envelope = EncryptedEnvelope {
+            version: ENVELOPE_VERSION_V3,
+            id: "v3".into(),
+            from_ledger_id: sender.ledger_id.clone(),
+            to_ledger_id: recipient.ledger_id.clone(),
+            ephemeral_pubkey: BASE64.encode(X25519PublicKey::from(&ephemeral_secret).as_bytes()),
+            encrypted_body: BASE64.encode(&ciphertext),
+            nonce: BASE64.encode(&nonce),
+            signature: String::new(),
+            timestamp: 1_700_000_000,
+            subject_hint: "Old".into(),
+            content_type: ContentType::Text,
+            read_receipt_requested: false,
+            recipients: vec![],
+        };
+        envelope.signature = BASE64.encode(sender.sign(&signing_payload(&envelope)));
+        assert_eq!(decrypt_envelope(&recipient, &envelope).unwrap().body, "from an older node");
+
+        // Claiming the new version switches the cipher, so it no longer opens
+        envelope.version = ENVELOPE_VERSION;
+        envelope.signature = BASE64.encode(sender.sign(&signing_payload(&envelope)));
+        assert!(decrypt_envelope(&recipient, &envelope).is_err());
+    }
+
     #[test]
     fn test_wrong_recipient_fails() {
         let sender = LedgerIdentity::generate().unwrap();
         let recipient = LedgerIdentity::generate().unwrap();
         let wrong_recipient = LedgerIdentity::generate().unwrap();
 
-        let mut envelope = encrypt_message(
+        let envelope = encrypt_message(
             &sender,
+            &recipient.ledger_id,
             &recipient.encryption_public_bytes(),
-            "Test",
-            "Secret message",
+            &OutgoingContent::text("Test", "Secret message"),
         ).unwrap();
 
-        envelope.to_ledger_id = recipient.ledger_id.clone();
-
         // Wrong recipient should fail to decrypt
         let result = decrypt_envelope(&wrong_recipient, &envelope);
         assert!(result.is_err());
@@ -156,13 +658,11 @@ mod tests {
 
         let mut envelope = encrypt_message(
             &sender,
+            &recipient.ledger_id,
             &recipient.encryption_public_bytes(),
-            "Test",
-            "Secret message",
+            &OutgoingContent::text("Test", "Secret message"),
         ).unwrap();
 
-        envelope.to_ledger_id = recipient.ledger_id.clone();
-
         // Tamper with the encrypted body
         let mut body_bytes = BASE64.decode(&envelope.encrypted_body).unwrap();
         if let Some(byte) = body_bytes.first_mut() {
@@ -173,4 +673,181 @@ mod tests {
         let result = decrypt_envelope(&recipient, &envelope);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_tampered_timestamp_fails() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        let mut envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &OutgoingContent::text("Test", "Secret message"),
+        ).unwrap();
+
+        // The timestamp is authenticated as associated data
+        envelope.timestamp += 1;
+
+        let result = decrypt_envelope(&recipient, &envelope);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_tampered_subject_hint_fails() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        let mut envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &OutgoingContent::text("Test", "Secret message"),
+        ).unwrap();
+
+        envelope.subject_hint = "Rewritten".into();
+
+        let result = decrypt_envelope(&recipient, &envelope);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_tampered_content_type_fails() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        let mut envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &OutgoingContent {
+                content_type: ContentType::Html,
+                ..OutgoingContent::text("Test", "<b>Secret</b>")
+            },
+        ).unwrap();
+        assert!(decrypt_envelope(&recipient, &envelope).is_ok());
+
+        envelope.content_type = ContentType::Text;
+        assert!(decrypt_envelope(&recipient, &envelope).is_err());
+    }
+
+    #[test]
+    fn test_tampered_read_receipt_flag_fails() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        let mut envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &OutgoingContent::text("Test", "Secret message"),
+        ).unwrap();
+
+        // A relay can't quietly ask for a receipt the sender never wanted
+        envelope.read_receipt_requested = true;
+        assert!(decrypt_envelope(&recipient, &envelope).is_err());
+    }
+
+    #[test]
+    fn test_group_message_roundtrip() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let members: Vec<LedgerIdentity> = (0..3).map(|_| LedgerIdentity::generate().unwrap()).collect();
+        let recipients: Vec<(String, Vec<u8>)> = members.iter()
+            .map(|m| (m.ledger_id.clone(), m.encryption_public_bytes()))
+            .collect();
+
+        let envelope = encrypt_group_message(
+            &sender,
+            &recipients,
+            &OutgoingContent::text("Team", "Hello all"),
+        ).unwrap();
+        assert_eq!(envelope.recipients.len(), 3);
+        for member in &members {
+            assert_eq!(decrypt_envelope(member, &envelope).unwrap().body, "Hello all");
+        }
+
+        // Someone left off the list can't read it
+        let outsider = LedgerIdentity::generate().unwrap();
+        assert!(decrypt_envelope(&outsider, &envelope).is_err());
+
+        // Nor can they add themselves by relabelling an entry
+        let mut relabelled = envelope.clone();
+        relabelled.recipients[0].ledger_id = outsider.ledger_id.clone();
+        assert!(decrypt_envelope(&outsider, &relabelled).is_err());
+
+        // Dropping a recipient breaks the signature for everyone else
+        let mut trimmed = envelope;
+        trimmed.recipients.pop();
+        assert!(decrypt_envelope(&members[0], &trimmed).is_err());
+    }
+
+    #[test]
+    fn test_mail_to_legacy_x25519_key_still_decrypts() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let legacy_public = X25519PublicKey::from(&recipient.legacy_encryption_secret);
+
+        let envelope = encrypt_message(
+            &sender,
+            &recipient.ledger_id,
+            legacy_public.as_bytes(),
+            &OutgoingContent::text("Test", "Sent before the upgrade"),
+        ).unwrap();
+
+        assert_eq!(decrypt_envelope(&recipient, &envelope).unwrap().body, "Sent before the upgrade");
+    }
+
+    #[test]
+    fn test_mail_to_retired_identity_decrypts_until_expiry() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let old = LedgerIdentity::generate().unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let envelope = encrypt_message(
+            &sender,
+            &old.ledger_id,
+            &old.encryption_public_bytes(),
+            &OutgoingContent::text("Test", "To the old ID"),
+        ).unwrap();
+        assert!(decrypt_envelope(&LedgerIdentity::generate().unwrap(), &envelope).is_err());
+
+        let rotated = LedgerIdentity::generate().unwrap().with_retired_keys(vec![old.retire(now + 60)]);
+        assert_eq!(decrypt_envelope(&rotated, &envelope).unwrap().body, "To the old ID");
+
+        let expired = LedgerIdentity::generate().unwrap().with_retired_keys(vec![old.retire(now - 1)]);
+        assert!(decrypt_envelope(&expired, &envelope).is_err());
+    }
+
+    #[test]
+    fn test_legacy_envelope_still_decrypts() {
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+
+        // Build an envelope the way version 0 clients did
+        let ephemeral_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient.encryption_public);
+        let hk = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
+        let mut sym_key = [0u8; 32];
+        hk.expand(b"ledger-message-key", &mut sym_key).unwrap();
+        let nonce_bytes = [7u8; 12];
+        let cipher = ChaCha20Poly1305::new_from_slice(&sym_key).unwrap();
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce_bytes), b"old message".as_slice()).unwrap();
+
+        let json = serde_json::json!({
+            "id": "legacy",
+            "from_ledger_id": sender.ledger_id,
+            "to_ledger_id": recipient.ledger_id,
+            "ephemeral_pubkey": BASE64.encode(X25519PublicKey::from(&ephemeral_secret).as_bytes()),
+            "encrypted_body": BASE64.encode(&ciphertext),
+            "nonce": BASE64.encode(nonce_bytes),
+            "signature": BASE64.encode(sender.sign(&ciphertext)),
+            "timestamp": 0,
+            "subject_hint": "Old",
+        });
+        let envelope: EncryptedEnvelope = serde_json::from_value(json).unwrap();
+        assert_eq!(envelope.version, ENVELOPE_VERSION_LEGACY);
+
+        let decrypted = decrypt_envelope(&recipient, &envelope).unwrap().body;
+        assert_eq!(decrypted, "old message");
+    }
 }
@@ -0,0 +1,63 @@
+use std::num::NonZeroUsize;
+
+use ed25519_dalek::VerifyingKey;
+use lru::LruCache;
+
+use super::keys::LedgerIdentity;
+use crate::error::Result;
+
+/// Senders whose parsed keys the node keeps
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Parsed Ed25519 keys of recent senders by Ledger ID, so a busy conversation
+/// doesn't base58-decode and decompress the same key for every envelope.
+/// Ledger IDs that don't parse are never cached.
+pub struct VerifyingKeyCache {
+    keys: LruCache<String, VerifyingKey>,
+}
+
+impl VerifyingKeyCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self { keys: LruCache::new(capacity) }
+    }
+
+    /// The key `ledger_id` encodes, parsed on first use
+    pub fn get(&mut self, ledger_id: &str) -> Result<VerifyingKey> {
+        if let Some(key) = self.keys.get(ledger_id) {
+            return Ok(*key);
+        }
+        let key = LedgerIdentity::verifying_key_from_ledger_id(ledger_id)?;
+        self.keys.put(ledger_id.to_string(), key);
+        Ok(key)
+    }
+}
+
+impl Default for VerifyingKeyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_keys_verify_like_parsed_ones() {
+        let mut cache = VerifyingKeyCache::new(2);
+        let identities: Vec<LedgerIdentity> = (0..3).map(|_| LedgerIdentity::generate().unwrap()).collect();
+        for identity in identities.iter().chain(&identities) {
+            let signature = identity.sign(b"hello");
+            let key = cache.get(&identity.ledger_id).unwrap();
+            assert_eq!(key, identity.verifying_key);
+            assert!(LedgerIdentity::verify_with_key(&key, b"hello", &signature).unwrap());
+            assert!(!LedgerIdentity::verify_with_key(&key, b"tampered", &signature).unwrap());
+        }
+        assert_eq!(cache.keys.len(), 2);
+
+        assert!(cache.get("ledger:0OIl").is_err());
+        assert!(cache.get("not-a-ledger-id").is_err());
+        assert_eq!(cache.keys.len(), 2);
+    }
+}
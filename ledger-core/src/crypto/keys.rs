@@ -1,9 +1,16 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
 use ed25519_dalek::{SigningKey, VerifyingKey, Signer, Verifier, Signature};
 use x25519_dalek::{StaticSecret, PublicKey as X25519PublicKey};
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::fs;
 
+use crate::error::{LedgerError, Result};
+
 /// Holds both signing (Ed25519) and encryption (X25519) keys
 #[derive(Clone)]
 pub struct LedgerIdentity {
@@ -11,45 +18,152 @@ pub struct LedgerIdentity {
     pub verifying_key: VerifyingKey,
     pub encryption_secret: StaticSecret,
     pub encryption_public: X25519PublicKey,
+    /// X25519 key from the original HKDF derivation, kept so mail encrypted
+    /// to it before the switch to the standard conversion still decrypts
+    pub legacy_encryption_secret: StaticSecret,
+    pub ledger_id: String,
+    /// Encryption keys of identities this node rotated away from
+    pub retired_keys: Vec<RetiredKey>,
+}
+
+/// The X25519 key of an identity replaced by `POST /api/identity/rotate`,
+/// kept so mail still addressed to it decrypts until `expires_at`
+#[derive(Clone)]
+pub struct RetiredKey {
     pub ledger_id: String,
+    pub encryption_secret: StaticSecret,
+    pub expires_at: i64,
+}
+
+impl RetiredKey {
+    /// Whether the grace period is still running at `now` (Unix seconds)
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires_at > now
+    }
+
+    /// The secret as stored: sealed like `identity.key` under `passphrase`,
+    /// or raw when the identity has none
+    pub fn sealed_secret(&self, passphrase: Option<&str>) -> Result<Vec<u8>> {
+        match passphrase.filter(|p| !p.is_empty()) {
+            Some(passphrase) => seal_seed(&self.encryption_secret.to_bytes(), passphrase),
+            None => Ok(self.encryption_secret.to_bytes().to_vec()),
+        }
+    }
+
+    /// Rebuild a retired key from a secret stored by [`Self::sealed_secret`]
+    pub fn open(ledger_id: String, stored: &[u8], expires_at: i64, passphrase: Option<&str>) -> Result<Self> {
+        let secret = if stored.starts_with(KEY_FILE_MAGIC) {
+            let passphrase = passphrase.filter(|p| !p.is_empty()).ok_or_else(|| {
+                LedgerError::crypto(format!("Retired key for {} is encrypted; a passphrase is required", ledger_id))
+            })?;
+            open_seed(stored, passphrase)
+                .map_err(|_| LedgerError::crypto(format!("Failed to decrypt retired key for {}: wrong passphrase?", ledger_id)))?
+        } else {
+            <[u8; 32]>::try_from(stored)
+                .map_err(|_| LedgerError::crypto(format!("Retired key for {} is corrupt", ledger_id)))?
+        };
+        Ok(Self { ledger_id, encryption_secret: secret.into(), expires_at })
+    }
+
+    /// Whether a stored secret is raw rather than sealed
+    pub fn is_plaintext(stored: &[u8]) -> bool {
+        !stored.starts_with(KEY_FILE_MAGIC)
+    }
+}
+
+/// Marks an identity file written in the versioned format
+const KEY_FILE_MAGIC: &[u8; 4] = b"LDGK";
+/// Versioned format: Argon2id-derived key, ChaCha20-Poly1305 sealed seed
+const KEY_FILE_VERSION_ENCRYPTED: u8 = 1;
+const KEY_FILE_SALT_LEN: usize = 16;
+const KEY_FILE_NONCE_LEN: usize = 12;
+
+/// Tags an identity export document
+const EXPORT_FORMAT: &str = "ledger-identity-export";
+const EXPORT_VERSION: u32 = 1;
+
+/// Days a rotated-away identity's key keeps decrypting mail when the
+/// `retired_key_days` setting is unset
+pub const DEFAULT_RETIRED_KEY_DAYS: i64 = 30;
+
+/// Hash rounds per safety number half, as in Signal's numeric fingerprints
+const FINGERPRINT_ITERATIONS: usize = 5200;
+const FINGERPRINT_VERSION: u16 = 0;
+
+/// A portable copy of an identity for moving it to another device: the seed
+/// sealed under an export passphrase in the encrypted `identity.key` format,
+/// alongside public metadata that is checked against it on import
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityExport {
+    pub format: String,
+    pub version: u32,
+    pub ledger_id: String,
+    pub exported_at: i64,
+    /// Base64 of an encrypted identity file
+    pub key_file: String,
 }
 
 impl LedgerIdentity {
-    /// Generate a new identity or load from disk
-    pub fn load_or_create(data_dir: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Generate a new identity or load from disk.
+    ///
+    /// A non-empty `passphrase` encrypts the seed at rest; `None` or an empty
+    /// passphrase keeps the legacy plaintext file.
+    pub fn load_or_create(
+        data_dir: &PathBuf,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
         let key_path = data_dir.join("identity.key");
+        let passphrase = passphrase.filter(|p| !p.is_empty());
 
         if key_path.exists() {
             tracing::info!("Loading existing identity from {:?}", key_path);
-            Self::load_from_file(&key_path)
+            let (identity, was_encrypted) = Self::load_from_file(&key_path, passphrase)?;
+            if passphrase.is_some() && !was_encrypted {
+                tracing::info!("Encrypting existing identity file with passphrase");
+                identity.save_to_file(&key_path, passphrase)?;
+            }
+            Ok(identity)
         } else {
             tracing::info!("Generating new identity...");
             let identity = Self::generate()?;
             fs::create_dir_all(data_dir)?;
-            identity.save_to_file(&key_path)?;
+            identity.save_to_file(&key_path, passphrase)?;
             tracing::info!("Identity saved to {:?}", key_path);
             Ok(identity)
         }
     }
 
+    /// Load the identity in `data_dir` without creating or rewriting it;
+    /// an error if there is none
+    pub fn load(data_dir: &PathBuf, passphrase: Option<&str>) -> Result<Self> {
+        let key_path = data_dir.join("identity.key");
+        if !key_path.exists() {
+            return Err(LedgerError::not_found(format!("Identity in {:?}", data_dir)));
+        }
+        let (identity, _) = Self::load_from_file(&key_path, passphrase.filter(|p| !p.is_empty()))?;
+        Ok(identity)
+    }
+
     /// Generate a brand new identity
-    pub fn generate() -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn generate() -> Result<Self> {
         let mut csprng = OsRng;
 
         // Ed25519 signing key
         let signing_key = SigningKey::generate(&mut csprng);
-        let verifying_key = signing_key.verifying_key();
+        Self::from_seed(&signing_key.to_bytes())
+    }
 
-        // X25519 encryption key (derived deterministically from signing key seed)
-        // We use the Ed25519 seed to derive a separate X25519 key via HKDF
-        let seed = signing_key.to_bytes();
-        let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(b"ledger-x25519"), &seed);
-        let mut x25519_bytes = [0u8; 32];
-        hk.expand(b"encryption-key", &mut x25519_bytes)
-            .map_err(|e| format!("HKDF expand error: {}", e))?;
+    /// Rebuild the full identity from its 32-byte Ed25519 seed
+    pub fn from_seed(seed: &[u8; 32]) -> Result<Self> {
+        let signing_key = SigningKey::from_bytes(seed);
+        let verifying_key = signing_key.verifying_key();
 
-        let encryption_secret = StaticSecret::from(x25519_bytes);
+        // X25519 encryption key: the standard Ed25519 → Curve25519 conversion
+        // (clamped SHA-512 of the seed), so its public half is the Montgomery
+        // form of our verifying key and anyone can compute it from the Ledger ID
+        let encryption_secret = StaticSecret::from(signing_key.to_scalar_bytes());
         let encryption_public = X25519PublicKey::from(&encryption_secret);
+        let legacy_encryption_secret = legacy_x25519_secret(seed)?;
 
         let ledger_id = format!("ledger:{}", bs58::encode(verifying_key.as_bytes()).into_string());
 
@@ -58,14 +172,102 @@ impl LedgerIdentity {
             verifying_key,
             encryption_secret,
             encryption_public,
+            legacy_encryption_secret,
             ledger_id,
+            retired_keys: Vec::new(),
         })
     }
 
+    /// This identity, also holding the keys of identities it replaced
+    pub fn with_retired_keys(mut self, retired_keys: Vec<RetiredKey>) -> Self {
+        self.retired_keys = retired_keys;
+        self
+    }
+
+    /// This identity's encryption key, as `RetiredKey` once rotated away from
+    pub fn retire(&self, expires_at: i64) -> RetiredKey {
+        RetiredKey {
+            ledger_id: self.ledger_id.clone(),
+            encryption_secret: self.encryption_secret.clone(),
+            expires_at,
+        }
+    }
+
+    /// Encode the signing seed as a 24-word BIP39 recovery phrase
+    pub fn to_mnemonic(&self) -> String {
+        bip39::Mnemonic::from_entropy(&self.signing_key.to_bytes())
+            .expect("32 bytes is valid BIP39 entropy")
+            .to_string()
+    }
+
+    /// Rebuild an identity from a 24-word BIP39 recovery phrase
+    pub fn from_mnemonic(phrase: &str) -> Result<Self> {
+        let normalized = phrase.split_whitespace()
+            .map(|w| w.to_lowercase())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mnemonic = bip39::Mnemonic::parse_normalized(&normalized)
+            .map_err(|e| LedgerError::invalid(format!("Invalid recovery phrase: {}", e)))?;
+        let seed = <[u8; 32]>::try_from(mnemonic.to_entropy().as_slice())
+            .map_err(|_| LedgerError::invalid("Invalid recovery phrase: expected 24 words"))?;
+        Self::from_seed(&seed)
+    }
+
+    /// Seal the identity under `passphrase` for moving to another device
+    pub fn export(&self, passphrase: &str) -> Result<IdentityExport> {
+        if passphrase.is_empty() {
+            return Err(LedgerError::invalid("An export passphrase is required"));
+        }
+        let key_file = seal_seed(&self.signing_key.to_bytes(), passphrase)?;
+        Ok(IdentityExport {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            ledger_id: self.ledger_id.clone(),
+            exported_at: chrono::Utc::now().timestamp(),
+            key_file: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, key_file),
+        })
+    }
+
+    /// Open an export made by [`LedgerIdentity::export`], checking that the
+    /// sealed seed really belongs to the Ledger ID it claims
+    pub fn from_export(export: &IdentityExport, passphrase: &str) -> Result<Self> {
+        if export.format != EXPORT_FORMAT {
+            return Err(LedgerError::invalid("Not a Ledger identity export"));
+        }
+        if export.version != EXPORT_VERSION {
+            return Err(LedgerError::invalid(format!("Unsupported identity export version: {}", export.version)));
+        }
+        let key_file = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, &export.key_file)
+            .map_err(|_| LedgerError::invalid("Identity export is corrupt"))?;
+        if !key_file.starts_with(KEY_FILE_MAGIC) {
+            return Err(LedgerError::invalid("Identity export is corrupt"));
+        }
+        let seed = open_seed(&key_file, passphrase)
+            .map_err(|_| LedgerError::invalid("Could not open identity export: wrong passphrase?"))?;
+        let identity = Self::from_seed(&seed)?;
+        if identity.ledger_id != export.ledger_id {
+            return Err(LedgerError::invalid("Identity export does not match its Ledger ID"));
+        }
+        Ok(identity)
+    }
+
+    /// Write the identity to `identity.key` in the data directory, replacing any existing one
+    pub fn save(&self, data_dir: &PathBuf, passphrase: Option<&str>) -> Result<()> {
+        fs::create_dir_all(data_dir)?;
+        self.save_to_file(&data_dir.join("identity.key"), passphrase.filter(|p| !p.is_empty()))
+    }
+
     /// Save identity to file (Ed25519 seed only — X25519 is derived)
-    fn save_to_file(&self, path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    fn save_to_file(
+        &self,
+        path: &PathBuf,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
         let seed = self.signing_key.to_bytes();
-        fs::write(path, seed)?;
+        match passphrase {
+            Some(passphrase) => fs::write(path, seal_seed(&seed, passphrase)?)?,
+            None => fs::write(path, seed)?,
+        }
         // Restrict permissions on Unix
         #[cfg(unix)]
         {
@@ -75,35 +277,27 @@ impl LedgerIdentity {
         Ok(())
     }
 
-    /// Load identity from file
-    fn load_from_file(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
-        let seed_bytes = fs::read(path)?;
-        if seed_bytes.len() != 32 {
-            return Err("Invalid identity file: expected 32 bytes".into());
-        }
-        let mut seed = [0u8; 32];
-        seed.copy_from_slice(&seed_bytes);
-
-        let signing_key = SigningKey::from_bytes(&seed);
-        let verifying_key = signing_key.verifying_key();
-
-        let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(b"ledger-x25519"), &seed);
-        let mut x25519_bytes = [0u8; 32];
-        hk.expand(b"encryption-key", &mut x25519_bytes)
-            .map_err(|e| format!("HKDF expand error: {}", e))?;
-
-        let encryption_secret = StaticSecret::from(x25519_bytes);
-        let encryption_public = X25519PublicKey::from(&encryption_secret);
+    /// Load identity from file, returning whether the file was encrypted
+    fn load_from_file(
+        path: &PathBuf,
+        passphrase: Option<&str>,
+    ) -> Result<(Self, bool)> {
+        let file_bytes = fs::read(path)?;
 
-        let ledger_id = format!("ledger:{}", bs58::encode(verifying_key.as_bytes()).into_string());
+        // Legacy format: the raw 32-byte seed
+        if file_bytes.len() == 32 {
+            let mut seed = [0u8; 32];
+            seed.copy_from_slice(&file_bytes);
+            return Ok((Self::from_seed(&seed)?, false));
+        }
 
-        Ok(Self {
-            signing_key,
-            verifying_key,
-            encryption_secret,
-            encryption_public,
-            ledger_id,
-        })
+        if !file_bytes.starts_with(KEY_FILE_MAGIC) {
+            return Err(LedgerError::crypto("Invalid identity file: unrecognized format"));
+        }
+        let passphrase = passphrase
+            .ok_or_else(|| LedgerError::config("Identity file is encrypted: set LEDGER_PASSPHRASE or pass --passphrase"))?;
+        let seed = open_seed(&file_bytes, passphrase)?;
+        Ok((Self::from_seed(&seed)?, true))
     }
 
     /// Sign a message
@@ -113,16 +307,44 @@ impl LedgerIdentity {
     }
 
     /// Verify a signature from a given public key
-    pub fn verify(pubkey_bytes: &[u8], data: &[u8], signature_bytes: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
-        let pubkey = VerifyingKey::from_bytes(
-            pubkey_bytes.try_into().map_err(|_| "Invalid public key length")?
-        )?;
+    pub fn verify(pubkey_bytes: &[u8], data: &[u8], signature_bytes: &[u8]) -> Result<bool> {
+        Self::verify_with_key(&Self::verifying_key_from_bytes(pubkey_bytes)?, data, signature_bytes)
+    }
+
+    /// Verify a signature against an already parsed public key, e.g. one
+    /// held in a [`super::key_cache::VerifyingKeyCache`]
+    pub fn verify_with_key(pubkey: &VerifyingKey, data: &[u8], signature_bytes: &[u8]) -> Result<bool> {
         let signature = Signature::from_bytes(
-            signature_bytes.try_into().map_err(|_| "Invalid signature length")?
+            signature_bytes.try_into().map_err(|_| LedgerError::crypto("Invalid signature length"))?
         );
         Ok(pubkey.verify(data, &signature).is_ok())
     }
 
+    /// The Ed25519 key a Ledger ID encodes
+    pub fn verifying_key_from_ledger_id(ledger_id: &str) -> Result<VerifyingKey> {
+        Self::verifying_key_from_bytes(&Self::pubkey_from_ledger_id(ledger_id)?)
+    }
+
+    fn verifying_key_from_bytes(pubkey_bytes: &[u8]) -> Result<VerifyingKey> {
+        Ok(VerifyingKey::from_bytes(
+            pubkey_bytes.try_into().map_err(|_| LedgerError::crypto("Invalid public key length"))?
+        )?)
+    }
+
+    /// Build the libp2p keypair from our Ed25519 seed, so the PeerId is stable
+    /// across restarts and deterministic from the Ledger ID
+    pub fn libp2p_keypair(&self) -> Result<libp2p::identity::Keypair> {
+        let seed = self.signing_key.to_bytes();
+        Ok(libp2p::identity::Keypair::ed25519_from_bytes(seed)?)
+    }
+
+    /// The libp2p PeerId a Ledger ID's node runs under (see [`Self::libp2p_keypair`])
+    pub fn peer_id_from_ledger_id(ledger_id: &str) -> Result<libp2p::PeerId> {
+        let pubkey = Self::pubkey_from_ledger_id(ledger_id)?;
+        let ed_public = libp2p::identity::ed25519::PublicKey::try_from_bytes(&pubkey)?;
+        Ok(libp2p::PeerId::from(libp2p::identity::PublicKey::from(ed_public)))
+    }
+
     /// Get public key bytes (Ed25519)
     pub fn public_key_bytes(&self) -> Vec<u8> {
         self.verifying_key.as_bytes().to_vec()
@@ -133,14 +355,127 @@ impl LedgerIdentity {
         self.encryption_public.as_bytes().to_vec()
     }
 
+    /// Map an Ed25519 public key to its X25519 (Montgomery) form
+    pub fn x25519_public_from_ed25519(ed_public: &[u8]) -> Result<[u8; 32]> {
+        let verifying_key = VerifyingKey::from_bytes(
+            ed_public.try_into().map_err(|_| LedgerError::crypto("Invalid public key length"))?
+        )?;
+        Ok(verifying_key.to_montgomery().to_bytes())
+    }
+
+    /// The X25519 key to encrypt to for a Ledger ID
+    pub fn encryption_key_from_ledger_id(ledger_id: &str) -> Result<[u8; 32]> {
+        Self::x25519_public_from_ed25519(&Self::pubkey_from_ledger_id(ledger_id)?)
+    }
+
+    /// Safety number for this identity and another party's Ed25519 public
+    /// key: 60 digits that both sides compute identically, and that change
+    /// if either key does. Compared out of band, it shows that neither
+    /// party was handed a substitute key.
+    pub fn safety_number(&self, other_pubkey: &[u8]) -> String {
+        let mut halves = [
+            fingerprint_digits(&self.public_key_bytes()),
+            fingerprint_digits(other_pubkey),
+        ];
+        halves.sort();
+        halves.concat()
+    }
+
     /// Parse a Ledger ID back to public key bytes
-    pub fn pubkey_from_ledger_id(ledger_id: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let id = ledger_id.strip_prefix("ledger:").ok_or("Invalid Ledger ID format")?;
+    pub fn pubkey_from_ledger_id(ledger_id: &str) -> Result<Vec<u8>> {
+        let id = ledger_id.strip_prefix("ledger:").ok_or_else(|| LedgerError::invalid("Invalid Ledger ID format"))?;
         let bytes = bs58::decode(id).into_vec()?;
         Ok(bytes)
     }
 }
 
+/// The X25519 secret identities used before the standard conversion:
+/// HKDF-SHA256 over the Ed25519 seed. Only needed to read old mail.
+fn legacy_x25519_secret(seed: &[u8; 32]) -> Result<StaticSecret> {
+    let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(b"ledger-x25519"), seed);
+    let mut x25519_bytes = [0u8; 32];
+    hk.expand(b"encryption-key", &mut x25519_bytes)
+        .map_err(|e| LedgerError::crypto(format!("HKDF expand error: {}", e)))?;
+    Ok(StaticSecret::from(x25519_bytes))
+}
+
+/// 30 digits for one key: iterated SHA-512, then six 5-byte chunks each
+/// reduced to 5 digits
+fn fingerprint_digits(pubkey: &[u8]) -> String {
+    use sha2::{Digest, Sha512};
+
+    let mut hash = Sha512::new()
+        .chain_update(FINGERPRINT_VERSION.to_be_bytes())
+        .chain_update(pubkey)
+        .finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(pubkey).finalize();
+    }
+
+    hash[..30].chunks(5)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |n, b| (n << 8) | *b as u64);
+            format!("{:05}", n % 100_000)
+        })
+        .collect()
+}
+
+/// Derive the file encryption key from a passphrase with Argon2id
+fn derive_file_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| LedgerError::crypto(format!("Argon2 error: {}", e)))?;
+    Ok(key)
+}
+
+/// Encrypt a seed into the versioned identity file format:
+/// `magic || version || salt || nonce || ciphertext`
+fn seal_seed(seed: &[u8; 32], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0u8; KEY_FILE_SALT_LEN];
+    let mut nonce_bytes = [0u8; KEY_FILE_NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce_bytes);
+
+    let key = derive_file_key(passphrase, &salt)?;
+    let cipher = ChaCha20Poly1305::new_from_slice(&key)
+        .map_err(|e| LedgerError::crypto(format!("Cipher init error: {}", e)))?;
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce_bytes), seed.as_slice())
+        .map_err(|e| LedgerError::crypto(format!("Encryption error: {}", e)))?;
+
+    let mut out = Vec::with_capacity(5 + salt.len() + nonce_bytes.len() + ciphertext.len());
+    out.extend_from_slice(KEY_FILE_MAGIC);
+    out.push(KEY_FILE_VERSION_ENCRYPTED);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a seed written by [`seal_seed`]
+fn open_seed(file_bytes: &[u8], passphrase: &str) -> Result<[u8; 32]> {
+    let header_len = KEY_FILE_MAGIC.len() + 1;
+    if file_bytes.len() < header_len + KEY_FILE_SALT_LEN + KEY_FILE_NONCE_LEN {
+        return Err(LedgerError::crypto("Invalid identity file: truncated"));
+    }
+    let version = file_bytes[KEY_FILE_MAGIC.len()];
+    if version != KEY_FILE_VERSION_ENCRYPTED {
+        return Err(LedgerError::crypto(format!("Unsupported identity file version: {}", version)));
+    }
+
+    let (salt, rest) = file_bytes[header_len..].split_at(KEY_FILE_SALT_LEN);
+    let (nonce_bytes, ciphertext) = rest.split_at(KEY_FILE_NONCE_LEN);
+
+    let key = derive_file_key(passphrase, salt)?;
+    let cipher = ChaCha20Poly1305::new_from_slice(&key)
+        .map_err(|e| LedgerError::crypto(format!("Cipher init error: {}", e)))?;
+    let seed = cipher.decrypt(Nonce::from_slice(nonce_bytes), ciphertext)
+        .map_err(|_| LedgerError::crypto("Failed to decrypt identity file: wrong passphrase?"))?;
+
+    <[u8; 32]>::try_from(seed.as_slice())
+        .map_err(|_| LedgerError::crypto("Invalid identity file: bad seed length"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -178,18 +513,151 @@ mod tests {
         assert_eq!(pubkey, identity.public_key_bytes());
     }
 
+    #[test]
+    fn test_encryption_key_derivable_from_ledger_id() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let derived = LedgerIdentity::encryption_key_from_ledger_id(&identity.ledger_id).unwrap();
+        assert_eq!(derived.to_vec(), identity.encryption_public_bytes());
+    }
+
+    #[test]
+    fn test_libp2p_keypair_matches_identity() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let keypair = identity.libp2p_keypair().unwrap();
+        let ed_public = keypair.public().try_into_ed25519().unwrap();
+        assert_eq!(ed_public.to_bytes().to_vec(), identity.public_key_bytes());
+
+        let again = identity.libp2p_keypair().unwrap();
+        assert_eq!(
+            libp2p::PeerId::from(keypair.public()),
+            libp2p::PeerId::from(again.public()),
+        );
+        assert_eq!(
+            LedgerIdentity::peer_id_from_ledger_id(&identity.ledger_id).unwrap(),
+            libp2p::PeerId::from(keypair.public()),
+        );
+    }
+
+    #[test]
+    fn test_safety_number_symmetric() {
+        let alice = LedgerIdentity::generate().unwrap();
+        let bob = LedgerIdentity::generate().unwrap();
+        let number = alice.safety_number(&bob.public_key_bytes());
+        assert_eq!(number.len(), 60);
+        assert!(number.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(number, bob.safety_number(&alice.public_key_bytes()));
+
+        // A substituted key gives a different number
+        let mallory = LedgerIdentity::generate().unwrap();
+        assert_ne!(number, alice.safety_number(&mallory.public_key_bytes()));
+    }
+
+    #[test]
+    fn test_mnemonic_roundtrip() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let phrase = identity.to_mnemonic();
+        assert_eq!(phrase.split_whitespace().count(), 24);
+
+        let recovered = LedgerIdentity::from_mnemonic(&phrase).unwrap();
+        assert_eq!(recovered.ledger_id, identity.ledger_id);
+        assert_eq!(recovered.encryption_public_bytes(), identity.encryption_public_bytes());
+    }
+
+    #[test]
+    fn test_mnemonic_bad_checksum_rejected() {
+        // Valid words, but the checksum for all-zero entropy ends in "art"
+        let bad = vec!["abandon"; 24].join(" ");
+        assert!(LedgerIdentity::from_mnemonic(&bad).is_err());
+
+        let good = format!("{} art", vec!["abandon"; 23].join(" "));
+        assert!(LedgerIdentity::from_mnemonic(&good).is_ok());
+
+        assert!(LedgerIdentity::from_mnemonic("not a real phrase").is_err());
+    }
+
+    #[test]
+    fn test_export_roundtrip() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let export = identity.export("correct horse").unwrap();
+        assert_eq!(export.ledger_id, identity.ledger_id);
+
+        let imported = LedgerIdentity::from_export(&export, "correct horse").unwrap();
+        assert_eq!(imported.signing_key.to_bytes(), identity.signing_key.to_bytes());
+
+        assert!(matches!(
+            LedgerIdentity::from_export(&export, "battery staple"),
+            Err(LedgerError::InvalidInput(_))
+        ));
+        let relabelled = IdentityExport {
+            ledger_id: LedgerIdentity::generate().unwrap().ledger_id,
+            ..export
+        };
+        assert!(LedgerIdentity::from_export(&relabelled, "correct horse").is_err());
+        assert!(identity.export("").is_err());
+    }
+
     #[test]
     fn test_save_load() {
         let tmp = std::env::temp_dir().join("ledger_test_identity");
         let _ = fs::remove_dir_all(&tmp);
         fs::create_dir_all(&tmp).unwrap();
 
-        let original = LedgerIdentity::load_or_create(&tmp).unwrap();
-        let loaded = LedgerIdentity::load_or_create(&tmp).unwrap();
+        let original = LedgerIdentity::load_or_create(&tmp, None).unwrap();
+        let loaded = LedgerIdentity::load_or_create(&tmp, None).unwrap();
 
         assert_eq!(original.ledger_id, loaded.ledger_id);
         assert_eq!(original.public_key_bytes(), loaded.public_key_bytes());
 
         let _ = fs::remove_dir_all(&tmp);
     }
+
+    #[test]
+    fn test_load_never_creates() {
+        let tmp = std::env::temp_dir().join("ledger_test_identity_load_only");
+        let _ = fs::remove_dir_all(&tmp);
+
+        assert!(matches!(LedgerIdentity::load(&tmp, None), Err(LedgerError::NotFound(_))));
+        assert!(!tmp.exists());
+
+        let original = LedgerIdentity::load_or_create(&tmp, Some("hunter2")).unwrap();
+        assert_eq!(LedgerIdentity::load(&tmp, Some("hunter2")).unwrap().ledger_id, original.ledger_id);
+        assert!(LedgerIdentity::load(&tmp, None).is_err());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn test_save_load_encrypted() {
+        let tmp = std::env::temp_dir().join("ledger_test_identity_encrypted");
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(&tmp).unwrap();
+
+        let original = LedgerIdentity::load_or_create(&tmp, Some("hunter2")).unwrap();
+        let on_disk = fs::read(tmp.join("identity.key")).unwrap();
+        assert!(on_disk.starts_with(KEY_FILE_MAGIC));
+
+        let loaded = LedgerIdentity::load_or_create(&tmp, Some("hunter2")).unwrap();
+        assert_eq!(original.ledger_id, loaded.ledger_id);
+
+        assert!(LedgerIdentity::load_or_create(&tmp, Some("wrong")).is_err());
+        assert!(LedgerIdentity::load_or_create(&tmp, None).is_err());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn test_plaintext_upgraded_with_passphrase() {
+        let tmp = std::env::temp_dir().join("ledger_test_identity_upgrade");
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(&tmp).unwrap();
+
+        let original = LedgerIdentity::load_or_create(&tmp, Some("")).unwrap();
+        assert_eq!(fs::read(tmp.join("identity.key")).unwrap().len(), 32);
+
+        let upgraded = LedgerIdentity::load_or_create(&tmp, Some("hunter2")).unwrap();
+        assert_eq!(original.ledger_id, upgraded.ledger_id);
+        assert!(fs::read(tmp.join("identity.key")).unwrap().starts_with(KEY_FILE_MAGIC));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
 }
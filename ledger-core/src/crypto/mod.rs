@@ -1,2 +1,6 @@
 pub mod keys;
 pub mod envelope;
+pub mod sealed;
+pub mod card;
+pub mod attachment;
+pub mod key_cache;
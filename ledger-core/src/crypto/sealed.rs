@@ -0,0 +1,93 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+use crate::error::{LedgerError, Result};
+
+/// Data encrypted to one X25519 key under a fresh ephemeral key. Unlike an
+/// envelope it is anonymous: nothing in it names or is signed by the sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedBox {
+    pub ephemeral_pubkey: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl SealedBox {
+    /// Encrypt `plaintext` so only the holder of `recipient_pubkey`'s secret can
+    /// read it; `aad` must be presented again to open it
+    pub fn seal(recipient_pubkey: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<Self> {
+        let ephemeral_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let ephemeral_public = X25519PublicKey::from(&ephemeral_secret);
+        let key = box_key(&ephemeral_secret, &X25519PublicKey::from(*recipient_pubkey))?;
+
+        let mut nonce_bytes = [0u8; 12];
+        rand::rngs::OsRng.fill_bytes(&mut nonce_bytes);
+        let ciphertext = ChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|e| LedgerError::crypto(format!("Cipher init error: {}", e)))?
+            .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: plaintext, aad })
+            .map_err(|e| LedgerError::crypto(format!("Encryption error: {}", e)))?;
+
+        Ok(Self {
+            ephemeral_pubkey: BASE64.encode(ephemeral_public.as_bytes()),
+            nonce: BASE64.encode(nonce_bytes),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+    }
+
+    /// Decrypt a box sealed to the public half of `secret`
+    pub fn open(&self, secret: &StaticSecret, aad: &[u8]) -> Result<Vec<u8>> {
+        let ephemeral_bytes = BASE64.decode(&self.ephemeral_pubkey)?;
+        let ephemeral_pubkey = X25519PublicKey::from(
+            <[u8; 32]>::try_from(ephemeral_bytes.as_slice())
+                .map_err(|_| LedgerError::crypto("Invalid ephemeral public key"))?
+        );
+        let nonce_bytes = BASE64.decode(&self.nonce)?;
+        if nonce_bytes.len() != 12 {
+            return Err(LedgerError::crypto("Invalid nonce length"));
+        }
+        let ciphertext = BASE64.decode(&self.ciphertext)?;
+
+        let key = box_key(secret, &ephemeral_pubkey)?;
+        ChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|e| LedgerError::crypto(format!("Cipher init error: {}", e)))?
+            .decrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &ciphertext, aad })
+            .map_err(|e| LedgerError::crypto(format!("Decryption error: {}", e)))
+    }
+}
+
+/// DH, then HKDF with a label of its own so box keys never collide with message keys
+fn box_key(secret: &StaticSecret, public: &X25519PublicKey) -> Result<[u8; 32]> {
+    let shared_secret = secret.diffie_hellman(public);
+    let hk = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
+    let mut key = [0u8; 32];
+    hk.expand(b"ledger-sealed-box", &mut key)
+        .map_err(|e| LedgerError::crypto(format!("HKDF error: {}", e)))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::LedgerIdentity;
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let recipient = LedgerIdentity::generate().unwrap();
+        let pubkey: [u8; 32] = recipient.encryption_public_bytes().try_into().unwrap();
+        let sealed = SealedBox::seal(&pubkey, b"hello", b"id-1").unwrap();
+
+        assert_eq!(sealed.open(&recipient.encryption_secret, b"id-1").unwrap(), b"hello");
+        // Bound to its associated data and its recipient
+        assert!(sealed.open(&recipient.encryption_secret, b"id-2").is_err());
+        let other = LedgerIdentity::generate().unwrap();
+        assert!(sealed.open(&other.encryption_secret, b"id-1").is_err());
+    }
+}
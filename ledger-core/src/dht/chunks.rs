@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Largest record value published as-is. Kademlia peers refuse packets over
+/// 16 KiB by default, so a bigger record would never leave our own store.
+pub const MAX_RECORD_BYTES: usize = 14 * 1024;
+
+/// Size of each piece a larger value is split into
+const CHUNK_BYTES: usize = 12 * 1024;
+
+/// Stored in place of a value too large for one record: the SHA-256 of each
+/// chunk, in order. Chunks live under `chunk_key(hash)`, so a chunk someone
+/// else wrote there is detected and skipped.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkManifest {
+    pub chunks: Vec<String>,
+}
+
+impl ChunkManifest {
+    /// The manifest a record value holds, if it is one
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        serde_json::from_slice(value).ok()
+    }
+
+    /// Rebuild the value from candidate records for each chunk, taking the
+    /// first whose hash matches
+    pub fn assemble(&self, candidates: &[Vec<Vec<u8>>]) -> Result<Vec<u8>, String> {
+        if candidates.len() != self.chunks.len() {
+            return Err("chunk count mismatch".into());
+        }
+        let mut value = Vec::new();
+        for (n, (hash, found)) in self.chunks.iter().zip(candidates).enumerate() {
+            let chunk = found.iter()
+                .find(|chunk| chunk_hash(chunk) == *hash)
+                .ok_or_else(|| format!("chunk {} of {} missing", n + 1, self.chunks.len()))?;
+            value.extend_from_slice(chunk);
+        }
+        Ok(value)
+    }
+}
+
+/// A `(key, value)` record to put
+pub type ChunkRecord = (Vec<u8>, Vec<u8>);
+
+/// DHT key of a chunk, derived from its content
+pub fn chunk_key(hash: &str) -> Vec<u8> {
+    format!("ledger:chunk:{}", hash).into_bytes()
+}
+
+/// Split a value into the chunk records to put and the manifest that replaces
+/// it, or fail if even the manifest would be too large for one record
+pub fn split(value: &[u8]) -> Result<(ChunkManifest, Vec<ChunkRecord>), String> {
+    let records: Vec<(String, Vec<u8>)> = value.chunks(CHUNK_BYTES)
+        .map(|chunk| (chunk_hash(chunk), chunk.to_vec()))
+        .collect();
+    let manifest = ChunkManifest {
+        chunks: records.iter().map(|(hash, _)| hash.clone()).collect(),
+    };
+
+    let manifest_len = serde_json::to_vec(&manifest).map_err(|e| format!("Serialize error: {}", e))?.len();
+    if manifest_len > MAX_RECORD_BYTES {
+        return Err(format!("{} byte record is too large for the DHT", value.len()));
+    }
+    let records = records.into_iter().map(|(hash, chunk)| (chunk_key(&hash), chunk)).collect();
+    Ok((manifest, records))
+}
+
+fn chunk_hash(chunk: &[u8]) -> String {
+    hex::encode(Sha256::digest(chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_assemble() {
+        let value: Vec<u8> = (0..3 * CHUNK_BYTES + 100).map(|i| i as u8).collect();
+        let (manifest, records) = split(&value).unwrap();
+        assert_eq!(records.len(), 4);
+        assert!(records.iter().all(|(_, chunk)| chunk.len() <= CHUNK_BYTES));
+
+        let stored = serde_json::to_vec(&manifest).unwrap();
+        assert_eq!(ChunkManifest::parse(&stored), Some(manifest.clone()));
+        // Mailboxes and acknowledgements are not manifests
+        assert_eq!(ChunkManifest::parse(b"[]"), None);
+        assert_eq!(ChunkManifest::parse(br#"{"reader":"ledger:x","chunks":[]}"#), None);
+
+        // A chunk overwritten by someone else is passed over
+        let mut candidates: Vec<Vec<Vec<u8>>> = records.iter().map(|(_, chunk)| vec![chunk.clone()]).collect();
+        candidates[1].insert(0, b"garbage".to_vec());
+        assert_eq!(manifest.assemble(&candidates).unwrap(), value);
+
+        candidates[2].clear();
+        assert!(manifest.assemble(&candidates).is_err());
+    }
+
+    #[test]
+    fn test_split_refuses_oversized_value() {
+        let value = vec![0u8; 1000 * CHUNK_BYTES];
+        assert!(split(&value).is_err());
+    }
+}
@@ -1 +1,3 @@
+pub mod chunks;
+pub mod republish;
 pub mod store;
@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::store::{self, MAILBOX_MAX_AGE};
+use crate::models::message::{DeliveryStatus, DhtEnvelope, EncryptedEnvelope};
+use crate::p2p::node::P2PCommand;
+use crate::store::db::Database;
+
+/// How often the worker looks for envelopes due to be republished
+const POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Remember an envelope just stored in the DHT so it is republished before it expires
+pub fn track(db: &Database, message_id: &str, recipient: &str, envelope: &EncryptedEnvelope) {
+    let result = serde_json::to_string(envelope)
+        .map_err(|e| e.into())
+        .and_then(|json| db.track_dht_envelope(message_id, recipient, &envelope.id, &json, chrono::Utc::now().timestamp()));
+    if let Err(e) = result {
+        tracing::error!("Failed to track DHT envelope {}: {}", envelope.id, e);
+    }
+}
+
+/// Republish stored envelopes halfway through their record lifetime (the
+/// `dht_ttl_hours` setting) until the recipient acknowledges them or they
+/// reach `MAILBOX_MAX_AGE`
+pub fn start(db: Arc<Database>, p2p_tx: mpsc::Sender<P2PCommand>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = chrono::Utc::now().timestamp();
+
+            match db.expire_dht_envelopes(now - MAILBOX_MAX_AGE.as_secs() as i64) {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Stopped republishing {} unacknowledged DHT envelope(s) past the maximum age", n),
+                Err(e) => tracing::error!("Failed to expire DHT envelopes: {}", e),
+            }
+
+            let half_ttl = store::record_ttl(&db).as_secs() as i64 / 2;
+            let due = match db.due_dht_envelopes(now - half_ttl) {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::error!("Failed to read DHT envelopes: {}", e);
+                    continue;
+                }
+            };
+            for (recipient, envelopes) in by_recipient(due) {
+                republish(&db, &p2p_tx, &recipient, envelopes).await;
+            }
+        }
+    });
+}
+
+fn by_recipient(envelopes: Vec<DhtEnvelope>) -> BTreeMap<String, Vec<DhtEnvelope>> {
+    let mut grouped: BTreeMap<String, Vec<DhtEnvelope>> = BTreeMap::new();
+    for envelope in envelopes {
+        grouped.entry(envelope.recipient.clone()).or_default().push(envelope);
+    }
+    grouped
+}
+
+/// Drop what the recipient acknowledged, then put the rest back in its mailbox
+async fn republish(db: &Database, p2p_tx: &mpsc::Sender<P2PCommand>, recipient: &str, tracked: Vec<DhtEnvelope>) {
+    let acked = match store::fetch_retrieval_ack(p2p_tx, recipient).await {
+        Ok(acked) => acked,
+        Err(e) => {
+            tracing::debug!("Could not read retrieval acknowledgement for {}: {}", recipient, e);
+            HashSet::new()
+        }
+    };
+
+    let mut pending = Vec::new();
+    for entry in tracked {
+        if acked.contains(&entry.envelope_id) {
+            acknowledge(db, &entry.envelope_id, recipient);
+            continue;
+        }
+        match serde_json::from_str::<EncryptedEnvelope>(&entry.envelope_json) {
+            Ok(envelope) => pending.push(envelope),
+            Err(e) => {
+                tracing::warn!("Dropping corrupt DHT envelope {}: {}", entry.envelope_id, e);
+                let _ = db.untrack_dht_envelope(&entry.envelope_id, recipient);
+            }
+        }
+    }
+    if pending.is_empty() {
+        return;
+    }
+
+    match store::put_mailbox(p2p_tx, recipient, &pending, &acked).await {
+        Ok(()) => {
+            let ids: Vec<String> = pending.into_iter().map(|e| e.id).collect();
+            tracing::debug!("Republished {} DHT envelope(s) for {}", ids.len(), recipient);
+            if let Err(e) = db.mark_dht_published(recipient, &ids, chrono::Utc::now().timestamp()) {
+                tracing::error!("Failed to record DHT republish: {}", e);
+            }
+        }
+        // Still due, so the next poll tries again
+        Err(e) => tracing::warn!("Failed to republish DHT envelopes for {}: {}", recipient, e),
+    }
+}
+
+/// The recipient has the envelope: stop republishing and mark the Sent copy delivered
+fn acknowledge(db: &Database, envelope_id: &str, recipient: &str) {
+    match db.untrack_dht_envelope(envelope_id, recipient) {
+        Ok(Some(message_id)) => {
+            tracing::info!("Recipient retrieved message {} from the DHT", message_id);
+            if let Err(e) = db.set_delivery_status(&message_id, &DeliveryStatus::Delivered) {
+                tracing::error!("Failed to update delivery status: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to untrack DHT envelope {}: {}", envelope_id, e),
+    }
+}
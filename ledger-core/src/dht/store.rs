@@ -1,20 +1,346 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use tokio::sync::mpsc;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 
+use super::chunks::{self, ChunkManifest, MAX_RECORD_BYTES};
+use crate::crypto::keys::LedgerIdentity;
+use crate::crypto::sealed::SealedBox;
 use crate::p2p::node::P2PCommand;
 use crate::models::message::EncryptedEnvelope;
+use crate::store::db::Database;
+
+/// Record lifetime when the `dht_ttl_hours` setting is missing or unreadable
+pub const DEFAULT_DHT_TTL: Duration = Duration::from_secs(72 * 3600);
+
+/// Envelopes older than this are dropped from mailboxes and no longer republished
+pub const MAILBOX_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Most envelope ids a retrieval acknowledgement lists; the oldest go first
+const MAX_ACK_IDS: usize = 1000;
+
+/// How long DHT records live, from the `dht_ttl_hours` setting
+pub fn record_ttl(db: &Database) -> Duration {
+    db.get_setting("dht_ttl_hours").ok().flatten()
+        .and_then(|hours| hours.parse::<u64>().ok())
+        .filter(|hours| *hours > 0)
+        .map(|hours| Duration::from_secs(hours * 3600))
+        .unwrap_or(DEFAULT_DHT_TTL)
+}
+
+/// DHT key under which a node publishes the PeerId serving its Ledger ID
+pub fn peer_record_key(ledger_id: &str) -> Vec<u8> {
+    format!("ledger:peer:{}", ledger_id).into_bytes()
+}
+
+/// Look up the PeerId a Ledger ID has published in the DHT
+pub async fn resolve_peer_id(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    ledger_id: &str,
+) -> Result<Option<libp2p::PeerId>, String> {
+    let Some(data) = dht_get(p2p_tx, peer_record_key(ledger_id)).await? else {
+        return Ok(None);
+    };
+
+    let peer_id = String::from_utf8(data)
+        .map_err(|e| format!("Invalid peer record: {}", e))?
+        .parse::<libp2p::PeerId>()
+        .map_err(|e| format!("Invalid peer record: {}", e))?;
+
+    // Anyone can write the record, but only the Ledger ID's own key yields this PeerId
+    let expected = LedgerIdentity::peer_id_from_ledger_id(ledger_id)
+        .map_err(|e| format!("Invalid Ledger ID: {}", e))?;
+    if peer_id != expected {
+        tracing::warn!("Ignoring spoofed peer record for {}", ledger_id);
+        return Ok(None);
+    }
+    Ok(Some(peer_id))
+}
+
+/// One envelope in a mailbox record, sealed to the mailbox owner so only
+/// they can see who it is from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MailboxEntry {
+    /// Envelope id, for deduplication and retrieval acknowledgements
+    id: String,
+    /// Envelope timestamp, so stale entries can be dropped without opening them
+    stored_at: i64,
+    /// The envelope JSON, with `id` as associated data
+    sealed: SealedBox,
+}
 
-/// Store an encrypted envelope in the DHT for offline retrieval
+/// DHT key of the mailbox for the holder of `encryption_pubkey`:
+/// HMAC-SHA256 keyed by the recipient's X25519 public key. Unlike the
+/// `ledger:msg:{ledger_id}` keys used before, it can't be matched to a Ledger ID
+/// without already knowing the recipient's key; mail left under those old keys
+/// is no longer looked up.
+fn mailbox_key(encryption_pubkey: &[u8]) -> Vec<u8> {
+    keyed_label(encryption_pubkey, b"ledger-mailbox")
+}
+
+/// DHT key of the retrieval acknowledgement the mailbox owner publishes after syncing
+fn ack_record_key(encryption_pubkey: &[u8]) -> Vec<u8> {
+    keyed_label(encryption_pubkey, b"ledger-mailbox-ack")
+}
+
+fn keyed_label(encryption_pubkey: &[u8], label: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(encryption_pubkey)
+        .expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The X25519 key mailbox entries for `ledger_id` are sealed to
+fn recipient_encryption_key(ledger_id: &str) -> Result<[u8; 32], String> {
+    LedgerIdentity::encryption_key_from_ledger_id(ledger_id)
+        .map_err(|e| format!("Invalid Ledger ID: {}", e))
+}
+
+/// Store encrypted envelopes in the DHT for offline retrieval.
+///
+/// The record under the recipient's key holds every pending envelope, so the
+/// existing list is fetched and merged before writing it back.
 pub async fn store_in_dht(
     p2p_tx: &mpsc::Sender<P2PCommand>,
     recipient_ledger_id: &str,
-    envelope: &EncryptedEnvelope,
+    envelopes: &[EncryptedEnvelope],
 ) -> Result<(), String> {
-    let key = format!("ledger:msg:{}", recipient_ledger_id);
-    let value = serde_json::to_vec(envelope).map_err(|e| format!("Serialize error: {}", e))?;
+    let acked = fetch_retrieval_ack(p2p_tx, recipient_ledger_id).await?;
+    put_mailbox(p2p_tx, recipient_ledger_id, envelopes, &acked).await
+}
+
+/// Merge `envelopes` into the recipient's mailbox record, leaving out
+/// envelopes the recipient has acknowledged and ones past `MAILBOX_MAX_AGE`
+pub async fn put_mailbox(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    recipient_ledger_id: &str,
+    envelopes: &[EncryptedEnvelope],
+    acked: &HashSet<String>,
+) -> Result<(), String> {
+    let recipient_key = recipient_encryption_key(recipient_ledger_id)?;
+    let key = mailbox_key(&recipient_key);
+
+    // Carry over what any node holds, so one with a stale copy doesn't lose entries
+    let existing = merge_records(&dht_get_all(p2p_tx, key.clone()).await?);
+    let incoming = envelopes.iter()
+        .map(|env| seal_entry(&recipient_key, env))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cutoff = chrono::Utc::now().timestamp() - MAILBOX_MAX_AGE.as_secs() as i64;
+    let merged: Vec<MailboxEntry> = merge_entries(existing, incoming)
+        .into_iter()
+        .filter(|entry| !acked.contains(&entry.id) && entry.stored_at >= cutoff)
+        .collect();
+    let value = serde_json::to_vec(&merged).map_err(|e| format!("Serialize error: {}", e))?;
+
+    dht_put(p2p_tx, key, value).await
+}
 
+/// Retrieve pending messages from the DHT mailbox of the holder of
+/// `encryption_secret`: ours, or a retired identity's. Nodes can hold
+/// different versions of the mailbox, so the whole query is waited out and
+/// every version found is merged.
+pub async fn retrieve_from_dht(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    encryption_secret: &StaticSecret,
+) -> Result<Option<Vec<EncryptedEnvelope>>, String> {
+    let key = mailbox_key(X25519PublicKey::from(encryption_secret).as_bytes());
+
+    let records = dht_get_all(p2p_tx, key).await?;
+    if records.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(open_entries(encryption_secret, merge_records(&records))))
+}
+
+fn seal_entry(recipient_key: &[u8; 32], envelope: &EncryptedEnvelope) -> Result<MailboxEntry, String> {
+    let json = serde_json::to_vec(envelope).map_err(|e| format!("Serialize error: {}", e))?;
+    let sealed = SealedBox::seal(recipient_key, &json, envelope.id.as_bytes())
+        .map_err(|e| format!("Sealing failed: {}", e))?;
+    Ok(MailboxEntry { id: envelope.id.clone(), stored_at: envelope.timestamp, sealed })
+}
+
+/// Open every entry sealed to us. Anyone can write the record, so entries
+/// that don't open or don't match their id are skipped.
+fn open_entries(encryption_secret: &StaticSecret, entries: Vec<MailboxEntry>) -> Vec<EncryptedEnvelope> {
+    entries.into_iter()
+        .filter_map(|entry| {
+            let opened = entry.sealed.open(encryption_secret, entry.id.as_bytes())
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_slice::<EncryptedEnvelope>(&json).map_err(|e| e.to_string()))
+                .and_then(|env| if env.id == entry.id { Ok(env) } else { Err("id mismatch".into()) });
+            match opened {
+                Ok(env) => Some(env),
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable mailbox entry {}: {}", entry.id, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Signed list of envelope ids a recipient has pulled out of its mailbox, so
+/// the senders republishing them can stop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrievalAck {
+    /// Ledger ID of the mailbox owner, whose key signs the acknowledgement
+    pub reader: String,
+    pub envelope_ids: Vec<String>,
+    pub signed_at: i64,
+    /// Base64 Ed25519 signature by the reader's key over every other field
+    pub signature: String,
+}
+
+impl RetrievalAck {
+    /// Build and sign an acknowledgement, keeping the last `MAX_ACK_IDS` ids
+    pub fn new(identity: &LedgerIdentity, mut envelope_ids: Vec<String>) -> Self {
+        if envelope_ids.len() > MAX_ACK_IDS {
+            envelope_ids.drain(..envelope_ids.len() - MAX_ACK_IDS);
+        }
+        let mut ack = Self {
+            reader: identity.ledger_id.clone(),
+            envelope_ids,
+            signed_at: chrono::Utc::now().timestamp(),
+            signature: String::new(),
+        };
+        ack.signature = BASE64.encode(identity.sign(&ack.signing_payload()));
+        ack
+    }
+
+    /// Check the acknowledgement is signed by the reader's key
+    pub fn verify(&self) -> Result<(), String> {
+        let pubkey = LedgerIdentity::pubkey_from_ledger_id(&self.reader)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        let signature = BASE64.decode(&self.signature)
+            .map_err(|e| format!("invalid signature encoding: {}", e))?;
+        match LedgerIdentity::verify(&pubkey, &self.signing_payload(), &signature) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("bad signature".into()),
+            Err(e) => Err(format!("bad signature: {}", e)),
+        }
+    }
+
+    /// Domain tag followed by each field length-prefixed, as for receipts
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut out = b"ledger-dht-ack".to_vec();
+        for field in std::iter::once(&self.reader).chain(&self.envelope_ids) {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        }
+        out.extend_from_slice(&self.signed_at.to_be_bytes());
+        out
+    }
+}
+
+/// Tell senders which of our mailbox envelopes we now hold
+pub async fn publish_retrieval_ack(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    identity: &LedgerIdentity,
+    envelope_ids: Vec<String>,
+) -> Result<(), String> {
+    let ack = RetrievalAck::new(identity, envelope_ids);
+    let value = serde_json::to_vec(&ack).map_err(|e| format!("Serialize error: {}", e))?;
+    dht_put(p2p_tx, ack_record_key(&identity.encryption_public_bytes()), value).await
+}
+
+/// Envelope ids `recipient_ledger_id` has acknowledged. Anyone can write the
+/// record, so one not signed by the recipient counts as no acknowledgement.
+pub async fn fetch_retrieval_ack(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    recipient_ledger_id: &str,
+) -> Result<HashSet<String>, String> {
+    let key = ack_record_key(&recipient_encryption_key(recipient_ledger_id)?);
+    let Some(data) = dht_get(p2p_tx, key).await? else {
+        return Ok(HashSet::new());
+    };
+    match serde_json::from_slice::<RetrievalAck>(&data) {
+        Ok(ack) if ack.reader == recipient_ledger_id && ack.verify().is_ok() => {
+            Ok(ack.envelope_ids.into_iter().collect())
+        }
+        _ => {
+            tracing::warn!("Ignoring invalid retrieval acknowledgement for {}", recipient_ledger_id);
+            Ok(HashSet::new())
+        }
+    }
+}
+
+/// Write `value` under `key`. A value too large for one record is put as
+/// chunk records first, then a `ChunkManifest` under `key`.
+async fn dht_put(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+) -> Result<(), String> {
+    if value.len() <= MAX_RECORD_BYTES {
+        return put_record(p2p_tx, key, value).await;
+    }
+
+    let (manifest, records) = chunks::split(&value)?;
+    tracing::debug!("Putting {} byte record as {} chunks", value.len(), records.len());
+    for (chunk_key, chunk) in records {
+        put_record(p2p_tx, chunk_key, chunk).await?;
+    }
+    let manifest = serde_json::to_vec(&manifest).map_err(|e| format!("Serialize error: {}", e))?;
+    put_record(p2p_tx, key, manifest).await
+}
+
+/// Fetch the value stored under `key`, reassembling it if it was chunked.
+/// A chunked value whose chunks can't all be found counts as missing.
+async fn dht_get(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    key: Vec<u8>,
+) -> Result<Option<Vec<u8>>, String> {
+    match get_record(p2p_tx, key).await? {
+        Some(value) => Ok(reassemble(p2p_tx, value).await),
+        None => Ok(None),
+    }
+}
+
+/// Fetch every distinct value stored under `key` across the nodes queried,
+/// reassembling chunked ones and leaving out any that can't be
+async fn dht_get_all(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    key: Vec<u8>,
+) -> Result<Vec<Vec<u8>>, String> {
+    let mut values = Vec::new();
+    for value in get_records(p2p_tx, key).await? {
+        values.extend(reassemble(p2p_tx, value).await);
+    }
+    Ok(values)
+}
+
+/// The value a record stands for: itself, or the chunks its manifest lists
+async fn reassemble(p2p_tx: &mpsc::Sender<P2PCommand>, value: Vec<u8>) -> Option<Vec<u8>> {
+    let Some(manifest) = ChunkManifest::parse(&value) else {
+        return Some(value);
+    };
+
+    let fetches = manifest.chunks.iter().map(|hash| get_records(p2p_tx, chunks::chunk_key(hash)));
+    let assembled = futures::future::try_join_all(fetches).await
+        .and_then(|candidates| manifest.assemble(&candidates));
+    match assembled {
+        Ok(value) => Some(value),
+        Err(e) => {
+            tracing::warn!("Skipping chunked DHT record: {}", e);
+            None
+        }
+    }
+}
+
+/// Put one record, which must fit in a Kademlia packet
+async fn put_record(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+) -> Result<(), String> {
     let (tx, mut rx) = mpsc::channel(1);
     p2p_tx.send(P2PCommand::DhtPut {
-        key: key.into_bytes(),
+        key,
         value,
         response_tx: tx,
     }).await.map_err(|e| format!("Channel send error: {}", e))?;
@@ -23,29 +349,152 @@ pub async fn store_in_dht(
         .ok_or_else(|| "No response from DHT put".to_string())?
 }
 
-/// Retrieve pending messages from the DHT for the local identity
-pub async fn retrieve_from_dht(
+/// Fetch the raw record value stored under `key`
+async fn get_record(
     p2p_tx: &mpsc::Sender<P2PCommand>,
-    own_ledger_id: &str,
-) -> Result<Option<Vec<EncryptedEnvelope>>, String> {
-    let key = format!("ledger:msg:{}", own_ledger_id);
-
+    key: Vec<u8>,
+) -> Result<Option<Vec<u8>>, String> {
     let (tx, mut rx) = mpsc::channel(1);
     p2p_tx.send(P2PCommand::DhtGet {
-        key: key.into_bytes(),
+        key,
+        response_tx: tx,
+    }).await.map_err(|e| format!("Channel send error: {}", e))?;
+
+    rx.recv().await
+        .ok_or_else(|| "No response from DHT get".to_string())?
+}
+
+/// Fetch every distinct raw record value stored under `key`
+async fn get_records(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    key: Vec<u8>,
+) -> Result<Vec<Vec<u8>>, String> {
+    let (tx, mut rx) = mpsc::channel(1);
+    p2p_tx.send(P2PCommand::DhtGetAll {
+        key,
         response_tx: tx,
     }).await.map_err(|e| format!("Channel send error: {}", e))?;
 
-    let result = rx.recv().await
-        .ok_or_else(|| "No response from DHT get".to_string())?;
+    rx.recv().await
+        .ok_or_else(|| "No response from DHT get".to_string())?
+}
+
+fn decode_mailbox(data: &[u8]) -> Result<Vec<MailboxEntry>, String> {
+    serde_json::from_slice(data).map_err(|e| format!("Deserialize error: {}", e))
+}
+
+/// Union the entries of several versions of a mailbox record. Anyone can
+/// write the record, so versions that don't decode are skipped.
+fn merge_records(records: &[Vec<u8>]) -> Vec<MailboxEntry> {
+    records.iter()
+        .filter_map(|data| match decode_mailbox(data) {
+            Ok(entries) => Some(entries),
+            Err(e) => {
+                tracing::warn!("Skipping unreadable mailbox record: {}", e);
+                None
+            }
+        })
+        .fold(Vec::new(), merge_entries)
+}
+
+/// Union two entry lists, keeping the first copy of each envelope `id`
+fn merge_entries(existing: Vec<MailboxEntry>, incoming: Vec<MailboxEntry>) -> Vec<MailboxEntry> {
+    let mut seen = HashSet::new();
+    existing.into_iter()
+        .chain(incoming)
+        .filter(|entry| seen.insert(entry.id.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match result {
-        Ok(Some(data)) => {
-            let envelope: EncryptedEnvelope = serde_json::from_slice(&data)
-                .map_err(|e| format!("Deserialize error: {}", e))?;
-            Ok(Some(vec![envelope]))
+    fn envelope(id: &str) -> EncryptedEnvelope {
+        EncryptedEnvelope {
+            version: 1,
+            id: id.to_string(),
+            from_ledger_id: "ledger:sender".into(),
+            to_ledger_id: "ledger:recipient".into(),
+            ephemeral_pubkey: String::new(),
+            encrypted_body: String::new(),
+            nonce: String::new(),
+            signature: String::new(),
+            timestamp: 0,
+            subject_hint: String::new(),
+            content_type: Default::default(),
+            read_receipt_requested: false,
+            recipients: vec![],
         }
-        Ok(None) => Ok(None),
-        Err(e) => Err(e),
+    }
+
+    fn entry(recipient: &LedgerIdentity, id: &str) -> MailboxEntry {
+        let key = recipient_encryption_key(&recipient.ledger_id).unwrap();
+        seal_entry(&key, &envelope(id)).unwrap()
+    }
+
+    #[test]
+    fn test_merge_dedupes_by_id() {
+        let recipient = LedgerIdentity::generate().unwrap();
+        let merged = merge_entries(
+            vec![entry(&recipient, "a"), entry(&recipient, "b")],
+            vec![entry(&recipient, "b"), entry(&recipient, "c")],
+        );
+        let ids: Vec<&str> = merged.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_merge_records_unions_versions() {
+        let recipient = LedgerIdentity::generate().unwrap();
+        let record = |ids: &[&str]| {
+            let entries: Vec<MailboxEntry> = ids.iter().map(|id| entry(&recipient, id)).collect();
+            serde_json::to_vec(&entries).unwrap()
+        };
+        let records = vec![record(&["a", "b"]), b"not a mailbox".to_vec(), record(&["c", "a"])];
+
+        let ids: Vec<String> = merge_records(&records).into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_mailbox_key_derived_from_recipient_key() {
+        let recipient = LedgerIdentity::generate().unwrap();
+        // Senders derive it from the Ledger ID, the owner from its own key
+        let from_ledger_id = mailbox_key(&recipient_encryption_key(&recipient.ledger_id).unwrap());
+        assert_eq!(from_ledger_id, mailbox_key(&recipient.encryption_public_bytes()));
+        assert_ne!(from_ledger_id, ack_record_key(&recipient.encryption_public_bytes()));
+    }
+
+    #[test]
+    fn test_only_recipient_opens_entries() {
+        let recipient = LedgerIdentity::generate().unwrap();
+        let mut swapped = entry(&recipient, "b");
+        swapped.id = "c".into();
+        let entries = vec![entry(&recipient, "a"), swapped];
+
+        let opened = open_entries(&recipient.encryption_secret, entries.clone());
+        let ids: Vec<&str> = opened.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["a"]);
+
+        let other = LedgerIdentity::generate().unwrap();
+        assert!(open_entries(&other.encryption_secret, entries).is_empty());
+    }
+
+    #[test]
+    fn test_retrieval_ack_verifies_and_rejects_tampering() {
+        let reader = LedgerIdentity::generate().unwrap();
+        let ack = RetrievalAck::new(&reader, vec!["a".into(), "b".into()]);
+        assert!(ack.verify().is_ok());
+
+        // A sender can't acknowledge envelopes on the recipient's behalf
+        let mut widened = ack.clone();
+        widened.envelope_ids.push("c".into());
+        assert!(widened.verify().is_err());
+
+        let ids = (0..MAX_ACK_IDS + 5).map(|i| i.to_string()).collect();
+        let capped = RetrievalAck::new(&reader, ids);
+        assert_eq!(capped.envelope_ids.len(), MAX_ACK_IDS);
+        assert_eq!(capped.envelope_ids[0], "5");
     }
 }
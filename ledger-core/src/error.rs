@@ -0,0 +1,80 @@
+use std::fmt::Display;
+
+/// Errors from the crypto, storage and delivery layers, split by what a
+/// caller can do about them. The API maps them to HTTP statuses.
+#[derive(Debug, thiserror::Error)]
+pub enum LedgerError {
+    /// The thing asked for doesn't exist (a message, contact, setting...)
+    #[error("{0} not found")]
+    NotFound(String),
+    /// The caller passed something malformed
+    #[error("{0}")]
+    InvalidInput(String),
+    /// Bad keys, signatures or ciphertext, or a wrong passphrase
+    #[error("{0}")]
+    Crypto(String),
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("Pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+    /// Peers, the DHT, Gmail or Tor couldn't be reached or refused
+    #[error("{0}")]
+    Network(String),
+    /// Missing or inconsistent setup, e.g. Gmail not configured
+    #[error("{0}")]
+    Config(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl LedgerError {
+    pub fn not_found(what: impl Display) -> Self {
+        LedgerError::NotFound(what.to_string())
+    }
+
+    pub fn invalid(e: impl Display) -> Self {
+        LedgerError::InvalidInput(e.to_string())
+    }
+
+    pub fn crypto(e: impl Display) -> Self {
+        LedgerError::Crypto(e.to_string())
+    }
+
+    pub fn network(e: impl Display) -> Self {
+        LedgerError::Network(e.to_string())
+    }
+
+    pub fn config(e: impl Display) -> Self {
+        LedgerError::Config(e.to_string())
+    }
+}
+
+// Decoding and key errors from the crypto crates mean bad key material
+impl From<base64::DecodeError> for LedgerError {
+    fn from(e: base64::DecodeError) -> Self {
+        LedgerError::crypto(e)
+    }
+}
+
+// Only Ledger IDs are base58
+impl From<bs58::decode::Error> for LedgerError {
+    fn from(e: bs58::decode::Error) -> Self {
+        LedgerError::invalid(format!("Invalid Ledger ID: {}", e))
+    }
+}
+
+impl From<ed25519_dalek::SignatureError> for LedgerError {
+    fn from(e: ed25519_dalek::SignatureError) -> Self {
+        LedgerError::crypto(e)
+    }
+}
+
+impl From<libp2p::identity::DecodingError> for LedgerError {
+    fn from(e: libp2p::identity::DecodingError) -> Self {
+        LedgerError::crypto(e)
+    }
+}
+
+pub type Result<T, E = LedgerError> = std::result::Result<T, E>;
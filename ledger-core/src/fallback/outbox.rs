@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::router::{self, DeliveryResult};
+use crate::dht;
+use crate::metrics::Metrics;
+use crate::models::message::{DeliveryStatus, EncryptedEnvelope, OutboxEntry};
+use crate::p2p::node::P2PCommand;
+use crate::store::db::Database;
+
+/// Delay before the first retry; doubles with each failed attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest wait between two retries
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Attempts after which a message is marked failed and dropped from the outbox
+const MAX_ATTEMPTS: u32 = 8;
+
+/// How often the worker looks for due envelopes. A peer connecting makes its
+/// envelopes due at once, so they go out on the next poll.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Wait before the retry following `attempts` failed ones
+pub fn backoff(attempts: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// Retry queued envelopes in the background until they go through or run
+/// out of attempts
+pub fn start(db: Arc<Database>, p2p_tx: mpsc::Sender<P2PCommand>, metrics: Arc<Metrics>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let due = match db.due_outbox(chrono::Utc::now().timestamp()) {
+                Ok(due) => due,
+                Err(e) => {
+                    tracing::error!("Failed to read outbox: {}", e);
+                    continue;
+                }
+            };
+            for entry in due {
+                retry(&db, &p2p_tx, &metrics, entry).await;
+            }
+        }
+    });
+}
+
+/// One delivery attempt: straight to the peer, else into the DHT
+async fn retry(db: &Database, p2p_tx: &mpsc::Sender<P2PCommand>, metrics: &Metrics, entry: OutboxEntry) {
+    let result = match router::send_envelope(db, p2p_tx, &entry.message_id, &entry.recipient, entry.envelope_json.clone()).await {
+        DeliveryResult::Failed(p2p_error) => match serde_json::from_str::<EncryptedEnvelope>(&entry.envelope_json) {
+            Ok(envelope) => match dht::store::store_in_dht(p2p_tx, &entry.recipient, std::slice::from_ref(&envelope)).await {
+                Ok(()) => {
+                    dht::republish::track(db, &entry.message_id, &entry.recipient, &envelope);
+                    DeliveryResult::DhtStored
+                }
+                Err(e) => DeliveryResult::Failed(format!("{}; DHT: {}", p2p_error, e)),
+            },
+            Err(e) => DeliveryResult::Failed(format!("Corrupt outbox envelope: {}", e)),
+        },
+        result => result,
+    };
+
+    let queued_for = Duration::from_secs((chrono::Utc::now().timestamp() - entry.created_at).max(0) as u64);
+    let method = match result {
+        DeliveryResult::P2pDirect => {
+            if let Err(e) = db.set_delivery_status(&entry.message_id, &DeliveryStatus::Delivered) {
+                tracing::error!("Failed to update delivery status: {}", e);
+            }
+            "p2p"
+        }
+        // Still pending until the recipient pulls it from the DHT
+        DeliveryResult::DhtStored => "dht",
+        DeliveryResult::Failed(e) => {
+            let attempts = entry.attempts + 1;
+            if attempts >= MAX_ATTEMPTS {
+                tracing::warn!("Giving up on message {} to {} after {} attempts: {}", entry.message_id, entry.recipient, attempts, e);
+                let _ = db.remove_outbox(&entry.id);
+                if let Err(e) = db.set_delivery_status(&entry.message_id, &DeliveryStatus::Failed) {
+                    tracing::error!("Failed to update delivery status: {}", e);
+                }
+            } else {
+                let next = chrono::Utc::now().timestamp() + backoff(attempts).as_secs() as i64;
+                tracing::debug!("Retry {} of message {} to {} failed: {}", attempts, entry.message_id, entry.recipient, e);
+                if let Err(e) = db.reschedule_outbox(&entry.id, attempts, next, &e) {
+                    tracing::error!("Failed to reschedule outbox entry: {}", e);
+                }
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    tracing::info!("Delivered queued message {} to {} via {}", entry.message_id, entry.recipient, method);
+    metrics.record_sent(method, queued_for);
+    if let Err(e) = db.remove_outbox(&entry.id) {
+        tracing::error!("Failed to remove outbox entry: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        assert_eq!(backoff(0), RETRY_BASE_DELAY);
+        assert_eq!(backoff(1), RETRY_BASE_DELAY * 2);
+        assert_eq!(backoff(3), RETRY_BASE_DELAY * 8);
+        assert_eq!(backoff(MAX_ATTEMPTS), RETRY_MAX_DELAY);
+        assert_eq!(backoff(u32::MAX), RETRY_MAX_DELAY);
+    }
+}
@@ -1,111 +1,446 @@
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use serde::Serialize;
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use crate::crypto::envelope::encrypt_message;
+use crate::crypto::envelope::{encrypt_group_message, encrypt_message};
 use crate::crypto::keys::LedgerIdentity;
 use crate::dht;
-use crate::gmail::smtp_client;
-use crate::models::message::*;
+use crate::error::{LedgerError, Result};
+use crate::gmail::{self, smtp_client};
+use crate::models::message::{Attachment, Contact, DeliveryMode, EncryptedEnvelope, Message, OutboxEntry, OutgoingContent, RecipientMethod, Recipients};
 use crate::p2p::node::P2PCommand;
+use crate::p2p::receipt::ReadReceipt;
+use crate::p2p::transfer::Download;
 use crate::store::db::Database;
 
 /// Delivery result indicating which method was used
+#[derive(Clone)]
 pub enum DeliveryResult {
     P2pDirect,
     DhtStored,
     GmailFallback,
     GmailDirect,
+    /// Nothing got through; the envelope waits in the outbox for a retry
+    Queued,
     Failed(String),
 }
 
-/// Route a message based on delivery mode settings
+impl std::fmt::Display for DeliveryResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryResult::P2pDirect => write!(f, "delivered directly over P2P"),
+            DeliveryResult::DhtStored => write!(f, "stored in the DHT for the recipient to collect"),
+            DeliveryResult::GmailFallback => write!(f, "sent as an encrypted Gmail fallback"),
+            DeliveryResult::GmailDirect => write!(f, "sent via Gmail"),
+            DeliveryResult::Queued => write!(f, "queued in the outbox for retry"),
+            DeliveryResult::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+impl DeliveryResult {
+    pub fn method(&self) -> RecipientMethod {
+        match self {
+            DeliveryResult::P2pDirect => RecipientMethod::P2p,
+            DeliveryResult::DhtStored => RecipientMethod::Dht,
+            DeliveryResult::GmailFallback => RecipientMethod::Fallback,
+            DeliveryResult::GmailDirect => RecipientMethod::Gmail,
+            DeliveryResult::Queued => RecipientMethod::Queued,
+            DeliveryResult::Failed(_) => RecipientMethod::Failed,
+        }
+    }
+}
+
+/// The `delivery_mode` setting, or auto if it is unset or unknown
+pub fn configured_mode(db: &Database) -> DeliveryMode {
+    let stored = db.get_setting("delivery_mode").ok().flatten();
+    stored.as_deref().and_then(DeliveryMode::parse).unwrap_or(DeliveryMode::Auto)
+}
+
+/// Which preference decided the delivery mode for a recipient
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeSource {
+    /// The `mode` given with the send
+    Request,
+    /// The contact's `preferred_delivery`
+    Contact,
+    /// The `delivery_mode` setting, or auto when it is unset
+    Setting,
+}
+
+/// The delivery mode for one recipient, with what decided it. An explicit
+/// per-request mode wins, then the contact's `preferred_delivery`, then the
+/// `delivery_mode` setting. `to` must already have its alias expanded.
+pub fn effective_mode(db: &Database, to: &str, requested: Option<DeliveryMode>) -> (DeliveryMode, ModeSource) {
+    if let Some(mode) = requested {
+        return (mode, ModeSource::Request);
+    }
+    let preferred = db.get_contact(to).ok().flatten().and_then(|contact| contact.preferred_delivery);
+    match preferred {
+        Some(mode) => (mode, ModeSource::Contact),
+        None => (configured_mode(db), ModeSource::Setting),
+    }
+}
+
+/// A recipient address that has been checked
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipient {
+    /// A Ledger ID, holding its Ed25519 public key
+    Ledger(Vec<u8>),
+    /// An email address with its domain lowercased
+    Email(String),
+}
+
+impl std::fmt::Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recipient::Ledger(pubkey) => write!(f, "ledger:{}", bs58::encode(pubkey).into_string()),
+            Recipient::Email(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Check that `addr` is a Ledger ID whose key can be encrypted to, or a
+/// syntactically valid email address
+pub fn parse_recipient(addr: &str) -> Result<Recipient> {
+    let addr = addr.trim();
+    if addr.starts_with("ledger:") {
+        let invalid = || LedgerError::invalid(format!("Invalid Ledger ID: {}", addr));
+        let pubkey = LedgerIdentity::pubkey_from_ledger_id(addr).map_err(|_| invalid())?;
+        // Also catches base58 of the wrong length or that isn't a curve point
+        LedgerIdentity::x25519_public_from_ed25519(&pubkey).map_err(|_| invalid())?;
+        return Ok(Recipient::Ledger(pubkey));
+    }
+    let email: lettre::Address = addr.parse()
+        .map_err(|e| LedgerError::invalid(format!("Invalid email address {:?}: {}", addr, e)))?;
+    Ok(Recipient::Email(format!("{}@{}", email.user(), email.domain().to_ascii_lowercase())))
+}
+
+/// The contact's Ledger ID for an `@alias`; any other address is returned as is
+pub fn expand_alias(db: &Database, addr: &str) -> Result<String> {
+    let addr = addr.trim();
+    if !addr.starts_with('@') {
+        return Ok(addr.to_string());
+    }
+    db.resolve_alias(addr)?.ok_or_else(|| LedgerError::not_found(format!("Alias {}", addr)))
+}
+
+/// Validate every recipient, replacing aliases with Ledger IDs and each
+/// address with its normalized form
+pub fn parse_recipients(db: &Database, recipients: &Recipients) -> Result<Recipients> {
+    let parse = |list: &[String]| -> Result<Vec<String>> {
+        list.iter()
+            .filter(|addr| !addr.trim().is_empty())
+            .map(|addr| parse_recipient(&expand_alias(db, addr)?).map(|r| r.to_string()))
+            .collect()
+    };
+    Ok(Recipients {
+        to: parse(&recipients.to)?,
+        cc: parse(&recipients.cc)?,
+        bcc: parse(&recipients.bcc)?,
+    })
+}
+
+/// The path a message takes, decided from the recipient and mode alone
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Route {
+    /// Straight to the peer, else into the outbox
+    P2p,
+    /// Straight to the peer, else the DHT and Gmail fallback, else the outbox
+    P2pWithFallback,
+    /// Plain email through Gmail
+    Gmail,
+}
+
+fn plan_route(to: &str, mode: DeliveryMode) -> std::result::Result<Route, &'static str> {
+    let is_ledger_id = to.starts_with("ledger:");
+    match mode {
+        DeliveryMode::P2pOnly if is_ledger_id => Ok(Route::P2p),
+        DeliveryMode::P2pOnly => Err("P2P mode requires a Ledger ID recipient"),
+        DeliveryMode::GmailOnly => Ok(Route::Gmail),
+        DeliveryMode::Auto if is_ledger_id => Ok(Route::P2pWithFallback),
+        // Regular email address — send via Gmail
+        DeliveryMode::Auto => Ok(Route::Gmail),
+    }
+}
+
+/// How one recipient's copy went, under the correlation ID its log lines carry
+#[derive(Clone)]
+pub struct RouteOutcome {
+    pub request_id: String,
+    pub result: DeliveryResult,
+}
+
+/// Route a message in the recipient's `effective_mode`, with `requested` the
+/// mode given with the send if any. `message_id` is the local Sent copy,
+/// whose delivery status follows the recipient's acknowledgement.
+///
+/// Every log line along the way is in a span tagged with a fresh
+/// `request_id`, returned with the result so a send can be found in the logs.
 pub async fn route_message(
     identity: &LedgerIdentity,
     db: &Database,
     p2p_tx: &mpsc::Sender<P2PCommand>,
+    message_id: &str,
     to: &str,
-    subject: &str,
-    body: &str,
-    mode: &str,
-) -> DeliveryResult {
-    let is_ledger_id = to.starts_with("ledger:");
+    content: &OutgoingContent,
+    requested: Option<DeliveryMode>,
+) -> RouteOutcome {
+    let request_id = new_request_id();
+    let span = tracing::info_span!("route", request_id = %request_id, to = %to.trim());
+    let result = route(identity, db, p2p_tx, message_id, to, content, requested)
+        .instrument(span.clone())
+        .await;
+    span.in_scope(|| match &result {
+        DeliveryResult::Failed(e) => tracing::warn!("Delivery failed: {}", e),
+        result => tracing::info!("Delivery finished: {}", result),
+    });
+    RouteOutcome { request_id, result }
+}
 
-    match mode {
-        "p2p_only" => {
-            if !is_ledger_id {
-                return DeliveryResult::Failed("P2P mode requires a Ledger ID recipient".into());
+/// Send one email to every address in `to` and `cc` that goes out through
+/// plain Gmail, so they see each other in the headers as with any mail
+/// client. Returns the addresses it covered, as listed in `recipients`, with
+/// the one outcome they share; BCC and everyone else are routed one by one.
+pub async fn route_email_group(
+    db: &Database,
+    recipients: &Recipients,
+    content: &OutgoingContent,
+    requested: Option<DeliveryMode>,
+) -> Option<(Vec<String>, RouteOutcome)> {
+    let (covered, emails) = email_group(db, recipients, requested);
+    if covered.is_empty() {
+        return None;
+    }
+    let request_id = new_request_id();
+    let span = tracing::info_span!("route", request_id = %request_id, to = %emails.joined());
+    let result = async {
+        let result = send_via_gmail(db, &emails, content).await;
+        log_attempt("Gmail", &result);
+        result
+    }.instrument(span.clone()).await;
+    span.in_scope(|| match &result {
+        DeliveryResult::Failed(e) => tracing::warn!("Delivery failed: {}", e),
+        result => tracing::info!("Delivery finished: {}", result),
+    });
+    Some((covered, RouteOutcome { request_id, result }))
+}
+
+/// The addresses in `to` and `cc` whose route is plain Gmail, and the email
+/// recipients they come to once aliases and contacts are resolved
+fn email_group(db: &Database, recipients: &Recipients, requested: Option<DeliveryMode>) -> (Vec<String>, Recipients) {
+    let mut covered: Vec<String> = Vec::new();
+    let mut emails = Recipients::default();
+    for (listed, resolved) in [(&recipients.to, &mut emails.to), (&recipients.cc, &mut emails.cc)] {
+        for addr in listed.iter().map(|addr| addr.trim()).filter(|addr| !addr.is_empty()) {
+            if covered.iter().any(|c| c == addr) {
+                continue;
+            }
+            let Ok(to) = expand_alias(db, addr) else { continue };
+            if plan_route(&to, effective_mode(db, &to, requested).0) != Ok(Route::Gmail) {
+                continue;
             }
-            try_p2p_delivery(identity, db, p2p_tx, to, subject, body).await
+            let Ok(email) = recipient_email(db, &to) else { continue };
+            covered.push(addr.to_string());
+            resolved.push(email);
         }
-        "gmail_only" => {
-            try_gmail_delivery(identity, db, to, subject, body, false).await
+    }
+    (covered, emails)
+}
+
+/// Short random ID to correlate one recipient's delivery logs
+fn new_request_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+/// Log how one delivery method went
+fn log_attempt(method: &str, result: &DeliveryResult) {
+    match result {
+        DeliveryResult::Failed(e) => tracing::info!("{} failed: {}", method, e),
+        result => tracing::info!("{}: {}", method, result),
+    }
+}
+
+async fn route(
+    identity: &LedgerIdentity,
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    message_id: &str,
+    to: &str,
+    content: &OutgoingContent,
+    requested: Option<DeliveryMode>,
+) -> DeliveryResult {
+    let to = match expand_alias(db, to) {
+        Ok(to) => to,
+        Err(e) => return DeliveryResult::Failed(e.to_string()),
+    };
+    let to = to.as_str();
+    let (mode, source) = effective_mode(db, to, requested);
+    let route = match plan_route(to, mode) {
+        Ok(route) => route,
+        Err(e) => return DeliveryResult::Failed(e.into()),
+    };
+    tracing::info!("Planned route {:?} for {} mode from {:?}", route, mode, source);
+    match route {
+        Route::P2p => {
+            let p2p_result = try_p2p_delivery(identity, db, p2p_tx, message_id, to, content).await;
+            log_attempt("P2P", &p2p_result);
+            match p2p_result {
+                DeliveryResult::Failed(e) => queue_for_retry(identity, db, message_id, to, content, e),
+                result => result,
+            }
         }
-        "auto" | _ => {
-            if is_ledger_id {
-                // Try P2P first
-                match try_p2p_delivery(identity, db, p2p_tx, to, subject, body).await {
-                    DeliveryResult::P2pDirect => DeliveryResult::P2pDirect,
-                    _ => {
-                        // P2P failed, try DHT storage
-                        tracing::info!("P2P delivery failed, trying DHT storage");
-                        let dht_result = try_dht_delivery(identity, db, p2p_tx, to, subject, body).await;
-
-                        // Also try Gmail fallback if configured
-                        let gmail_result = try_gmail_delivery(identity, db, to, subject, body, true).await;
-
-                        match gmail_result {
-                            DeliveryResult::GmailFallback => DeliveryResult::GmailFallback,
-                            _ => match dht_result {
-                                DeliveryResult::DhtStored => DeliveryResult::DhtStored,
-                                _ => DeliveryResult::Failed("All delivery methods failed".into()),
-                            }
-                        }
-                    }
+        Route::Gmail => {
+            let gmail_result = try_gmail_delivery(db, to, content).await;
+            log_attempt("Gmail", &gmail_result);
+            gmail_result
+        }
+        Route::P2pWithFallback => {
+            // Try P2P first
+            let p2p_result = try_p2p_delivery(identity, db, p2p_tx, message_id, to, content).await;
+            log_attempt("P2P", &p2p_result);
+            if let DeliveryResult::P2pDirect = p2p_result {
+                return p2p_result;
+            }
+
+            // One envelope for both, so a recipient who collects
+            // it twice stores it once
+            let envelope = match seal(identity, db, message_id, to, content) {
+                Ok(env) => env,
+                Err(e) => return queue_for_retry(identity, db, message_id, to, content, e.to_string()),
+            };
+
+            // P2P failed, try DHT storage
+            let dht_result = try_dht_delivery(db, p2p_tx, message_id, to, &envelope).await;
+            log_attempt("DHT", &dht_result);
+
+            // Also try Gmail fallback if configured
+            let gmail_result = try_gmail_fallback(db, to, &envelope).await;
+            log_attempt("Gmail fallback", &gmail_result);
+
+            match gmail_result {
+                DeliveryResult::GmailFallback => DeliveryResult::GmailFallback,
+                _ => match dht_result {
+                    DeliveryResult::DhtStored => DeliveryResult::DhtStored,
+                    _ => queue_for_retry(
+                        identity, db, message_id, to, content,
+                        "All delivery methods failed".into(),
+                    ),
                 }
-            } else {
-                // Regular email address — send via Gmail
-                try_gmail_delivery(identity, db, to, subject, body, false).await
             }
         }
     }
 }
 
-/// Try P2P direct delivery
-async fn try_p2p_delivery(
-    identity: &LedgerIdentity,
+/// How `route_message` would most likely deliver to a recipient
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RoutePreview {
+    /// "p2p", "dht", "fallback", "gmail" or "queued"; "none" if the send would fail
+    pub method: &'static str,
+    /// Whether the message would reach the recipient (or their mail server) straight away
+    pub reachable: bool,
+    pub reason: String,
+    /// The mode the recipient would be sent in
+    pub mode: DeliveryMode,
+    /// Where `mode` came from: "request" beats "contact" beats "setting"
+    pub mode_source: ModeSource,
+}
+
+/// Predict the delivery path for `to` from what the node already knows,
+/// without encrypting, dialling, querying the DHT or refreshing Gmail tokens
+pub async fn preview_route(
     db: &Database,
     p2p_tx: &mpsc::Sender<P2PCommand>,
     to: &str,
-    subject: &str,
-    body: &str,
-) -> DeliveryResult {
-    // Look up recipient's encryption public key from contacts
+    requested: Option<DeliveryMode>,
+) -> RoutePreview {
+    let (mode, mode_source) = effective_mode(db, to, requested);
+    let PlannedPath { method, reachable, reason } = preview_plan(db, p2p_tx, to, mode).await;
+    RoutePreview { method, reachable, reason, mode, mode_source }
+}
+
+/// The path part of a `RoutePreview`
+struct PlannedPath {
+    method: &'static str,
+    reachable: bool,
+    reason: String,
+}
+
+impl PlannedPath {
+    fn new(method: &'static str, reachable: bool, reason: impl Into<String>) -> Self {
+        Self { method, reachable, reason: reason.into() }
+    }
+}
+
+async fn preview_plan(
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    to: &str,
+    mode: DeliveryMode,
+) -> PlannedPath {
+    let route = match plan_route(to, mode) {
+        Ok(route) => route,
+        Err(e) => return PlannedPath::new("none", false, e),
+    };
+    let gmail_configured = matches!(db.gmail_config(), Ok(Some(_)));
+
+    if route == Route::Gmail {
+        return match (gmail_configured, recipient_email(db, to)) {
+            (false, _) => PlannedPath::new("none", false, "Gmail not configured"),
+            (true, Err(DeliveryResult::Failed(e))) => PlannedPath::new("none", false, e),
+            (true, _) => PlannedPath::new("gmail", true, "sent via Gmail"),
+        };
+    }
+
+    // Sealing needs the contact's encryption key
     let contact = match db.get_contact(to) {
-        Ok(Some(c)) => c,
-        _ => {
-            tracing::warn!("No contact found for {}", to);
-            return DeliveryResult::Failed("Recipient not in contacts".into());
-        }
+        Ok(Some(contact)) => contact,
+        _ => return PlannedPath::new("none", false, "Contact not found"),
     };
+    if let Err(e) = contact_encryption_key(&contact) {
+        return PlannedPath::new("none", false, e.to_string());
+    }
 
-    // Decode recipient's encryption public key
-    let recipient_enc_pubkey = match base64::Engine::decode(
-        &base64::engine::general_purpose::STANDARD,
-        &contact.public_key,
-    ) {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            return DeliveryResult::Failed(format!("Invalid contact public key: {}", e));
-        }
+    let (tx, mut rx) = mpsc::channel(1);
+    let _ = p2p_tx.send(P2PCommand::GetPeers { response_tx: tx }).await;
+    let peers = rx.recv().await.unwrap_or_default();
+    let known_peer = db.get_peer_for_ledger_id(to).ok().flatten().map(|m| m.peer_id);
+    if known_peer.as_ref().is_some_and(|peer_id| peers.iter().any(|p| &p.peer_id == peer_id)) {
+        return PlannedPath::new("p2p", true, "peer connected");
+    }
+    let not_connected = match known_peer {
+        Some(_) => "peer known but not connected; a direct dial is tried first",
+        None => "no known peer; its DHT record is looked up first",
     };
 
-    // Encrypt the message
-    let mut envelope = match encrypt_message(identity, &recipient_enc_pubkey, subject, body) {
+    match route {
+        Route::P2pWithFallback if gmail_configured && contact.gmail_address.is_some() => PlannedPath::new(
+            "fallback", true, format!("{}, then sent as an encrypted Gmail fallback", not_connected),
+        ),
+        Route::P2pWithFallback if !peers.is_empty() => PlannedPath::new(
+            "dht", false, format!("{}, then stored in the DHT for the recipient to collect", not_connected),
+        ),
+        _ => PlannedPath::new(
+            "queued", false, format!("{}, then queued in the outbox for retry", not_connected),
+        ),
+    }
+}
+
+/// Try P2P direct delivery, succeeding only once the recipient accepts it
+async fn try_p2p_delivery(
+    identity: &LedgerIdentity,
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    message_id: &str,
+    to: &str,
+    content: &OutgoingContent,
+) -> DeliveryResult {
+    let envelope = match seal(identity, db, message_id, to, content) {
         Ok(env) => env,
-        Err(e) => {
-            return DeliveryResult::Failed(format!("Encryption failed: {}", e));
-        }
+        Err(e) => return DeliveryResult::Failed(e.to_string()),
     };
-    envelope.to_ledger_id = to.to_string();
 
     let envelope_json = match serde_json::to_string(&envelope) {
         Ok(j) => j,
@@ -114,127 +449,445 @@ async fn try_p2p_delivery(
         }
     };
 
-    // Try to find a connected peer for this Ledger ID
-    // For prototype: we try sending to all connected peers
-    let (tx, mut rx) = mpsc::channel(1);
-    let _ = p2p_tx.send(P2PCommand::GetPeers { response_tx: tx }).await;
+    send_envelope(db, p2p_tx, message_id, to, envelope_json).await
+}
 
-    if let Some(peers) = rx.recv().await {
-        if peers.is_empty() {
-            return DeliveryResult::Failed("No connected peers".into());
-        }
+/// Send an already encrypted envelope straight to the peer serving `to`
+pub(crate) async fn send_envelope(
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    message_id: &str,
+    to: &str,
+    envelope_json: String,
+) -> DeliveryResult {
+    let peer_id = match resolve_peer(db, p2p_tx, to).await {
+        Ok(peer_id) => peer_id,
+        Err(e) => return DeliveryResult::Failed(e.to_string()),
+    };
 
-        // Try the first connected peer (in production, we'd route to the right one)
-        let peer_id_str = &peers[0].peer_id;
-        if let Ok(peer_id) = peer_id_str.parse::<libp2p::PeerId>() {
-            let (resp_tx, mut resp_rx) = mpsc::channel(1);
-            let _ = p2p_tx.send(P2PCommand::SendMessage {
-                peer_id,
-                envelope_json,
-                response_tx: resp_tx,
-            }).await;
-
-            if let Some(Ok(())) = resp_rx.recv().await {
-                return DeliveryResult::P2pDirect;
-            }
-        }
+    if let Err(e) = ensure_connected(db, p2p_tx, peer_id).await {
+        return DeliveryResult::Failed(format!("Recipient peer unreachable: {}", e));
     }
 
-    DeliveryResult::Failed("P2P delivery failed".into())
+    let (resp_tx, mut resp_rx) = mpsc::channel(1);
+    let _ = p2p_tx.send(P2PCommand::SendMessage {
+        peer_id,
+        message_id: message_id.to_string(),
+        envelope_json,
+        response_tx: resp_tx,
+    }).await;
+
+    match resp_rx.recv().await {
+        Some(Ok(())) => DeliveryResult::P2pDirect,
+        Some(Err(e)) => DeliveryResult::Failed(format!("P2P delivery failed: {}", e)),
+        None => DeliveryResult::Failed("P2P delivery failed".into()),
+    }
 }
 
-/// Try DHT offline storage
-async fn try_dht_delivery(
+/// Send the sender of `msg` a signed receipt saying we read it. Best effort:
+/// a sender we can't reach simply never hears back.
+pub async fn send_read_receipt(
     identity: &LedgerIdentity,
     db: &Database,
     p2p_tx: &mpsc::Sender<P2PCommand>,
+    msg: &Message,
+) -> Result<()> {
+    let peer_id = resolve_peer(db, p2p_tx, &msg.from_id).await?;
+    ensure_connected(db, p2p_tx, peer_id).await?;
+    let receipt = ReadReceipt::new(identity, &msg.id, &msg.from_id);
+    p2p_tx.send(P2PCommand::SendReceipt { peer_id, receipt })
+        .await
+        .map_err(|e| LedgerError::network(format!("Channel send error: {}", e)))
+}
+
+/// Fetch a pending attachment of a message from `from_ledger_id` off their
+/// node, which streams it in encrypted chunks
+pub async fn fetch_attachment(
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    from_ledger_id: &str,
+    attachment: &Attachment,
+) -> Result<Vec<u8>> {
+    let (Some(sha256), Some(key)) = (attachment.sha256.clone(), attachment.transfer_key.clone()) else {
+        return Err(LedgerError::invalid("Attachment wasn't sent over P2P"));
+    };
+    let peer_id = resolve_peer(db, p2p_tx, from_ledger_id).await?;
+    ensure_connected(db, p2p_tx, peer_id).await?;
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let download = Download::new(sha256, key, attachment.size as u64)?;
+    p2p_tx.send(P2PCommand::FetchAttachment { peer_id, download, response_tx: tx })
+        .await
+        .map_err(|e| LedgerError::network(format!("Channel send error: {}", e)))?;
+    match rx.recv().await {
+        Some(Ok(data)) => Ok(data),
+        Some(Err(e)) => Err(LedgerError::Network(e)),
+        None => Err(LedgerError::network("No response from P2P node")),
+    }
+}
+
+/// Resolve which peer serves a Ledger ID, locally first, then via the DHT
+async fn resolve_peer(
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    ledger_id: &str,
+) -> Result<libp2p::PeerId> {
+    let known_peer = db.get_peer_for_ledger_id(ledger_id).ok().flatten()
+        .and_then(|m| m.peer_id.parse::<libp2p::PeerId>().ok());
+    match known_peer {
+        Some(peer_id) => Ok(peer_id),
+        None => match dht::store::resolve_peer_id(p2p_tx, ledger_id).await {
+            Ok(Some(peer_id)) => Ok(peer_id),
+            Ok(None) => Err(LedgerError::not_found(format!("Peer record for {}", ledger_id))),
+            Err(e) => Err(LedgerError::network(format!("Peer lookup failed: {}", e))),
+        },
+    }
+}
+
+/// Encrypt the message for `to` and park it in the outbox, where the retry
+/// worker picks it up. Falls back to `Failed(error)` if it can't be queued.
+fn queue_for_retry(
+    identity: &LedgerIdentity,
+    db: &Database,
+    message_id: &str,
     to: &str,
-    subject: &str,
-    body: &str,
+    content: &OutgoingContent,
+    error: String,
 ) -> DeliveryResult {
-    let contact = match db.get_contact(to) {
-        Ok(Some(c)) => c,
-        _ => return DeliveryResult::Failed("No contact for DHT delivery".into()),
+    let queued = seal(identity, db, message_id, to, content)
+        .and_then(|envelope| {
+            let now = chrono::Utc::now().timestamp();
+            let entry = OutboxEntry {
+                // Not the envelope id: a group envelope is queued once per recipient
+                id: uuid::Uuid::new_v4().to_string(),
+                message_id: message_id.to_string(),
+                recipient: to.to_string(),
+                envelope_json: serde_json::to_string(&envelope)?,
+                attempts: 0,
+                next_attempt_at: now + super::outbox::backoff(0).as_secs() as i64,
+                last_error: Some(error.clone()),
+                created_at: now,
+            };
+            db.enqueue_outbox(&entry)
+        });
+
+    match queued {
+        Ok(()) => {
+            tracing::info!("Delivery to {} failed ({}); queued for retry", to, error);
+            DeliveryResult::Queued
+        }
+        Err(e) => {
+            tracing::warn!("Could not queue message for {}: {}", to, e);
+            DeliveryResult::Failed(error)
+        }
+    }
+}
+
+/// Encrypt `content` for the contact `to`, or take the group envelope if it
+/// lists them, noting the read receipt we then expect back if one is requested
+fn seal(
+    identity: &LedgerIdentity,
+    db: &Database,
+    message_id: &str,
+    to: &str,
+    content: &OutgoingContent,
+) -> Result<EncryptedEnvelope> {
+    let group = content.group.as_ref()
+        .filter(|envelope| envelope.recipients.iter().any(|entry| entry.ledger_id == to));
+    let envelope = match group {
+        Some(envelope) => envelope.clone(),
+        None => {
+            let contact = db.get_contact(to)?
+                .ok_or_else(|| LedgerError::not_found(format!("Contact {}", to)))?;
+            let key = contact_encryption_key(&contact)?;
+            encrypt_message(identity, to, &key, content)
+                .map_err(|e| LedgerError::crypto(format!("Encryption failed: {}", e)))?
+        }
     };
+    if content.read_receipt_requested {
+        db.expect_read_receipt(&envelope.id, message_id, to)?;
+    }
+    Ok(envelope)
+}
+
+/// One envelope for every Ledger ID in to and cc whose contact has an
+/// encryption key, if there are at least two. Anyone left out is sealed for
+/// separately, and so fails as they would on their own; recipients sent
+/// plain email in their `effective_mode` don't need one.
+pub fn seal_group(
+    identity: &LedgerIdentity,
+    db: &Database,
+    recipients: &Recipients,
+    content: &OutgoingContent,
+    requested: Option<DeliveryMode>,
+) -> Option<EncryptedEnvelope> {
+    let visible = Recipients { to: recipients.to.clone(), cc: recipients.cc.clone(), bcc: vec![] };
+    let members: Vec<(String, Vec<u8>)> = visible.all().into_iter()
+        .filter(|to| to.starts_with("ledger:"))
+        .filter(|to| effective_mode(db, to, requested).0 != DeliveryMode::GmailOnly)
+        .filter_map(|to| {
+            let contact = db.get_contact(to).ok().flatten()?;
+            contact_encryption_key(&contact).ok().map(|key| (to.to_string(), key))
+        })
+        .collect();
+    if members.len() < 2 {
+        return None;
+    }
+    match encrypt_group_message(identity, &members, content) {
+        Ok(envelope) => Some(envelope),
+        Err(e) => {
+            tracing::warn!("Group encryption failed, sealing for each recipient: {}", e);
+            None
+        }
+    }
+}
 
-    let recipient_enc_pubkey = match base64::Engine::decode(
-        &base64::engine::general_purpose::STANDARD,
-        &contact.public_key,
-    ) {
-        Ok(bytes) => bytes,
-        Err(_) => return DeliveryResult::Failed("Invalid contact public key".into()),
+/// Decode the contact's X25519 encryption key
+fn contact_encryption_key(contact: &Contact) -> Result<Vec<u8>> {
+    let encoded = contact.encryption_public_key.as_deref()
+        .ok_or_else(|| LedgerError::config("Contact has no encryption public key"))?;
+    base64::Engine::decode(&base64::engine::general_purpose::STANDARD, encoded)
+        .map_err(|e| LedgerError::crypto(format!("Invalid contact encryption key: {}", e)))
+}
+
+/// Make sure we have a connection to `peer_id`: dial it directly, and if that
+/// fails go through the configured relay's circuit
+async fn ensure_connected(
+    db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    peer_id: libp2p::PeerId,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let _ = p2p_tx.send(P2PCommand::GetPeers { response_tx: tx }).await;
+    let connected = rx.recv().await
+        .map(|peers| peers.iter().any(|p| p.peer_id == peer_id.to_string()))
+        .unwrap_or(false);
+    if connected {
+        return Ok(());
+    }
+
+    let direct_err = match dial_peer(p2p_tx, peer_id, vec![]).await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
     };
 
-    let mut envelope = match encrypt_message(identity, &recipient_enc_pubkey, subject, body) {
-        Ok(env) => env,
-        Err(e) => return DeliveryResult::Failed(format!("Encryption failed: {}", e)),
+    let Some(relay_addr) = db.get_setting("relay_addr").ok().flatten().filter(|a| !a.is_empty()) else {
+        return Err(direct_err);
     };
-    envelope.to_ledger_id = to.to_string();
+    let circuit = relay_addr.parse::<Multiaddr>()
+        .map_err(|e| LedgerError::config(format!("Invalid relay_addr: {}", e)))?
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(peer_id));
+    tracing::info!("Direct dial to {} failed ({}); trying relay circuit", peer_id, direct_err);
+    dial_peer(p2p_tx, peer_id, vec![circuit]).await
+}
 
-    match dht::store::store_in_dht(p2p_tx, to, &envelope).await {
-        Ok(()) => DeliveryResult::DhtStored,
-        Err(e) => DeliveryResult::Failed(format!("DHT storage failed: {}", e)),
+async fn dial_peer(
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    peer_id: libp2p::PeerId,
+    addresses: Vec<Multiaddr>,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(1);
+    p2p_tx.send(P2PCommand::DialPeer { peer_id, addresses, response_tx: tx })
+        .await
+        .map_err(|e| LedgerError::network(format!("Channel send error: {}", e)))?;
+    match rx.recv().await {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(e)) => Err(LedgerError::Network(e)),
+        None => Err(LedgerError::network("No response from P2P node")),
     }
 }
 
-/// Try Gmail delivery (direct or fallback)
-async fn try_gmail_delivery(
-    identity: &LedgerIdentity,
+/// Try DHT offline storage
+async fn try_dht_delivery(
     db: &Database,
+    p2p_tx: &mpsc::Sender<P2PCommand>,
+    message_id: &str,
     to: &str,
-    subject: &str,
-    body: &str,
-    encrypted_fallback: bool,
+    envelope: &EncryptedEnvelope,
 ) -> DeliveryResult {
-    // Get Gmail config
-    let email = match db.get_setting("gmail_email") {
-        Ok(Some(e)) => e,
-        _ => return DeliveryResult::Failed("Gmail not configured".into()),
-    };
-    let app_password = match db.get_setting("gmail_app_password") {
-        Ok(Some(p)) => p,
-        _ => return DeliveryResult::Failed("Gmail not configured".into()),
-    };
-
-    let config = GmailConfig {
-        email,
-        app_password,
-        imap_host: None,
-        smtp_host: None,
-    };
-
-    // Determine recipient email
-    let recipient_email = if to.starts_with("ledger:") {
-        // Look up their Gmail address from contacts
-        match db.get_contact(to) {
-            Ok(Some(c)) => match c.gmail_address {
-                Some(addr) => addr,
-                None => return DeliveryResult::Failed("No Gmail address for Ledger contact".into()),
-            },
-            _ => return DeliveryResult::Failed("Contact not found".into()),
+    match dht::store::store_in_dht(p2p_tx, to, std::slice::from_ref(envelope)).await {
+        Ok(()) => {
+            dht::republish::track(db, message_id, to, envelope);
+            DeliveryResult::DhtStored
         }
-    } else {
-        to.to_string()
+        Err(e) => DeliveryResult::Failed(format!("DHT storage failed: {}", e)),
+    }
+}
+
+/// Send a plain email through Gmail
+async fn try_gmail_delivery(db: &Database, to: &str, content: &OutgoingContent) -> DeliveryResult {
+    let config = match gmail_config(db).await {
+        Ok(config) => config,
+        Err(result) => return result,
+    };
+    let recipient_email = match recipient_email(db, to) {
+        Ok(addr) => addr,
+        Err(result) => return result,
     };
+    send_with_config(&config, &Recipients::single(recipient_email), content).await
+}
 
-    if encrypted_fallback {
-        // Send encrypted payload as fallback
-        let payload = serde_json::json!({
-            "from": identity.ledger_id,
-            "subject": subject,
-            "body": body,
-            "timestamp": chrono::Utc::now().timestamp(),
-        });
-        let payload_str = serde_json::to_string(&payload).unwrap_or_default();
+/// Send one plain email to all of `emails`
+async fn send_via_gmail(db: &Database, emails: &Recipients, content: &OutgoingContent) -> DeliveryResult {
+    match gmail_config(db).await {
+        Ok(config) => send_with_config(&config, emails, content).await,
+        Err(result) => result,
+    }
+}
 
-        match smtp_client::send_encrypted_fallback(&config, &recipient_email, &payload_str).await {
-            Ok(()) => DeliveryResult::GmailFallback,
-            Err(e) => DeliveryResult::Failed(format!("Gmail fallback failed: {}", e)),
-        }
-    } else {
-        match smtp_client::send_email(&config, &recipient_email, subject, body).await {
-            Ok(()) => DeliveryResult::GmailDirect,
-            Err(e) => DeliveryResult::Failed(format!("Gmail send failed: {}", e)),
+async fn send_with_config(
+    config: &crate::models::message::GmailConfig,
+    emails: &Recipients,
+    content: &OutgoingContent,
+) -> DeliveryResult {
+    match smtp_client::send_email(config, emails, &content.subject, &content.body, content.content_type, &content.attachments, None).await {
+        Ok(()) => DeliveryResult::GmailDirect,
+        Err(e) => DeliveryResult::Failed(format!("Gmail send failed: {}", e)),
+    }
+}
+
+/// Email a sealed envelope to a Ledger contact's Gmail address; their node
+/// decrypts and verifies it when it fetches the mail
+async fn try_gmail_fallback(db: &Database, to: &str, envelope: &EncryptedEnvelope) -> DeliveryResult {
+    let config = match gmail_config(db).await {
+        Ok(config) => config,
+        Err(result) => return result,
+    };
+    let recipient_email = match recipient_email(db, to) {
+        Ok(addr) => addr,
+        Err(result) => return result,
+    };
+    let payload = match serde_json::to_vec(envelope) {
+        Ok(json) => base64::Engine::encode(&base64::engine::general_purpose::STANDARD, json),
+        Err(e) => return DeliveryResult::Failed(format!("Failed to encode envelope: {}", e)),
+    };
+
+    match smtp_client::send_encrypted_fallback(&config, &recipient_email, &payload).await {
+        Ok(()) => DeliveryResult::GmailFallback,
+        Err(e) => DeliveryResult::Failed(format!("Gmail fallback failed: {}", e)),
+    }
+}
+
+async fn gmail_config(db: &Database) -> std::result::Result<crate::models::message::GmailConfig, DeliveryResult> {
+    match gmail::oauth::fresh_config(db).await {
+        Ok(Some(config)) => Ok(config),
+        Ok(None) => Err(DeliveryResult::Failed("Gmail not configured".into())),
+        Err(e) => Err(DeliveryResult::Failed(format!("Gmail auth failed: {}", e))),
+    }
+}
+
+/// The email address to send to: a Ledger contact's Gmail address, or `to` itself
+fn recipient_email(db: &Database, to: &str) -> std::result::Result<String, DeliveryResult> {
+    if !to.starts_with("ledger:") {
+        return Ok(to.to_string());
+    }
+    match db.get_contact(to) {
+        Ok(Some(c)) => c.gmail_address
+            .ok_or_else(|| DeliveryResult::Failed("No Gmail address for Ledger contact".into())),
+        _ => Err(DeliveryResult::Failed("Contact not found".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_route() {
+        let ledger_id = LedgerIdentity::generate().unwrap().ledger_id;
+        assert_eq!(plan_route(&ledger_id, DeliveryMode::Auto), Ok(Route::P2pWithFallback));
+        assert_eq!(plan_route(&ledger_id, DeliveryMode::P2pOnly), Ok(Route::P2p));
+        assert_eq!(plan_route(&ledger_id, DeliveryMode::GmailOnly), Ok(Route::Gmail));
+        assert_eq!(plan_route("bob@example.com", DeliveryMode::Auto), Ok(Route::Gmail));
+        assert!(plan_route("bob@example.com", DeliveryMode::P2pOnly).is_err());
+    }
+
+    #[test]
+    fn test_effective_mode_precedence() {
+        let dir = std::env::temp_dir().join("ledger_test_router_effective_mode");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+        let contact = |ledger_id: &str, preferred_delivery| Contact {
+            ledger_id: ledger_id.into(),
+            public_key: String::new(),
+            encryption_public_key: None,
+            display_name: None,
+            gmail_address: None,
+            verified: false,
+            alias: None,
+            preferred_delivery,
+        };
+        db.upsert_contact(&contact("ledger:alice", Some(DeliveryMode::GmailOnly))).unwrap();
+        db.upsert_contact(&contact("ledger:bob", None)).unwrap();
+
+        assert_eq!(effective_mode(&db, "ledger:bob", None), (DeliveryMode::Auto, ModeSource::Setting));
+        db.set_setting("delivery_mode", "p2p_only").unwrap();
+        assert_eq!(effective_mode(&db, "ledger:bob", None), (DeliveryMode::P2pOnly, ModeSource::Setting));
+        assert_eq!(effective_mode(&db, "bob@example.com", None), (DeliveryMode::P2pOnly, ModeSource::Setting));
+
+        // The contact's preference beats the setting, and a requested mode beats both
+        assert_eq!(effective_mode(&db, "ledger:alice", None), (DeliveryMode::GmailOnly, ModeSource::Contact));
+        assert_eq!(
+            effective_mode(&db, "ledger:alice", Some(DeliveryMode::Auto)),
+            (DeliveryMode::Auto, ModeSource::Request)
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_email_group() {
+        let dir = std::env::temp_dir().join("ledger_test_router_email_group");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+        db.upsert_contact(&Contact {
+            ledger_id: "ledger:alice".into(),
+            public_key: String::new(),
+            encryption_public_key: None,
+            display_name: None,
+            gmail_address: Some("alice@gmail.com".into()),
+            verified: false,
+            alias: None,
+            preferred_delivery: Some(DeliveryMode::GmailOnly),
+        }).unwrap();
+
+        let recipients = Recipients {
+            to: vec!["bob@example.com".into(), "ledger:carol".into()],
+            cc: vec!["ledger:alice".into(), "bob@example.com".into(), "dave@example.com".into()],
+            bcc: vec!["erin@example.com".into()],
+        };
+        let (covered, emails) = email_group(&db, &recipients, None);
+        assert_eq!(covered, ["bob@example.com", "ledger:alice", "dave@example.com"]);
+        assert_eq!(emails.to, ["bob@example.com"]);
+        assert_eq!(emails.cc, ["alice@gmail.com", "dave@example.com"]);
+        assert!(emails.bcc.is_empty());
+
+        // Nothing goes by Gmail in P2P-only mode
+        let (covered, _) = email_group(&db, &recipients, Some(DeliveryMode::P2pOnly));
+        assert!(covered.is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_recipient() {
+        let identity = LedgerIdentity::generate().unwrap();
+        assert_eq!(
+            parse_recipient(&format!("  {} ", identity.ledger_id)).unwrap(),
+            Recipient::Ledger(identity.public_key_bytes())
+        );
+        assert_eq!(
+            parse_recipient("Alice@Example.COM").unwrap().to_string(),
+            "Alice@example.com"
+        );
+
+        for bad in ["ledger:", "ledger:0OIl", "ledger:3mJr7AoUXx2Wqd", "alice", "alice@", "@example.com", ""] {
+            assert!(
+                matches!(parse_recipient(bad), Err(LedgerError::InvalidInput(_))),
+                "{:?} should be rejected",
+                bad
+            );
         }
     }
 }
@@ -1,91 +1,482 @@
-use crate::models::message::{GmailConfig, Message, DeliveryMethod, Folder};
+use futures::TryStreamExt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
 
-/// Fetch new messages from Gmail via IMAP
-pub fn fetch_messages(
+use crate::crypto::envelope::decrypt_envelope;
+use crate::crypto::keys::LedgerIdentity;
+use crate::error::LedgerError;
+use crate::models::message::{Attachment, ContentType, EncryptedEnvelope, GmailConfig, ImapCursor, Message, DeliveryMethod, DeliveryStatus, Folder, SignatureStatus};
+
+/// How often the IDLE command is refreshed
+const IDLE_KEEPALIVE: Duration = Duration::from_secs(20 * 60);
+/// Reconnect delay after the first IDLE failure, doubling up to the max.
+/// Without a usable Gmail config the loop checks again every max backoff.
+const IDLE_MIN_BACKOFF: Duration = Duration::from_secs(5);
+const IDLE_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Fetch all mail that reached INBOX after `cursor`, oldest first in pages
+/// of `page_size`, and hand each message to `store`. `cursor` only moves past
+/// a message once `store` has kept it (returned true); the first one it
+/// can't keep ends the fetch, so it and everything after it come back next
+/// time. Returns the messages kept. Bodies are read with `BODY.PEEK[]`, so
+/// `\Seen` flags on the server are left alone.
+pub async fn fetch_messages<F>(
     config: &GmailConfig,
-    max_count: u32,
-) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>> {
+    cursor: &mut ImapCursor,
+    page_size: usize,
+    mut store: F,
+) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: FnMut(&mut Message) -> bool,
+{
+    let mut session = login(config).await?;
+
+    let mailbox = session.select("INBOX").await?;
+    cursor.sync(&config.email, mailbox.uid_validity.unwrap_or(0));
+
+    let uids = uids_after(&mut session, cursor.last_uid).await?;
+    let mut kept = Vec::new();
+    for page in uids.chunks(page_size.max(1)) {
+        for mut msg in fetch_uids(&mut session, page).await? {
+            if !store(&mut msg) {
+                session.logout().await?;
+                return Ok(kept);
+            }
+            cursor.last_uid = cursor.last_uid.max(msg.imap_uid.unwrap_or(0));
+            kept.push(msg);
+        }
+        // Past anything in the page that didn't parse, too
+        if let Some(&last) = page.last() {
+            cursor.last_uid = cursor.last_uid.max(last);
+        }
+    }
+
+    session.logout().await?;
+
+    Ok(kept)
+}
+
+/// Flag these INBOX messages `\Seen`, unless the mailbox has been renumbered
+/// since they were fetched under `uid_validity`
+pub async fn mark_seen(
+    config: &GmailConfig,
+    uid_validity: u32,
+    uids: &[u32],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if uids.is_empty() {
+        return Ok(());
+    }
+    let mut session = login(config).await?;
+
+    let mailbox = session.select("INBOX").await?;
+    if mailbox.uid_validity.unwrap_or(0) != uid_validity {
+        tracing::warn!("INBOX UIDVALIDITY changed; not marking {} message(s) seen", uids.len());
+    } else {
+        let set = uids.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        let _: Vec<_> = session.uid_store(set, "+FLAGS.SILENT (\\Seen)").await?.try_collect().await?;
+    }
+
+    session.logout().await?;
+    Ok(())
+}
+
+/// UIDs above `after`, in ascending order
+async fn uids_after(session: &mut ImapSession, after: u32) -> async_imap::error::Result<Vec<u32>> {
+    // `n:*` always matches the highest UID, even one below n
+    let mut uids: Vec<u32> = session.uid_search(format!("UID {}:*", after + 1)).await?
+        .into_iter()
+        .filter(|&uid| uid > after)
+        .collect();
+    uids.sort_unstable();
+    Ok(uids)
+}
+
+/// Fetch and parse the messages with these (ascending) UIDs
+async fn fetch_uids(session: &mut ImapSession, uids: &[u32]) -> async_imap::error::Result<Vec<Message>> {
+    let (Some(first), Some(last)) = (uids.first(), uids.last()) else {
+        return Ok(vec![]);
+    };
+    // The range holds no other UIDs, since `uids` is every one above some point
+    let fetched: Vec<async_imap::types::Fetch> = session
+        .uid_fetch(format!("{}:{}", first, last), "(UID BODY.PEEK[])").await?
+        .try_collect().await?;
+    let mut messages: Vec<Message> = fetched.iter()
+        .filter_map(|fetch| {
+            let mut msg = parse_message(fetch.body()?)?;
+            msg.imap_uid = fetch.uid;
+            Some(msg)
+        })
+        .collect();
+    messages.sort_by_key(|msg| msg.imap_uid);
+    Ok(messages)
+}
+
+type ImapSession = async_imap::Session<tokio_native_tls::TlsStream<tokio::net::TcpStream>>;
+
+/// SASL XOAUTH2 initial response, as Gmail expects it
+struct XOAuth2<'a> {
+    user: &'a str,
+    access_token: &'a str,
+}
+
+impl async_imap::Authenticator for XOAuth2<'_> {
+    type Response = String;
+
+    fn process(&mut self, _challenge: &[u8]) -> Self::Response {
+        format!("user={}\x01auth=Bearer {}\x01\x01", self.user, self.access_token)
+    }
+}
+
+/// Connect (through Tor if configured) and authenticate, using XOAUTH2 when an
+/// access token is configured and the app password otherwise
+async fn login(config: &GmailConfig) -> Result<ImapSession, Box<dyn std::error::Error + Send + Sync>> {
     let imap_host = config.imap_host.as_deref().unwrap_or("imap.gmail.com");
-    let tls = native_tls::TlsConnector::builder().build()?;
+    let tls = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::builder().build()?);
 
-    let client = imap::connect((imap_host, 993), imap_host, &tls)?;
-    let mut session = client.login(&config.email, &config.app_password)
-        .map_err(|e| format!("IMAP login failed: {}", e.0))?;
+    let tcp = match config.socks_proxy {
+        Some(ref proxy) => crate::tor::connect(proxy, imap_host, 993).await?,
+        None => tokio::net::TcpStream::connect((imap_host, 993)).await?,
+    };
+    let mut client = async_imap::Client::new(tls.connect(imap_host, tcp).await?);
+    client.read_response().await?.ok_or("IMAP server closed the connection before its greeting")?;
 
-    session.select("INBOX")?;
+    let session = match config.access_token {
+        Some(ref access_token) => {
+            let auth = XOAuth2 { user: &config.email, access_token };
+            client.authenticate("XOAUTH2", auth).await
+                .map_err(|e| format!("IMAP XOAUTH2 login failed: {}", e.0))?
+        }
+        None => client.login(&config.email, &config.app_password).await
+            .map_err(|e| format!("IMAP login failed: {}", e.0))?,
+    };
+    Ok(session)
+}
 
-    // Fetch the last N messages
-    let sequence = if max_count > 0 {
-        let exists = session.select("INBOX")?.exists;
-        if exists == 0 {
-            session.logout()?;
-            return Ok(vec![]);
+/// Parse a raw RFC 822 message into an inbox `Message`
+pub(crate) fn parse_message(body: &[u8]) -> Option<Message> {
+    let parsed = match mailparse::parse_mail(body) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            tracing::warn!("Failed to parse email: {}", e);
+            return None;
         }
-        let start = if exists > max_count { exists - max_count + 1 } else { 1 };
-        format!("{}:{}", start, exists)
+    };
+
+    let from = parsed.headers.iter()
+        .find(|h| h.get_key().eq_ignore_ascii_case("from"))
+        .map(|h| h.get_value())
+        .unwrap_or_default();
+
+    let to = parsed.headers.iter()
+        .find(|h| h.get_key().eq_ignore_ascii_case("to"))
+        .map(|h| h.get_value())
+        .unwrap_or_default();
+
+    let subject = parsed.headers.iter()
+        .find(|h| h.get_key().eq_ignore_ascii_case("subject"))
+        .map(|h| h.get_value())
+        .unwrap_or_default();
+
+    let email_message_id = parsed.headers.iter()
+        .find(|h| h.get_key().eq_ignore_ascii_case("message-id"))
+        .map(|h| h.get_value().trim().to_string())
+        .filter(|id| !id.is_empty());
+
+    let body_text = extract_body_text(&parsed);
+    let spam_score = super::spam::content_score(&parsed, &subject, &body_text);
+
+    // Check if this is a Ledger fallback message
+    let is_fallback = subject.contains(super::smtp_client::FALLBACK_SUBJECT);
+    let delivery = if is_fallback {
+        DeliveryMethod::Fallback
     } else {
-        "1:*".to_string()
+        DeliveryMethod::Gmail
     };
 
-    let messages_result = session.fetch(&sequence, "RFC822")?;
-
-    let mut messages = Vec::new();
-
-    for fetch in messages_result.iter() {
-        if let Some(body) = fetch.body() {
-            match mailparse::parse_mail(body) {
-                Ok(parsed) => {
-                    let from = parsed.headers.iter()
-                        .find(|h| h.get_key().eq_ignore_ascii_case("from"))
-                        .map(|h| h.get_value())
-                        .unwrap_or_default();
-
-                    let to = parsed.headers.iter()
-                        .find(|h| h.get_key().eq_ignore_ascii_case("to"))
-                        .map(|h| h.get_value())
-                        .unwrap_or_default();
-
-                    let subject = parsed.headers.iter()
-                        .find(|h| h.get_key().eq_ignore_ascii_case("subject"))
-                        .map(|h| h.get_value())
-                        .unwrap_or_default();
-
-                    let body_text = parsed.get_body().unwrap_or_default();
-
-                    // Check if this is a Ledger fallback message
-                    let is_fallback = subject.contains("[Ledger Encrypted Fallback]");
-                    let delivery = if is_fallback {
-                        DeliveryMethod::Fallback
-                    } else {
-                        DeliveryMethod::Gmail
-                    };
-
-                    let msg = Message {
-                        id: uuid::Uuid::new_v4().to_string(),
-                        from_id: from,
-                        to_id: to,
-                        subject,
-                        body: body_text,
-                        timestamp: chrono::Utc::now().timestamp(),
-                        delivery_method: delivery,
-                        is_read: false,
-                        folder: Folder::Inbox,
-                        signature: None,
-                        encrypted: is_fallback,
-                    };
-
-                    messages.push(msg);
-                }
+    let id = uuid::Uuid::new_v4().to_string();
+    let attachments = extract_attachments(&parsed, &id);
+
+    Some(Message {
+        id,
+        from_id: from,
+        to_id: to,
+        subject,
+        body: body_text,
+        timestamp: chrono::Utc::now().timestamp(),
+        delivery_method: delivery,
+        is_read: false,
+        folder: Folder::Inbox,
+        signature: None,
+        signature_status: SignatureStatus::Unsigned,
+        encrypted: is_fallback,
+        delivery_status: DeliveryStatus::Delivered,
+        // HTML-only mail is reduced to text by extract_body_text
+        content_type: ContentType::Text,
+        is_starred: false,
+        email_message_id,
+        imap_uid: None,
+        attachments,
+        read_receipt_requested: false,
+        read_receipts: vec![],
+        spam_score: Some(spam_score),
+        deliveries: vec![],
+    })
+}
+
+/// Pick the readable text of a message: the first `text/plain` part found
+/// anywhere in the MIME tree, else the first `text/html` part with its markup
+/// stripped. Attachments are skipped.
+fn extract_body_text(parsed: &mailparse::ParsedMail) -> String {
+    if let Some(text) = find_part(parsed, "text/plain") {
+        return text;
+    }
+    find_part(parsed, "text/html")
+        .map(|html| html_to_text(&html))
+        .unwrap_or_default()
+}
+
+/// Depth-first search for the first inline part of the given MIME type
+fn find_part(part: &mailparse::ParsedMail, mimetype: &str) -> Option<String> {
+    if part.subparts.is_empty() {
+        let is_attachment = part.get_content_disposition().disposition
+            == mailparse::DispositionType::Attachment;
+        if is_attachment || !part.ctype.mimetype.eq_ignore_ascii_case(mimetype) {
+            return None;
+        }
+        return decode_part(part);
+    }
+    part.subparts.iter().find_map(|sub| find_part(sub, mimetype))
+}
+
+/// Collect every part marked `Content-Disposition: attachment`, decoded
+fn extract_attachments(parsed: &mailparse::ParsedMail, message_id: &str) -> Vec<Attachment> {
+    let mut attachments = Vec::new();
+    let mut stack = vec![parsed];
+    while let Some(part) = stack.pop() {
+        // Reversed so parts come out in document order
+        stack.extend(part.subparts.iter().rev());
+
+        let disposition = part.get_content_disposition();
+        if disposition.disposition != mailparse::DispositionType::Attachment {
+            continue;
+        }
+        let data = match part.get_body_raw() {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("Failed to decode attachment: {}", e);
+                continue;
+            }
+        };
+        let filename = disposition.params.get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .cloned()
+            .unwrap_or_else(|| "attachment".to_string());
+
+        attachments.push(Attachment {
+            id: uuid::Uuid::new_v4().to_string(),
+            message_id: message_id.to_string(),
+            filename,
+            mime_type: part.ctype.mimetype.clone(),
+            size: data.len() as i64,
+            data,
+            ..Default::default()
+        });
+    }
+    attachments
+}
+
+/// Undo the part's Content-Transfer-Encoding (base64, quoted-printable) and
+/// convert its charset to UTF-8
+fn decode_part(part: &mailparse::ParsedMail) -> Option<String> {
+    let raw = part.get_body_raw().ok()?;
+    let charset = part.ctype.charset.to_ascii_lowercase();
+    if charset == "utf-8" || charset == "us-ascii" {
+        return Some(String::from_utf8_lossy(&raw).into_owned());
+    }
+    // get_body() knows how to convert other charsets
+    part.get_body().ok()
+}
+
+/// Crude HTML-to-text: drops tags, `<script>`/`<style>` contents and comments,
+/// turns block-level tags into line breaks and decodes common entities
+pub(crate) fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[1..end].to_ascii_lowercase();
+        let closing = tag.starts_with('/');
+        let name = tag.trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        rest = &rest[end + 1..];
+
+        match name {
+            "script" | "style" if !closing => {
+                let close = format!("</{}", name);
+                rest = rest.to_ascii_lowercase().find(&close)
+                    .and_then(|i| rest[i..].find('>').map(|j| &rest[i + j + 1..]))
+                    .unwrap_or("");
+            }
+            "br" | "p" | "div" | "tr" | "li" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                text.push('\n');
+            }
+            _ => {}
+        }
+    }
+    text.push_str(rest);
+
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    // Collapse the whitespace HTML source formatting leaves behind
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if !line.is_empty() || lines.last().is_some_and(|l| !l.is_empty()) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+/// Watch INBOX with IMAP IDLE, sending each newly arrived message over `tx`
+/// with the cursor to save once it is stored.
+///
+/// Dropped connections are retried with exponential backoff, which starts
+/// over once a connection gets as far as selecting INBOX; returns only once
+/// the receiving side of `tx` is gone. `load_config` is called before every
+/// connection so expired OAuth2 tokens get refreshed and the saved cursor is
+/// picked up. It returns `None` until Gmail is set up; `configured` is
+/// notified when that changes, and cuts any wait short.
+pub async fn idle_loop<F, Fut>(load_config: F, configured: Arc<Notify>, tx: mpsc::Sender<(Message, ImapCursor)>)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Option<(GmailConfig, ImapCursor)>>,
+{
+    let mut backoff = IDLE_MIN_BACKOFF;
+
+    loop {
+        let wait = match load_config().await {
+            Some((config, cursor)) => match idle_session(&config, cursor, &tx, &mut backoff).await {
+                Ok(()) => return,
                 Err(e) => {
-                    tracing::warn!("Failed to parse email: {}", e);
+                    tracing::warn!("IMAP IDLE connection lost: {}; retrying in {:?}", e, backoff);
+                    let wait = backoff;
+                    backoff = (backoff * 2).min(IDLE_MAX_BACKOFF);
+                    wait
                 }
+            },
+            None => IDLE_MAX_BACKOFF,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = configured.notified() => tracing::debug!("Gmail settings changed; reconnecting IMAP IDLE"),
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+/// One IDLE connection: log in, then forward new mail until the connection
+/// fails. Once INBOX is selected the connection counts as good and `backoff`
+/// goes back to the minimum.
+async fn idle_session(
+    config: &GmailConfig,
+    mut cursor: ImapCursor,
+    tx: &mpsc::Sender<(Message, ImapCursor)>,
+    backoff: &mut Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut session = login(config).await?;
+
+    let mailbox = session.select("INBOX").await?;
+    *backoff = IDLE_MIN_BACKOFF;
+    cursor.sync(&config.email, mailbox.uid_validity.unwrap_or(0));
+    if cursor.last_uid == 0 {
+        // Nothing imported yet: watch for new mail rather than importing the backlog
+        cursor.last_uid = mailbox.uid_next.unwrap_or(1).saturating_sub(1);
+    }
+    tracing::info!("IMAP IDLE watching INBOX ({} messages)", mailbox.exists);
+
+    loop {
+        let mut idle = session.idle();
+        idle.init().await?;
+        {
+            // Re-issue IDLE before Gmail's ~29 minute cutoff; a timeout and
+            // new data both mean it's time to look for mail
+            let (wait, _stop) = idle.wait_with_timeout(IDLE_KEEPALIVE);
+            wait.await?;
+        }
+        session = idle.done().await?;
+
+        let uids = uids_after(&mut session, cursor.last_uid).await?;
+        for msg in fetch_uids(&mut session, &uids).await? {
+            cursor.last_uid = cursor.last_uid.max(msg.imap_uid.unwrap_or(0));
+            if tx.send((msg, cursor.clone())).await.is_err() {
+                let _ = session.logout().await;
+                return Ok(());
             }
         }
+        if let Some(&last) = uids.last() {
+            cursor.last_uid = last;
+        }
     }
+}
 
-    session.logout()?;
+/// Decrypt a fetched fallback email in place. An opened message takes the
+/// envelope's id, so a copy that also came over P2P or the DHT is the same
+/// message; if the payload won't open or its signature doesn't verify, the
+/// raw blob stays and the message is marked invalid.
+pub fn open_fallback(msg: &mut Message, identity: &LedgerIdentity) {
+    if msg.delivery_method != DeliveryMethod::Fallback {
+        return;
+    }
+    let opened = decode_fallback(&msg.body)
+        .and_then(|env| decrypt_envelope(identity, &env).map(|plaintext| (env, plaintext)));
+    match opened {
+        Ok((env, plaintext)) => {
+            let mut opened = Message::from_envelope(&env, identity.ledger_id.clone(), plaintext);
+            opened.delivery_method = DeliveryMethod::Fallback;
+            opened.email_message_id = msg.email_message_id.take();
+            opened.imap_uid = msg.imap_uid;
+            opened.attachments = std::mem::take(&mut msg.attachments);
+            for attachment in &mut opened.attachments {
+                attachment.message_id = opened.id.clone();
+            }
+            *msg = opened;
+        }
+        Err(e) => {
+            tracing::warn!("Could not open fallback email from {}: {}", msg.from_id, e);
+            msg.signature_status = SignatureStatus::Invalid;
+        }
+    }
+}
 
-    Ok(messages)
+/// The envelope inside a fallback body: base64 of its JSON
+fn decode_fallback(body: &str) -> crate::error::Result<EncryptedEnvelope> {
+    let payload = extract_encrypted_payload(body)
+        .ok_or_else(|| LedgerError::invalid("No encrypted payload in fallback email"))?;
+    // Mail clients may have re-wrapped the blob
+    let compact: String = payload.split_whitespace().collect();
+    let json = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, compact)?;
+    Ok(serde_json::from_slice(&json)?)
 }
 
 /// Extract encrypted payload from a fallback message body
@@ -103,3 +494,124 @@ pub fn extract_encrypted_payload(body: &str) -> Option<String> {
         Some(payload)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const MULTIPART_FIXTURE: &[u8] = include_bytes!("../../tests/fixtures/multipart_alternative.eml");
+
+    #[test]
+    fn test_multipart_prefers_plain_text() {
+        let msg = parse_message(MULTIPART_FIXTURE).unwrap();
+        assert_eq!(msg.subject, "Lunch on Friday?");
+        assert_eq!(msg.email_message_id.as_deref(), Some("<lunch-1234@example.com>"));
+        assert_eq!(
+            msg.body.replace("\r\n", "\n").trim(),
+            "Hi Bob,\n\nAre we still on for lunch on Friday? Caf\u{e9} at noon.\n\n-- Alice",
+        );
+    }
+
+    #[test]
+    fn test_attachment_extracted() {
+        let raw = b"From: a@example.com\r\n\
+Subject: report\r\n\
+Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+\r\n\
+--b1\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+See attached.\r\n\
+--b1\r\n\
+Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+Content-Disposition: attachment; filename=\"report.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0xLjQK\r\n\
+--b1--\r\n";
+        let msg = parse_message(raw).unwrap();
+        assert_eq!(msg.body.trim(), "See attached.");
+        assert_eq!(msg.attachments.len(), 1);
+        let attachment = &msg.attachments[0];
+        assert_eq!(attachment.filename, "report.pdf");
+        assert_eq!(attachment.mime_type, "application/pdf");
+        assert_eq!(attachment.message_id, msg.id);
+        assert_eq!(attachment.data, b"%PDF-1.4\n");
+        assert_eq!(attachment.size, 9);
+    }
+
+    #[test]
+    fn test_html_only_is_stripped() {
+        let raw = b"From: a@example.com\r\n\
+Subject: html\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+PGh0bWw+PHN0eWxlPnB7Y29sb3I6cmVkfTwvc3R5bGU+PHA+SGVsbG8gJmFtcDsgd2VsY29tZTwvcD48cD5TZWNvbmQ8YnI+bGluZTwvcD48L2h0bWw+\r\n";
+        let msg = parse_message(raw).unwrap();
+        assert_eq!(msg.body, "Hello & welcome\n\nSecond\nline");
+    }
+
+    #[test]
+    fn test_fallback_opened_and_verified() {
+        let alice = LedgerIdentity::generate().unwrap();
+        let bob = LedgerIdentity::generate().unwrap();
+        let content = crate::models::message::OutgoingContent::text("Hi", "Over Gmail");
+        let mut envelope = crate::crypto::envelope::encrypt_message(
+            &alice, &bob.ledger_id, &bob.encryption_public_bytes(), &content,
+        ).unwrap();
+        let fetched = |envelope: &EncryptedEnvelope| {
+            let payload = base64::Engine::encode(
+                &base64::engine::general_purpose::STANDARD,
+                serde_json::to_vec(envelope).unwrap(),
+            );
+            let raw = format!(
+                "From: alice@example.com\r\nSubject: {}\r\n\r\n{}",
+                super::super::smtp_client::FALLBACK_SUBJECT,
+                super::super::smtp_client::fallback_body(&payload).replace('\n', "\r\n"),
+            );
+            parse_message(raw.as_bytes()).unwrap()
+        };
+
+        let mut msg = fetched(&envelope);
+        open_fallback(&mut msg, &bob);
+        assert_eq!(msg.id, envelope.id);
+        assert_eq!(msg.from_id, alice.ledger_id);
+        assert_eq!(msg.body, "Over Gmail");
+        assert_eq!(msg.delivery_method, DeliveryMethod::Fallback);
+        assert_eq!(msg.signature_status, SignatureStatus::Valid);
+
+        envelope.subject_hint = "Tampered".into();
+        let mut msg = fetched(&envelope);
+        open_fallback(&mut msg, &bob);
+        assert_eq!(msg.signature_status, SignatureStatus::Invalid);
+        assert!(msg.body.contains("--- BEGIN LEDGER ENCRYPTED MESSAGE ---"));
+    }
+
+    #[tokio::test]
+    async fn test_idle_waits_for_gmail_to_be_configured() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let configured = Arc::new(Notify::new());
+        let (tx, rx) = mpsc::channel(1);
+        let counter = loads.clone();
+        let idle = tokio::spawn(idle_loop(
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { None }
+            },
+            configured.clone(),
+            tx,
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        // Configuring Gmail tries again straight away, not after the backoff
+        configured.notify_one();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        drop(rx);
+        tokio::time::timeout(Duration::from_secs(1), idle).await.unwrap().unwrap();
+    }
+}
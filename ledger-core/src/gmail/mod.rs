@@ -1,2 +1,4 @@
 pub mod imap_client;
+pub mod oauth;
 pub mod smtp_client;
+pub mod spam;
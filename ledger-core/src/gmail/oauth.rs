@@ -0,0 +1,156 @@
+use serde::Deserialize;
+
+use crate::models::message::GmailConfig;
+use crate::store::db::Database;
+
+const AUTH_ENDPOINT: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const TOKEN_ENDPOINT: &str = "https://oauth2.googleapis.com/token";
+/// Full mailbox scope — the only one Gmail accepts for IMAP and SMTP
+const GMAIL_SCOPE: &str = "https://mail.google.com/";
+/// Refresh this long before the access token actually expires
+const EXPIRY_MARGIN_SECS: i64 = 60;
+
+/// Token endpoint response (RFC 6749 §5.1)
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: i64,
+    /// Only returned on the initial code exchange (with `access_type=offline`)
+    pub refresh_token: Option<String>,
+}
+
+/// Google consent page URL for the auth-code flow
+pub fn authorization_url(
+    client_id: &str,
+    redirect_uri: &str,
+    state: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let url = reqwest::Url::parse_with_params(AUTH_ENDPOINT, &[
+        ("client_id", client_id),
+        ("redirect_uri", redirect_uri),
+        ("response_type", "code"),
+        ("scope", GMAIL_SCOPE),
+        ("access_type", "offline"),
+        // Force the consent screen so Google issues a refresh token every time
+        ("prompt", "consent"),
+        ("state", state),
+    ])?;
+    Ok(url.into())
+}
+
+/// Exchange an authorization code for access and refresh tokens
+pub async fn exchange_code(
+    client_id: &str,
+    client_secret: &str,
+    code: &str,
+    redirect_uri: &str,
+    proxy: Option<&str>,
+) -> Result<TokenResponse, Box<dyn std::error::Error + Send + Sync>> {
+    token_request(proxy, &[
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("grant_type", "authorization_code"),
+    ]).await
+}
+
+/// Obtain a new access token using a refresh token
+pub async fn refresh_access_token(
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+    proxy: Option<&str>,
+) -> Result<TokenResponse, Box<dyn std::error::Error + Send + Sync>> {
+    token_request(proxy, &[
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("refresh_token", refresh_token),
+        ("grant_type", "refresh_token"),
+    ]).await
+}
+
+/// POST to the token endpoint, through the SOCKS5 `proxy` (Tor) if given
+async fn token_request(
+    proxy: Option<&str>,
+    form: &[(&str, &str)],
+) -> Result<TokenResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        // socks5h: let the proxy resolve the hostname
+        client = client.proxy(reqwest::Proxy::all(format!("socks5h://{}", proxy))?);
+    }
+    let response = client.build()?
+        .post(TOKEN_ENDPOINT)
+        .form(form)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Token endpoint returned {}: {}", status, body).into());
+    }
+    Ok(response.json().await?)
+}
+
+/// Persist tokens from the token endpoint into settings
+pub fn store_tokens(
+    db: &Database,
+    tokens: &TokenResponse,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let expiry = chrono::Utc::now().timestamp() + tokens.expires_in;
+    db.set_setting("gmail_access_token", &tokens.access_token).map_err(|e| e.to_string())?;
+    db.set_setting("gmail_token_expiry", &expiry.to_string()).map_err(|e| e.to_string())?;
+    // Refreshes usually omit the refresh token; keep the one we have
+    if let Some(ref refresh_token) = tokens.refresh_token {
+        db.set_setting("gmail_refresh_token", refresh_token).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Load the Gmail config, refreshing the OAuth2 access token first if it is
+/// missing or about to expire. App-password configs are returned as-is.
+pub async fn fresh_config(
+    db: &Database,
+) -> Result<Option<GmailConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(mut config) = db.gmail_config().map_err(|e| e.to_string())? else {
+        return Ok(None);
+    };
+
+    let (Some(refresh_token), Some(client_id), Some(client_secret)) = (
+        config.refresh_token.clone(),
+        config.oauth_client_id.clone(),
+        config.oauth_client_secret.clone(),
+    ) else {
+        return Ok(Some(config));
+    };
+
+    let expires_soon = config.token_expiry
+        .map(|expiry| expiry - EXPIRY_MARGIN_SECS <= chrono::Utc::now().timestamp())
+        .unwrap_or(true);
+    if config.access_token.is_none() || expires_soon {
+        let tokens = refresh_access_token(&client_id, &client_secret, &refresh_token, config.socks_proxy.as_deref()).await?;
+        store_tokens(db, &tokens)?;
+        config.token_expiry = Some(chrono::Utc::now().timestamp() + tokens.expires_in);
+        config.access_token = Some(tokens.access_token);
+        tracing::info!("Refreshed Gmail OAuth2 access token");
+    }
+    Ok(Some(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorization_url_encodes_params() {
+        let url = authorization_url("client-1", "http://127.0.0.1:8420/api/gmail/oauth/callback", "xyz").unwrap();
+        assert!(url.starts_with(AUTH_ENDPOINT));
+        assert!(url.contains("client_id=client-1"));
+        assert!(url.contains("redirect_uri=http%3A%2F%2F127.0.0.1%3A8420%2Fapi%2Fgmail%2Foauth%2Fcallback"));
+        assert!(url.contains("scope=https%3A%2F%2Fmail.google.com%2F"));
+        assert!(url.contains("access_type=offline"));
+        assert!(url.contains("state=xyz"));
+    }
+}
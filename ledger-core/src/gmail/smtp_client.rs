@@ -1,47 +1,233 @@
 use lettre::{
-    message::header::ContentType,
-    transport::smtp::authentication::Credentials,
+    message::{header, Attachment as MimeAttachment, MultiPart, SinglePart},
+    transport::smtp::{
+        authentication::{Credentials, Mechanism},
+        client::{AsyncSmtpConnection, TlsParameters},
+        extension::ClientId,
+        SUBMISSION_PORT,
+    },
     AsyncSmtpTransport, AsyncTransport, Message as LettreMessage, Tokio1Executor,
 };
 
-use crate::models::message::GmailConfig;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-/// Send an email via Gmail SMTP
+use super::imap_client::html_to_text;
+use crate::models::message::{Attachment, ContentType, GmailConfig, Recipients};
+
+/// Messages a minute sent through Gmail when `gmail_send_rate` is unset
+pub const DEFAULT_GMAIL_SEND_RATE: u32 = 20;
+
+/// Retries after a send fails in a way that can't have delivered it, waiting
+/// `SEND_RETRY_BACKOFF` and doubling each time
+const MAX_SEND_RETRIES: u32 = 3;
+const SEND_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// When the next message may go out, shared by every send
+static NEXT_SEND_SLOT: tokio::sync::Mutex<Option<Instant>> = tokio::sync::Mutex::const_new(None);
+
+/// The pooled transport for direct connections, rebuilt when the account,
+/// host or credentials change
+static SHARED_TRANSPORT: Mutex<Option<(TransportKey, AsyncSmtpTransport<Tokio1Executor>)>> = Mutex::new(None);
+
+/// Host, login and secret a pooled transport was built with
+type TransportKey = (String, String, String);
+
+/// Send an email via Gmail SMTP, adding any attachments as MIME parts. Sends
+/// are spaced to `config.send_rate` a minute, and retried with backoff when
+/// Gmail reports a temporary failure.
 pub async fn send_email(
     config: &GmailConfig,
-    to: &str,
+    recipients: &Recipients,
     subject: &str,
     body: &str,
+    content_type: ContentType,
+    attachments: &[Attachment],
+    in_reply_to: Option<&str>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let smtp_host = config.smtp_host.as_deref().unwrap_or("smtp.gmail.com");
+    let email = build_message(&config.email, recipients, subject, body, content_type, attachments, in_reply_to)?;
+
+    send_with_retries(config.send_rate, SEND_RETRY_BACKOFF, || transmit(config, &email)).await?;
+
+    tracing::info!("Email sent to {} via Gmail SMTP", recipients.joined());
+    Ok(())
+}
+
+/// How far a send got before it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Connecting, STARTTLS or logging in: Gmail hasn't seen the message yet
+    Connect,
+    /// MAIL FROM through the end of DATA
+    Transfer,
+}
 
-    let email = LettreMessage::builder()
-        .from(config.email.parse()?)
-        .to(to.parse()?)
-        .subject(subject)
-        .header(ContentType::TEXT_PLAIN)
-        .body(body.to_string())?;
+/// A failed send and the stage it failed at
+#[derive(Debug)]
+struct SendFailure {
+    stage: Stage,
+    error: Box<dyn std::error::Error>,
+}
 
-    let creds = Credentials::new(config.email.clone(), config.app_password.clone());
+impl SendFailure {
+    fn at(stage: Stage) -> impl FnOnce(Box<dyn std::error::Error>) -> Self {
+        move |error| Self { stage, error }
+    }
 
-    let mailer = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
-        .credentials(creds)
-        .build();
+    /// Whether sending again can't deliver the message twice. A 4xx reply
+    /// means Gmail refused the command, so nothing was accepted. Any other
+    /// failure is only safe before the transfer starts: a connection lost
+    /// during DATA may already have delivered it. Rejected logins (5xx) and
+    /// client errors won't fix themselves.
+    fn is_retryable(&self) -> bool {
+        let smtp = self.error.downcast_ref::<lettre::transport::smtp::Error>();
+        match self.stage {
+            Stage::Connect => !smtp.is_some_and(|e| e.is_permanent() || e.is_client()),
+            Stage::Transfer => smtp.is_some_and(|e| e.is_transient()),
+        }
+    }
+}
 
-    mailer.send(email).await?;
+/// Make `attempt` in the next send slot, retrying failures that can't have
+/// delivered the message with doubling backoff
+async fn send_with_retries<F, Fut>(
+    rate: u32,
+    mut backoff: Duration,
+    mut attempt: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), SendFailure>>,
+{
+    let mut retries = 0;
+    loop {
+        wait_for_send_slot(rate).await;
+        match attempt().await {
+            Ok(()) => return Ok(()),
+            Err(failure) if retries < MAX_SEND_RETRIES && failure.is_retryable() => {
+                tracing::warn!("Gmail send failed ({}); retrying in {:?}", failure.error, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(failure) => return Err(failure.error),
+        }
+    }
+}
 
-    tracing::info!("Email sent to {} via Gmail SMTP", to);
+/// Hand one message to Gmail's SMTP server. The pooled transport connects
+/// inside `send`, so its failures all count as the transfer stage.
+async fn transmit(config: &GmailConfig, email: &LettreMessage) -> Result<(), SendFailure> {
+    let smtp_host = config.smtp_host.as_deref().unwrap_or("smtp.gmail.com");
+    let (credentials, mechanisms) = match config.access_token {
+        Some(ref access_token) => (
+            Credentials::new(config.email.clone(), access_token.clone()),
+            vec![Mechanism::Xoauth2],
+        ),
+        None => (
+            Credentials::new(config.email.clone(), config.app_password.clone()),
+            vec![Mechanism::Plain, Mechanism::Login],
+        ),
+    };
+
+    match config.socks_proxy {
+        Some(ref proxy) => {
+            // AsyncSmtpTransport can't use a proxy, so drive the connection by hand
+            let mut conn = connect_via_proxy(proxy, smtp_host, &mechanisms, &credentials)
+                .await
+                .map_err(SendFailure::at(Stage::Connect))?;
+            conn.send(email.envelope(), &email.formatted())
+                .await
+                .map_err(|e| SendFailure::at(Stage::Transfer)(e.into()))?;
+            let _ = conn.quit().await;
+        }
+        None => {
+            let secret = config.access_token.clone().unwrap_or_else(|| config.app_password.clone());
+            let key = (smtp_host.to_string(), config.email.clone(), secret);
+            let mailer = shared_transport(key, || {
+                Ok(AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
+                    .credentials(credentials)
+                    .authentication(mechanisms)
+                    .build())
+            })
+            .map_err(SendFailure::at(Stage::Connect))?;
+            mailer.send(email.clone())
+                .await
+                .map_err(|e| SendFailure::at(Stage::Transfer)(e.into()))?;
+        }
+    }
     Ok(())
 }
 
+/// Connect through the SOCKS proxy, upgrade to TLS and log in
+async fn connect_via_proxy(
+    proxy: &str,
+    smtp_host: &str,
+    mechanisms: &[Mechanism],
+    credentials: &Credentials,
+) -> Result<AsyncSmtpConnection, Box<dyn std::error::Error>> {
+    let stream = crate::tor::connect(proxy, smtp_host, SUBMISSION_PORT).await?;
+    // Don't leak the local hostname in EHLO
+    let hello = ClientId::Domain("localhost".into());
+    let mut conn = AsyncSmtpConnection::connect_with_transport(Box::new(stream), &hello).await?;
+    conn.starttls(TlsParameters::new(smtp_host.into())?, &hello).await?;
+    conn.auth(mechanisms, credentials).await?;
+    Ok(conn)
+}
+
+/// The pooled transport for `key`, building it (and dropping any other) if needed
+fn shared_transport(
+    key: TransportKey,
+    build: impl FnOnce() -> Result<AsyncSmtpTransport<Tokio1Executor>, Box<dyn std::error::Error>>,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, Box<dyn std::error::Error>> {
+    let mut shared = SHARED_TRANSPORT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match *shared {
+        Some((ref current, ref mailer)) if *current == key => Ok(mailer.clone()),
+        _ => {
+            let mailer = build()?;
+            *shared = Some((key, mailer.clone()));
+            Ok(mailer)
+        }
+    }
+}
+
+/// Wait for the next free send slot, spacing sends `60 / rate` seconds apart.
+/// A rate of 0 sends without waiting.
+async fn wait_for_send_slot(rate: u32) {
+    if rate == 0 {
+        return;
+    }
+    let spacing = Duration::from_secs(60) / rate;
+    let slot = claim_send_slot(&mut *NEXT_SEND_SLOT.lock().await, Instant::now(), spacing);
+    tokio::time::sleep_until(slot.into()).await;
+}
+
+/// Take the first slot at or after `now` and move `next` one `spacing` past
+/// it. Idle time doesn't bank slots for a later burst.
+fn claim_send_slot(next: &mut Option<Instant>, now: Instant, spacing: Duration) -> Instant {
+    let slot = next.map_or(now, |next| next.max(now));
+    *next = Some(slot + spacing);
+    slot
+}
+
 /// Send an encrypted fallback email (encrypted body as base64 in the message)
 pub async fn send_encrypted_fallback(
     config: &GmailConfig,
     to: &str,
     encrypted_payload: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let subject = "[Ledger Encrypted Fallback]";
-    let body = format!(
+    let body = fallback_body(encrypted_payload);
+    send_email(config, &Recipients::single(to), FALLBACK_SUBJECT, &body, ContentType::Text, &[], None).await
+}
+
+/// Subject that marks an email as carrying a Ledger envelope
+pub const FALLBACK_SUBJECT: &str = "[Ledger Encrypted Fallback]";
+
+/// Body of a fallback email, with the payload between the markers that
+/// `extract_encrypted_payload` looks for
+pub(crate) fn fallback_body(encrypted_payload: &str) -> String {
+    format!(
         "This message was sent by the Ledger encrypted mail client.\n\
          The recipient's Ledger node was unreachable, so this encrypted fallback was sent.\n\
          \n\
@@ -49,7 +235,221 @@ pub async fn send_encrypted_fallback(
          {}\n\
          --- END LEDGER ENCRYPTED MESSAGE ---\n",
         encrypted_payload
-    );
+    )
+}
+
+/// Build the MIME message. Bcc addresses only go into the SMTP envelope;
+/// lettre leaves the Bcc header out of the formatted message. A reply sets
+/// `In-Reply-To` and `References` so mail clients keep it in the thread.
+/// HTML bodies go out as multipart/alternative with a tag-stripped plain part.
+fn build_message(
+    from: &str,
+    recipients: &Recipients,
+    subject: &str,
+    body: &str,
+    content_type: ContentType,
+    attachments: &[Attachment],
+    in_reply_to: Option<&str>,
+) -> Result<LettreMessage, Box<dyn std::error::Error>> {
+    let mut builder = LettreMessage::builder()
+        .from(from.parse()?)
+        .subject(subject);
+    if let Some(parent) = in_reply_to {
+        builder = builder
+            .in_reply_to(parent.to_string())
+            .references(parent.to_string());
+    }
+    for to in &recipients.to {
+        builder = builder.to(to.parse()?);
+    }
+    for cc in &recipients.cc {
+        builder = builder.cc(cc.parse()?);
+    }
+    for bcc in &recipients.bcc {
+        builder = builder.bcc(bcc.parse()?);
+    }
+
+    let email = match (content_type, attachments.is_empty()) {
+        (ContentType::Text, true) => builder
+            .header(header::ContentType::TEXT_PLAIN)
+            .body(body.to_string())?,
+        (ContentType::Html, true) => builder.multipart(html_alternative(body))?,
+        (_, false) => {
+            let mut parts = match content_type {
+                ContentType::Text => MultiPart::mixed().singlepart(SinglePart::plain(body.to_string())),
+                ContentType::Html => MultiPart::mixed().multipart(html_alternative(body)),
+            };
+            for attachment in attachments {
+                let mime_type = header::ContentType::parse(&attachment.mime_type)
+                    .map_err(|e| format!("Invalid MIME type {:?}: {}", attachment.mime_type, e))?;
+                parts = parts.singlepart(
+                    MimeAttachment::new(attachment.filename.clone())
+                        .body(attachment.data.clone(), mime_type),
+                );
+            }
+            builder.multipart(parts)?
+        }
+    };
+    Ok(email)
+}
+
+/// An HTML body with a plain-text rendering for clients that won't show HTML
+fn html_alternative(html: &str) -> MultiPart {
+    MultiPart::alternative_plain_html(html_to_text(html), html.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bcc_kept_out_of_headers() {
+        let recipients = Recipients {
+            to: vec!["alice@example.com".into()],
+            cc: vec!["bob@example.com".into()],
+            bcc: vec!["carol@example.com".into()],
+        };
+        let email = build_message("me@example.com", &recipients, "Hi", "Hello", ContentType::Text, &[], None).unwrap();
+
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("Cc: bob@example.com"));
+        assert!(!formatted.contains("carol@example.com"));
+        assert_eq!(email.envelope().to().len(), 3);
+    }
+
+    #[test]
+    fn test_reply_sets_threading_headers() {
+        let parent = "<abc123@mail.gmail.com>";
+        let email = build_message(
+            "me@example.com",
+            &Recipients::single("alice@example.com"),
+            "Re: Hi",
+            "Hello",
+            ContentType::Text,
+            &[],
+            Some(parent),
+        ).unwrap();
+
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains(&format!("In-Reply-To: {}", parent)));
+        assert!(formatted.contains(&format!("References: {}", parent)));
+    }
+
+    #[test]
+    fn test_html_body_has_plain_alternative() {
+        let email = build_message(
+            "me@example.com",
+            &Recipients::single("alice@example.com"),
+            "News",
+            "<p>Hello <b>there</b></p>",
+            ContentType::Html,
+            &[],
+            None,
+        ).unwrap();
+
+        let formatted = String::from_utf8(email.formatted()).unwrap();
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(formatted.contains("text/plain"));
+        assert!(formatted.contains("text/html"));
+        assert!(formatted.contains("Hello there"));
+    }
+
+    #[test]
+    fn test_send_slots_spaced() {
+        let spacing = Duration::from_secs(3);
+        let start = Instant::now();
+        let mut next = None;
+
+        assert_eq!(claim_send_slot(&mut next, start, spacing), start);
+        // A burst queues up one spacing apart
+        assert_eq!(claim_send_slot(&mut next, start, spacing), start + spacing);
+        assert_eq!(claim_send_slot(&mut next, start + Duration::from_secs(1), spacing), start + spacing * 2);
+        // After a quiet spell the next send goes straight out
+        let later = start + Duration::from_secs(60);
+        assert_eq!(claim_send_slot(&mut next, later, spacing), later);
+        assert_eq!(next, Some(later + spacing));
+    }
+
+    /// A lettre error for Gmail answering the greeting with `reply`
+    async fn smtp_error(reply: &'static str) -> Box<dyn std::error::Error> {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(reply.as_bytes()).await.unwrap();
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let hello = ClientId::Domain("localhost".into());
+        match AsyncSmtpConnection::connect_with_transport(Box::new(stream), &hello).await {
+            Ok(_) => panic!("greeting {:?} accepted", reply),
+            Err(e) => e.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_undelivered_sends_retried() {
+        let failure = |stage, error| SendFailure { stage, error };
+        let dropped = || Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset)) as Box<dyn std::error::Error>;
+
+        assert!(failure(Stage::Connect, dropped()).is_retryable());
+        assert!(failure(Stage::Connect, smtp_error("421 busy\r\n").await).is_retryable());
+        assert!(!failure(Stage::Connect, smtp_error("554 go away\r\n").await).is_retryable());
+        assert!(failure(Stage::Transfer, smtp_error("451 try later\r\n").await).is_retryable());
+        // The message may already be with Gmail
+        assert!(!failure(Stage::Transfer, dropped()).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_retry_loop() {
+        // Connection failures are retried until one attempt gets through
+        let mut attempts = 0;
+        let result = send_with_retries(0, Duration::ZERO, || {
+            attempts += 1;
+            let outcome = match attempts {
+                1 | 2 => Err(SendFailure { stage: Stage::Connect, error: "refused".into() }),
+                _ => Ok(()),
+            };
+            async move { outcome }
+        }).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+
+        // and give up after MAX_SEND_RETRIES
+        let mut attempts = 0;
+        let result = send_with_retries(0, Duration::ZERO, || {
+            attempts += 1;
+            async { Err(SendFailure { stage: Stage::Connect, error: "refused".into() }) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_SEND_RETRIES + 1);
+
+        // A lost connection mid-transfer is never retried
+        let mut attempts = 0;
+        let result = send_with_retries(0, Duration::ZERO, || {
+            attempts += 1;
+            async { Err(SendFailure { stage: Stage::Transfer, error: "connection reset".into() }) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_shared_transport_rebuilt_on_new_key() {
+        let key = |secret: &str| ("smtp.test".to_string(), "me@example.com".to_string(), secret.to_string());
+        let mut builds = 0;
+        let mut get = |secret: &str| {
+            shared_transport(key(secret), || {
+                builds += 1;
+                Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("smtp.test").build())
+            }).unwrap();
+        };
 
-    send_email(config, to, subject, &body).await
+        get("one");
+        get("one");
+        get("two");
+        get("one");
+        assert_eq!(builds, 3);
+    }
 }
@@ -0,0 +1,157 @@
+use crate::error::Result;
+use crate::models::message::{Folder, Message};
+use crate::store::db::Database;
+
+/// Score at or above which imported mail is filed in Spam when the
+/// `spam_threshold` setting is unset. 0 turns filtering off.
+pub const DEFAULT_SPAM_THRESHOLD: u32 = 6;
+
+/// The sender is neither a contact's Gmail address nor marked not-spam before
+const UNKNOWN_SENDER: u32 = 2;
+/// The subject has letters and none of them are lowercase
+const SHOUTING_SUBJECT: u32 = 2;
+/// Per spam phrase in the subject or body, up to `MAX_PHRASE_HITS` of them
+const SPAM_PHRASE: u32 = 2;
+const MAX_PHRASE_HITS: u32 = 3;
+/// No `DKIM-Signature` header, which nearly all legitimate bulk mail carries
+const MISSING_DKIM: u32 = 3;
+
+/// Phrases common in spam, matched case-insensitively
+const SPAM_PHRASES: &[&str] = &[
+    "act now",
+    "claim your prize",
+    "click here",
+    "congratulations, you",
+    "crypto giveaway",
+    "double your money",
+    "free money",
+    "limited time offer",
+    "no credit check",
+    "risk-free",
+    "urgent response needed",
+    "wire transfer",
+    "you have been selected",
+    "you've won",
+];
+
+/// Score what the message itself gives away: its subject, body and headers.
+/// The sender is scored later by `classify`, which can see the contacts.
+pub fn content_score(parsed: &mailparse::ParsedMail, subject: &str, body: &str) -> u32 {
+    let mut score = 0;
+
+    let letters = subject.chars().filter(|c| c.is_alphabetic()).count();
+    if letters >= 4 && !subject.chars().any(char::is_lowercase) {
+        score += SHOUTING_SUBJECT;
+    }
+
+    let text = format!("{}\n{}", subject, body).to_lowercase();
+    let hits = SPAM_PHRASES.iter().filter(|phrase| text.contains(*phrase)).count() as u32;
+    score += hits.min(MAX_PHRASE_HITS) * SPAM_PHRASE;
+
+    let signed = parsed.headers.iter().any(|h| h.get_key().eq_ignore_ascii_case("dkim-signature"));
+    if !signed {
+        score += MISSING_DKIM;
+    }
+    score
+}
+
+/// Finish scoring a fetched email and file it in Spam if it reaches the
+/// `spam_threshold` setting. Mail from known senders is never filed there.
+pub fn classify(db: &Database, msg: &mut Message) -> Result<()> {
+    let Some(score) = msg.spam_score else {
+        return Ok(());
+    };
+    let sender = sender_address(&msg.from_id);
+    if db.is_known_sender(&sender)? {
+        return Ok(());
+    }
+    let score = score + UNKNOWN_SENDER;
+    msg.spam_score = Some(score);
+
+    let threshold = db.get_setting("spam_threshold")?
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_SPAM_THRESHOLD);
+    if threshold > 0 && score >= threshold {
+        tracing::info!("Filing email from {} as spam (score {})", sender, score);
+        msg.folder = Folder::Spam;
+    }
+    Ok(())
+}
+
+/// The bare, lowercased address of a `From` header such as `Name <a@b.c>`
+pub fn sender_address(from: &str) -> String {
+    let address = match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &from[start + 1..end],
+        _ => from,
+    };
+    address.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(raw: &str) -> u32 {
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let subject = parsed.headers.iter()
+            .find(|h| h.get_key().eq_ignore_ascii_case("subject"))
+            .map(|h| h.get_value())
+            .unwrap_or_default();
+        content_score(&parsed, &subject, &parsed.get_body().unwrap())
+    }
+
+    #[test]
+    fn test_content_score_signals() {
+        let clean = "DKIM-Signature: v=1; d=example.com\r\nSubject: Lunch on Friday?\r\n\r\nShall we?\r\n";
+        assert_eq!(score(clean), 0);
+
+        let unsigned = "Subject: Lunch on Friday?\r\n\r\nShall we?\r\n";
+        assert_eq!(score(unsigned), MISSING_DKIM);
+
+        let shouting = "DKIM-Signature: v=1\r\nSubject: YOU'VE WON!!!\r\n\r\nClick here and act now.\r\n";
+        assert_eq!(score(shouting), SHOUTING_SUBJECT + 3 * SPAM_PHRASE);
+
+        // A subject without letters isn't shouting
+        assert_eq!(score("DKIM-Signature: v=1\r\nSubject: 2024-01\r\n\r\nok\r\n"), 0);
+    }
+
+    #[test]
+    fn test_classify_files_unknown_senders_until_allowed() {
+        let dir = std::env::temp_dir().join("ledger_test_spam_classify");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+        let email = |score| {
+            let mut msg = Message::new("Promo <deals@example.com>".into(), "me@gmail.com".into(), "Hi".into(), String::new());
+            msg.spam_score = Some(score);
+            msg
+        };
+
+        let mut msg = email(DEFAULT_SPAM_THRESHOLD - UNKNOWN_SENDER);
+        classify(&db, &mut msg).unwrap();
+        assert_eq!(msg.folder, Folder::Spam);
+        assert_eq!(msg.spam_score, Some(DEFAULT_SPAM_THRESHOLD));
+
+        let mut msg = email(DEFAULT_SPAM_THRESHOLD - UNKNOWN_SENDER - 1);
+        classify(&db, &mut msg).unwrap();
+        assert_eq!(msg.folder, Folder::Inbox);
+
+        db.allow_sender("Deals@Example.com").unwrap();
+        let mut msg = email(20);
+        classify(&db, &mut msg).unwrap();
+        assert_eq!(msg.folder, Folder::Inbox);
+
+        // A threshold of 0 turns filtering off
+        db.set_setting("spam_threshold", "0").unwrap();
+        let mut msg = Message { from_id: "other@example.com".into(), ..email(20) };
+        classify(&db, &mut msg).unwrap();
+        assert_eq!(msg.folder, Folder::Inbox);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sender_address() {
+        assert_eq!(sender_address("Alice Example <Alice@Example.com>"), "alice@example.com");
+        assert_eq!(sender_address(" bob@example.com "), "bob@example.com");
+    }
+}
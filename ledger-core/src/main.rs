@@ -1,143 +1,523 @@
 mod api;
+mod cli;
+mod config;
 mod crypto;
 mod dht;
+mod error;
 mod fallback;
 mod gmail;
+mod metrics;
 mod models;
 mod p2p;
 mod store;
+mod tor;
 
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
-use clap::Parser;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 use crypto::keys::LedgerIdentity;
+use models::message::MessageEvent;
 use p2p::node::P2PCommand;
 use store::db::Database;
 
+/// How long messages stay in Trash before being purged
+const TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often the database is compacted when `nightly_vacuum` is on
+const VACUUM_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
 /// Shared application state
 pub struct AppState {
     pub identity: Arc<LedgerIdentity>,
     pub db: Arc<Database>,
     pub p2p_tx: mpsc::Sender<P2PCommand>,
     pub peer_id: libp2p::PeerId,
+    pub data_dir: PathBuf,
+    /// The passphrase `identity.key` is encrypted under; a key written over it
+    /// keeps it unless the request gives another
+    pub key_passphrase: Option<String>,
+    /// Fan-out of real-time events to WebSocket clients
+    pub events: broadcast::Sender<MessageEvent>,
+    pub started_at: std::time::Instant,
+    pub metrics: Arc<metrics::Metrics>,
+    /// Bearer token required on `/api/*`; `None` leaves the API open
+    pub api_token: Option<String>,
+    /// Contacts online now, kept current by the P2P node
+    pub online: Arc<p2p::presence::OnlineContacts>,
+    /// Woken when Gmail is configured or authorized, so IMAP IDLE connects
+    pub gmail_configured: Arc<Notify>,
+}
+
+/// Build the HTTPS server config from a PEM certificate chain and private key
+fn load_tls_config(cert: &PathBuf, key: &PathBuf) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read TLS certificate {:?}: {}", cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("Failed to read TLS key {:?}: {}", key, e))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
+
+/// Periodically delete messages that have sat in Trash past the retention period
+fn start_trash_purge(db: Arc<Database>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cutoff = chrono::Utc::now().timestamp() - TRASH_RETENTION.as_secs() as i64;
+            match db.purge_trash(cutoff) {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Purged {} message(s) from trash", n),
+                Err(e) => tracing::error!("Trash purge failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Compact the database once a day while the `nightly_vacuum` setting is `true`
+fn start_nightly_vacuum(db: Arc<Database>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(VACUUM_INTERVAL);
+        // The first tick is immediate; wait a full day before the first run
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if db.get_setting("nightly_vacuum").ok().flatten().as_deref() != Some("true") {
+                continue;
+            }
+            let vacuum_db = db.clone();
+            match tokio::task::spawn_blocking(move || vacuum_db.vacuum()).await {
+                Ok(Ok(reclaimed)) => tracing::info!("Vacuumed database, reclaiming {} bytes", reclaimed),
+                Ok(Err(e)) => tracing::error!("Database vacuum failed: {}", e),
+                Err(e) => tracing::error!("Database vacuum task failed: {}", e),
+            }
+        }
+    });
 }
 
-/// Ledger Core — Decentralized Encrypted Mail Engine
-#[derive(Parser, Debug)]
-#[command(name = "ledger-core", version, about)]
-struct Args {
-    /// REST API port
-    #[arg(long, default_value_t = 8420)]
-    port: u16,
-
-    /// libp2p swarm port
-    #[arg(long, default_value_t = 9420)]
-    p2p_port: u16,
-
-    /// Data directory
-    #[arg(long)]
-    data_dir: Option<String>,
+/// Run IMAP IDLE in the background and store whatever it delivers. Until
+/// Gmail is configured it waits on `configured`.
+fn start_gmail_idle(
+    db: Arc<Database>,
+    identity: Arc<LedgerIdentity>,
+    events: broadcast::Sender<MessageEvent>,
+    metrics: Arc<metrics::Metrics>,
+    configured: Arc<Notify>,
+) {
+    let (tx, mut rx) = mpsc::channel(64);
+    let idle_db = db.clone();
+    tokio::spawn(gmail::imap_client::idle_loop(
+        move || {
+            let db = idle_db.clone();
+            async move {
+                match gmail::oauth::fresh_config(&db).await {
+                    Ok(Some(config)) => match db.imap_cursor(&config.email) {
+                        Ok(cursor) => Some((config, cursor)),
+                        Err(e) => {
+                            tracing::warn!("Failed to load Gmail import position: {}", e);
+                            None
+                        }
+                    },
+                    Ok(None) => None,
+                    Err(e) => {
+                        tracing::warn!("Failed to load Gmail config: {}", e);
+                        None
+                    }
+                }
+            }
+        },
+        configured,
+        tx,
+    ));
+
+    tokio::spawn(async move {
+        // Mail that failed to store, retried ahead of the next batch
+        let mut retry = Vec::new();
+        while let Some(first) = rx.recv().await {
+            // Take whatever else has arrived too, so seen flags go out in one batch
+            let mut batch = std::mem::take(&mut retry);
+            batch.push(first);
+            while let Ok(next) = rx.try_recv() {
+                batch.push(next);
+            }
+
+            let last_cursor = batch.last().map(|(_, cursor)| cursor.clone());
+            let (imported, failed) = api::gmail::store_idle_batch(&db, &identity, &events, &metrics, batch);
+            retry = failed;
+            if let Some(cursor) = last_cursor {
+                api::gmail::mark_imported_seen(&db, &cursor, imported).await;
+            }
+        }
+    });
+}
+
+/// Resolve the data directory and load (or create) the identity in it
+fn open_identity(args: &cli::NodeArgs) -> Result<(PathBuf, LedgerIdentity), Box<dyn std::error::Error>> {
+    let data_dir = args.data_dir();
+    tracing::info!("Data directory: {:?}", data_dir);
+
+    let identity = LedgerIdentity::load_or_create(&data_dir, args.passphrase.as_deref())?;
+    tracing::info!("Ledger ID: {}", identity.ledger_id);
+    Ok((data_dir, identity))
+}
+
+/// Give the identity the keys of identities it was rotated from that are
+/// still in their grace period, forgetting expired ones. They're sealed under
+/// the passphrase, like `identity.key`.
+fn add_retired_keys(args: &cli::NodeArgs, identity: LedgerIdentity, db: &Database) -> Result<Arc<LedgerIdentity>, Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now().timestamp();
+    let purged = db.purge_retired_keys(now)?;
+    if purged > 0 {
+        tracing::info!("Forgot {} expired retired key(s)", purged);
+    }
+    let passphrase = args.passphrase.as_deref().filter(|p| !p.is_empty());
+    if let Some(passphrase) = passphrase {
+        let sealed = db.seal_retired_keys(passphrase)?;
+        if sealed > 0 {
+            tracing::info!("Encrypted {} retired key(s) with passphrase", sealed);
+        }
+    }
+    let retired_keys = db.get_retired_keys(now, passphrase)?;
+    for key in &retired_keys {
+        tracing::info!("Still decrypting mail to retired {} until {}", key.ledger_id, key.expires_at);
+    }
+    Ok(Arc::new(identity.with_retired_keys(retired_keys)))
+}
+
+/// Open the database, keyed with the passphrase if it is (or is to be) encrypted,
+/// and store the settings `ledger.toml` sets
+fn open_database(args: &cli::NodeArgs, data_dir: &PathBuf) -> Result<Arc<Database>, Box<dyn std::error::Error>> {
+    let db_key = if args.encrypt_db || Database::is_encrypted(data_dir) {
+        match args.passphrase.as_deref().filter(|p| !p.is_empty()) {
+            Some(passphrase) => Some(passphrase),
+            None => return Err("An encrypted database needs --passphrase (or LEDGER_PASSPHRASE)".into()),
+        }
+    } else {
+        None
+    };
+    let db = Arc::new(Database::open_with_key(data_dir, db_key)?);
+    args.config.apply_settings(&db)?;
+    tracing::info!("Database initialized");
+    Ok(db)
+}
+
+/// Start the P2P node, through Tor if enabled, bootstrapping from the command
+/// line plus the `bootstrap_nodes` setting
+async fn start_p2p(
+    args: &cli::NodeArgs,
+    identity: Arc<LedgerIdentity>,
+    db: Arc<Database>,
+    events: broadcast::Sender<MessageEvent>,
+    metrics: Arc<metrics::Metrics>,
+    online: Arc<p2p::presence::OnlineContacts>,
+) -> Result<(mpsc::Sender<P2PCommand>, libp2p::PeerId), Box<dyn std::error::Error>> {
+    // With Tor enabled nothing may bypass the proxy, so refuse to start without it
+    let tor = match db.tor_proxy()? {
+        Some(proxy) => {
+            tor::check_proxy(&proxy).await?;
+            tracing::info!("Routing outbound traffic through Tor at {}", proxy);
+            let onion_addr = if db.get_setting("tor_onion_service")?.as_deref() == Some("true") {
+                let addr = tor::start_onion_service(&db, args.p2p_port()).await?;
+                tracing::info!("Reachable as onion service {}", addr);
+                Some(addr)
+            } else {
+                None
+            };
+            Some(tor::TorConfig { socks_addr: proxy, onion_addr })
+        }
+        None => None,
+    };
+
+    let mut bootstrap_nodes = args.bootstrap.clone();
+    for addr in db.get_setting("bootstrap_nodes")?.unwrap_or_default().split([',', '\n']) {
+        let addr = addr.trim();
+        if addr.is_empty() {
+            continue;
+        }
+        match addr.parse() {
+            Ok(addr) => bootstrap_nodes.push(addr),
+            Err(e) => tracing::warn!("Ignoring invalid bootstrap node {:?}: {}", addr, e),
+        }
+    }
+
+    let protocols = args.protocols()?;
+    let config = p2p::node::NodeConfig {
+        p2p_port: args.p2p_port(),
+        tor,
+        bootstrap_nodes,
+        protocols,
+        events,
+        metrics,
+        online,
+    };
+    let (p2p_tx, peer_id) = p2p::node::start_node(identity, db, config).await?;
+    tracing::info!("P2P node started, peer ID: {}", peer_id);
+    Ok((p2p_tx, peer_id))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
+    let (mut node, mut command) = cli::Cli::node_and_command();
+    node.load_config()?;
+    if let cli::Command::Serve(ref mut args) = command {
+        args.apply_config(&node.config);
+    }
+
+    // One-shot commands print their result on stdout and only warnings on stderr
+    let (default_filter, writer) = match command {
+        cli::Command::Serve(_) => ("info", BoxMakeWriter::new(std::io::stdout)),
+        _ => ("warn", BoxMakeWriter::new(std::io::stderr)),
+    };
+    let writer = match node.log_dir {
+        Some(ref dir) => {
+            std::fs::create_dir_all(dir)?;
+            let log = std::fs::OpenOptions::new().create(true).append(true).open(dir.join("ledger.log"))?;
+            BoxMakeWriter::new(std::sync::Mutex::new(log))
+        }
+        None => writer,
+    };
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default_filter)),
         )
+        .with_writer(writer)
+        .with_ansi(node.log_dir.is_none())
         .init();
 
-    let args = Args::parse();
+    match command {
+        cli::Command::Serve(args) => serve(&node, args).await,
+        cli::Command::Send(args) => cli::send(&node, args).await,
+        cli::Command::Identity => cli::identity(&node),
+        cli::Command::Peers(args) => cli::peers(&node, args).await,
+    }
+}
+
+/// Run the node, its background workers and the REST API until the server stops
+async fn serve(node: &cli::NodeArgs, args: cli::ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (data_dir, identity) = open_identity(node)?;
+    let db = open_database(node, &data_dir)?;
+    let identity = add_retired_keys(node, identity, &db)?;
 
-    // Determine data directory
-    let data_dir = if let Some(ref dir) = args.data_dir {
-        PathBuf::from(dir)
-    } else {
-        dirs::data_local_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("ledger")
+    let api_token = match args.api_token.clone() {
+        Some(token) => Some(token),
+        None => db.get_setting("api_token")?,
+    }.filter(|t| !t.is_empty());
+    if api_token.is_some() {
+        tracing::info!("REST API requires a bearer token");
+    }
+    // Anything beyond loopback can read and send mail, so never expose it unauthenticated
+    if !args.bind().is_loopback() && api_token.is_none() {
+        return Err(format!("Refusing to bind the API to {} without an API token (--api-token)", args.bind()).into());
+    }
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+        _ => None,
     };
 
-    tracing::info!("Data directory: {:?}", data_dir);
+    // Real-time events for WebSocket clients
+    let (events, _) = broadcast::channel::<MessageEvent>(256);
 
-    // Initialize identity
-    let identity = Arc::new(LedgerIdentity::load_or_create(&data_dir)?);
-    tracing::info!("Ledger ID: {}", identity.ledger_id);
+    let metrics = Arc::new(metrics::Metrics::new());
+    let online = Arc::new(p2p::presence::OnlineContacts::default());
+    let (p2p_tx, peer_id) = start_p2p(node, identity.clone(), db.clone(), events.clone(), metrics.clone(), online.clone()).await?;
 
-    // Initialize database
-    let db = Arc::new(Database::open(&data_dir)?);
-    tracing::info!("Database initialized");
+    // Keep retrying sends that found no route
+    fallback::outbox::start(db.clone(), p2p_tx.clone(), metrics.clone());
 
-    // Start P2P node
-    let (p2p_tx, peer_id) = p2p::node::start_node(
-        args.p2p_port,
-        identity.clone(),
-        db.clone(),
-    ).await?;
+    // Keep our DHT mailbox entries alive until recipients collect them
+    dht::republish::start(db.clone(), p2p_tx.clone());
 
-    tracing::info!("P2P node started, peer ID: {}", peer_id);
+    // Push Gmail into the inbox as it arrives, once it's set up
+    let gmail_configured = Arc::new(Notify::new());
+    start_gmail_idle(db.clone(), identity.clone(), events.clone(), metrics.clone(), gmail_configured.clone());
+
+    // Empty old items out of Trash
+    start_trash_purge(db.clone());
+    start_nightly_vacuum(db.clone());
 
     // Start REST API server
-    let api_port = args.port;
+    let api_port = args.port();
+    let p2p_shutdown = p2p_tx.clone();
     let state = web::Data::new(AppState {
         identity: identity.clone(),
         db: db.clone(),
         p2p_tx,
         peer_id,
+        data_dir: data_dir.clone(),
+        key_passphrase: node.passphrase.clone().filter(|p| !p.is_empty()),
+        events,
+        started_at: std::time::Instant::now(),
+        metrics,
+        api_token,
+        online,
+        gmail_configured,
     });
 
-    tracing::info!("Starting REST API on 127.0.0.1:{}", api_port);
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    let api_url = format!("{}://{}", scheme, std::net::SocketAddr::new(args.bind(), api_port));
+    tracing::info!("Starting REST API on {}", api_url);
 
     println!("\n╔══════════════════════════════════════════╗");
     println!("║         LEDGER CORE v0.1.0               ║");
     println!("╠══════════════════════════════════════════╣");
     println!("║  Ledger ID: {}...  ║", &identity.ledger_id[..30]);
-    println!("║  API:       http://127.0.0.1:{:<5}       ║", api_port);
-    println!("║  P2P:       /ip4/0.0.0.0/tcp/{:<5}      ║", args.p2p_port);
+    println!("║  API:       {:<29}║", api_url);
+    println!("║  P2P:       /ip4/0.0.0.0/tcp/{:<5}      ║", node.p2p_port());
     println!("║  Peer ID:   {}... ║", &peer_id.to_string()[..30]);
     println!("╚══════════════════════════════════════════╝\n");
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
+            .expose_any_header()
             .max_age(3600);
 
         App::new()
+            // CORS outermost so preflight requests never need the token
+            .wrap(actix_web::middleware::from_fn(api::auth::require_token))
             .wrap(cors)
             .app_data(state.clone())
+            // Health
+            .service(api::health::health)
+            .service(api::health::metrics)
             // Identity
             .service(api::identity::get_identity)
+            .service(api::identity::get_card)
+            .service(api::identity::get_qr)
+            .service(api::identity::get_mnemonic)
+            .service(api::identity::recover_identity)
+            .service(api::identity::rotate_identity)
+            .service(api::identity::export_identity)
+            .service(api::identity::import_identity)
             // Messages
             .service(api::messages::list_messages)
+            // Registered before `/api/messages/{id}` so "counts" and "export" aren't taken as ids
+            .service(api::messages::message_counts)
+            .service(api::messages::export_mbox)
             .service(api::messages::get_message)
             .service(api::messages::send_message)
+            .service(api::messages::preview_message)
+            .service(api::messages::import_messages)
             .service(api::messages::delete_message)
+            .service(api::messages::restore_message)
+            .service(api::messages::not_spam)
+            .service(api::messages::move_message)
+            .service(api::messages::bulk_messages)
+            .service(api::messages::set_read)
+            .service(api::messages::set_starred)
+            .service(api::messages::mark_all_read)
+            .service(api::messages::list_attachments)
+            .service(api::messages::download_attachment)
+            .service(api::messages::export_eml)
+            // Drafts
+            .service(api::drafts::list_drafts)
+            .service(api::drafts::create_draft)
+            .service(api::drafts::update_draft)
+            .service(api::drafts::delete_draft)
+            .service(api::drafts::send_draft)
+            // Outbox
+            .service(api::outbox::list_outbox)
+            .service(api::maintenance::vacuum)
+            // Real-time events
+            .service(api::ws::events_ws)
             // Peers
             .service(api::peers::list_peers)
             .service(api::peers::connect_peer)
+            .service(api::peers::disconnect_peer)
+            // Broadcast
+            .service(api::broadcast::broadcast)
+            .service(api::blocklist::list_blocked)
+            .service(api::blocklist::block)
+            .service(api::blocklist::unblock)
+            // DHT
+            .service(api::dht::sync_dht)
             // Gmail
             .service(api::gmail::get_gmail_config)
             .service(api::gmail::set_gmail_config)
             .service(api::gmail::fetch_gmail)
             .service(api::gmail::send_gmail)
+            .service(api::gmail::oauth_start)
+            .service(api::gmail::oauth_callback)
             // Settings & Contacts
             .service(api::settings::get_settings)
             .service(api::settings::update_settings)
             .service(api::settings::list_contacts)
             .service(api::settings::add_contact)
-    })
-    .bind(format!("127.0.0.1:{}", api_port))?
-    .run()
-    .await?;
+            .service(api::settings::import_card)
+            .service(api::settings::get_contact)
+            .service(api::settings::contact_safety_number)
+            .service(api::settings::update_contact)
+            .service(api::settings::delete_contact)
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_23((args.bind(), api_port), config)?,
+        None => server.bind((args.bind(), api_port))?,
+    };
+    // Our own handler replaces actix's so the node and database shut down with it
+    let server = server.disable_signals().run();
+    let handle = server.handle();
+    tokio::select! {
+        result = server => result?,
+        _ = shutdown_signal() => {
+            tracing::info!("Shutting down; finishing in-flight requests");
+            // Stops accepting connections and waits for running handlers
+            handle.stop(true).await;
+        }
+    }
 
+    shut_down(&p2p_shutdown, &db).await
+}
+
+/// Stop the P2P node, waiting for it to flush, then checkpoint the database
+async fn shut_down(p2p_tx: &mpsc::Sender<P2PCommand>, db: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    let (tx, mut rx) = mpsc::channel(1);
+    if p2p_tx.send(P2PCommand::Shutdown { response_tx: tx }).await.is_ok() {
+        rx.recv().await;
+    }
+    db.checkpoint()?;
+    tracing::info!("Shutdown complete");
     Ok(())
 }
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::warn!("Ctrl-C handler unavailable: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => { sigterm.recv().await; }
+            Err(e) => {
+                tracing::warn!("SIGTERM handler unavailable: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -1,5 +1,9 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+use crate::crypto::attachment;
+use crate::crypto::envelope::OpenedEnvelope;
 
 /// Delivery method for a message
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -38,6 +42,12 @@ pub enum Folder {
     Inbox,
     Sent,
     Drafts,
+    Trash,
+    Archive,
+    /// Signed public bulletins received over gossip
+    Broadcast,
+    /// Imported Gmail that scored at or above `spam_threshold`
+    Spam,
 }
 
 impl std::fmt::Display for Folder {
@@ -46,30 +56,187 @@ impl std::fmt::Display for Folder {
             Folder::Inbox => write!(f, "inbox"),
             Folder::Sent => write!(f, "sent"),
             Folder::Drafts => write!(f, "drafts"),
+            Folder::Trash => write!(f, "trash"),
+            Folder::Archive => write!(f, "archive"),
+            Folder::Broadcast => write!(f, "broadcast"),
+            Folder::Spam => write!(f, "spam"),
         }
     }
 }
 
 impl Folder {
     pub fn from_str(s: &str) -> Self {
+        Self::parse(s).unwrap_or(Folder::Inbox)
+    }
+
+    /// Strict counterpart of `from_str` for validating user input
+    pub fn parse(s: &str) -> Option<Self> {
         match s {
-            "inbox" => Folder::Inbox,
-            "sent" => Folder::Sent,
-            "drafts" => Folder::Drafts,
-            _ => Folder::Inbox,
+            "inbox" => Some(Folder::Inbox),
+            "sent" => Some(Folder::Sent),
+            "drafts" => Some(Folder::Drafts),
+            "trash" => Some(Folder::Trash),
+            "archive" => Some(Folder::Archive),
+            "broadcast" => Some(Folder::Broadcast),
+            "spam" => Some(Folder::Spam),
+            _ => None,
         }
     }
 }
 
-/// Delivery mode preference
+/// Whether the recipient has acknowledged an outgoing message
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Rejected,
+    /// Gave up after exhausting delivery retries
+    Failed,
+}
+
+impl std::fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryStatus::Pending => write!(f, "pending"),
+            DeliveryStatus::Delivered => write!(f, "delivered"),
+            DeliveryStatus::Rejected => write!(f, "rejected"),
+            DeliveryStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl DeliveryStatus {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "pending" => DeliveryStatus::Pending,
+            "rejected" => DeliveryStatus::Rejected,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Delivered,
+        }
+    }
+}
+
+/// Outcome of checking a message's sender signature when it arrived
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    Valid,
+    Invalid,
+    /// Plain email, or mail we sent
+    #[default]
+    Unsigned,
+}
+
+impl std::fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureStatus::Valid => write!(f, "valid"),
+            SignatureStatus::Invalid => write!(f, "invalid"),
+            SignatureStatus::Unsigned => write!(f, "unsigned"),
+        }
+    }
+}
+
+impl SignatureStatus {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "valid" => SignatureStatus::Valid,
+            "invalid" => SignatureStatus::Invalid,
+            _ => SignatureStatus::Unsigned,
+        }
+    }
+}
+
+/// How a message body should be rendered
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentType {
+    #[default]
+    Text,
+    Html,
+}
+
+impl std::fmt::Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentType::Text => write!(f, "text"),
+            ContentType::Html => write!(f, "html"),
+        }
+    }
+}
+
+impl ContentType {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "html" => ContentType::Html,
+            _ => ContentType::Text,
+        }
+    }
+}
+
+/// Delivery mode preference
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
 pub enum DeliveryMode {
     P2pOnly,
     GmailOnly,
     Auto,
 }
 
+impl std::fmt::Display for DeliveryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryMode::P2pOnly => write!(f, "p2p_only"),
+            DeliveryMode::GmailOnly => write!(f, "gmail_only"),
+            DeliveryMode::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl DeliveryMode {
+    /// Parse a mode name, rejecting anything unknown
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "p2p_only" => Some(DeliveryMode::P2pOnly),
+            "gmail_only" => Some(DeliveryMode::GmailOnly),
+            "auto" => Some(DeliveryMode::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Whose P2P messages the node accepts
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceivePolicy {
+    /// Anyone not blocked
+    #[default]
+    Open,
+    /// Only Ledger IDs saved as contacts
+    ContactsOnly,
+}
+
+impl std::fmt::Display for ReceivePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReceivePolicy::Open => write!(f, "open"),
+            ReceivePolicy::ContactsOnly => write!(f, "contacts_only"),
+        }
+    }
+}
+
+impl ReceivePolicy {
+    /// Parse a policy name, rejecting anything unknown
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "open" => Some(ReceivePolicy::Open),
+            "contacts_only" => Some(ReceivePolicy::ContactsOnly),
+            _ => None,
+        }
+    }
+}
+
 /// A Ledger message
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
@@ -83,7 +250,63 @@ pub struct Message {
     pub is_read: bool,
     pub folder: Folder,
     pub signature: Option<String>,
+    #[serde(default)]
+    pub signature_status: SignatureStatus,
     pub encrypted: bool,
+    pub delivery_status: DeliveryStatus,
+    #[serde(default)]
+    pub content_type: ContentType,
+    #[serde(default)]
+    pub is_starred: bool,
+    /// `Message-ID` header of a fetched email, sent back as `in_reply_to` when replying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_message_id: Option<String>,
+    /// UID of a fetched email in the Gmail INBOX
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imap_uid: Option<u32>,
+    /// Attachment metadata; bytes are fetched separately
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// On received mail: the sender asked to be told when it is opened
+    #[serde(default)]
+    pub read_receipt_requested: bool,
+    /// On sent mail: who was asked for a read receipt and when they read it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read_receipts: Vec<ReadReceiptStatus>,
+    /// On fetched Gmail: how spam-like it looked; higher is worse
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spam_score: Option<u32>,
+    /// In the response to a send: how each recipient's copy went. Not stored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deliveries: Vec<RecipientDelivery>,
+}
+
+/// A recipient asked for a read receipt; `read_at` is set once theirs arrives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadReceiptStatus {
+    pub recipient: String,
+    pub read_at: Option<i64>,
+}
+
+/// How one recipient's copy of a sent message went out
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientMethod {
+    P2p,
+    /// Waiting in the DHT for the recipient to collect
+    Dht,
+    Fallback,
+    Gmail,
+    /// Waiting in the outbox for a retry
+    Queued,
+    Failed,
+}
+
+/// A recipient of a sent message and how their copy went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientDelivery {
+    pub recipient: String,
+    pub method: RecipientMethod,
 }
 
 impl Message {
@@ -99,18 +322,350 @@ impl Message {
             is_read: false,
             folder: Folder::Inbox,
             signature: None,
+            signature_status: SignatureStatus::Unsigned,
             encrypted: false,
+            delivery_status: DeliveryStatus::Delivered,
+            content_type: ContentType::Text,
+            is_starred: false,
+            email_message_id: None,
+            imap_uid: None,
+            attachments: vec![],
+            read_receipt_requested: false,
+            read_receipts: vec![],
+            spam_score: None,
+            deliveries: vec![],
+        }
+    }
+
+    /// Build an inbox message from a decrypted P2P envelope. Decryption has
+    /// already checked the signature, so it is recorded as valid.
+    pub fn from_envelope(env: &EncryptedEnvelope, to_id: String, opened: OpenedEnvelope) -> Self {
+        // A group message lists everyone it went to
+        let to_id = if env.recipients.is_empty() { to_id } else { env.to_ledger_id.clone() };
+        let attachments = opened.attachments.into_iter()
+            .filter_map(|reference| Attachment::from_ref(&env.id, reference))
+            .collect();
+        Self {
+            id: env.id.clone(),
+            from_id: env.from_ledger_id.clone(),
+            to_id,
+            subject: opened.subject,
+            body: opened.body,
+            timestamp: env.timestamp,
+            delivery_method: DeliveryMethod::P2p,
+            is_read: false,
+            folder: Folder::Inbox,
+            signature: Some(env.signature.clone()),
+            signature_status: SignatureStatus::Valid,
+            encrypted: true,
+            delivery_status: DeliveryStatus::Delivered,
+            content_type: env.content_type,
+            is_starred: false,
+            email_message_id: None,
+            imap_uid: None,
+            attachments,
+            read_receipt_requested: env.read_receipt_requested,
+            read_receipts: vec![],
+            spam_score: None,
+            deliveries: vec![],
         }
     }
 }
 
+/// A file attached to a message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub message_id: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i64,
+    /// Decoded file contents; only loaded for downloads
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    /// Hex SHA-256 of the contents, for attachments sent or received over P2P
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Key the contents are encrypted under, chunk by chunk, on the way over P2P
+    #[serde(skip)]
+    pub transfer_key: Option<Vec<u8>>,
+    /// Received over P2P and not yet fetched from the sender
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+}
+
+impl Attachment {
+    /// A file to send over P2P, hashed and given its own transfer key
+    pub fn outgoing(message_id: &str, filename: String, mime_type: String, data: Vec<u8>) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            message_id: message_id.to_string(),
+            filename,
+            mime_type,
+            size: data.len() as i64,
+            sha256: Some(attachment::content_hash(&data)),
+            transfer_key: Some(attachment::new_transfer_key().to_vec()),
+            data,
+            pending: false,
+        }
+    }
+
+    /// How an envelope refers to this attachment; `None` for one that can't
+    /// be fetched over P2P
+    pub fn to_ref(&self) -> Option<AttachmentRef> {
+        Some(AttachmentRef {
+            filename: self.filename.clone(),
+            mime_type: self.mime_type.clone(),
+            size: self.size as u64,
+            sha256: self.sha256.clone()?,
+            key: BASE64.encode(self.transfer_key.as_ref()?),
+        })
+    }
+
+    /// A not yet fetched attachment of received message `message_id`.
+    /// `None` if the reference's key is malformed, or it claims more than
+    /// `MAX_ATTACHMENT_BYTES`.
+    pub fn from_ref(message_id: &str, reference: AttachmentRef) -> Option<Self> {
+        if reference.size > crate::crypto::attachment::MAX_ATTACHMENT_BYTES {
+            tracing::warn!("Dropping {} byte attachment {:?} of {}: over the size limit", reference.size, reference.filename, message_id);
+            return None;
+        }
+        let key = BASE64.decode(&reference.key).ok().filter(|key| key.len() == 32)?;
+        Some(Self {
+            id: Uuid::new_v4().to_string(),
+            message_id: message_id.to_string(),
+            filename: reference.filename,
+            mime_type: reference.mime_type,
+            size: reference.size as i64,
+            data: vec![],
+            sha256: Some(reference.sha256),
+            transfer_key: Some(key),
+            // Nothing to fetch for an empty file
+            pending: reference.size > 0,
+        })
+    }
+}
+
+/// Part of an attachment's contents, read to serve a chunk request
+#[derive(Debug)]
+pub struct AttachmentChunk {
+    pub transfer_key: Vec<u8>,
+    /// Size of the whole attachment
+    pub size: u64,
+    pub data: Vec<u8>,
+}
+
+/// How a sealed envelope refers to an attachment, which the recipient
+/// fetches from the sender by its hash
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    pub filename: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// Hex SHA-256 of the contents
+    pub sha256: String,
+    /// Base64 key the chunks are encrypted under
+    pub key: String,
+}
+
 /// Request to send a message
 #[derive(Debug, Deserialize)]
 pub struct SendMessageRequest {
+    /// One address, or several; two or more Ledger IDs across `to` and `cc`
+    /// share a single group envelope
+    pub to: AddressList,
+    pub subject: String,
+    pub body: String,
+    /// "p2p_only", "gmail_only" or "auto"; the `delivery_mode` setting when omitted
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub content_type: ContentType,
+    #[serde(default)]
+    pub cc: Vec<String>,
+    /// Delivered to, but never listed in what the other recipients see
+    #[serde(default)]
+    pub bcc: Vec<String>,
+    /// Ask Ledger recipients to confirm when they open the message
+    #[serde(default)]
+    pub read_receipt: bool,
+    /// Files Ledger recipients fetch from this node over P2P; attached to
+    /// the email for Gmail recipients
+    #[serde(default)]
+    pub attachments: Vec<OutgoingAttachment>,
+}
+
+/// Which messages `Database::query_messages` returns; unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    /// Only this folder; everything but Trash when unset
+    pub folder: Option<String>,
+    pub starred_only: bool,
+    pub unread_only: bool,
+    /// Sender's Ledger ID or email address, matched case-insensitively. A
+    /// bare address also matches a `Name <address>` sender.
+    pub from: Option<String>,
+    /// Sent at or after this Unix time
+    pub after: Option<i64>,
+    /// Sent before this Unix time
+    pub before: Option<i64>,
+}
+
+impl MessageFilter {
+    /// Everything in `folder`
+    pub fn folder(folder: &str) -> Self {
+        Self { folder: Some(folder.to_string()), ..Default::default() }
+    }
+}
+
+/// Request to preview how a message would be delivered
+#[derive(Debug, Deserialize)]
+pub struct PreviewRequest {
+    pub to: String,
+    /// As for sending; the `delivery_mode` setting when omitted
+    pub mode: Option<String>,
+}
+
+/// A single address or a list of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum AddressList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl AddressList {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            AddressList::One(addr) => vec![addr.clone()],
+            AddressList::Many(addrs) => addrs.clone(),
+        }
+    }
+}
+
+/// What an outgoing message says, whoever it ends up addressed to
+#[derive(Debug, Clone, Default)]
+pub struct OutgoingContent {
+    pub subject: String,
+    pub body: String,
+    pub content_type: ContentType,
+    pub read_receipt_requested: bool,
+    /// Already sealed for several Ledger recipients; those it lists get this
+    /// envelope instead of one of their own
+    pub group: Option<EncryptedEnvelope>,
+    /// Seal the subject inside envelopes instead of sending it as `subject_hint`
+    pub encrypt_subject: bool,
+    /// Referenced from envelopes by hash, and attached to plain emails
+    pub attachments: Vec<Attachment>,
+}
+
+impl OutgoingContent {
+    pub fn text(subject: &str, body: &str) -> Self {
+        Self {
+            subject: subject.to_string(),
+            body: body.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Everyone an outgoing message is addressed to
+#[derive(Debug, Clone, Default)]
+pub struct Recipients {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+}
+
+impl Recipients {
+    /// A single `to` recipient with no CC or BCC
+    pub fn single(to: impl Into<String>) -> Self {
+        Self { to: vec![to.into()], ..Default::default() }
+    }
+
+    /// Every address in to, cc, bcc order, each listed once
+    pub fn all(&self) -> Vec<&str> {
+        let mut seen = std::collections::HashSet::new();
+        self.to.iter()
+            .chain(&self.cc)
+            .chain(&self.bcc)
+            .map(|addr| addr.trim())
+            .filter(|addr| !addr.is_empty() && seen.insert(*addr))
+            .collect()
+    }
+
+    /// Comma-joined list of every recipient, recorded as the Sent copy's `to_id`
+    pub fn joined(&self) -> String {
+        self.all().join(", ")
+    }
+}
+
+/// Contents of a draft; every field may be left blank while composing
+#[derive(Debug, Deserialize)]
+pub struct DraftRequest {
+    #[serde(default)]
     pub to: String,
+    #[serde(default)]
     pub subject: String,
+    #[serde(default)]
     pub body: String,
-    pub mode: Option<String>, // "p2p_only", "gmail_only", "auto"
+    #[serde(default)]
+    pub content_type: ContentType,
+}
+
+/// Request to mark a message read or unread
+#[derive(Debug, Deserialize)]
+pub struct SetReadRequest {
+    pub read: bool,
+}
+
+/// Request to file a message into another folder
+#[derive(Debug, Deserialize)]
+pub struct MoveMessageRequest {
+    pub folder: String,
+}
+
+/// Request to star or unstar a message
+#[derive(Debug, Deserialize)]
+pub struct SetStarredRequest {
+    pub starred: bool,
+}
+
+/// Request to mark a whole folder as read
+#[derive(Debug, Deserialize)]
+pub struct MarkAllReadRequest {
+    pub folder: String,
+}
+
+/// What to do to every message in a bulk request
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkAction {
+    /// Move to Trash, or delete outright with `permanent`
+    Delete,
+    /// File into `folder`
+    Move,
+}
+
+/// Request to delete or move many messages at once
+#[derive(Debug, Deserialize)]
+pub struct BulkMessagesRequest {
+    pub ids: Vec<String>,
+    pub action: BulkAction,
+    /// Where `move` files the messages
+    #[serde(default)]
+    pub folder: Option<String>,
+    /// Whether `delete` skips Trash
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+/// How a bulk delete or move went
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct BulkResult {
+    /// Messages changed; a soft delete skips those already in Trash
+    pub affected: usize,
+    /// Requested ids with no message
+    pub not_found: Vec<String>,
 }
 
 /// Request to connect to a peer
@@ -119,13 +674,52 @@ pub struct ConnectPeerRequest {
     pub multiaddr: String,
 }
 
-/// Gmail configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Gmail configuration. With OAuth2 tokens present, SMTP/IMAP authenticate
+/// via XOAUTH2; otherwise the app password is used.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GmailConfig {
     pub email: String,
+    #[serde(default)]
     pub app_password: String,
     pub imap_host: Option<String>,
     pub smtp_host: Option<String>,
+    /// OAuth2 client registered in Google Cloud, needed for the auth-code flow
+    #[serde(default)]
+    pub oauth_client_id: Option<String>,
+    #[serde(default)]
+    pub oauth_client_secret: Option<String>,
+    #[serde(default)]
+    pub access_token: Option<String>,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// Unix time the access token expires
+    #[serde(default)]
+    pub token_expiry: Option<i64>,
+    /// SOCKS5 proxy for IMAP/SMTP/OAuth connections (Tor); never set from the API
+    #[serde(skip)]
+    pub socks_proxy: Option<String>,
+    /// Messages a minute to send at most (0 = unlimited), from the `gmail_send_rate` setting
+    #[serde(skip)]
+    pub send_rate: u32,
+}
+
+/// How far a Gmail INBOX has been imported, so fetches only ask for newer
+/// mail. UIDs only mean anything within one account and `UIDVALIDITY`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImapCursor {
+    pub account: String,
+    pub uid_validity: u32,
+    /// Highest UID imported so far
+    pub last_uid: u32,
+}
+
+impl ImapCursor {
+    /// Start over if the mailbox is not the one this cursor was counting in
+    pub fn sync(&mut self, account: &str, uid_validity: u32) {
+        if self.account != account || self.uid_validity != uid_validity {
+            *self = ImapCursor { account: account.to_string(), uid_validity, last_uid: 0 };
+        }
+    }
 }
 
 /// Request to send Gmail
@@ -134,6 +728,27 @@ pub struct GmailSendRequest {
     pub to: String,
     pub subject: String,
     pub body: String,
+    #[serde(default)]
+    pub content_type: ContentType,
+    #[serde(default)]
+    pub attachments: Vec<OutgoingAttachment>,
+    /// `Message-ID` of the email being replied to, for threading
+    pub in_reply_to: Option<String>,
+    #[serde(default)]
+    pub cc: Vec<String>,
+    /// Delivered to, but left out of the message headers
+    #[serde(default)]
+    pub bcc: Vec<String>,
+}
+
+/// File to attach to an outgoing message
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutgoingAttachment {
+    pub filename: String,
+    /// Defaults to `application/octet-stream`
+    pub mime_type: Option<String>,
+    /// Base64-encoded file contents
+    pub data: String,
 }
 
 /// Settings update request
@@ -141,15 +756,156 @@ pub struct GmailSendRequest {
 pub struct Settings {
     pub delivery_mode: Option<String>,
     pub tor_enabled: Option<bool>,
+    /// SOCKS5 proxy address used when Tor is enabled
+    pub tor_socks_addr: Option<String>,
+    /// Publish the P2P node as an onion service when Tor is enabled; applied at startup
+    pub tor_onion_service: Option<bool>,
+    /// Tor control port used to create the onion service
+    pub tor_control_addr: Option<String>,
+    /// Control port password, if Tor uses `HashedControlPassword`
+    pub tor_control_password: Option<String>,
+    /// Circuit Relay v2 node (`/ip4/…/tcp/…/p2p/<id>`) used to reach peers behind NAT
+    pub relay_addr: Option<String>,
+    /// Comma-separated Kademlia bootstrap multiaddrs, used at startup
+    pub bootstrap_nodes: Option<String>,
     pub dht_ttl_hours: Option<u64>,
+    pub replay_window_hours: Option<u64>,
+    /// Largest envelope accepted from a peer; applied at startup
+    pub max_message_bytes: Option<u64>,
+    /// Inbound messages per minute allowed from one peer (0 = unlimited); applied at startup
+    pub peer_rate_limit: Option<u32>,
+    /// Bearer token required on the REST API (empty disables); applied at startup
+    pub api_token: Option<String>,
+    /// Answer senders' read receipt requests when a message is opened
+    pub send_read_receipts: Option<bool>,
+    /// `open` or `contacts_only`
+    pub receive_policy: Option<String>,
+    /// Compact the database once a day
+    pub nightly_vacuum: Option<bool>,
+    /// Name put on this node's contact card
+    pub display_name: Option<String>,
+    /// Messages a minute sent through Gmail at most (0 = unlimited)
+    pub gmail_send_rate: Option<u32>,
+    /// Days a rotated-away identity's key keeps decrypting mail
+    pub retired_key_days: Option<u32>,
+    /// Seal subjects inside envelopes rather than sending them in the clear
+    pub encrypt_subject: Option<bool>,
+    /// Spam score at which fetched Gmail goes to Spam (0 = never)
+    pub spam_threshold: Option<u32>,
+    /// Flag imported Gmail `\Seen` on the server
+    pub gmail_mark_seen: Option<bool>,
+}
+
+/// Which side opened a peer connection
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionDirection {
+    Inbound,
+    Outbound,
 }
 
 /// Peer info
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub peer_id: String,
+    /// Remote address of the peer's first open connection
     pub address: String,
+    /// From the peer directory, once the peer has identified or announced itself
     pub ledger_id: Option<String>,
+    pub direction: ConnectionDirection,
+    /// Seconds since the first open connection was established
+    pub connected_secs: u64,
+}
+
+/// Whether peers outside our network can dial us, as AutoNAT last found
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NatStatus {
+    /// Peers dialled us back on one of our addresses
+    Public,
+    /// Dial-backs failed: only relays and the DHT reach us
+    Private,
+    /// Not enough probes answered yet
+    #[default]
+    Unknown,
+}
+
+impl From<&libp2p::autonat::NatStatus> for NatStatus {
+    fn from(status: &libp2p::autonat::NatStatus) -> Self {
+        match status {
+            libp2p::autonat::NatStatus::Public(_) => NatStatus::Public,
+            libp2p::autonat::NatStatus::Private => NatStatus::Private,
+            libp2p::autonat::NatStatus::Unknown => NatStatus::Unknown,
+        }
+    }
+}
+
+/// Lightweight swarm summary for health checks
+#[derive(Debug, Serialize)]
+pub struct NodeStatus {
+    pub listeners: Vec<String>,
+    pub connected_peers: usize,
+    pub nat_status: NatStatus,
+    /// The address peers reached us on, when `nat_status` is public
+    pub public_address: Option<String>,
+}
+
+/// A Ledger ID → PeerId mapping learned from the network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerMapping {
+    pub ledger_id: String,
+    pub peer_id: String,
+    pub multiaddr: String,
+    pub last_seen: i64,
+}
+
+/// An encrypted envelope waiting to be retried after every delivery method failed
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxEntry {
+    /// Envelope id
+    pub id: String,
+    /// Sent copy whose delivery status the retry updates
+    pub message_id: String,
+    pub recipient: String,
+    #[serde(skip)]
+    pub envelope_json: String,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+/// An envelope this node put in a recipient's DHT mailbox and keeps republishing
+#[derive(Debug, Clone)]
+pub struct DhtEnvelope {
+    pub envelope_id: String,
+    /// Sent copy marked delivered once the recipient acknowledges retrieval
+    pub message_id: String,
+    pub recipient: String,
+    pub envelope_json: String,
+    pub stored_at: i64,
+    pub published_at: i64,
+}
+
+/// Request to publish a public bulletin
+#[derive(Debug, Deserialize)]
+pub struct BroadcastRequest {
+    pub subject: String,
+    pub body: String,
+}
+
+/// Request to block a Ledger ID
+#[derive(Debug, Deserialize)]
+pub struct BlockRequest {
+    pub ledger_id: String,
+}
+
+/// A blocked Ledger ID and the PeerId its key maps to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockedPeer {
+    pub ledger_id: String,
+    pub peer_id: String,
+    pub blocked_at: i64,
 }
 
 /// Identity info
@@ -157,12 +913,40 @@ pub struct PeerInfo {
 pub struct IdentityInfo {
     pub ledger_id: String,
     pub public_key: String,
+    /// Base64 X25519 key, for contacts to encrypt to
+    pub encryption_public_key: String,
     pub peer_id: String,
 }
 
+/// Request to restore an identity from its recovery phrase
+#[derive(Debug, Deserialize)]
+pub struct RecoverIdentityRequest {
+    pub mnemonic: String,
+    pub passphrase: Option<String>,
+}
+
+/// Request to replace the identity with a newly generated one
+#[derive(Debug, Deserialize)]
+pub struct RotateIdentityRequest {
+    pub passphrase: Option<String>,
+}
+
+/// Request to replace the identity with one exported from another device
+#[derive(Debug, Deserialize)]
+pub struct ImportIdentityRequest {
+    pub blob: crate::crypto::keys::IdentityExport,
+    /// The passphrase the export was sealed with
+    pub passphrase: String,
+    /// Encrypts the new `identity.key` at rest; defaults to the node's passphrase
+    pub key_passphrase: Option<String>,
+}
+
 /// Encrypted envelope for P2P transport
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedEnvelope {
+    /// Signing/AAD scheme; absent on envelopes from before versioning
+    #[serde(default)]
+    pub version: u8,
     pub id: String,
     pub from_ledger_id: String,
     pub to_ledger_id: String,
@@ -172,6 +956,23 @@ pub struct EncryptedEnvelope {
     pub signature: String,
     pub timestamp: i64,
     pub subject_hint: String,
+    /// Signed from version 2 on; older envelopes are plain text
+    #[serde(default)]
+    pub content_type: ContentType,
+    /// Signed from version 3 on
+    #[serde(default)]
+    pub read_receipt_requested: bool,
+    /// Group envelopes only: the body's content key wrapped for each recipient
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<WrappedKey>,
+}
+
+/// A group envelope's content key, encrypted to one recipient
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WrappedKey {
+    pub ledger_id: String,
+    /// Base64 of the nonce followed by the encrypted key
+    pub wrapped_key: String,
 }
 
 /// Contact entry
@@ -179,8 +980,102 @@ pub struct EncryptedEnvelope {
 pub struct Contact {
     pub ledger_id: String,
     pub public_key: String,
+    /// Base64 X25519 key messages to this contact are encrypted to
+    #[serde(default)]
+    pub encryption_public_key: Option<String>,
     pub display_name: Option<String>,
     pub gmail_address: Option<String>,
+    /// The user compared safety numbers with this contact and they matched
+    #[serde(default)]
+    pub verified: bool,
+    /// Unique name to send to instead of the Ledger ID, e.g. `@alice`
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// How to deliver to this contact, in place of the `delivery_mode`
+    /// setting; a mode given with a send still wins. `None` follows the setting.
+    #[serde(default)]
+    pub preferred_delivery: Option<DeliveryMode>,
+}
+
+/// Longest alias accepted, not counting the `@`
+const MAX_ALIAS_LEN: usize = 32;
+
+impl Contact {
+    /// An alias in its stored form: lowercase with a leading `@`. `None` if it
+    /// is empty, too long, or has characters other than letters, digits, `.`,
+    /// `_` and `-`.
+    pub fn normalize_alias(alias: &str) -> Option<String> {
+        let name = alias.trim();
+        let name = name.strip_prefix('@').unwrap_or(name).to_lowercase();
+        let valid = !name.is_empty()
+            && name.len() <= MAX_ALIAS_LEN
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+        valid.then(|| format!("@{}", name))
+    }
+}
+
+/// Request to add a contact. For a Ledger ID only `ledger_id` is needed:
+/// both public keys are derived from it when omitted.
+#[derive(Debug, Deserialize)]
+pub struct AddContactRequest {
+    pub ledger_id: String,
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub encryption_public_key: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub gmail_address: Option<String>,
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// "auto", "p2p_only" or "gmail_only"; omitted follows the `delivery_mode` setting
+    #[serde(default)]
+    pub preferred_delivery: Option<String>,
+}
+
+/// A contact card to import, as JSON or in its `ledger-card:` compact form
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ImportCardRequest {
+    Compact { card: String },
+    Card(crate::crypto::card::ContactCard),
+}
+
+/// Partial contact update; omitted fields are left unchanged, empty strings clear them
+#[derive(Debug, Deserialize)]
+pub struct UpdateContactRequest {
+    pub display_name: Option<String>,
+    pub gmail_address: Option<String>,
+    pub verified: Option<bool>,
+    pub alias: Option<String>,
+    /// A delivery mode, or an empty string to go back to the `delivery_mode` setting
+    pub preferred_delivery: Option<String>,
+}
+
+/// Real-time event pushed to WebSocket clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageEvent {
+    /// An inbound message was stored
+    NewMessage { id: String },
+    /// A recipient's read receipt arrived for a sent message
+    MessageRead { id: String, reader: String },
+    /// A contact came online (announced itself) or went offline (its
+    /// connection closed, or it stopped announcing)
+    Presence { ledger_id: String, online: bool },
+    /// The first connection to a peer opened; `ledger_id` if we know whose node it is
+    PeerConnected {
+        peer_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ledger_id: Option<String>,
+    },
+    /// The last connection to a peer closed
+    PeerDisconnected {
+        peer_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ledger_id: Option<String>,
+    },
 }
 
 /// Generic API response wrapper
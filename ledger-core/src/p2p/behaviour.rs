@@ -1,35 +1,49 @@
 use libp2p::{
-    gossipsub, identify, kad, mdns,
+    autonat, dcutr, gossipsub, identify, kad, mdns, relay,
     request_response::{self, ProtocolSupport},
-    swarm::NetworkBehaviour,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
 };
 
-use super::protocol::{LedgerRequest, LedgerResponse, PROTOCOL_NAME};
+use super::codec::{FileCodec, LedgerCodec};
+use super::protocol::{agent_version, FileRequest, FileResponse, LedgerRequest, LedgerResponse, Protocols};
 
 /// Ledger's composite network behaviour
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "LedgerBehaviourEvent")]
 pub struct LedgerBehaviour {
     /// Direct message delivery
-    pub request_response: request_response::cbor::Behaviour<LedgerRequest, LedgerResponse>,
+    pub request_response: request_response::Behaviour<LedgerCodec>,
+    /// Attachments fetched chunk by chunk, apart from the message size limit
+    pub file_transfer: request_response::Behaviour<FileCodec>,
     /// Pub/sub for announcements
     pub gossipsub: gossipsub::Behaviour,
     /// DHT for offline message storage & peer discovery
     pub kademlia: kad::Behaviour<kad::store::MemoryStore>,
-    /// Local peer discovery
-    pub mdns: mdns::tokio::Behaviour,
+    /// Local peer discovery; off behind Tor, since it announces our LAN address
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     /// Peer identification
     pub identify: identify::Behaviour,
+    /// Reach peers behind NAT through a relay's circuit
+    pub relay_client: relay::client::Behaviour,
+    /// Upgrade relayed connections to direct ones by hole punching; off behind Tor
+    pub dcutr: Toggle<dcutr::Behaviour>,
+    /// Learn whether we're reachable from outside by asking peers to dial us
+    /// back; off behind Tor, where it would confirm our real address
+    pub autonat: Toggle<autonat::Behaviour>,
 }
 
 /// Combined events from all sub-behaviours
 #[derive(Debug)]
 pub enum LedgerBehaviourEvent {
     RequestResponse(request_response::Event<LedgerRequest, LedgerResponse>),
+    FileTransfer(request_response::Event<FileRequest, FileResponse>),
     Gossipsub(gossipsub::Event),
     Kademlia(kad::Event),
     Mdns(mdns::Event),
     Identify(identify::Event),
+    RelayClient(relay::client::Event),
+    Dcutr(dcutr::Event),
+    Autonat(autonat::Event),
 }
 
 impl From<request_response::Event<LedgerRequest, LedgerResponse>> for LedgerBehaviourEvent {
@@ -38,6 +52,12 @@ impl From<request_response::Event<LedgerRequest, LedgerResponse>> for LedgerBeha
     }
 }
 
+impl From<request_response::Event<FileRequest, FileResponse>> for LedgerBehaviourEvent {
+    fn from(e: request_response::Event<FileRequest, FileResponse>) -> Self {
+        LedgerBehaviourEvent::FileTransfer(e)
+    }
+}
+
 impl From<gossipsub::Event> for LedgerBehaviourEvent {
     fn from(e: gossipsub::Event) -> Self {
         LedgerBehaviourEvent::Gossipsub(e)
@@ -62,14 +82,44 @@ impl From<identify::Event> for LedgerBehaviourEvent {
     }
 }
 
+impl From<relay::client::Event> for LedgerBehaviourEvent {
+    fn from(e: relay::client::Event) -> Self {
+        LedgerBehaviourEvent::RelayClient(e)
+    }
+}
+
+impl From<dcutr::Event> for LedgerBehaviourEvent {
+    fn from(e: dcutr::Event) -> Self {
+        LedgerBehaviourEvent::Dcutr(e)
+    }
+}
+
+impl From<autonat::Event> for LedgerBehaviourEvent {
+    fn from(e: autonat::Event) -> Self {
+        LedgerBehaviourEvent::Autonat(e)
+    }
+}
+
 impl LedgerBehaviour {
     pub fn new(
         local_peer_id: libp2p::PeerId,
         keypair: &libp2p::identity::Keypair,
+        ledger_id: &str,
+        relay_client: relay::client::Behaviour,
+        max_message_bytes: u64,
+        protocols: &Protocols,
+        tor: bool,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        // Request-Response for direct messaging
-        let request_response = request_response::cbor::Behaviour::new(
-            [(PROTOCOL_NAME, ProtocolSupport::Full)],
+        // Request-Response for direct messaging, capped at `max_message_bytes` per request
+        let request_response = request_response::Behaviour::with_codec(
+            LedgerCodec::new(max_message_bytes),
+            [(protocols.messaging.clone(), ProtocolSupport::Full)],
+            request_response::Config::default(),
+        );
+
+        let file_transfer = request_response::Behaviour::with_codec(
+            FileCodec,
+            [(protocols.files.clone(), ProtocolSupport::Full)],
             request_response::Config::default(),
         );
 
@@ -85,29 +135,55 @@ impl LedgerBehaviour {
             gossipsub_config,
         ).map_err(|e| format!("Gossipsub error: {}", e))?;
 
-        // Kademlia DHT
+        // Kademlia DHT, kept apart from other networks' by its protocol name
         let store = kad::store::MemoryStore::new(local_peer_id);
-        let mut kademlia = kad::Behaviour::new(local_peer_id, store);
+        let mut kad_config = kad::Config::default();
+        kad_config.set_protocol_names(vec![protocols.kademlia.clone()]);
+        let mut kademlia = kad::Behaviour::with_config(local_peer_id, store, kad_config);
         kademlia.set_mode(Some(kad::Mode::Server));
 
         // mDNS for local discovery
-        let mdns = mdns::tokio::Behaviour::new(
-            mdns::Config::default(),
-            local_peer_id,
-        )?;
+        let mdns = match tor {
+            true => None,
+            false => Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?),
+        };
 
-        // Identify protocol
-        let identify = identify::Behaviour::new(identify::Config::new(
-            "/ledger/id/1.0.0".to_string(),
-            keypair.public(),
-        ));
+        // Identify protocol, advertising our Ledger ID in the agent version
+        let identify = identify::Behaviour::new(
+            identify::Config::new(protocols.identify.clone(), keypair.public())
+                .with_agent_version(agent_version(ledger_id)),
+        );
 
         Ok(Self {
             request_response,
+            file_transfer,
             gossipsub,
             kademlia,
-            mdns,
+            mdns: mdns.into(),
             identify,
+            relay_client,
+            dcutr: (!tor).then(|| dcutr::Behaviour::new(local_peer_id)).into(),
+            autonat: (!tor).then(|| autonat::Behaviour::new(local_peer_id, autonat::Config::default())).into(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_address_revealing_behaviours_off_behind_tor() {
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let build = |tor| {
+            let (_, relay_client) = relay::client::new(peer_id);
+            LedgerBehaviour::new(peer_id, &keypair, "ledger:me", relay_client, 1024, &Protocols::default(), tor).unwrap()
+        };
+
+        let direct = build(false);
+        assert!(direct.mdns.is_enabled() && direct.autonat.is_enabled() && direct.dcutr.is_enabled());
+        let tor = build(true);
+        assert!(!tor.mdns.is_enabled() && !tor.autonat.is_enabled() && !tor.dcutr.is_enabled());
+    }
+}
@@ -0,0 +1,113 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::presence::ANNOUNCE_TOPIC;
+use crate::crypto::keys::LedgerIdentity;
+use crate::models::message::*;
+
+/// Bulletins older than this are not stored, so a replayed one can't resurface
+/// after its copy was deleted
+const MAX_BULLETIN_AGE_SECS: i64 = 24 * 60 * 60;
+
+/// How far ahead of our clock a bulletin timestamp may be
+const MAX_FUTURE_SKEW_SECS: i64 = 5 * 60;
+
+/// Signed public post that every node on the announce topic receives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bulletin {
+    pub id: String,
+    /// Ledger ID of the author, whose key signs the bulletin
+    pub ledger_id: String,
+    pub subject: String,
+    pub body: String,
+    pub timestamp: i64,
+    /// Base64 Ed25519 signature by the author's key over every other field
+    pub signature: String,
+}
+
+impl Bulletin {
+    /// Build and sign a bulletin from this node's identity
+    pub fn new(identity: &LedgerIdentity, subject: &str, body: &str) -> Self {
+        let mut bulletin = Self {
+            id: Uuid::new_v4().to_string(),
+            ledger_id: identity.ledger_id.clone(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            signature: String::new(),
+        };
+        bulletin.signature = BASE64.encode(identity.sign(&bulletin.signing_payload()));
+        bulletin
+    }
+
+    /// Check the bulletin is recent and signed by its author's key
+    pub fn verify(&self, now: i64) -> Result<(), String> {
+        if self.timestamp > now + MAX_FUTURE_SKEW_SECS {
+            return Err("timestamp in the future".into());
+        }
+        if self.timestamp < now - MAX_BULLETIN_AGE_SECS {
+            return Err("stale timestamp".into());
+        }
+
+        let pubkey = LedgerIdentity::pubkey_from_ledger_id(&self.ledger_id)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        let signature = BASE64.decode(&self.signature)
+            .map_err(|e| format!("invalid signature encoding: {}", e))?;
+        match LedgerIdentity::verify(&pubkey, &self.signing_payload(), &signature) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("bad signature".into()),
+            Err(e) => Err(format!("bad signature: {}", e)),
+        }
+    }
+
+    /// The bulletin as a message in the Broadcast folder
+    pub fn to_message(&self) -> Message {
+        let mut msg = Message::new(
+            self.ledger_id.clone(),
+            ANNOUNCE_TOPIC.to_string(),
+            self.subject.clone(),
+            self.body.clone(),
+        );
+        msg.id = self.id.clone();
+        msg.timestamp = self.timestamp;
+        msg.folder = Folder::Broadcast;
+        msg.signature = Some(self.signature.clone());
+        msg.signature_status = SignatureStatus::Valid;
+        msg
+    }
+
+    /// Domain tag followed by each field length-prefixed, as for envelopes
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut out = b"ledger-bulletin".to_vec();
+        for field in [&self.id, &self.ledger_id, &self.subject, &self.body] {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        }
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bulletin_verifies_and_rejects_tampering() {
+        let author = LedgerIdentity::generate().unwrap();
+        let bulletin = Bulletin::new(&author, "Hello", "Posting to everyone");
+        assert!(bulletin.verify(bulletin.timestamp).is_ok());
+        assert!(bulletin.verify(bulletin.timestamp + MAX_BULLETIN_AGE_SECS + 1).is_err());
+
+        let mut edited = bulletin.clone();
+        edited.body = "Something else".into();
+        assert!(edited.verify(edited.timestamp).is_err());
+
+        // Someone else can't claim to be the author
+        let other = LedgerIdentity::generate().unwrap();
+        let mut forged = bulletin;
+        forged.ledger_id = other.ledger_id;
+        assert!(forged.verify(forged.timestamp).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{request_response, StreamProtocol};
+use std::io;
+
+use super::protocol::{FileRequest, FileResponse, LedgerRequest, LedgerResponse};
+use crate::crypto::attachment::CHUNK_SIZE;
+
+/// Default cap on an inbound request, overridable with the `max_message_bytes` setting
+pub const DEFAULT_MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+/// Largest `max_message_bytes` allowed, so the limit still bounds memory
+pub const MAX_MESSAGE_BYTES_CEILING: u64 = 64 * 1024 * 1024;
+
+/// Responses are a flag and a short error string
+const RESPONSE_SIZE_MAXIMUM: u64 = 64 * 1024;
+
+/// CBOR framing around `envelope_json`, so an envelope of exactly the limit still fits
+const REQUEST_FRAMING_BYTES: u64 = 64;
+
+/// A chunk request is a hash and an index
+const FILE_REQUEST_SIZE_MAXIMUM: u64 = 1024;
+
+/// A base64 chunk with its nonce and tag, plus framing
+const FILE_RESPONSE_SIZE_MAXIMUM: u64 = (CHUNK_SIZE as u64 + 1024) * 4 / 3;
+
+/// CBOR codec for `/ledger/msg/1.0.0`, wire-compatible with libp2p's `cbor`
+/// codec but with a configurable request size limit
+#[derive(Debug, Clone)]
+pub struct LedgerCodec {
+    max_request_bytes: u64,
+}
+
+impl LedgerCodec {
+    pub fn new(max_request_bytes: u64) -> Self {
+        Self { max_request_bytes }
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for LedgerCodec {
+    type Protocol = StreamProtocol;
+    type Request = LedgerRequest;
+    type Response = LedgerResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<LedgerRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        // Read one byte past the limit so an oversized request is detected
+        // without buffering the rest of it
+        let data = read_limited(io, self.max_request_bytes.saturating_add(REQUEST_FRAMING_BYTES)).await?;
+        decode(&data)
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<LedgerResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_limited(io, RESPONSE_SIZE_MAXIMUM).await?;
+        decode(&data)
+    }
+
+    async fn write_request<T>(&mut self, _: &Self::Protocol, io: &mut T, req: LedgerRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&encode(&req)?).await
+    }
+
+    async fn write_response<T>(&mut self, _: &Self::Protocol, io: &mut T, resp: LedgerResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&encode(&resp)?).await
+    }
+}
+
+/// CBOR codec for `/ledger/file/1.0.0`, sized for one chunk per response
+#[derive(Debug, Clone, Default)]
+pub struct FileCodec;
+
+#[async_trait]
+impl request_response::Codec for FileCodec {
+    type Protocol = StreamProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(&read_limited(io, FILE_REQUEST_SIZE_MAXIMUM).await?)
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(&read_limited(io, FILE_RESPONSE_SIZE_MAXIMUM).await?)
+    }
+
+    async fn write_request<T>(&mut self, _: &Self::Protocol, io: &mut T, req: FileRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&encode(&req)?).await
+    }
+
+    async fn write_response<T>(&mut self, _: &Self::Protocol, io: &mut T, resp: FileResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&encode(&resp)?).await
+    }
+}
+
+/// Read the whole stream, failing once more than `limit` bytes have arrived
+async fn read_limited<T>(io: &mut T, limit: u64) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut data = Vec::new();
+    io.take(limit.saturating_add(1)).read_to_end(&mut data).await?;
+    if data.len() as u64 > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message exceeds the {} byte limit", limit),
+        ));
+    }
+    Ok(data)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(data: &[u8]) -> io::Result<T> {
+    cbor4ii::serde::from_slice(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    cbor4ii::serde::to_vec(Vec::new(), value).map_err(|e| io::Error::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use request_response::Codec;
+
+    const PROTOCOL: StreamProtocol = StreamProtocol::new("/test/1");
+
+    fn request(len: usize) -> Vec<u8> {
+        encode(&LedgerRequest::Envelope { envelope_json: "x".repeat(len) }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_within_limit_roundtrips() {
+        let mut codec = LedgerCodec::new(1024);
+        let data = request(1024);
+        let req = codec.read_request(&PROTOCOL, &mut futures::io::Cursor::new(data)).await;
+        assert!(matches!(req.unwrap(), LedgerRequest::Envelope { envelope_json } if envelope_json.len() == 1024));
+    }
+
+    #[tokio::test]
+    async fn test_receipt_request_roundtrips() {
+        let identity = crate::crypto::keys::LedgerIdentity::generate().unwrap();
+        let receipt = crate::p2p::receipt::ReadReceipt::new(&identity, "env-1", "ledger:sender");
+        let data = encode(&LedgerRequest::Receipt { receipt }).unwrap();
+        let mut codec = LedgerCodec::new(1024);
+        let req = codec.read_request(&PROTOCOL, &mut futures::io::Cursor::new(data)).await.unwrap();
+        assert!(matches!(req, LedgerRequest::Receipt { receipt } if receipt.verify().is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_full_chunk_response_fits() {
+        let key = crate::crypto::attachment::new_transfer_key();
+        let sealed = crate::crypto::attachment::seal_chunk(&key, "hash", 0, &vec![7u8; CHUNK_SIZE]).unwrap();
+        let response = FileResponse::Chunk { data: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, sealed), chunks: 1 };
+        let data = encode(&response).unwrap();
+        let read = FileCodec.read_response(&PROTOCOL, &mut futures::io::Cursor::new(data)).await.unwrap();
+        assert!(matches!(read, FileResponse::Chunk { chunks: 1, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_huge_limit_does_not_overflow() {
+        let mut codec = LedgerCodec::new(u64::MAX);
+        let req = codec.read_request(&PROTOCOL, &mut futures::io::Cursor::new(request(16))).await;
+        assert!(matches!(req.unwrap(), LedgerRequest::Envelope { envelope_json } if envelope_json.len() == 16));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_rejected() {
+        let mut codec = LedgerCodec::new(1024);
+        let data = request(1024 + REQUEST_FRAMING_BYTES as usize);
+        let err = codec.read_request(&PROTOCOL, &mut futures::io::Cursor::new(data)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
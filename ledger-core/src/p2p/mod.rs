@@ -1,3 +1,10 @@
 pub mod node;
 pub mod behaviour;
 pub mod protocol;
+pub mod codec;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod presence;
+pub mod receipt;
+pub mod bulletin;
+pub mod transfer;
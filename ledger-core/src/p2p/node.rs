@@ -1,38 +1,88 @@
 use libp2p::{
     futures::StreamExt,
-    identity::Keypair,
+    kad::{GetRecordError, GetRecordOk, QueryId, QueryResult},
     noise, tcp, yamux,
-    swarm::SwarmEvent,
-    Multiaddr, PeerId, Swarm,
+    request_response::OutboundRequestId,
+    multiaddr::Protocol,
+    core::ConnectedPoint,
+    swarm::{dial_opts::{DialOpts, PeerCondition}, ConnectionError, ConnectionId, SwarmEvent},
+    Multiaddr, PeerId, Swarm, Transport,
 };
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
 
 use super::behaviour::{LedgerBehaviour, LedgerBehaviourEvent};
-use super::protocol::{LedgerRequest, LedgerResponse};
+use super::bulletin::Bulletin;
+use super::codec::{DEFAULT_MAX_MESSAGE_BYTES, MAX_MESSAGE_BYTES_CEILING};
+use super::presence::{OnlineContacts, Presence, PRESENCE_INTERVAL};
+use super::rate_limit::{RateLimiter, DEFAULT_PEER_RATE_LIMIT};
+use super::reconnect::Reconnects;
+use super::transfer::{self, Download};
+use super::protocol::{ledger_id_from_agent_version, Announcement, LedgerRequest, LedgerResponse, PeerNetwork, Protocols};
+use super::receipt::ReadReceipt;
 use crate::crypto::envelope;
+use crate::crypto::key_cache::VerifyingKeyCache;
 use crate::crypto::keys::LedgerIdentity;
+use crate::metrics::Metrics;
 use crate::models::message::*;
 use crate::store::db::Database;
+use crate::tor::{self, transport::Socks5Transport, TorConfig};
 
 /// Commands that can be sent to the P2P node from the REST API
 #[derive(Debug)]
 pub enum P2PCommand {
-    /// Send a message to a peer
+    /// Send a message to a peer; answered once the peer acknowledges it
     SendMessage {
         peer_id: PeerId,
+        /// Local message the envelope was built from, updated when the peer replies
+        message_id: String,
         envelope_json: String,
         response_tx: mpsc::Sender<Result<(), String>>,
     },
+    /// Fetch every chunk of an attachment from a peer; answered with the
+    /// contents once they match their hash
+    FetchAttachment {
+        peer_id: PeerId,
+        download: Download,
+        response_tx: mpsc::Sender<Result<Vec<u8>, String>>,
+    },
+    /// Tell a sender we read their message; nobody waits on the reply
+    SendReceipt {
+        peer_id: PeerId,
+        receipt: ReadReceipt,
+    },
     /// Connect to a peer by multiaddr
     ConnectPeer {
         addr: Multiaddr,
         response_tx: mpsc::Sender<Result<PeerId, String>>,
     },
+    /// Dial a known peer, optionally at the given addresses (e.g. a relay circuit)
+    DialPeer {
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+        response_tx: mpsc::Sender<Result<PeerId, String>>,
+    },
+    /// Close every connection to a peer (e.g. one that was just blocked);
+    /// answered with whether it was connected
+    DisconnectPeer {
+        peer_id: PeerId,
+        response_tx: Option<mpsc::Sender<bool>>,
+    },
+    /// Publish a signed bulletin on the announce topic
+    PublishBulletin {
+        bulletin: Bulletin,
+        response_tx: mpsc::Sender<Result<(), String>>,
+    },
     /// Get connected peers
     GetPeers {
         response_tx: mpsc::Sender<Vec<PeerInfo>>,
     },
+    /// Listener addresses and connected peer count, without the peer list
+    GetStatus {
+        response_tx: mpsc::Sender<NodeStatus>,
+    },
     /// Store in DHT
     DhtPut {
         key: Vec<u8>,
@@ -44,41 +94,286 @@ pub enum P2PCommand {
         key: Vec<u8>,
         response_tx: mpsc::Sender<Result<Option<Vec<u8>>, String>>,
     },
+    /// Retrieve every distinct value the nodes closest to `key` hold,
+    /// answered once the query has finished
+    DhtGetAll {
+        key: Vec<u8>,
+        response_tx: mpsc::Sender<Result<Vec<Vec<u8>>, String>>,
+    },
+    /// Republish our peer record, wait for pending DHT puts, close every
+    /// connection and stop the node; answered once the event loop has finished
+    Shutdown {
+        response_tx: mpsc::Sender<()>,
+    },
+}
+
+/// How long a `ConnectPeer`/`DialPeer` dial may take before the caller gets an error
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Who is waiting on a Kademlia `get_record` query
+enum DhtGetResponder {
+    /// A `DhtGet`, answered with the first record found
+    First(mpsc::Sender<Result<Option<Vec<u8>>, String>>),
+    /// A `DhtGetAll`, with the distinct values found so far
+    All(Vec<Vec<u8>>, mpsc::Sender<Result<Vec<Vec<u8>>, String>>),
+}
+
+/// Who is waiting on an attachment download
+type FetchResponder = mpsc::Sender<Result<Vec<u8>, String>>;
+
+/// How often we re-publish our Ledger ID → PeerId record in the DHT
+const PEER_RECORD_REPUBLISH: Duration = Duration::from_secs(30 * 60);
+
+/// How long shutdown keeps the swarm running to finish the final republish and close connections
+const SHUTDOWN_FLUSH: Duration = Duration::from_secs(5);
+
+/// Known peer addresses not seen for this long are forgotten at startup
+const KNOWN_PEER_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How far ahead of our clock an envelope timestamp may be
+const MAX_FUTURE_SKEW_SECS: i64 = 5 * 60;
+
+/// Oldest envelope accepted when `replay_window_hours` is unset
+const DEFAULT_REPLAY_WINDOW_HOURS: i64 = 72;
+
+/// Longest `replay_window_hours` the settings API accepts
+pub const MAX_REPLAY_WINDOW_HOURS: u64 = 24 * 365;
+
+/// State owned by the swarm event loop for requests that resolve on later swarm events
+#[derive(Default)]
+struct NodeState {
+    /// Outbound dials waiting for `ConnectionEstablished`, with their deadline
+    pending_dials: HashMap<ConnectionId, (Instant, mpsc::Sender<Result<PeerId, String>>)>,
+    /// Outstanding Kademlia `get_record` queries and who is waiting on them
+    pending_dht_gets: HashMap<QueryId, DhtGetResponder>,
+    /// `DhtPut` records still being replicated to other peers, which shutdown waits for
+    pending_dht_puts: HashSet<QueryId>,
+    /// Outgoing messages awaiting the peer's `LedgerResponse`, by message id
+    pending_sends: HashMap<OutboundRequestId, (String, mpsc::Sender<Result<(), String>>)>,
+    /// Attachment downloads waiting on their next chunk
+    pending_fetches: HashMap<OutboundRequestId, (Download, FetchResponder)>,
+    /// Largest envelope we accept from, or send to, a peer
+    max_message_bytes: usize,
+    /// Per-peer budget for inbound messages
+    rate_limiter: RateLimiter,
+    /// Per-peer budget for attachment chunk requests
+    chunk_rate_limiter: RateLimiter,
+    /// How each connected peer's first open connection was made
+    connections: HashMap<PeerId, PeerConnection>,
+    /// Known peers and contacts to redial after their connection dropped
+    reconnects: Reconnects,
+    /// Contacts currently online, shared with the API
+    online: Arc<OnlineContacts>,
+    /// Protocol names and gossip topic of the network we joined
+    protocols: Protocols,
+    /// Parsed keys of recent senders, to check envelope signatures
+    verifying_keys: VerifyingKeyCache,
+    /// Dial peers' onion addresses first, which Tor reaches without an exit
+    prefer_onion: bool,
+}
+
+/// Details of a peer's first open connection, reported by `GetPeers`
+struct PeerConnection {
+    address: Multiaddr,
+    direction: ConnectionDirection,
+    since: Instant,
+}
+
+impl PeerConnection {
+    fn new(endpoint: &ConnectedPoint) -> Self {
+        let direction = if endpoint.is_dialer() {
+            ConnectionDirection::Outbound
+        } else {
+            ConnectionDirection::Inbound
+        };
+        Self {
+            address: endpoint.get_remote_address().clone(),
+            direction,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl NodeState {
+    /// Fail any pending dials whose deadline has passed
+    async fn expire_dials(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<ConnectionId> = self.pending_dials.iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            if let Some((_, response_tx)) = self.pending_dials.remove(&id) {
+                tracing::warn!("Dial {:?} timed out", id);
+                let _ = response_tx.send(Err("Dial timed out".into())).await;
+            }
+        }
+    }
+}
+
+/// What the node runs with besides its identity and database
+pub struct NodeConfig {
+    pub p2p_port: u16,
+    /// With Tor, outbound dials go through its SOCKS5 proxy, one address at
+    /// a time and onion addresses first, and an onion service is advertised
+    /// in place of the public listen address
+    pub tor: Option<TorConfig>,
+    /// Seeds for the Kademlia routing table; each must end in `/p2p/<peer id>`
+    pub bootstrap_nodes: Vec<Multiaddr>,
+    /// Keeps the node on one network; see `Protocols::for_network`
+    pub protocols: Protocols,
+    pub events: broadcast::Sender<MessageEvent>,
+    pub metrics: Arc<Metrics>,
+    pub online: Arc<OnlineContacts>,
 }
 
 /// Start the libp2p swarm and return a command channel
 pub async fn start_node(
-    p2p_port: u16,
     identity: Arc<LedgerIdentity>,
     db: Arc<Database>,
+    config: NodeConfig,
 ) -> Result<(mpsc::Sender<P2PCommand>, PeerId), Box<dyn std::error::Error>> {
+    let NodeConfig { p2p_port, tor, bootstrap_nodes, protocols, events, metrics, online } = config;
+
     // Create libp2p identity from our Ed25519 key
-    let local_keypair = Keypair::generate_ed25519();
+    let local_keypair = identity.libp2p_keypair()?;
     let local_peer_id = PeerId::from(local_keypair.public());
 
     tracing::info!("Local libp2p peer ID: {}", local_peer_id);
 
+    let max_message_bytes = db.get_setting("max_message_bytes")?
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES)
+        .min(MAX_MESSAGE_BYTES_CEILING);
+    let peer_rate_limit = db.get_setting("peer_rate_limit")?
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_PEER_RATE_LIMIT);
+
     // Build swarm
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_keypair.clone())
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|_key| {
-            LedgerBehaviour::new(local_peer_id, &local_keypair)
-                .expect("Failed to create behaviour")
-        })?
-        .with_swarm_config(|c| c.with_idle_connection_timeout(std::time::Duration::from_secs(60)))
-        .build();
-
-    // Listen on TCP
-    let listen_addr: Multiaddr = format!("/ip4/0.0.0.0/tcp/{}", p2p_port).parse()?;
+    let tor_enabled = tor.is_some();
+    let prefer_onion = tor_enabled;
+    let behaviour = |_key: &libp2p::identity::Keypair, relay_client| {
+        LedgerBehaviour::new(local_peer_id, &local_keypair, &identity.ledger_id, relay_client, max_message_bytes, &protocols, tor_enabled)
+            .expect("Failed to create behaviour")
+    };
+    let swarm_config = |c: libp2p::swarm::Config| {
+        let c = c.with_idle_connection_timeout(std::time::Duration::from_secs(60));
+        // Otherwise every address is dialed at once and the order means nothing
+        match prefer_onion {
+            true => c.with_dial_concurrency_factor(std::num::NonZeroU8::MIN),
+            false => c,
+        }
+    };
+
+    // QUIC runs over UDP, which Tor can't carry
+    let quic_enabled = !tor_enabled;
+    let onion_addr = tor.as_ref().and_then(|t| t.onion_addr.clone());
+
+    let builder = libp2p::SwarmBuilder::with_existing_identity(local_keypair.clone()).with_tokio();
+    let mut swarm = match tor {
+        // Same TCP + noise + yamux stack, but dialing through the SOCKS5 proxy
+        Some(tor) => builder
+            .with_other_transport(|key| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                Ok(Socks5Transport::new(tor.socks_addr)
+                    .upgrade(libp2p::core::upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            })?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(behaviour)?
+            .with_swarm_config(swarm_config)
+            .build(),
+        None => builder
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            // QUIC copes better with NAT; TCP stays for peers without it
+            .with_quic()
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(behaviour)?
+            .with_swarm_config(swarm_config)
+            .build(),
+    };
+
+    // Listen on TCP, and QUIC on the same port number over UDP. Behind Tor,
+    // only listen where an onion service forwards to, so the node can't be
+    // found at its own address; the onion address is the only one advertised.
+    let listen_host = if tor_enabled { "127.0.0.1" } else { "0.0.0.0" };
+    let listen_addr: Multiaddr = format!("/ip4/{}/tcp/{}", listen_host, p2p_port).parse()?;
     swarm.listen_on(listen_addr)?;
+    if let Some(addr) = onion_addr {
+        // Identify hands it to every peer we meet
+        swarm.add_external_address(addr);
+    }
+    if quic_enabled {
+        let quic_addr: Multiaddr = format!("/ip4/0.0.0.0/udp/{}/quic-v1", p2p_port).parse()?;
+        swarm.listen_on(quic_addr)?;
+    }
+
+    // Re-learn peers from the previous run so we don't start from an empty routing table
+    let cutoff = chrono::Utc::now().timestamp() - KNOWN_PEER_TTL.as_secs() as i64;
+    match db.prune_known_peers(cutoff) {
+        Ok(n) if n > 0 => tracing::debug!("Pruned {} stale known peer address(es)", n),
+        Ok(_) => {}
+        Err(e) => tracing::warn!("Failed to prune known peers: {}", e),
+    }
+    let mut known: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+    for (peer_id, addr) in db.get_known_peers()? {
+        match (peer_id.parse::<PeerId>(), addr.parse::<Multiaddr>()) {
+            (Ok(peer_id), Ok(addr)) => known.entry(peer_id).or_default().push(addr),
+            _ => tracing::warn!("Ignoring invalid known peer {} at {}", peer_id, addr),
+        }
+    }
+    if !known.is_empty() {
+        tracing::info!("Reconnecting to {} known peer(s)", known.len());
+    }
+    for (peer_id, mut addrs) in known {
+        if prefer_onion {
+            tor::prefer_onion(&mut addrs);
+        }
+        for addr in &addrs {
+            swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+        }
+        if let Err(e) = swarm.dial(DialOpts::peer_id(peer_id).addresses(addrs).build()) {
+            tracing::debug!("Failed to dial known peer {}: {}", peer_id, e);
+        }
+    }
+
+    // Join the wider DHT through the bootstrap nodes
+    let mut bootstrap_count = 0;
+    for addr in bootstrap_nodes {
+        match addr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => {
+                swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                bootstrap_count += 1;
+            }
+            _ => tracing::warn!("Ignoring bootstrap node without /p2p/<peer id>: {}", addr),
+        }
+    }
+    if bootstrap_count > 0 {
+        match swarm.behaviour_mut().kademlia.bootstrap() {
+            Ok(_) => tracing::info!("Bootstrapping Kademlia from {} node(s)", bootstrap_count),
+            Err(e) => tracing::warn!("Kademlia bootstrap not started: {}", e),
+        }
+    }
+
+    // Reserve a slot on the relay so peers behind other NATs can reach us
+    if let Some(relay_addr) = db.get_setting("relay_addr")?.filter(|a| !a.is_empty()) {
+        match relay_addr.parse::<Multiaddr>() {
+            Ok(addr) => {
+                swarm.listen_on(addr.with(Protocol::P2pCircuit))?;
+                tracing::info!("Requesting relay reservation on {}", relay_addr);
+            }
+            Err(e) => tracing::warn!("Ignoring invalid relay_addr {:?}: {}", relay_addr, e),
+        }
+    }
 
     // Subscribe to gossipsub topic for announcements
-    let topic = libp2p::gossipsub::IdentTopic::new("ledger-announce");
+    tracing::info!("Joining network {:?}", protocols.network_id);
+    let topic = libp2p::gossipsub::IdentTopic::new(&protocols.announce_topic);
     swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
 
     // Command channel
@@ -89,15 +384,52 @@ pub async fn start_node(
     let db_clone = db.clone();
 
     tokio::spawn(async move {
+        let mut state = NodeState {
+            max_message_bytes: max_message_bytes as usize,
+            rate_limiter: RateLimiter::new(peer_rate_limit),
+            chunk_rate_limiter: RateLimiter::new(transfer::CHUNK_RATE_LIMIT),
+            online,
+            protocols,
+            prefer_onion,
+            ..Default::default()
+        };
+        let mut sweep = tokio::time::interval(Duration::from_secs(1));
+        let mut republish = tokio::time::interval(PEER_RECORD_REPUBLISH);
+        let mut announce = tokio::time::interval(PRESENCE_INTERVAL);
+
         loop {
             tokio::select! {
                 // Handle swarm events
                 event = swarm.select_next_some() => {
-                    handle_swarm_event(&mut swarm, event, &identity_clone, &db_clone).await;
+                    handle_swarm_event(&mut swarm, &mut state, event, &identity_clone, &db_clone, &events, &metrics).await;
                 }
                 // Handle commands from REST API
                 Some(cmd) = cmd_rx.recv() => {
-                    handle_command(&mut swarm, cmd).await;
+                    if let P2PCommand::Shutdown { response_tx } = cmd {
+                        shut_down(&mut swarm, &mut state, &identity_clone, &db_clone, &events, &metrics, local_peer_id).await;
+                        let _ = response_tx.send(()).await;
+                        break;
+                    }
+                    handle_command(&mut swarm, &mut state, &db_clone, cmd).await;
+                }
+                // Time out requests that never resolved
+                _ = sweep.tick() => {
+                    state.expire_dials().await;
+                    state.rate_limiter.expire_idle(Instant::now());
+                    state.chunk_rate_limiter.expire_idle(Instant::now());
+                    redial_dropped_peers(&mut swarm, &mut state, &db_clone);
+                    for ledger_id in state.online.expire(Instant::now()) {
+                        tracing::debug!("{} stopped announcing itself", ledger_id);
+                        let _ = events.send(MessageEvent::Presence { ledger_id, online: false });
+                    }
+                }
+                // Keep our peer record alive so others can route to us
+                _ = republish.tick() => {
+                    publish_peer_record(&mut swarm, &identity_clone, local_peer_id);
+                }
+                // Tell the gossip mesh we're online and where to reach us
+                _ = announce.tick() => {
+                    publish_presence(&mut swarm, &state.protocols.announce_topic, &identity_clone, local_peer_id);
                 }
             }
         }
@@ -106,12 +438,448 @@ pub async fn start_node(
     Ok((cmd_tx, local_peer_id))
 }
 
+/// Publish `ledger:peer:{ledger_id}` → our PeerId so senders can find this node
+fn publish_peer_record(swarm: &mut Swarm<LedgerBehaviour>, identity: &LedgerIdentity, peer_id: PeerId) -> Option<libp2p::kad::QueryId> {
+    let record = libp2p::kad::Record::new(
+        crate::dht::store::peer_record_key(&identity.ledger_id),
+        peer_id.to_string().into_bytes(),
+    );
+    swarm.behaviour_mut().kademlia.put_record(record, libp2p::kad::Quorum::One)
+        .map_err(|e| tracing::warn!("Failed to publish peer record: {:?}", e))
+        .ok()
+}
+
+/// Republish our peer record one last time so it outlives this run, let
+/// pending `DhtPut`s reach other peers, then close every connection. The swarm keeps handling events meanwhile, bounded by `SHUTDOWN_FLUSH`.
+async fn shut_down(
+    swarm: &mut Swarm<LedgerBehaviour>,
+    state: &mut NodeState,
+    identity: &LedgerIdentity,
+    db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    metrics: &Metrics,
+    peer_id: PeerId,
+) {
+    tracing::info!("Stopping P2P node");
+    let mut republish = publish_peer_record(swarm, identity, peer_id);
+    let mut disconnecting = false;
+
+    let flushed = tokio::time::timeout(SHUTDOWN_FLUSH, async {
+        loop {
+            // Once our puts have settled, hang up on everyone and wait for the closes
+            if republish.is_none() && state.pending_dht_puts.is_empty() {
+                if swarm.network_info().num_peers() == 0 {
+                    return;
+                }
+                if !disconnecting {
+                    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                    for peer in peers {
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                    disconnecting = true;
+                }
+            }
+
+            let event = swarm.select_next_some().await;
+            if let SwarmEvent::Behaviour(LedgerBehaviourEvent::Kademlia(
+                libp2p::kad::Event::OutboundQueryProgressed { id, step, .. }
+            )) = &event {
+                if Some(*id) == republish && step.last {
+                    republish = None;
+                }
+            }
+            handle_swarm_event(swarm, state, event, identity, db, events, metrics).await;
+        }
+    }).await;
+
+    if flushed.is_err() {
+        tracing::warn!("P2P shutdown timed out; dropping the remaining connections");
+    }
+}
+
+/// Announce our Ledger ID, PeerId and reachable addresses on the gossip topic
+fn publish_presence(swarm: &mut Swarm<LedgerBehaviour>, topic: &str, identity: &LedgerIdentity, peer_id: PeerId) {
+    let mut listen_addrs: Vec<String> = Vec::new();
+    for addr in swarm.external_addresses().chain(swarm.listeners()) {
+        // Wildcard and loopback listen addresses mean nothing to other nodes
+        let unspecified = addr.iter().any(|p| match p {
+            Protocol::Ip4(ip) => ip.is_unspecified() || ip.is_loopback(),
+            Protocol::Ip6(ip) => ip.is_unspecified() || ip.is_loopback(),
+            _ => false,
+        });
+        let addr = addr.to_string();
+        if !unspecified && !listen_addrs.contains(&addr) {
+            listen_addrs.push(addr);
+        }
+    }
+
+    let presence = Announcement::Presence(Presence::new(identity, peer_id, listen_addrs));
+    let data = match serde_json::to_vec(&presence) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Failed to encode presence: {}", e);
+            return;
+        }
+    };
+    let topic = libp2p::gossipsub::IdentTopic::new(topic);
+    match swarm.behaviour_mut().gossipsub.publish(topic, data) {
+        Ok(_) => tracing::debug!("Announced presence"),
+        // Nobody to tell yet; the next tick will try again
+        Err(libp2p::gossipsub::PublishError::InsufficientPeers) => {}
+        Err(e) => tracing::warn!("Failed to announce presence: {}", e),
+    }
+}
+
+/// Decode a gossipsub message and hand it to the handler for its kind
+fn handle_gossip_message(
+    swarm: &mut Swarm<LedgerBehaviour>,
+    state: &NodeState,
+    identity: &LedgerIdentity,
+    db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    message: libp2p::gossipsub::Message,
+) {
+    match serde_json::from_slice::<Announcement>(&message.data) {
+        Ok(Announcement::Presence(presence)) => {
+            handle_presence(swarm, &state.online, identity, db, events, presence, message.source)
+        }
+        Ok(Announcement::Bulletin(bulletin)) => accept_bulletin(identity, db, events, &bulletin),
+        // Possibly a kind added by a newer node
+        Err(e) => tracing::debug!("Ignoring unrecognised announcement on {}: {}", message.topic, e),
+    }
+}
+
+/// Learn where a Ledger ID can be reached from a peer's presence
+/// announcement, and tell WebSocket clients when a contact comes online.
+/// `source` is the gossipsub author, which relays can't forge.
+fn handle_presence(
+    swarm: &mut Swarm<LedgerBehaviour>,
+    online: &OnlineContacts,
+    identity: &LedgerIdentity,
+    db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    presence: Presence,
+    source: Option<PeerId>,
+) {
+    if presence.ledger_id == identity.ledger_id {
+        return;
+    }
+    if let Err(e) = presence.verify(chrono::Utc::now().timestamp()) {
+        tracing::warn!("Ignoring presence for {}: {}", presence.ledger_id, e);
+        return;
+    }
+    let Ok(peer_id) = presence.peer_id.parse::<PeerId>() else {
+        return;
+    };
+    if source != Some(peer_id) {
+        tracing::warn!("Ignoring presence for {} published by another peer", presence.ledger_id);
+        return;
+    }
+    if db.is_blocked(&presence.ledger_id).unwrap_or(false) {
+        return;
+    }
+
+    let addrs: Vec<Multiaddr> = presence.listen_addrs.iter()
+        .filter_map(|a| a.parse().ok())
+        .collect();
+    tracing::debug!("{} is online as {}", presence.ledger_id, peer_id);
+    if matches!(db.get_contact(&presence.ledger_id), Ok(Some(_))) && online.seen(&presence.ledger_id, Instant::now()) {
+        let _ = events.send(MessageEvent::Presence { ledger_id: presence.ledger_id.clone(), online: true });
+    }
+    record_peer_mapping(db, &presence.ledger_id, &peer_id, addrs.first());
+    retry_outbox_for_peer(db, &peer_id);
+    for addr in addrs {
+        remember_peer_address(db, &peer_id, &addr);
+        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+    }
+}
+
+/// Store a verified bulletin in the Broadcast folder, subject to the blocklist
+/// and receive policy
+fn accept_bulletin(
+    identity: &LedgerIdentity,
+    db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    bulletin: &Bulletin,
+) {
+    // Our own copy was stored when we published it
+    if bulletin.ledger_id == identity.ledger_id {
+        return;
+    }
+    if let Err(e) = bulletin.verify(chrono::Utc::now().timestamp()) {
+        tracing::warn!("Ignoring bulletin from {}: {}", bulletin.ledger_id, e);
+        return;
+    }
+    if let Some(reason) = db.sender_rejection(&bulletin.ledger_id) {
+        tracing::debug!("Ignoring bulletin from {}: {}", bulletin.ledger_id, reason);
+        return;
+    }
+    // Gossip can deliver the same bulletin more than once
+    if !matches!(db.get_message(&bulletin.id), Ok(None)) {
+        return;
+    }
+
+    let msg = bulletin.to_message();
+    match db.insert_message(&msg) {
+        Ok(()) => {
+            tracing::info!("Bulletin {} from {}", msg.id, msg.from_id);
+            let _ = events.send(MessageEvent::NewMessage { id: msg.id });
+        }
+        Err(e) => tracing::error!("Failed to store bulletin: {}", e),
+    }
+}
+
+/// Remember which peer serves a Ledger ID, if the peer's key actually backs that ID
+fn record_peer_mapping(db: &Database, ledger_id: &str, peer_id: &PeerId, addr: Option<&Multiaddr>) {
+    match LedgerIdentity::peer_id_from_ledger_id(ledger_id) {
+        Ok(expected) if expected == *peer_id => {}
+        _ => {
+            tracing::warn!("Peer {} claimed Ledger ID {} it doesn't own", peer_id, ledger_id);
+            return;
+        }
+    }
+
+    let addr = addr.map(|a| a.to_string()).unwrap_or_default();
+    if let Err(e) = db.upsert_peer_mapping(ledger_id, &peer_id.to_string(), &addr) {
+        tracing::error!("Failed to store peer mapping: {}", e);
+    }
+}
+
+/// Bring forward queued envelopes for a peer that just became reachable
+fn retry_outbox_for_peer(db: &Database, peer_id: &PeerId) {
+    match db.retry_outbox_for_peer(&peer_id.to_string()) {
+        Ok(0) => {}
+        Ok(n) => tracing::info!("{} is reachable; retrying {} queued message(s)", peer_id, n),
+        Err(e) => tracing::error!("Failed to reschedule outbox: {}", e),
+    }
+}
+
+/// Persist an address a peer was reachable at so it can be re-dialled after a restart
+fn remember_peer_address(db: &Database, peer_id: &PeerId, addr: &Multiaddr) {
+    if let Err(e) = db.upsert_known_peer(&peer_id.to_string(), &addr.to_string()) {
+        tracing::error!("Failed to store known peer: {}", e);
+    }
+}
+
+/// Stop dialling a peer: drop its addresses from the routing table and our
+/// records, and its reconnect schedule
+fn forget_peer(swarm: &mut Swarm<LedgerBehaviour>, state: &mut NodeState, db: &Database, peer_id: &PeerId) {
+    swarm.behaviour_mut().kademlia.remove_peer(peer_id);
+    state.reconnects.remove(peer_id);
+    if let Err(e) = db.forget_known_peer(&peer_id.to_string()) {
+        tracing::error!("Failed to forget known peer: {}", e);
+    }
+}
+
+/// Addresses to redial a peer at, if it's one to keep a link to: a peer we
+/// know an address for, or a contact's, and not blocked
+fn reconnect_addresses(db: &Database, peer_id: &PeerId) -> Option<Vec<Multiaddr>> {
+    let peer = peer_id.to_string();
+    if db.is_peer_blocked(&peer).unwrap_or(true) {
+        return None;
+    }
+    let addrs: Vec<Multiaddr> = db.get_known_peer_addresses(&peer).unwrap_or_default()
+        .iter()
+        .filter_map(|a| a.parse().ok())
+        .collect();
+    let is_contact = || db.get_ledger_id_for_peer(&peer).ok().flatten()
+        .is_some_and(|ledger_id| matches!(db.get_contact(&ledger_id), Ok(Some(_))));
+    // A contact's addresses may still turn up through Kademlia
+    (!addrs.is_empty() || is_contact()).then_some(addrs)
+}
+
+/// Redial dropped peers whose backoff has run out. One that has since been
+/// blocked or forgotten is taken off the schedule instead.
+fn redial_dropped_peers(swarm: &mut Swarm<LedgerBehaviour>, state: &mut NodeState, db: &Database) {
+    for peer_id in state.reconnects.due(Instant::now()) {
+        let Some(mut addrs) = reconnect_addresses(db, &peer_id) else {
+            state.reconnects.remove(&peer_id);
+            continue;
+        };
+        if state.prefer_onion {
+            tor::prefer_onion(&mut addrs);
+        }
+        tracing::debug!("Redialling dropped peer {}", peer_id);
+        let opts = DialOpts::peer_id(peer_id)
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .addresses(addrs)
+            .build();
+        if let Err(e) = swarm.dial(opts) {
+            tracing::debug!("Failed to redial {}: {}", peer_id, e);
+        }
+    }
+}
+
+/// Ask `peer_id` for the next chunk of `download`, or answer the caller once
+/// every chunk is in
+async fn request_next_chunk(
+    swarm: &mut Swarm<LedgerBehaviour>,
+    state: &mut NodeState,
+    peer_id: PeerId,
+    download: Download,
+    response_tx: FetchResponder,
+) {
+    match download.next_request() {
+        Some(request) => {
+            let request_id = swarm.behaviour_mut().file_transfer.send_request(&peer_id, request);
+            state.pending_fetches.insert(request_id, (download, response_tx));
+        }
+        None => {
+            let _ = response_tx.send(download.finish().map_err(|e| e.to_string())).await;
+        }
+    }
+}
+
+/// Decrypt, validate and store an inbound envelope, returning the reply for the sender
+fn accept_envelope(
+    identity: &LedgerIdentity,
+    db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    envelope_json: &str,
+    max_message_bytes: usize,
+    verifying_keys: &mut VerifyingKeyCache,
+    metrics: &Metrics,
+) -> LedgerResponse {
+    if envelope_json.len() > max_message_bytes {
+        tracing::warn!("Rejecting {} byte envelope (limit {})", envelope_json.len(), max_message_bytes);
+        return LedgerResponse::rejected(format!("envelope exceeds {} bytes", max_message_bytes));
+    }
+
+    let env = match serde_json::from_str::<EncryptedEnvelope>(envelope_json) {
+        Ok(env) => env,
+        Err(e) => {
+            tracing::error!("Failed to parse envelope: {}", e);
+            return LedgerResponse::rejected(e.to_string());
+        }
+    };
+
+    if let Some(reason) = db.sender_rejection(&env.from_ledger_id) {
+        tracing::info!("Dropping envelope {} from {}: {}", env.id, env.from_ledger_id, reason);
+        return LedgerResponse::rejected(reason);
+    }
+
+    if let Some(reason) = legacy_rejection(&env) {
+        tracing::warn!("Rejecting envelope {} from {}: {}", env.id, env.from_ledger_id, reason);
+        return LedgerResponse::rejected(reason);
+    }
+
+    let plaintext = match envelope::decrypt_envelope_cached(identity, &env, verifying_keys) {
+        Ok(plaintext) => plaintext,
+        Err(e) => {
+            metrics.decrypt_failures.inc();
+            tracing::error!("Decryption failed: {}", e);
+            return LedgerResponse::rejected(e.to_string());
+        }
+    };
+
+    if let Some(reason) = replay_rejection(db, &env, chrono::Utc::now().timestamp()) {
+        tracing::warn!("Rejecting envelope {}: {}", env.id, reason);
+        return LedgerResponse::rejected("replay/stale");
+    }
+
+    let msg = Message::from_envelope(&env, identity.ledger_id.clone(), plaintext);
+    if let Err(e) = db.insert_message(&msg) {
+        // Not accepted, so the sender keeps it queued and retries
+        tracing::error!("Failed to store message: {}", e);
+        return LedgerResponse::rejected("failed to store message");
+    }
+    metrics.messages_received.inc();
+    let _ = events.send(MessageEvent::NewMessage { id: msg.id.clone() });
+
+    tracing::info!("Message decrypted and stored: {}", env.id);
+    LedgerResponse::accepted()
+}
+
+/// Verify a read receipt for one of our envelopes and record it on the sent message
+fn accept_receipt(
+    identity: &LedgerIdentity,
+    db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    receipt: &ReadReceipt,
+) -> LedgerResponse {
+    if receipt.sender != identity.ledger_id {
+        return LedgerResponse::rejected("receipt is for another sender");
+    }
+    if let Err(e) = receipt.verify() {
+        tracing::warn!("Rejecting read receipt from {}: {}", receipt.reader, e);
+        return LedgerResponse::rejected(e);
+    }
+
+    match db.record_read_receipt(&receipt.envelope_id, &receipt.reader, receipt.read_at) {
+        Ok(Some(message_id)) => {
+            tracing::info!("{} read message {}", receipt.reader, message_id);
+            let _ = events.send(MessageEvent::MessageRead { id: message_id, reader: receipt.reader.clone() });
+            LedgerResponse::accepted()
+        }
+        // Never asked for, or a duplicate
+        Ok(None) => LedgerResponse::rejected("no receipt expected"),
+        Err(e) => {
+            tracing::error!("Failed to store read receipt: {}", e);
+            LedgerResponse::rejected("failed to store receipt")
+        }
+    }
+}
+
+/// Version 0 envelopes sign only the ciphertext, so anyone holding one can
+/// give it a fresh id and timestamp and pass `replay_rejection`. Peers and
+/// DHT mailboxes don't take them; Gmail fallback mail still opens.
+pub(crate) fn legacy_rejection(env: &EncryptedEnvelope) -> Option<&'static str> {
+    (env.version == envelope::ENVELOPE_VERSION_LEGACY).then_some("unsigned envelope header (version 0)")
+}
+
+/// Why an envelope looks replayed or stale, if it does
+fn replay_rejection(db: &Database, env: &EncryptedEnvelope, now: i64) -> Option<&'static str> {
+    let window_hours = db.get_setting("replay_window_hours").ok().flatten()
+        .and_then(|h| h.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_REPLAY_WINDOW_HOURS);
+
+    if env.timestamp > now + MAX_FUTURE_SKEW_SECS {
+        return Some("timestamp in the future");
+    }
+    // Saturating, since a window written straight into the database is unchecked
+    if env.timestamp < now.saturating_sub(window_hours.saturating_mul(3600)) {
+        return Some("older than replay window");
+    }
+    // Envelope ids are chosen by senders and readable in DHT mailboxes, so one
+    // only counts as seen when it came from the same sender
+    match db.get_message(&env.id) {
+        Ok(None) => None,
+        Ok(Some(stored)) if stored.from_id == env.from_ledger_id => Some("envelope id already stored"),
+        Ok(Some(_)) => Some("envelope id taken by another sender"),
+        Err(e) => {
+            tracing::error!("Replay check failed: {}", e);
+            Some("replay check failed")
+        }
+    }
+}
+
+/// Feed swarm and sub-behaviour events to libp2p's metrics
+fn record_libp2p_metrics(metrics: &Metrics, event: &SwarmEvent<LedgerBehaviourEvent>) {
+    use libp2p::metrics::Recorder;
+
+    metrics.p2p.record(event);
+    if let SwarmEvent::Behaviour(behaviour_event) = event {
+        match behaviour_event {
+            LedgerBehaviourEvent::Kademlia(e) => metrics.p2p.record(e),
+            LedgerBehaviourEvent::Gossipsub(e) => metrics.p2p.record(e),
+            LedgerBehaviourEvent::Identify(e) => metrics.p2p.record(e),
+            LedgerBehaviourEvent::Dcutr(e) => metrics.p2p.record(e),
+            _ => {}
+        }
+    }
+}
+
 async fn handle_swarm_event(
     swarm: &mut Swarm<LedgerBehaviour>,
+    state: &mut NodeState,
     event: SwarmEvent<LedgerBehaviourEvent>,
     identity: &LedgerIdentity,
     db: &Database,
+    events: &broadcast::Sender<MessageEvent>,
+    metrics: &Metrics,
 ) {
+    record_libp2p_metrics(metrics, &event);
+
     match event {
         SwarmEvent::Behaviour(LedgerBehaviourEvent::RequestResponse(
             libp2p::request_response::Event::Message { message, peer }
@@ -120,67 +888,117 @@ async fn handle_swarm_event(
                 libp2p::request_response::Message::Request { request, channel, .. } => {
                     tracing::info!("Received message from peer: {}", peer);
 
-                    // Try to decrypt the envelope
-                    match serde_json::from_str::<EncryptedEnvelope>(&request.envelope_json) {
-                        Ok(env) => {
-                            match envelope::decrypt_envelope(identity, &env) {
-                                Ok(plaintext) => {
-                                    let msg = Message {
-                                        id: env.id.clone(),
-                                        from_id: env.from_ledger_id.clone(),
-                                        to_id: identity.ledger_id.clone(),
-                                        subject: env.subject_hint.clone(),
-                                        body: plaintext,
-                                        timestamp: env.timestamp,
-                                        delivery_method: DeliveryMethod::P2p,
-                                        is_read: false,
-                                        folder: Folder::Inbox,
-                                        signature: Some(env.signature.clone()),
-                                        encrypted: true,
-                                    };
-
-                                    if let Err(e) = db.insert_message(&msg) {
-                                        tracing::error!("Failed to store message: {}", e);
-                                    }
-
-                                    let _ = swarm.behaviour_mut().request_response.send_response(
-                                        channel,
-                                        LedgerResponse { accepted: true, error: None },
-                                    );
-                                    tracing::info!("Message decrypted and stored: {}", env.id);
-                                }
-                                Err(e) => {
-                                    tracing::error!("Decryption failed: {}", e);
-                                    let _ = swarm.behaviour_mut().request_response.send_response(
-                                        channel,
-                                        LedgerResponse { accepted: false, error: Some(e.to_string()) },
-                                    );
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Failed to parse envelope: {}", e);
-                            let _ = swarm.behaviour_mut().request_response.send_response(
-                                channel,
-                                LedgerResponse { accepted: false, error: Some(e.to_string()) },
-                            );
-                        }
+                    // Refuse before doing any decrypt/DB work on the peer's behalf
+                    if !state.rate_limiter.check(peer, Instant::now()) {
+                        tracing::warn!("Rate limiting peer {}", peer);
+                        let _ = swarm.behaviour_mut().request_response
+                            .send_response(channel, LedgerResponse::rejected("rate limited"));
+                        return;
                     }
+
+                    let response = match request {
+                        LedgerRequest::Envelope { envelope_json } => {
+                            accept_envelope(
+                                identity, db, events, &envelope_json,
+                                state.max_message_bytes, &mut state.verifying_keys, metrics,
+                            )
+                        }
+                        LedgerRequest::Receipt { receipt } => accept_receipt(identity, db, events, &receipt),
+                    };
+                    let _ = swarm.behaviour_mut().request_response.send_response(channel, response);
                 }
-                libp2p::request_response::Message::Response { response, .. } => {
-                    if response.accepted {
-                        tracing::info!("Message accepted by peer {}", peer);
+                libp2p::request_response::Message::Response { request_id, response } => {
+                    let Some((message_id, response_tx)) = state.pending_sends.remove(&request_id) else {
+                        // A read receipt; nothing is waiting on it
+                        if !response.accepted {
+                            tracing::debug!("Read receipt rejected by {}: {:?}", peer, response.error);
+                        }
+                        return;
+                    };
+
+                    // The sender records the outcome on the Sent copy: on a first
+                    // send that row isn't written until every recipient is routed
+                    let reply = if response.accepted {
+                        tracing::info!("Message {} accepted by peer {}", message_id, peer);
+                        Ok(())
                     } else {
-                        tracing::warn!("Message rejected by peer {}: {:?}", peer, response.error);
+                        tracing::warn!("Message {} rejected by peer {}: {:?}", message_id, peer, response.error);
+                        Err(response.error.unwrap_or_else(|| "Rejected by peer".into()))
+                    };
+                    let _ = response_tx.send(reply).await;
+                }
+            }
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::RequestResponse(
+            libp2p::request_response::Event::OutboundFailure { peer, request_id, error }
+        )) => {
+            tracing::warn!("Failed to send message to {}: {}", peer, error);
+            if let Some((_, response_tx)) = state.pending_sends.remove(&request_id) {
+                let _ = response_tx.send(Err(format!("Send failed: {}", error))).await;
+            }
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::RequestResponse(
+            libp2p::request_response::Event::InboundFailure { peer, error, .. }
+        )) => {
+            // Includes requests the codec refused for exceeding `max_message_bytes`
+            tracing::warn!("Inbound request from {} failed: {}", peer, error);
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::FileTransfer(
+            libp2p::request_response::Event::Message { message, peer }
+        )) => {
+            match message {
+                libp2p::request_response::Message::Request { request, channel, .. } => {
+                    tracing::debug!("Peer {} asked for chunk {} of {}", peer, request.chunk, request.sha256);
+                    let response = transfer::answer_chunk_request(db, &mut state.chunk_rate_limiter, peer, &request, Instant::now());
+                    let _ = swarm.behaviour_mut().file_transfer.send_response(channel, response);
+                }
+                libp2p::request_response::Message::Response { request_id, response } => {
+                    let Some((mut download, response_tx)) = state.pending_fetches.remove(&request_id) else {
+                        return;
+                    };
+                    match download.accept(response) {
+                        Ok(()) => request_next_chunk(swarm, state, peer, download, response_tx).await,
+                        Err(e) => {
+                            let _ = response_tx.send(Err(e.to_string())).await;
+                        }
                     }
                 }
             }
         }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::FileTransfer(
+            libp2p::request_response::Event::OutboundFailure { peer, request_id, error }
+        )) => {
+            tracing::warn!("Failed to fetch attachment chunk from {}: {}", peer, error);
+            if let Some((_, response_tx)) = state.pending_fetches.remove(&request_id) {
+                let _ = response_tx.send(Err(format!("Fetch failed: {}", error))).await;
+            }
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::FileTransfer(
+            libp2p::request_response::Event::InboundFailure { peer, error, .. }
+        )) => {
+            tracing::debug!("Chunk request from {} failed: {}", peer, error);
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Gossipsub(
+            libp2p::gossipsub::Event::Message { message, .. }
+        )) => {
+            handle_gossip_message(swarm, state, identity, db, events, message);
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Gossipsub(
+            libp2p::gossipsub::Event::Subscribed { peer_id, topic }
+        )) => {
+            tracing::debug!("Peer {} subscribed to {}", peer_id, topic);
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Gossipsub(
+            libp2p::gossipsub::Event::Unsubscribed { peer_id, topic }
+        )) => {
+            tracing::debug!("Peer {} unsubscribed from {}", peer_id, topic);
+        }
         SwarmEvent::Behaviour(LedgerBehaviourEvent::Mdns(mdns_event)) => {
             match mdns_event {
                 libp2p::mdns::Event::Discovered(peers) => {
                     for (peer_id, addr) in peers {
                         tracing::info!("mDNS discovered peer: {} at {}", peer_id, addr);
+                        remember_peer_address(db, &peer_id, &addr);
                         swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
                     }
                 }
@@ -191,86 +1009,582 @@ async fn handle_swarm_event(
                 }
             }
         }
-        SwarmEvent::Behaviour(LedgerBehaviourEvent::Kademlia(kad_event)) => {
-            match kad_event {
-                libp2p::kad::Event::RoutingUpdated { peer, .. } => {
-                    tracing::debug!("Kademlia routing updated for peer: {}", peer);
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Kademlia(
+            libp2p::kad::Event::RoutingUpdated { peer, .. }
+        )) => {
+            tracing::debug!("Kademlia routing updated for peer: {}", peer);
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Kademlia(
+            libp2p::kad::Event::OutboundQueryProgressed { result: QueryResult::Bootstrap(result), .. }
+        )) => match result {
+            Ok(ok) if ok.num_remaining == 0 => tracing::info!("Kademlia bootstrap complete"),
+            Ok(ok) => tracing::debug!("Kademlia bootstrap: {} bucket(s) remaining", ok.num_remaining),
+            Err(e) => tracing::warn!("Kademlia bootstrap failed: {}", e),
+        },
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Kademlia(
+            libp2p::kad::Event::OutboundQueryProgressed { id, result: QueryResult::GetRecord(result), step, .. }
+        )) => {
+            let response_tx = match state.pending_dht_gets.remove(&id) {
+                Some(DhtGetResponder::First(response_tx)) => response_tx,
+                Some(DhtGetResponder::All(mut values, response_tx)) => {
+                    let error = match result {
+                        Ok(GetRecordOk::FoundRecord(peer_record)) => {
+                            if !values.contains(&peer_record.record.value) {
+                                values.push(peer_record.record.value);
+                            }
+                            None
+                        }
+                        Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => None,
+                        Err(GetRecordError::NotFound { .. }) => None,
+                        Err(e) => Some(format!("DHT get error: {}", e)),
+                    };
+                    if !step.last {
+                        state.pending_dht_gets.insert(id, DhtGetResponder::All(values, response_tx));
+                        return;
+                    }
+                    // A query that timed out still counts if it found something
+                    let reply = match error {
+                        Some(e) if values.is_empty() => Err(e),
+                        _ => Ok(values),
+                    };
+                    let _ = response_tx.send(reply).await;
+                    return;
                 }
-                _ => {}
+                None => return,
+            };
+
+            let reply = match result {
+                Ok(GetRecordOk::FoundRecord(peer_record)) => {
+                    // First record wins; stop the query from doing more work
+                    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                        query.finish();
+                    }
+                    Ok(Some(peer_record.record.value))
+                }
+                Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => Ok(None),
+                Err(GetRecordError::NotFound { .. }) => Ok(None),
+                Err(e) => Err(format!("DHT get error: {}", e)),
+            };
+            let _ = response_tx.send(reply).await;
+        }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Kademlia(
+            libp2p::kad::Event::OutboundQueryProgressed { id, result: QueryResult::PutRecord(result), step, .. }
+        )) => {
+            if !step.last || !state.pending_dht_puts.remove(&id) {
+                return;
+            }
+            if let Err(e) = result {
+                tracing::warn!("DHT put didn't reach the quorum: {}", e);
             }
         }
-        SwarmEvent::Behaviour(LedgerBehaviourEvent::Identify(identify_event)) => {
-            if let libp2p::identify::Event::Received { peer_id, info } = identify_event {
-                tracing::info!("Identified peer {}: {:?}", peer_id, info.protocols);
-                for addr in info.listen_addrs {
-                    swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Identify(
+            libp2p::identify::Event::Received { peer_id, info }
+        )) => {
+            tracing::info!("Identified peer {}: {:?}", peer_id, info.protocols);
+            match state.protocols.network_of(&info) {
+                PeerNetwork::Ours => {}
+                PeerNetwork::Other => {
+                    // Found through mDNS or a bootstrap node, but no use to us
+                    tracing::info!("Disconnecting {} from another network ({})", peer_id, info.protocol_version);
+                    forget_peer(swarm, state, db, &peer_id);
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                    return;
                 }
+                // Relays and the like: keep the connection, but they carry no mail
+                PeerNetwork::NotLedger => return,
+            }
+            if let Some(ledger_id) = ledger_id_from_agent_version(&info.agent_version) {
+                record_peer_mapping(db, &ledger_id, &peer_id, info.listen_addrs.first());
+            }
+            for addr in info.listen_addrs {
+                remember_peer_address(db, &peer_id, &addr);
+                swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
             }
         }
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::RelayClient(event)) => match event {
+            libp2p::relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal, .. } => {
+                if !renewal {
+                    tracing::info!("Relay {} accepted our reservation", relay_peer_id);
+                }
+            }
+            libp2p::relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                tracing::info!("Opened relayed circuit via {}", relay_peer_id);
+            }
+            libp2p::relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                tracing::info!("Peer {} reached us through the relay", src_peer_id);
+            }
+        },
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Dcutr(event)) => match event.result {
+            Ok(_) => tracing::info!("Hole punch to {} succeeded; connection is now direct", event.remote_peer_id),
+            Err(e) => tracing::info!("Hole punch to {} failed ({}); staying on the relayed connection", event.remote_peer_id, e),
+        },
+        SwarmEvent::Behaviour(LedgerBehaviourEvent::Autonat(
+            libp2p::autonat::Event::StatusChanged { old, new }
+        )) => match new {
+            libp2p::autonat::NatStatus::Public(address) => {
+                tracing::info!("NAT status {:?} -> Public, reachable at {}", NatStatus::from(&old), address);
+            }
+            new => tracing::info!("NAT status {:?} -> {:?}", NatStatus::from(&old), NatStatus::from(&new)),
+        },
         SwarmEvent::NewListenAddr { address, .. } => {
             tracing::info!("Listening on {}", address);
         }
-        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+        SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+            if db.is_peer_blocked(&peer_id.to_string()).unwrap_or(false) {
+                tracing::info!("Refusing connection from blocked peer {}", peer_id);
+                let _ = swarm.disconnect_peer_id(peer_id);
+                if let Some((_, response_tx)) = state.pending_dials.remove(&connection_id) {
+                    let _ = response_tx.send(Err("Peer is blocked".into())).await;
+                }
+                return;
+            }
             tracing::info!("Connected to peer: {}", peer_id);
+            if let Entry::Vacant(entry) = state.connections.entry(peer_id) {
+                entry.insert(PeerConnection::new(&endpoint));
+                let _ = events.send(MessageEvent::PeerConnected {
+                    peer_id: peer_id.to_string(),
+                    ledger_id: db.get_ledger_id_for_peer(&peer_id.to_string()).ok().flatten(),
+                });
+            }
+            state.reconnects.remove(&peer_id);
+            metrics.connected_peers.set(swarm.network_info().num_peers() as i64);
+            retry_outbox_for_peer(db, &peer_id);
+            if let Some((_, response_tx)) = state.pending_dials.remove(&connection_id) {
+                let _ = response_tx.send(Ok(peer_id)).await;
+            }
+        }
+        SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+            tracing::warn!("Outgoing connection failed: {}", error);
+            if let Some((_, response_tx)) = state.pending_dials.remove(&connection_id) {
+                let _ = response_tx.send(Err(format!("Dial error: {}", error))).await;
+            }
         }
-        SwarmEvent::ConnectionClosed { peer_id, .. } => {
+        SwarmEvent::ConnectionClosed { peer_id, num_established, cause, .. } => {
+            if num_established > 0 {
+                return;
+            }
             tracing::info!("Disconnected from peer: {}", peer_id);
+            // Blocked peers were refused before being recorded, so never announced
+            let announced = state.connections.remove(&peer_id).is_some();
+            metrics.connected_peers.set(swarm.network_info().num_peers() as i64);
+            let ledger_id = db.get_ledger_id_for_peer(&peer_id.to_string()).ok().flatten();
+            if announced {
+                let _ = events.send(MessageEvent::PeerDisconnected {
+                    peer_id: peer_id.to_string(),
+                    ledger_id: ledger_id.clone(),
+                });
+            }
+            if let Some(ledger_id) = ledger_id.filter(|ledger_id| state.online.gone(ledger_id)) {
+                let _ = events.send(MessageEvent::Presence { ledger_id, online: false });
+            }
+            // Only a link that broke is redialled: we closed the rest ourselves,
+            // or they went idle
+            if matches!(cause, Some(ConnectionError::IO(_))) && reconnect_addresses(db, &peer_id).is_some() {
+                tracing::debug!("Scheduling reconnect to {}", peer_id);
+                state.reconnects.schedule(peer_id, Instant::now());
+            }
         }
         _ => {}
     }
 }
 
+/// Start a dial whose outcome is reported on `response_tx`
+async fn start_dial(
+    swarm: &mut Swarm<LedgerBehaviour>,
+    state: &mut NodeState,
+    opts: DialOpts,
+    response_tx: mpsc::Sender<Result<PeerId, String>>,
+) {
+    let connection_id = opts.connection_id();
+    match swarm.dial(opts) {
+        Ok(_) => {
+            // Answered once the connection is established, fails or times out
+            state.pending_dials.insert(
+                connection_id,
+                (Instant::now() + DIAL_TIMEOUT, response_tx),
+            );
+        }
+        Err(e) => {
+            let _ = response_tx.send(Err(format!("Dial error: {}", e))).await;
+        }
+    }
+}
+
 async fn handle_command(
     swarm: &mut Swarm<LedgerBehaviour>,
+    state: &mut NodeState,
+    db: &Database,
     cmd: P2PCommand,
 ) {
     match cmd {
-        P2PCommand::SendMessage { peer_id, envelope_json, response_tx } => {
-            let request = LedgerRequest { envelope_json };
-            swarm.behaviour_mut().request_response.send_request(&peer_id, request);
-            let _ = response_tx.send(Ok(())).await;
+        P2PCommand::SendMessage { peer_id, message_id, envelope_json, response_tx } => {
+            // Peers enforce their own limit; catch the common case before sending
+            if envelope_json.len() > state.max_message_bytes {
+                let _ = response_tx.send(Err(format!(
+                    "Envelope is {} bytes; the limit is {}", envelope_json.len(), state.max_message_bytes
+                ))).await;
+                return;
+            }
+            let request = LedgerRequest::Envelope { envelope_json };
+            let request_id = swarm.behaviour_mut().request_response.send_request(&peer_id, request);
+            // Answered when the peer's response (or a failure) comes back
+            state.pending_sends.insert(request_id, (message_id, response_tx));
+        }
+        P2PCommand::FetchAttachment { peer_id, download, response_tx } => {
+            request_next_chunk(swarm, state, peer_id, download, response_tx).await;
+        }
+        P2PCommand::SendReceipt { peer_id, receipt } => {
+            swarm.behaviour_mut().request_response.send_request(&peer_id, LedgerRequest::Receipt { receipt });
         }
         P2PCommand::ConnectPeer { addr, response_tx } => {
-            match swarm.dial(addr.clone()) {
-                Ok(_) => {
-                    tracing::info!("Dialing {}", addr);
-                    // We don't know the peer ID until connection is established
-                    let _ = response_tx.send(Ok(PeerId::random())).await;
-                }
-                Err(e) => {
-                    let _ = response_tx.send(Err(format!("Dial error: {}", e))).await;
+            tracing::info!("Dialing {}", addr);
+            let opts = DialOpts::unknown_peer_id().address(addr).build();
+            start_dial(swarm, state, opts, response_tx).await;
+        }
+        P2PCommand::DialPeer { peer_id, mut addresses, response_tx } => {
+            tracing::info!("Dialing peer {}", peer_id);
+            let opts = if state.prefer_onion {
+                // Kademlia's addresses come last, so put the ones we remember
+                // ahead of them where onion addresses can be sorted first
+                if addresses.is_empty() {
+                    addresses = db.get_known_peer_addresses(&peer_id.to_string()).unwrap_or_default()
+                        .iter()
+                        .filter_map(|a| a.parse().ok())
+                        .collect();
                 }
+                tor::prefer_onion(&mut addresses);
+                DialOpts::peer_id(peer_id).addresses(addresses).extend_addresses_through_behaviour().build()
+            } else {
+                // Known addresses from Kademlia/Identify are tried alongside these
+                DialOpts::peer_id(peer_id).addresses(addresses).build()
+            };
+            start_dial(swarm, state, opts, response_tx).await;
+        }
+        P2PCommand::DisconnectPeer { peer_id, response_tx } => {
+            state.reconnects.remove(&peer_id);
+            let connected = swarm.disconnect_peer_id(peer_id).is_ok();
+            if connected {
+                tracing::info!("Disconnected from peer {}", peer_id);
+            }
+            if let Some(response_tx) = response_tx {
+                let _ = response_tx.send(connected).await;
             }
         }
         P2PCommand::GetPeers { response_tx } => {
-            let peers: Vec<PeerInfo> = swarm.connected_peers()
-                .map(|p| PeerInfo {
-                    peer_id: p.to_string(),
-                    address: String::new(),
-                    ledger_id: None,
+            let peers: Vec<PeerInfo> = state.connections.iter()
+                .map(|(peer_id, conn)| PeerInfo {
+                    peer_id: peer_id.to_string(),
+                    address: conn.address.to_string(),
+                    ledger_id: db.get_ledger_id_for_peer(&peer_id.to_string()).ok().flatten(),
+                    direction: conn.direction,
+                    connected_secs: conn.since.elapsed().as_secs(),
                 })
                 .collect();
             let _ = response_tx.send(peers).await;
         }
-        P2PCommand::DhtPut { key, value, response_tx } => {
-            let record = libp2p::kad::Record {
-                key: libp2p::kad::RecordKey::new(&key),
-                value,
-                publisher: None,
-                expires: Some(std::time::Instant::now() + std::time::Duration::from_secs(72 * 3600)),
+        P2PCommand::PublishBulletin { bulletin, response_tx } => {
+            let reply = serde_json::to_vec(&Announcement::Bulletin(bulletin))
+                .map_err(|e| format!("Failed to encode bulletin: {}", e))
+                .and_then(|data| {
+                    let topic = libp2p::gossipsub::IdentTopic::new(&state.protocols.announce_topic);
+                    match swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                        Ok(_) => Ok(()),
+                        Err(libp2p::gossipsub::PublishError::InsufficientPeers) => {
+                            Err("No peers to publish to".to_string())
+                        }
+                        Err(e) => Err(format!("Failed to publish bulletin: {}", e)),
+                    }
+                });
+            let _ = response_tx.send(reply).await;
+        }
+        P2PCommand::GetStatus { response_tx } => {
+            // AutoNAT is off behind Tor, where the status stays unknown
+            let autonat = swarm.behaviour().autonat.as_ref();
+            let status = NodeStatus {
+                listeners: swarm.listeners().map(|a| a.to_string()).collect(),
+                connected_peers: swarm.network_info().num_peers(),
+                nat_status: autonat.map(|a| NatStatus::from(&a.nat_status())).unwrap_or(NatStatus::Unknown),
+                public_address: autonat.and_then(|a| a.public_address()).map(|a| a.to_string()),
             };
+            let _ = response_tx.send(status).await;
+        }
+        P2PCommand::DhtPut { key, value, response_tx } => {
+            let record = dht_record(db, key, value);
             match swarm.behaviour_mut().kademlia.put_record(record, libp2p::kad::Quorum::One) {
-                Ok(_) => { let _ = response_tx.send(Ok(())).await; }
+                Ok(query_id) => {
+                    state.pending_dht_puts.insert(query_id);
+                    let _ = response_tx.send(Ok(())).await;
+                }
                 Err(e) => { let _ = response_tx.send(Err(format!("DHT put error: {:?}", e))).await; }
             }
         }
         P2PCommand::DhtGet { key, response_tx } => {
-            let _query_id = swarm.behaviour_mut().kademlia.get_record(
+            let query_id = swarm.behaviour_mut().kademlia.get_record(
+                libp2p::kad::RecordKey::new(&key),
+            );
+            // Answered when the query reports its result
+            state.pending_dht_gets.insert(query_id, DhtGetResponder::First(response_tx));
+        }
+        P2PCommand::DhtGetAll { key, response_tx } => {
+            let query_id = swarm.behaviour_mut().kademlia.get_record(
                 libp2p::kad::RecordKey::new(&key),
             );
-            // In a full implementation, we'd track the query and return the result
-            // For now, just acknowledge
-            let _ = response_tx.send(Ok(None)).await;
+            // Answered on the query's last step
+            state.pending_dht_gets.insert(query_id, DhtGetResponder::All(Vec::new(), response_tx));
         }
+        // Needs the identity for the final republish, so the event loop handles it
+        P2PCommand::Shutdown { .. } => {}
+    }
+}
+
+/// A DHT record that expires after the `dht_ttl_hours` setting
+fn dht_record(db: &Database, key: Vec<u8>, value: Vec<u8>) -> libp2p::kad::Record {
+    libp2p::kad::Record {
+        key: libp2p::kad::RecordKey::new(&key),
+        value,
+        publisher: None,
+        expires: Some(Instant::now() + crate::dht::store::record_ttl(db)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::envelope::encrypt_message;
+
+    fn temp_db(name: &str) -> (Database, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        (Database::open(&dir).unwrap(), dir)
+    }
+
+    const MAX: usize = DEFAULT_MAX_MESSAGE_BYTES as usize;
+
+    fn envelope_json(sender: &LedgerIdentity, recipient: &LedgerIdentity) -> String {
+        let env = encrypt_message(
+            sender,
+            &recipient.ledger_id,
+            &recipient.encryption_public_bytes(),
+            &OutgoingContent::text("Subject", "Body"),
+        ).unwrap();
+        serde_json::to_string(&env).unwrap()
+    }
+
+    #[test]
+    fn test_dht_record_expiry_follows_ttl_setting() {
+        let (db, dir) = temp_db("ledger_test_node_dht_ttl");
+        let expires_in = |db: &Database| {
+            let record = dht_record(db, b"key".to_vec(), b"value".to_vec());
+            record.expires.unwrap().duration_since(Instant::now()).as_secs()
+        };
+
+        db.set_setting("dht_ttl_hours", "1").unwrap();
+        assert!((3590..=3600).contains(&expires_in(&db)));
+
+        // Unparsable values fall back to the default
+        db.set_setting("dht_ttl_hours", "soon").unwrap();
+        assert!((71 * 3600..=72 * 3600).contains(&expires_in(&db)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_duplicate_envelope_rejected() {
+        let (db, dir) = temp_db("ledger_test_node_replay");
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let json = envelope_json(&sender, &recipient);
+
+        let (events, _) = broadcast::channel(8);
+        assert!(accept_envelope(&recipient, &db, &events, &json, MAX, &mut VerifyingKeyCache::default(), &Metrics::new()).accepted);
+
+        let replayed = accept_envelope(&recipient, &db, &events, &json, MAX, &mut VerifyingKeyCache::default(), &Metrics::new());
+        assert!(!replayed.accepted);
+        assert_eq!(replayed.error.as_deref(), Some("replay/stale"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_envelope_id_taken_by_another_sender() {
+        let (db, dir) = temp_db("ledger_test_node_id_squatting");
+        let sender = LedgerIdentity::generate().unwrap();
+        let squatter = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let json = envelope_json(&sender, &recipient);
+        let env: EncryptedEnvelope = serde_json::from_str(&json).unwrap();
+
+        // Someone who read the id off the DHT got a message in under it first
+        let mut squatted = Message::new(squatter.ledger_id.clone(), recipient.ledger_id.clone(), "Subject".into(), "Body".into());
+        squatted.id = env.id.clone();
+        db.insert_message(&squatted).unwrap();
+
+        assert_eq!(replay_rejection(&db, &env, env.timestamp), Some("envelope id taken by another sender"));
+        let (events, _) = broadcast::channel(8);
+        let response = accept_envelope(&recipient, &db, &events, &json, MAX, &mut VerifyingKeyCache::default(), &Metrics::new());
+        assert!(!response.accepted);
+        assert_eq!(db.get_message(&env.id).unwrap().unwrap().from_id, squatter.ledger_id);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_envelope_not_accepted_when_store_fails() {
+        let (db, dir) = temp_db("ledger_test_node_store_failure");
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        rusqlite::Connection::open(dir.join("ledger.db")).unwrap()
+            .execute_batch("CREATE TRIGGER full BEFORE INSERT ON messages BEGIN SELECT RAISE(FAIL, 'disk full'); END;").unwrap();
+
+        let (events, _) = broadcast::channel(8);
+        let response = accept_envelope(&recipient, &db, &events, &envelope_json(&sender, &recipient), MAX, &mut VerifyingKeyCache::default(), &Metrics::new());
+        assert!(!response.accepted);
+        assert_eq!(response.error.as_deref(), Some("failed to store message"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_legacy_envelope_refused() {
+        let (db, dir) = temp_db("ledger_test_node_legacy_envelope");
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let mut env: EncryptedEnvelope = serde_json::from_str(&envelope_json(&sender, &recipient)).unwrap();
+        env.version = envelope::ENVELOPE_VERSION_LEGACY;
+
+        let (events, _) = broadcast::channel(8);
+        let response = accept_envelope(&recipient, &db, &events, &serde_json::to_string(&env).unwrap(), MAX, &mut VerifyingKeyCache::default(), &Metrics::new());
+        assert!(!response.accepted);
+        assert_eq!(response.error.as_deref(), legacy_rejection(&env));
+        assert!(db.get_message(&env.id).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_receive_policy() {
+        let (db, dir) = temp_db("ledger_test_node_receive_policy");
+        let friend = LedgerIdentity::generate().unwrap();
+        let stranger = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let (events, _) = broadcast::channel(8);
+        let accept = |sender: &LedgerIdentity| {
+            accept_envelope(&recipient, &db, &events, &envelope_json(sender, &recipient), MAX, &mut VerifyingKeyCache::default(), &Metrics::new())
+        };
+
+        // Open by default
+        assert!(accept(&stranger).accepted);
+
+        db.upsert_contact(&Contact {
+            ledger_id: friend.ledger_id.clone(),
+            public_key: String::new(),
+            encryption_public_key: None,
+            display_name: None,
+            gmail_address: None,
+            verified: false,
+            alias: None,
+            preferred_delivery: None,
+        }).unwrap();
+        db.set_setting("receive_policy", "contacts_only").unwrap();
+        let rejected = accept(&stranger);
+        assert!(!rejected.accepted);
+        assert_eq!(rejected.error.as_deref(), Some("not in contacts"));
+        assert!(accept(&friend).accepted);
+
+        let peer_id = friend.libp2p_keypair().unwrap().public().to_peer_id();
+        db.block(&friend.ledger_id, &peer_id.to_string()).unwrap();
+        assert_eq!(accept(&friend).error.as_deref(), Some("blocked"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bulletin_stored_once() {
+        let (db, dir) = temp_db("ledger_test_node_bulletin");
+        let author = LedgerIdentity::generate().unwrap();
+        let local = LedgerIdentity::generate().unwrap();
+        let (events, mut rx) = broadcast::channel(8);
+
+        let bulletin = Bulletin::new(&author, "News", "Hello everyone");
+        accept_bulletin(&local, &db, &events, &bulletin);
+        accept_bulletin(&local, &db, &events, &bulletin);
+        assert!(matches!(rx.try_recv(), Ok(MessageEvent::NewMessage { id }) if id == bulletin.id));
+        assert!(rx.try_recv().is_err());
+
+        let stored = db.get_message(&bulletin.id).unwrap().unwrap();
+        assert_eq!(stored.folder, Folder::Broadcast);
+        assert_eq!(stored.from_id, author.ledger_id);
+
+        let mut forged = Bulletin::new(&author, "News", "Hello again");
+        forged.body = "Send me your keys".into();
+        accept_bulletin(&local, &db, &events, &forged);
+        assert!(db.get_message(&forged.id).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_oversized_envelope_rejected() {
+        let (db, dir) = temp_db("ledger_test_node_oversized");
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let json = envelope_json(&sender, &recipient);
+
+        let (events, _) = broadcast::channel(8);
+        let response = accept_envelope(&recipient, &db, &events, &json, json.len() - 1, &mut VerifyingKeyCache::default(), &Metrics::new());
+        assert!(!response.accepted);
+        assert!(db.query_messages(&MessageFilter::default(), 10, 0).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stale_and_future_timestamps_rejected() {
+        let (db, dir) = temp_db("ledger_test_node_stale");
+        let sender = LedgerIdentity::generate().unwrap();
+        let recipient = LedgerIdentity::generate().unwrap();
+        let mut env: EncryptedEnvelope =
+            serde_json::from_str(&envelope_json(&sender, &recipient)).unwrap();
+        let now = env.timestamp;
+
+        assert_eq!(replay_rejection(&db, &env, now), None);
+
+        env.timestamp = now - 73 * 3600;
+        assert!(replay_rejection(&db, &env, now).is_some());
+
+        env.timestamp = now + MAX_FUTURE_SKEW_SECS + 1;
+        assert!(replay_rejection(&db, &env, now).is_some());
+
+        // A huge window saturates rather than overflowing
+        db.set_setting("replay_window_hours", &i64::MAX.to_string()).unwrap();
+        env.timestamp = now - 73 * 3600;
+        assert_eq!(replay_rejection(&db, &env, now), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_receipt_recorded_once() {
+        let (db, dir) = temp_db("ledger_test_node_receipt");
+        let sender = LedgerIdentity::generate().unwrap();
+        let reader = LedgerIdentity::generate().unwrap();
+        let stranger = LedgerIdentity::generate().unwrap();
+        db.expect_read_receipt("env-1", "msg-1", &reader.ledger_id).unwrap();
+
+        let (events, mut rx) = broadcast::channel(8);
+        // Only the recipient the envelope went to can confirm it
+        let wrong_reader = ReadReceipt::new(&stranger, "env-1", &sender.ledger_id);
+        assert!(!accept_receipt(&sender, &db, &events, &wrong_reader).accepted);
+
+        let receipt = ReadReceipt::new(&reader, "env-1", &sender.ledger_id);
+        assert!(accept_receipt(&sender, &db, &events, &receipt).accepted);
+        assert!(matches!(rx.try_recv(), Ok(MessageEvent::MessageRead { id, .. }) if id == "msg-1"));
+        assert!(!accept_receipt(&sender, &db, &events, &receipt).accepted);
+
+        let receipts = db.get_read_receipts("msg-1").unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].read_at, Some(receipt.read_at));
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
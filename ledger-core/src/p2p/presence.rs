@@ -0,0 +1,174 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::crypto::keys::LedgerIdentity;
+
+/// Gossipsub topic presence announcements are published on
+pub const ANNOUNCE_TOPIC: &str = "ledger-announce";
+
+/// How often a node announces itself
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Announcements older than this (or this far ahead of our clock) are ignored
+const MAX_PRESENCE_AGE_SECS: i64 = 15 * 60;
+
+/// A contact missing three announcements in a row counts as offline
+const PRESENCE_TTL: Duration = PRESENCE_INTERVAL.saturating_mul(3);
+
+/// Signed "I'm online" announcement mapping a Ledger ID to the peer serving it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub ledger_id: String,
+    pub peer_id: String,
+    pub listen_addrs: Vec<String>,
+    pub timestamp: i64,
+    /// Base64 Ed25519 signature by the Ledger ID's key over every other field
+    pub signature: String,
+}
+
+impl Presence {
+    /// Build and sign an announcement for this node
+    pub fn new(identity: &LedgerIdentity, peer_id: PeerId, listen_addrs: Vec<String>) -> Self {
+        let mut presence = Self {
+            ledger_id: identity.ledger_id.clone(),
+            peer_id: peer_id.to_string(),
+            listen_addrs,
+            timestamp: chrono::Utc::now().timestamp(),
+            signature: String::new(),
+        };
+        presence.signature = BASE64.encode(identity.sign(&presence.signing_payload()));
+        presence
+    }
+
+    /// Check the announcement is fresh, signed by the Ledger ID's key and
+    /// names the PeerId that key yields
+    pub fn verify(&self, now: i64) -> Result<(), String> {
+        if (now - self.timestamp).abs() > MAX_PRESENCE_AGE_SECS {
+            return Err("stale timestamp".into());
+        }
+
+        let pubkey = LedgerIdentity::pubkey_from_ledger_id(&self.ledger_id)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        let signature = BASE64.decode(&self.signature)
+            .map_err(|e| format!("invalid signature encoding: {}", e))?;
+        match LedgerIdentity::verify(&pubkey, &self.signing_payload(), &signature) {
+            Ok(true) => {}
+            Ok(false) => return Err("bad signature".into()),
+            Err(e) => return Err(format!("bad signature: {}", e)),
+        }
+
+        let expected = LedgerIdentity::peer_id_from_ledger_id(&self.ledger_id)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        if expected.to_string() != self.peer_id {
+            return Err("peer ID does not belong to the Ledger ID".into());
+        }
+        Ok(())
+    }
+
+    /// Domain tag followed by each field length-prefixed, as for envelopes
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut out = b"ledger-presence".to_vec();
+        let fields = [&self.ledger_id, &self.peer_id].into_iter().chain(&self.listen_addrs);
+        out.extend_from_slice(&(self.listen_addrs.len() as u32).to_be_bytes());
+        for field in fields {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        }
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out
+    }
+}
+
+/// Contacts currently online, by Ledger ID, with when they last announced
+/// themselves. Shared between the P2P node, which updates it, and the API.
+#[derive(Default)]
+pub struct OnlineContacts {
+    last_seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl OnlineContacts {
+    /// Note an announcement; true if the contact wasn't online before
+    pub fn seen(&self, ledger_id: &str, now: Instant) -> bool {
+        self.lock().insert(ledger_id.to_string(), now).is_none()
+    }
+
+    /// Mark a contact offline; true if it was online
+    pub fn gone(&self, ledger_id: &str) -> bool {
+        self.lock().remove(ledger_id).is_some()
+    }
+
+    /// Mark offline, and return, every contact silent for longer than `PRESENCE_TTL`
+    pub fn expire(&self, now: Instant) -> Vec<String> {
+        let mut last_seen = self.lock();
+        let stale: Vec<String> = last_seen.iter()
+            .filter(|(_, seen)| now.saturating_duration_since(**seen) > PRESENCE_TTL)
+            .map(|(ledger_id, _)| ledger_id.clone())
+            .collect();
+        for ledger_id in &stale {
+            last_seen.remove(ledger_id);
+        }
+        stale
+    }
+
+    /// Ledger IDs of the contacts online now
+    pub fn online(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.last_seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence(identity: &LedgerIdentity) -> Presence {
+        let peer_id = LedgerIdentity::peer_id_from_ledger_id(&identity.ledger_id).unwrap();
+        Presence::new(identity, peer_id, vec!["/ip4/192.0.2.1/tcp/4001".into()])
+    }
+
+    #[test]
+    fn test_presence_verifies() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let presence = presence(&identity);
+        assert!(presence.verify(presence.timestamp).is_ok());
+        assert!(presence.verify(presence.timestamp + MAX_PRESENCE_AGE_SECS + 1).is_err());
+    }
+
+    #[test]
+    fn test_tampered_presence_rejected() {
+        let identity = LedgerIdentity::generate().unwrap();
+        let mut redirected = presence(&identity);
+        redirected.listen_addrs = vec!["/ip4/203.0.113.9/tcp/4001".into()];
+        assert!(redirected.verify(redirected.timestamp).is_err());
+
+        // A validly signed announcement can't claim someone else's PeerId
+        let other = LedgerIdentity::generate().unwrap();
+        let other_peer = LedgerIdentity::peer_id_from_ledger_id(&other.ledger_id).unwrap();
+        let spoofed = Presence::new(&identity, other_peer, vec![]);
+        assert!(spoofed.verify(spoofed.timestamp).is_err());
+    }
+
+    #[test]
+    fn test_online_contacts_come_and_go() {
+        let online = OnlineContacts::default();
+        let start = Instant::now();
+        assert!(online.seen("ledger:a", start));
+        assert!(!online.seen("ledger:a", start + PRESENCE_INTERVAL));
+        assert!(online.seen("ledger:b", start));
+
+        // b stopped announcing; a's second announcement keeps it online
+        assert_eq!(online.expire(start + PRESENCE_TTL + Duration::from_secs(1)), vec!["ledger:b".to_string()]);
+        assert_eq!(online.online(), vec!["ledger:a".to_string()]);
+
+        assert!(online.gone("ledger:a"));
+        assert!(!online.gone("ledger:a"));
+        assert!(online.online().is_empty());
+    }
+}
@@ -1,12 +1,131 @@
+use libp2p::StreamProtocol;
 use serde::{Deserialize, Serialize};
 
+use super::bulletin::Bulletin;
+use super::presence::{Presence, ANNOUNCE_TOPIC};
+use super::receipt::ReadReceipt;
+use crate::error::{LedgerError, Result};
+
 /// Protocol name for Ledger message exchange
-pub const PROTOCOL_NAME: libp2p::StreamProtocol = libp2p::StreamProtocol::new("/ledger/msg/1.0.0");
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ledger/msg/1.0.0");
+
+/// Protocol name for fetching attachments chunk by chunk
+pub const FILE_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ledger/file/1.0.0");
+
+/// Network whose protocols keep the names from before network IDs
+pub const DEFAULT_NETWORK_ID: &str = "mainnet";
+
+/// Longest network ID accepted by `--network-id`
+const MAX_NETWORK_ID_LEN: usize = 32;
+
+/// The protocols a node speaks, namespaced by network ID so nodes on
+/// different networks never exchange messages, gossip or DHT records
+#[derive(Debug, Clone)]
+pub struct Protocols {
+    pub network_id: String,
+    /// Direct message exchange
+    pub messaging: StreamProtocol,
+    /// Attachment chunk transfer
+    pub files: StreamProtocol,
+    pub identify: String,
+    pub kademlia: StreamProtocol,
+    /// Gossipsub topic for presence and bulletins
+    pub announce_topic: String,
+}
+
+impl Default for Protocols {
+    fn default() -> Self {
+        Self {
+            network_id: DEFAULT_NETWORK_ID.to_string(),
+            messaging: PROTOCOL_NAME,
+            files: FILE_PROTOCOL_NAME,
+            identify: "/ledger/id/1.0.0".to_string(),
+            kademlia: libp2p::kad::PROTOCOL_NAME,
+            announce_topic: ANNOUNCE_TOPIC.to_string(),
+        }
+    }
+}
+
+impl Protocols {
+    /// Protocol names for `network_id`, which may hold lowercase letters,
+    /// digits, `-` and `_`
+    pub fn for_network(network_id: &str) -> Result<Self> {
+        if network_id == DEFAULT_NETWORK_ID {
+            return Ok(Self::default());
+        }
+        let valid = !network_id.is_empty()
+            && network_id.len() <= MAX_NETWORK_ID_LEN
+            && network_id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid {
+            return Err(LedgerError::config(format!(
+                "Invalid network ID {:?}: use up to {} lowercase letters, digits, '-' or '_'",
+                network_id, MAX_NETWORK_ID_LEN,
+            )));
+        }
+        let protocol = |name: &str| StreamProtocol::try_from_owned(format!("/ledger/{}/{}/1.0.0", network_id, name))
+            .map_err(|e| LedgerError::config(format!("Invalid network ID {:?}: {}", network_id, e)));
+        Ok(Self {
+            network_id: network_id.to_string(),
+            messaging: protocol("msg")?,
+            files: protocol("file")?,
+            identify: format!("/ledger/{}/id/1.0.0", network_id),
+            kademlia: protocol("kad")?,
+            announce_topic: format!("{}/{}", ANNOUNCE_TOPIC, network_id),
+        })
+    }
+}
 
-/// Request sent from one Ledger peer to another
+/// Where a peer's Identify info puts it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerNetwork {
+    /// A Ledger node on our network
+    Ours,
+    /// A Ledger node on another network, which we don't keep connections to
+    Other,
+    /// Not a Ledger node, such as a plain relay
+    NotLedger,
+}
+
+impl Protocols {
+    /// Which network a peer is on, from its Identify protocol version and
+    /// the protocols it speaks. Ours needs both to match.
+    pub fn network_of(&self, info: &libp2p::identify::Info) -> PeerNetwork {
+        if info.protocol_version == self.identify && info.protocols.contains(&self.messaging) {
+            PeerNetwork::Ours
+        } else if info.protocol_version.starts_with("/ledger/")
+            || ledger_id_from_agent_version(&info.agent_version).is_some()
+        {
+            PeerNetwork::Other
+        } else {
+            PeerNetwork::NotLedger
+        }
+    }
+}
+
+/// Identify agent version advertising the node's Ledger ID: `ledger-core/{version}/{ledger_id}`
+pub fn agent_version(ledger_id: &str) -> String {
+    format!("ledger-core/{}/{}", env!("CARGO_PKG_VERSION"), ledger_id)
+}
+
+/// Extract the Ledger ID from a peer's Identify agent version, if it is a Ledger node
+pub fn ledger_id_from_agent_version(agent_version: &str) -> Option<String> {
+    let mut parts = agent_version.splitn(3, '/');
+    if parts.next()? != "ledger-core" {
+        return None;
+    }
+    let _version = parts.next()?;
+    parts.next()
+        .filter(|id| id.starts_with("ledger:"))
+        .map(String::from)
+}
+
+/// Request sent from one Ledger peer to another. Untagged, so envelopes keep
+/// the `{envelope_json}` shape nodes without receipts send and expect.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LedgerRequest {
-    pub envelope_json: String,
+#[serde(untagged)]
+pub enum LedgerRequest {
+    Envelope { envelope_json: String },
+    Receipt { receipt: ReadReceipt },
 }
 
 /// Response after receiving a message
@@ -15,3 +134,117 @@ pub struct LedgerResponse {
     pub accepted: bool,
     pub error: Option<String>,
 }
+
+impl LedgerResponse {
+    pub fn accepted() -> Self {
+        Self { accepted: true, error: None }
+    }
+
+    pub fn rejected(error: impl Into<String>) -> Self {
+        Self { accepted: false, error: Some(error.into()) }
+    }
+}
+
+/// Request for one chunk of an attachment, named by its hash and the id of
+/// the transfer key the requester holds for it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileRequest {
+    pub sha256: String,
+    pub key_id: String,
+    pub chunk: u32,
+}
+
+/// A chunk sealed under the attachment's transfer key, or word that the
+/// peer doesn't have the attachment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileResponse {
+    Chunk {
+        /// Base64 of the chunk's nonce and ciphertext
+        data: String,
+        /// How many chunks the whole attachment takes
+        chunks: u32,
+    },
+    NotFound,
+    /// The peer won't serve us: we're blocked or asking too fast
+    Refused { reason: String },
+}
+
+/// Payload of a message on the `ledger-announce` gossipsub topic,
+/// tagged by `type` so new kinds can be added without confusing old nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Announcement {
+    Presence(Presence),
+    Bulletin(Bulletin),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_version_roundtrip() {
+        let id = "ledger:3k9abc";
+        assert_eq!(ledger_id_from_agent_version(&agent_version(id)).as_deref(), Some(id));
+        assert_eq!(ledger_id_from_agent_version("rust-libp2p/0.44.0"), None);
+        assert_eq!(ledger_id_from_agent_version("ledger-core/0.1.0/not-an-id"), None);
+    }
+
+    #[test]
+    fn test_announcement_tagged_by_type() {
+        let json = r#"{"type":"presence","ledger_id":"ledger:x","peer_id":"p","listen_addrs":[],"timestamp":0,"signature":""}"#;
+        assert!(matches!(serde_json::from_str::<Announcement>(json), Ok(Announcement::Presence(_))));
+        assert!(serde_json::from_str::<Announcement>(r#"{"type":"unknown"}"#).is_err());
+    }
+
+    #[test]
+    fn test_network_id_namespaces_protocols() {
+        let mainnet = Protocols::for_network(DEFAULT_NETWORK_ID).unwrap();
+        assert_eq!(mainnet.messaging.as_ref(), "/ledger/msg/1.0.0");
+        assert_eq!(mainnet.files.as_ref(), "/ledger/file/1.0.0");
+        assert_eq!(mainnet.identify, "/ledger/id/1.0.0");
+        assert_eq!(mainnet.kademlia.as_ref(), "/ipfs/kad/1.0.0");
+        assert_eq!(mainnet.announce_topic, ANNOUNCE_TOPIC);
+
+        let testnet = Protocols::for_network("testnet").unwrap();
+        assert_eq!(testnet.messaging.as_ref(), "/ledger/testnet/msg/1.0.0");
+        assert_eq!(testnet.files.as_ref(), "/ledger/testnet/file/1.0.0");
+        assert_eq!(testnet.identify, "/ledger/testnet/id/1.0.0");
+        assert_eq!(testnet.kademlia.as_ref(), "/ledger/testnet/kad/1.0.0");
+        assert_eq!(testnet.announce_topic, "ledger-announce/testnet");
+
+        for bad in ["", "Test", "a/b", "net work", &"x".repeat(MAX_NETWORK_ID_LEN + 1)] {
+            assert!(Protocols::for_network(bad).is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_network_of_identified_peer() {
+        let mainnet = Protocols::default();
+        let testnet = Protocols::for_network("testnet").unwrap();
+        let info = |protocols: &Protocols, agent_version: &str| libp2p::identify::Info {
+            public_key: libp2p::identity::Keypair::generate_ed25519().public(),
+            protocol_version: protocols.identify.clone(),
+            agent_version: agent_version.to_string(),
+            listen_addrs: vec![],
+            protocols: vec![protocols.messaging.clone(), protocols.files.clone()],
+            observed_addr: "/ip4/127.0.0.1/tcp/9420".parse().unwrap(),
+        };
+        let ledger = agent_version("ledger:3k9abc");
+
+        assert_eq!(mainnet.network_of(&info(&mainnet, &ledger)), PeerNetwork::Ours);
+        assert_eq!(mainnet.network_of(&info(&testnet, &ledger)), PeerNetwork::Other);
+        assert_eq!(testnet.network_of(&info(&mainnet, &ledger)), PeerNetwork::Other);
+
+        // The right version alone isn't enough
+        let mut silent = info(&mainnet, &ledger);
+        silent.protocols.clear();
+        assert_eq!(mainnet.network_of(&silent), PeerNetwork::Other);
+
+        let mut relay = info(&mainnet, "rust-libp2p/0.44.0");
+        relay.protocol_version = "/ipfs/0.1.0".into();
+        relay.protocols = vec![StreamProtocol::new("/libp2p/circuit/relay/0.2.0/hop")];
+        assert_eq!(mainnet.network_of(&relay), PeerNetwork::NotLedger);
+    }
+}
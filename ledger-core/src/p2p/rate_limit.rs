@@ -0,0 +1,99 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Inbound messages a peer may send per minute when `peer_rate_limit` is unset
+pub const DEFAULT_PEER_RATE_LIMIT: u32 = 60;
+
+/// Buckets untouched for this long are full again and can be dropped
+const IDLE_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket per peer: each holds up to `per_minute` tokens and refills
+/// continuously at `per_minute` tokens a minute. A limit of 0 disables it.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: HashMap<PeerId, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self { per_minute, buckets: HashMap::new() }
+    }
+
+    /// Take a token for `peer`, returning false if its budget is spent
+    pub fn check(&mut self, peer: PeerId, now: Instant) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        let capacity = self.per_minute as f64;
+        let bucket = self.buckets.entry(peer).or_insert(Bucket { tokens: capacity, last_refill: now });
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget peers that have been quiet long enough to have refilled
+    pub fn expire_idle(&mut self, now: Instant) {
+        self.buckets.retain(|_, b| now.saturating_duration_since(b.last_refill) < IDLE_EXPIRY);
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_PEER_RATE_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_spent_then_refills() {
+        let mut limiter = RateLimiter::new(2);
+        let peer = PeerId::random();
+        let start = Instant::now();
+
+        assert!(limiter.check(peer, start));
+        assert!(limiter.check(peer, start));
+        assert!(!limiter.check(peer, start));
+        // Another peer has its own bucket
+        assert!(limiter.check(PeerId::random(), start));
+
+        // Two per minute: one token back after 30s
+        assert!(limiter.check(peer, start + Duration::from_secs(30)));
+        assert!(!limiter.check(peer, start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_idle_buckets_expire() {
+        let mut limiter = RateLimiter::new(1);
+        let start = Instant::now();
+        limiter.check(PeerId::random(), start);
+
+        limiter.expire_idle(start + Duration::from_secs(60));
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.expire_idle(start + IDLE_EXPIRY);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_zero_disables_limit() {
+        let mut limiter = RateLimiter::new(0);
+        let peer = PeerId::random();
+        assert!((0..1000).all(|_| limiter.check(peer, Instant::now())));
+    }
+}
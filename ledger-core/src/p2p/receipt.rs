@@ -0,0 +1,79 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::keys::LedgerIdentity;
+
+/// Signed notice that a recipient opened a message whose envelope asked for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadReceipt {
+    /// Id of the envelope that was read
+    pub envelope_id: String,
+    /// Ledger ID of the reader, whose key signs the receipt
+    pub reader: String,
+    /// Ledger ID of the original sender the receipt is for
+    pub sender: String,
+    pub read_at: i64,
+    /// Base64 Ed25519 signature by the reader's key over every other field
+    pub signature: String,
+}
+
+impl ReadReceipt {
+    /// Build and sign a receipt for `envelope_id`, received from `sender`
+    pub fn new(identity: &LedgerIdentity, envelope_id: &str, sender: &str) -> Self {
+        let mut receipt = Self {
+            envelope_id: envelope_id.to_string(),
+            reader: identity.ledger_id.clone(),
+            sender: sender.to_string(),
+            read_at: chrono::Utc::now().timestamp(),
+            signature: String::new(),
+        };
+        receipt.signature = BASE64.encode(identity.sign(&receipt.signing_payload()));
+        receipt
+    }
+
+    /// Check the receipt is signed by the reader's key
+    pub fn verify(&self) -> Result<(), String> {
+        let pubkey = LedgerIdentity::pubkey_from_ledger_id(&self.reader)
+            .map_err(|e| format!("invalid Ledger ID: {}", e))?;
+        let signature = BASE64.decode(&self.signature)
+            .map_err(|e| format!("invalid signature encoding: {}", e))?;
+        match LedgerIdentity::verify(&pubkey, &self.signing_payload(), &signature) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("bad signature".into()),
+            Err(e) => Err(format!("bad signature: {}", e)),
+        }
+    }
+
+    /// Domain tag followed by each field length-prefixed, as for envelopes
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut out = b"ledger-receipt".to_vec();
+        for field in [&self.envelope_id, &self.reader, &self.sender] {
+            out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            out.extend_from_slice(field.as_bytes());
+        }
+        out.extend_from_slice(&self.read_at.to_be_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipt_verifies_and_rejects_tampering() {
+        let reader = LedgerIdentity::generate().unwrap();
+        let sender = LedgerIdentity::generate().unwrap();
+        let receipt = ReadReceipt::new(&reader, "env-1", &sender.ledger_id);
+        assert!(receipt.verify().is_ok());
+
+        let mut other_message = receipt.clone();
+        other_message.envelope_id = "env-2".into();
+        assert!(other_message.verify().is_err());
+
+        // Someone else can't claim to be the reader
+        let mut forged = receipt;
+        forged.reader = sender.ledger_id.clone();
+        assert!(forged.verify().is_err());
+    }
+}
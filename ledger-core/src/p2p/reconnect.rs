@@ -0,0 +1,98 @@
+use libp2p::PeerId;
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Wait before the first redial after a connection drops
+const INITIAL_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest wait between redials
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+struct Backoff {
+    /// Redials made so far without reconnecting
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Redial schedule for peers whose connection dropped. Each wait doubles up
+/// to `MAX_DELAY`, with jitter so peers that lost the same link don't all
+/// redial together.
+#[derive(Default)]
+pub struct Reconnects {
+    peers: HashMap<PeerId, Backoff>,
+}
+
+impl Reconnects {
+    /// Start redialling `peer`, unless it is already scheduled
+    pub fn schedule(&mut self, peer: PeerId, now: Instant) {
+        self.peers.entry(peer).or_insert_with(|| Backoff {
+            attempts: 0,
+            next_attempt: now + jittered(INITIAL_DELAY),
+        });
+    }
+
+    /// Peers due a redial, each moved on to its next, longer wait
+    pub fn due(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut due = Vec::new();
+        for (peer, backoff) in &mut self.peers {
+            if backoff.next_attempt <= now {
+                backoff.attempts += 1;
+                backoff.next_attempt = now + jittered(delay(backoff.attempts));
+                due.push(*peer);
+            }
+        }
+        due
+    }
+
+    /// Stop redialling `peer`: it reconnected or shouldn't be reconnected to
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+}
+
+/// Wait after `attempts` failed redials, before jitter
+fn delay(attempts: u32) -> Duration {
+    INITIAL_DELAY.saturating_mul(2u32.saturating_pow(attempts)).min(MAX_DELAY)
+}
+
+/// Somewhere between half and all of `delay`
+fn jittered(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_doubles_up_to_cap() {
+        assert_eq!(delay(0), INITIAL_DELAY);
+        assert_eq!(delay(1), INITIAL_DELAY * 2);
+        assert_eq!(delay(3), INITIAL_DELAY * 8);
+        assert_eq!(delay(20), MAX_DELAY);
+        assert_eq!(delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_due_peers_back_off_until_removed() {
+        let mut reconnects = Reconnects::default();
+        let peer = PeerId::random();
+        let start = Instant::now();
+        reconnects.schedule(peer, start);
+
+        assert!(reconnects.due(start).is_empty());
+        let first = start + INITIAL_DELAY;
+        assert_eq!(reconnects.due(first), vec![peer]);
+        // Not again before half the doubled delay has passed
+        assert!(reconnects.due(first + INITIAL_DELAY / 2).is_empty());
+        assert_eq!(reconnects.due(first + INITIAL_DELAY * 2), vec![peer]);
+
+        // A second disconnect doesn't reset the backoff
+        reconnects.schedule(peer, start);
+        assert_eq!(reconnects.peers[&peer].attempts, 2);
+
+        reconnects.remove(&peer);
+        assert!(reconnects.due(start + MAX_DELAY).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use libp2p::PeerId;
+use std::time::Instant;
+
+use super::protocol::{FileRequest, FileResponse};
+use super::rate_limit::RateLimiter;
+use crate::crypto::attachment::{self, CHUNK_SIZE, MAX_ATTACHMENT_BYTES};
+use crate::error::{LedgerError, Result};
+use crate::store::db::Database;
+
+/// Chunk requests a peer may make per minute: a 64 MiB attachment in under
+/// half a minute, without sharing the budget for messages
+pub const CHUNK_RATE_LIMIT: u32 = 600;
+
+/// Answer a chunk request from `peer`, unless it is blocked or has spent its
+/// `limiter` budget
+pub fn answer_chunk_request(
+    db: &Database,
+    limiter: &mut RateLimiter,
+    peer: PeerId,
+    request: &FileRequest,
+    now: Instant,
+) -> FileResponse {
+    if db.is_peer_blocked(&peer.to_string()).unwrap_or(true) {
+        return FileResponse::Refused { reason: "blocked".into() };
+    }
+    if !limiter.check(peer, now) {
+        return FileResponse::Refused { reason: "rate limited".into() };
+    }
+    serve_chunk(db, request)
+}
+
+/// Answer a peer's request for a chunk of an attachment we hold in full.
+/// Anyone with the hash and key id may ask, but only holders of the transfer
+/// key can read what comes back.
+pub fn serve_chunk(db: &Database, request: &FileRequest) -> FileResponse {
+    let offset = request.chunk as u64 * CHUNK_SIZE as u64;
+    let chunk = match db.attachment_chunk(&request.sha256, &request.key_id, offset, CHUNK_SIZE) {
+        Ok(Some(found)) => found,
+        Ok(None) => return FileResponse::NotFound,
+        Err(e) => {
+            tracing::error!("Failed to read attachment {}: {}", request.sha256, e);
+            return FileResponse::NotFound;
+        }
+    };
+    let Some(chunks) = attachment::chunk_count(chunk.size).filter(|&chunks| request.chunk < chunks) else {
+        return FileResponse::NotFound;
+    };
+    match attachment::seal_chunk(&chunk.transfer_key, &request.sha256, request.chunk, &chunk.data) {
+        Ok(sealed) => FileResponse::Chunk { data: BASE64.encode(sealed), chunks },
+        Err(e) => {
+            tracing::error!("Failed to encrypt chunk {} of {}: {}", request.chunk, request.sha256, e);
+            FileResponse::NotFound
+        }
+    }
+}
+
+/// An attachment being fetched from its sender one chunk at a time
+#[derive(Debug)]
+pub struct Download {
+    sha256: String,
+    key: Vec<u8>,
+    size: u64,
+    chunks: u32,
+    data: Vec<u8>,
+    next_chunk: u32,
+}
+
+impl Download {
+    /// Start fetching an attachment of `size` bytes, which must be within
+    /// `MAX_ATTACHMENT_BYTES`
+    pub fn new(sha256: String, key: Vec<u8>, size: u64) -> Result<Self> {
+        let chunks = attachment::chunk_count(size).filter(|_| size <= MAX_ATTACHMENT_BYTES)
+            .ok_or_else(|| LedgerError::invalid(format!(
+                "Attachment is {} bytes; the limit is {}", size, MAX_ATTACHMENT_BYTES,
+            )))?;
+        Ok(Self { sha256, key, size, chunks, data: Vec::new(), next_chunk: 0 })
+    }
+
+    /// The request for the next chunk, or `None` once every chunk has arrived
+    pub fn next_request(&self) -> Option<FileRequest> {
+        (self.next_chunk < self.chunks).then(|| FileRequest {
+            sha256: self.sha256.clone(),
+            key_id: attachment::key_id(&self.key),
+            chunk: self.next_chunk,
+        })
+    }
+
+    /// Decrypt the answer to `next_request` and append it
+    pub fn accept(&mut self, response: FileResponse) -> Result<()> {
+        let (data, chunks) = match response {
+            FileResponse::Chunk { data, chunks } => (data, chunks),
+            FileResponse::NotFound => {
+                return Err(LedgerError::not_found(format!("Attachment {} on the sender", self.sha256)));
+            }
+            FileResponse::Refused { reason } => {
+                return Err(LedgerError::network(format!("Sender refused the attachment: {}", reason)));
+            }
+        };
+        if chunks != self.chunks {
+            return Err(LedgerError::network("Sender's copy of the attachment is a different size"));
+        }
+        let chunk = attachment::open_chunk(&self.key, &self.sha256, self.next_chunk, &BASE64.decode(data)?)?;
+        if self.data.len() as u64 + chunk.len() as u64 > self.size {
+            return Err(LedgerError::network("Sender sent more than the attachment's size"));
+        }
+        self.data.extend_from_slice(&chunk);
+        self.next_chunk += 1;
+        Ok(())
+    }
+
+    /// The reassembled contents, provided they hash to what the envelope said
+    pub fn finish(self) -> Result<Vec<u8>> {
+        if self.data.len() as u64 != self.size || attachment::content_hash(&self.data) != self.sha256 {
+            return Err(LedgerError::crypto("Fetched attachment doesn't match its hash"));
+        }
+        Ok(self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::message::{Attachment, Message};
+
+    fn fetch(db: &Database, mut download: Download) -> Result<Vec<u8>> {
+        while let Some(request) = download.next_request() {
+            download.accept(serve_chunk(db, &request))?;
+        }
+        download.finish()
+    }
+
+    #[test]
+    fn test_attachment_streams_in_chunks() {
+        let dir = std::env::temp_dir().join("ledger_test_transfer_chunks");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+
+        let contents: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| i as u8).collect();
+        let mut msg = Message::new("ledger:me".into(), "ledger:you".into(), "Files".into(), String::new());
+        let sent = Attachment::outgoing(&msg.id, "big.bin".into(), "application/octet-stream".into(), contents.clone());
+        let reference = sent.to_ref().unwrap();
+        msg.attachments.push(sent);
+        db.insert_message(&msg).unwrap();
+
+        let received = Attachment::from_ref("env-1", reference.clone()).unwrap();
+        assert!(received.pending);
+        let download = |key| Download::new(reference.sha256.clone(), key, reference.size).unwrap();
+        assert_eq!(fetch(&db, download(received.transfer_key.clone().unwrap())).unwrap(), contents);
+
+        // The wrong key can't open the chunks, and unknown hashes aren't served
+        let wrong_key = attachment::new_transfer_key().to_vec();
+        assert!(fetch(&db, download(wrong_key)).is_err());
+        let unknown = Download::new(attachment::content_hash(b"other"), received.transfer_key.clone().unwrap(), 5).unwrap();
+        assert!(matches!(fetch(&db, unknown), Err(LedgerError::NotFound(_))));
+
+        // Blocked or greedy peers get nothing
+        let request = download(received.transfer_key.unwrap()).next_request().unwrap();
+        let mut limiter = RateLimiter::new(1);
+        let (peer, blocked) = (PeerId::random(), PeerId::random());
+        db.block("ledger:blocked", &blocked.to_string()).unwrap();
+        let now = Instant::now();
+        let answer = |limiter: &mut RateLimiter, peer| answer_chunk_request(&db, limiter, peer, &request, now);
+        assert!(matches!(answer(&mut limiter, blocked), FileResponse::Refused { reason } if reason == "blocked"));
+        assert!(matches!(answer(&mut limiter, peer), FileResponse::Chunk { chunks: 3, .. }));
+        assert!(matches!(answer(&mut limiter, peer), FileResponse::Refused { reason } if reason == "rate limited"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_copies_under_different_keys_each_served_under_their_own() {
+        let dir = std::env::temp_dir().join("ledger_test_transfer_keys");
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir).unwrap();
+
+        // The same file sent twice, each time under a fresh transfer key
+        let contents = b"same file".to_vec();
+        let mut references = Vec::new();
+        for _ in 0..2 {
+            let mut msg = Message::new("ledger:me".into(), "ledger:you".into(), "File".into(), String::new());
+            let sent = Attachment::outgoing(&msg.id, "a.txt".into(), "text/plain".into(), contents.clone());
+            references.push(sent.to_ref().unwrap());
+            msg.attachments.push(sent);
+            db.insert_message(&msg).unwrap();
+        }
+        assert_ne!(references[0].key, references[1].key);
+
+        for reference in references {
+            let key = Attachment::from_ref("env-1", reference.clone()).unwrap().transfer_key.unwrap();
+            let download = Download::new(reference.sha256, key, reference.size).unwrap();
+            assert_eq!(fetch(&db, download).unwrap(), contents);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_oversized_attachments_refused() {
+        let key = attachment::new_transfer_key().to_vec();
+        let hash = attachment::content_hash(b"big");
+        assert!(Download::new(hash.clone(), key.clone(), MAX_ATTACHMENT_BYTES).is_ok());
+        assert!(Download::new(hash.clone(), key.clone(), MAX_ATTACHMENT_BYTES + 1).is_err());
+        assert!(Download::new(hash.clone(), key.clone(), u64::MAX).is_err());
+
+        let reference = crate::models::message::AttachmentRef {
+            filename: "huge.bin".into(),
+            mime_type: "application/octet-stream".into(),
+            size: MAX_ATTACHMENT_BYTES + 1,
+            sha256: hash,
+            key: BASE64.encode(key),
+        };
+        assert!(Attachment::from_ref("env-1", reference).is_none());
+    }
+}
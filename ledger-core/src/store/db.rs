@@ -1,67 +1,178 @@
-use rusqlite::{Connection, params, Result as SqlResult};
-use std::path::PathBuf;
-use std::sync::Mutex;
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter, Result as SqlResult, ToSql};
+use std::path::{Path, PathBuf};
 
+use crate::crypto::attachment;
+use crate::crypto::keys::RetiredKey;
+use crate::error::{LedgerError, Result};
 use crate::models::message::*;
 
-/// Thread-safe SQLite database wrapper
+/// Columns read by `row_to_message`, in order
+const MESSAGE_COLUMNS: &str = "id, from_id, to_id, subject, body, timestamp, delivery_method, is_read, folder, signature, encrypted, delivery_status, email_message_id, content_type, is_starred, read_receipt_requested, signature_status, imap_uid, spam_score";
+
+/// First bytes of every unencrypted SQLite file
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// How long `vacuum` waits for in-flight work to hand back each pooled connection
+const VACUUM_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Columns read by `row_to_contact`, in order
+const CONTACT_COLUMNS: &str = "ledger_id, public_key, encryption_public_key, display_name, gmail_address, verified, alias, preferred_delivery";
+
+/// Thread-safe SQLite database wrapper over a connection pool, so reads from
+/// the API, the P2P node and background tasks run in parallel
 pub struct Database {
-    conn: Mutex<Connection>,
+    pool: r2d2::Pool<SqliteConnectionManager>,
+    /// `ledger.db`, for measuring what `vacuum` frees
+    path: PathBuf,
 }
 
 impl Database {
     /// Open or create database at the given path
-    pub fn open(data_dir: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn open(data_dir: &PathBuf) -> Result<Self> {
+        Self::open_with_key(data_dir, None)
+    }
+
+    /// Open or create the database, encrypted with SQLCipher under `key` if
+    /// one is given. An unencrypted database is converted the first time a
+    /// key is supplied; an encrypted one can't be opened without it.
+    pub fn open_with_key(data_dir: &PathBuf, key: Option<&str>) -> Result<Self> {
         std::fs::create_dir_all(data_dir)?;
         let db_path = data_dir.join("ledger.db");
-        let conn = Connection::open(&db_path)?;
+        match key {
+            None if Self::is_encrypted(data_dir) => {
+                return Err(LedgerError::config("ledger.db is encrypted; start with --encrypt-db and the identity passphrase"));
+            }
+            Some(key) if db_path.exists() && !Self::is_encrypted(data_dir) => {
+                tracing::info!("Encrypting {:?}", db_path);
+                Self::encrypt_in_place(&db_path, key)?;
+            }
+            _ => {}
+        }
 
-        // Enable WAL mode for better concurrency
-        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+        let key = key.map(String::from);
+        let manager = SqliteConnectionManager::file(&db_path).with_init(move |conn| {
+            if let Some(ref key) = key {
+                conn.pragma_update(None, "key", key)?;
+            }
+            // Writers wait for each other instead of failing with SQLITE_BUSY
+            conn.busy_timeout(std::time::Duration::from_secs(5))
+        });
+        let pool = r2d2::Pool::new(manager)?;
 
-        let db = Self {
-            conn: Mutex::new(conn),
-        };
+        // The key is only checked once the file is read
+        pool.get()?.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|e| match e.sqlite_error_code() {
+                Some(rusqlite::ErrorCode::NotADatabase) => LedgerError::crypto("Wrong passphrase for ledger.db"),
+                _ => e.into(),
+            })?;
+
+        // Enable WAL mode for better concurrency; it persists in the file
+        pool.get()?.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        let db = Self { pool, path: db_path };
         db.initialize_tables()?;
         Ok(db)
     }
 
-    /// Create tables if they don't exist
-    fn initialize_tables(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
-
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                from_id TEXT NOT NULL,
-                to_id TEXT NOT NULL,
-                subject TEXT DEFAULT '',
-                body TEXT DEFAULT '',
-                timestamp INTEGER NOT NULL,
-                delivery_method TEXT DEFAULT 'p2p',
-                is_read INTEGER DEFAULT 0,
-                folder TEXT DEFAULT 'inbox',
-                signature TEXT,
-                encrypted INTEGER DEFAULT 0
-            );
+    /// Whether the data directory holds a database that isn't plain SQLite,
+    /// i.e. one encrypted by SQLCipher
+    pub fn is_encrypted(data_dir: &Path) -> bool {
+        let mut header = [0u8; 16];
+        match std::fs::File::open(data_dir.join("ledger.db")) {
+            Ok(mut file) => std::io::Read::read_exact(&mut file, &mut header).is_ok() && &header != SQLITE_HEADER,
+            Err(_) => false,
+        }
+    }
 
-            CREATE TABLE IF NOT EXISTS contacts (
-                ledger_id TEXT PRIMARY KEY,
-                public_key TEXT NOT NULL,
-                encryption_public_key TEXT,
-                display_name TEXT,
-                gmail_address TEXT
-            );
+    /// Encrypt a plaintext database: export it into a keyed copy alongside,
+    /// then swap the copy in
+    fn encrypt_in_place(db_path: &Path, key: &str) -> Result<()> {
+        let encrypted_path = db_path.with_extension("db.encrypting");
+        let _ = std::fs::remove_file(&encrypted_path);
+        {
+            let conn = Connection::open(db_path)?;
+            // Fold the WAL into the main file so the export sees everything
+            conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+            conn.execute("ATTACH DATABASE ?1 AS encrypted KEY ?2", params![encrypted_path.to_string_lossy(), key])?;
+            conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+            let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            conn.pragma_update(Some(rusqlite::DatabaseName::Attached("encrypted")), "user_version", version)?;
+            conn.execute_batch("DETACH DATABASE encrypted;")?;
+        }
+        std::fs::rename(&encrypted_path, db_path)?;
+        for suffix in ["-wal", "-shm"] {
+            let mut sidecar = db_path.as_os_str().to_owned();
+            sidecar.push(suffix);
+            let _ = std::fs::remove_file(sidecar);
+        }
+        Ok(())
+    }
 
-            CREATE TABLE IF NOT EXISTS settings (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            );
+    /// Check a connection out of the pool
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.pool.get()?)
+    }
 
-            CREATE INDEX IF NOT EXISTS idx_messages_folder ON messages(folder);
-            CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
-            CREATE INDEX IF NOT EXISTS idx_messages_to_id ON messages(to_id);"
-        )?;
+    /// Whether a connection can be checked out within `timeout` and answers a trivial query
+    pub fn ping(&self, timeout: std::time::Duration) -> bool {
+        match self.pool.get_timeout(timeout) {
+            Ok(conn) => conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0)).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Fold the WAL back into `ledger.db` and truncate it, so nothing is left
+    /// half-applied in the side files when the process exits
+    pub fn checkpoint(&self) -> Result<()> {
+        Self::checkpoint_on(&*self.conn()?)
+    }
+
+    fn checkpoint_on(conn: &Connection) -> Result<()> {
+        let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+        if busy != 0 {
+            return Err(LedgerError::Db(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                Some("WAL checkpoint blocked by an open transaction".into()),
+            )));
+        }
+        Ok(())
+    }
+
+    /// Rebuild `ledger.db` without its free pages and truncate the WAL,
+    /// returning how many bytes that gave back. VACUUM needs the database to
+    /// itself, so every pooled connection is checked out first: in-flight work
+    /// finishes, and new checkouts wait in the pool instead of failing on the lock.
+    pub fn vacuum(&self) -> Result<u64> {
+        let before = self.size_on_disk();
+        let mut held = Vec::new();
+        for _ in 0..self.pool.max_size() {
+            held.push(self.pool.get_timeout(VACUUM_DRAIN_TIMEOUT)?);
+        }
+        held[0].execute_batch("VACUUM;")?;
+        Self::checkpoint_on(&held[0])?;
+        drop(held);
+        Ok(before.saturating_sub(self.size_on_disk()))
+    }
+
+    /// Bytes taken by `ledger.db` and its WAL
+    pub fn size_on_disk(&self) -> u64 {
+        let mut wal = self.path.as_os_str().to_owned();
+        wal.push("-wal");
+        [self.path.as_os_str(), wal.as_os_str()].iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    /// Migrate the schema to the latest version, then fill in data and default settings
+    fn initialize_tables(&self) -> Result<()> {
+        let mut conn = self.conn()?;
+
+        super::migrations::migrate(&mut conn)?;
+        Self::backfill_contact_encryption_keys(&conn)?;
+        Self::rederive_contact_encryption_keys(&conn)?;
 
         // Insert default settings
         conn.execute(
@@ -72,22 +183,139 @@ impl Database {
             "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
             params!["tor_enabled", "false"],
         )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["tor_socks_addr", crate::tor::DEFAULT_SOCKS_ADDR],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["tor_onion_service", "false"],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["tor_control_addr", crate::tor::control::DEFAULT_CONTROL_ADDR],
+        )?;
         conn.execute(
             "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
             params!["dht_ttl_hours", "72"],
         )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["replay_window_hours", "72"],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["max_message_bytes", crate::p2p::codec::DEFAULT_MAX_MESSAGE_BYTES.to_string()],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["peer_rate_limit", crate::p2p::rate_limit::DEFAULT_PEER_RATE_LIMIT.to_string()],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["send_read_receipts", "true"],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["gmail_mark_seen", "false"],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["receive_policy", ReceivePolicy::Open.to_string()],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["nightly_vacuum", "false"],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["gmail_send_rate", crate::gmail::smtp_client::DEFAULT_GMAIL_SEND_RATE.to_string()],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["retired_key_days", crate::crypto::keys::DEFAULT_RETIRED_KEY_DAYS.to_string()],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
+            params!["spam_threshold", crate::gmail::spam::DEFAULT_SPAM_THRESHOLD.to_string()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Fill in `encryption_public_key` for contacts saved before it was written.
+    ///
+    /// Delivery used to base64-decode `public_key` as the X25519 key, so a
+    /// 32-byte base64 value there is kept as-is. Otherwise `public_key` (or the
+    /// Ledger ID) holds the Ed25519 key, which is mapped to X25519.
+    fn backfill_contact_encryption_keys(conn: &Connection) -> Result<()> {
+        use base64::Engine;
+        let b64 = base64::engine::general_purpose::STANDARD;
+
+        let mut stmt = conn.prepare(
+            "SELECT ledger_id, public_key FROM contacts WHERE encryption_public_key IS NULL"
+        )?;
+        let pending = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (ledger_id, public_key) in pending {
+            let legacy = b64.decode(&public_key).ok().filter(|k| k.len() == 32);
+            let derived = || {
+                let ed_public = bs58::decode(&public_key).into_vec().ok()
+                    .filter(|k| k.len() == 32)
+                    .or_else(|| crate::crypto::keys::LedgerIdentity::pubkey_from_ledger_id(&ledger_id).ok())?;
+                crate::crypto::keys::LedgerIdentity::x25519_public_from_ed25519(&ed_public).ok()
+                    .map(|k| k.to_vec())
+            };
+            let Some(key) = legacy.or_else(derived) else {
+                tracing::warn!("Cannot derive an encryption key for contact {}", ledger_id);
+                continue;
+            };
+            conn.execute(
+                "UPDATE contacts SET encryption_public_key = ?1 WHERE ledger_id = ?2",
+                params![b64.encode(key), ledger_id],
+            )?;
+        }
+        Ok(())
+    }
 
+    /// A Ledger contact's encryption key always follows from its Ledger ID.
+    /// Recompute any stored key that doesn't: one copied from a node still on
+    /// the old HKDF derivation, or one substituted when the contact was added
+    /// before `POST /api/contacts` checked it. Upgraded nodes still decrypt
+    /// mail sent to their old key.
+    fn rederive_contact_encryption_keys(conn: &Connection) -> Result<()> {
+        use base64::Engine;
+
+        let mut stmt = conn.prepare(
+            "SELECT ledger_id, encryption_public_key FROM contacts WHERE ledger_id LIKE 'ledger:%'"
+        )?;
+        let contacts = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (ledger_id, stored) in contacts {
+            match crate::crypto::keys::LedgerIdentity::encryption_key_from_ledger_id(&ledger_id) {
+                Ok(key) => {
+                    let key = base64::engine::general_purpose::STANDARD.encode(key);
+                    if stored.as_deref() != Some(key.as_str()) {
+                        conn.execute(
+                            "UPDATE contacts SET encryption_public_key = ?1 WHERE ledger_id = ?2",
+                            params![key, ledger_id],
+                        )?;
+                    }
+                }
+                Err(e) => tracing::warn!("Cannot derive an encryption key for contact {}: {}", ledger_id, e),
+            }
+        }
         Ok(())
     }
 
     // ── Messages ──
 
     /// Insert a message
-    pub fn insert_message(&self, msg: &Message) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
+    pub fn insert_message(&self, msg: &Message) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT OR REPLACE INTO messages (id, from_id, to_id, subject, body, timestamp, delivery_method, is_read, folder, signature, encrypted)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            &format!("INSERT OR REPLACE INTO messages ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)", MESSAGE_COLUMNS),
             params![
                 msg.id,
                 msg.from_id,
@@ -100,32 +328,49 @@ impl Database {
                 msg.folder.to_string(),
                 msg.signature,
                 msg.encrypted as i32,
+                msg.delivery_status.to_string(),
+                msg.email_message_id,
+                msg.content_type.to_string(),
+                msg.is_starred as i32,
+                msg.read_receipt_requested as i32,
+                msg.signature_status.to_string(),
+                msg.imap_uid,
+                msg.spam_score,
             ],
         )?;
+        for attachment in &msg.attachments {
+            conn.execute(
+                "INSERT OR REPLACE INTO attachments (id, message_id, filename, mime_type, size, data, sha256, transfer_key)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    attachment.id,
+                    msg.id,
+                    attachment.filename,
+                    attachment.mime_type,
+                    attachment.size,
+                    attachment.data,
+                    attachment.sha256,
+                    attachment.transfer_key,
+                ],
+            )?;
+        }
         Ok(())
     }
 
-    /// Get all messages, optionally filtered by folder
-    pub fn get_messages(&self, folder: Option<&str>) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
-
-        let (query, param_values): (&str, Vec<String>) = if let Some(f) = folder {
-            ("SELECT id, from_id, to_id, subject, body, timestamp, delivery_method, is_read, folder, signature, encrypted
-              FROM messages WHERE folder = ?1 ORDER BY timestamp DESC",
-             vec![f.to_string()])
-        } else {
-            ("SELECT id, from_id, to_id, subject, body, timestamp, delivery_method, is_read, folder, signature, encrypted
-              FROM messages ORDER BY timestamp DESC",
-             vec![])
-        };
-
-        let mut stmt = conn.prepare(query)?;
+    /// A page of the messages matching `filter`, newest first
+    pub fn query_messages(&self, filter: &MessageFilter, limit: u32, offset: u32) -> Result<Vec<Message>> {
+        let mut query = MessageQuery::new(filter);
+        let sql = format!(
+            "SELECT {} FROM messages WHERE {} ORDER BY timestamp DESC, id LIMIT ? OFFSET ?",
+            MESSAGE_COLUMNS,
+            query.where_clause(),
+        );
+        query.values.push(Box::new(limit));
+        query.values.push(Box::new(offset));
 
-        let rows = if param_values.is_empty() {
-            stmt.query_map([], Self::row_to_message)?
-        } else {
-            stmt.query_map(params![param_values[0]], Self::row_to_message)?
-        };
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(query.values.iter()), Self::row_to_message)?;
 
         let mut messages = Vec::new();
         for row in rows {
@@ -135,30 +380,197 @@ impl Database {
     }
 
     /// Get a single message by ID
-    pub fn get_message(&self, id: &str) -> Result<Option<Message>, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
-        let mut stmt = conn.prepare(
-            "SELECT id, from_id, to_id, subject, body, timestamp, delivery_method, is_read, folder, signature, encrypted
-             FROM messages WHERE id = ?1"
-        )?;
+    pub fn get_message(&self, id: &str) -> Result<Option<Message>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS))?;
         let mut rows = stmt.query_map(params![id], Self::row_to_message)?;
         Ok(rows.next().transpose()?)
     }
 
-    /// Delete a message
-    pub fn delete_message(&self, id: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
-        let affected = conn.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+    /// Move a message to Trash, remembering where it came from
+    pub fn trash_message(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(Self::trash_row(&conn, id, chrono::Utc::now().timestamp())? > 0)
+    }
+
+    fn trash_row(conn: &Connection, id: &str, now: i64) -> SqlResult<usize> {
+        conn.execute(
+            "UPDATE messages SET previous_folder = folder, folder = 'trash', deleted_at = ?2
+             WHERE id = ?1 AND folder != 'trash'",
+            params![id, now],
+        )
+    }
+
+    /// Move a trashed message back to the folder it was deleted from
+    pub fn restore_message(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let affected = conn.execute(
+            "UPDATE messages SET folder = COALESCE(previous_folder, 'inbox'), previous_folder = NULL, deleted_at = NULL
+             WHERE id = ?1 AND folder = 'trash'",
+            params![id],
+        )?;
         Ok(affected > 0)
     }
 
+    /// File a message into `folder`. Moving into Trash records where it came
+    /// from, as `trash_message` does; moving anywhere else clears that.
+    pub fn move_message(&self, id: &str, folder: &Folder) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(Self::move_row(&conn, id, folder, chrono::Utc::now().timestamp())? > 0)
+    }
+
+    fn move_row(conn: &Connection, id: &str, folder: &Folder, now: i64) -> SqlResult<usize> {
+        conn.execute(
+            "UPDATE messages SET
+                previous_folder = CASE
+                    WHEN ?2 != 'trash' THEN NULL
+                    WHEN folder = 'trash' THEN previous_folder
+                    ELSE folder END,
+                deleted_at = CASE WHEN ?2 = 'trash' THEN COALESCE(deleted_at, ?3) ELSE NULL END,
+                folder = ?2
+             WHERE id = ?1",
+            params![id, folder.to_string(), now],
+        )
+    }
+
+    /// Permanently delete messages that have been in Trash since before `cutoff`
+    pub fn purge_trash(&self, cutoff: i64) -> Result<usize> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM attachments WHERE message_id IN
+             (SELECT id FROM messages WHERE folder = 'trash' AND deleted_at < ?1)",
+            params![cutoff],
+        )?;
+        conn.execute(
+            "DELETE FROM read_receipts WHERE message_id IN
+             (SELECT id FROM messages WHERE folder = 'trash' AND deleted_at < ?1)",
+            params![cutoff],
+        )?;
+        let purged = conn.execute(
+            "DELETE FROM messages WHERE folder = 'trash' AND deleted_at < ?1",
+            params![cutoff],
+        )?;
+        Ok(purged)
+    }
+
+    /// Permanently delete a message
+    pub fn delete_message(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(Self::delete_row(&conn, id)? > 0)
+    }
+
+    fn delete_row(conn: &Connection, id: &str) -> SqlResult<usize> {
+        let affected = conn.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+        conn.execute("DELETE FROM attachments WHERE message_id = ?1", params![id])?;
+        conn.execute("DELETE FROM read_receipts WHERE message_id = ?1", params![id])?;
+        Ok(affected)
+    }
+
+    /// Trash every message in `ids`, or with `permanent` delete them outright,
+    /// all in one transaction. Messages already in Trash stay there.
+    pub fn bulk_delete(&self, ids: &[String], permanent: bool) -> Result<BulkResult> {
+        let now = chrono::Utc::now().timestamp();
+        self.bulk_apply(ids, |conn, id| {
+            if permanent { Self::delete_row(conn, id) } else { Self::trash_row(conn, id, now) }
+        })
+    }
+
+    /// File every message in `ids` into `folder` in one transaction, as
+    /// `move_message` does
+    pub fn bulk_move(&self, ids: &[String], folder: &Folder) -> Result<BulkResult> {
+        let now = chrono::Utc::now().timestamp();
+        self.bulk_apply(ids, |conn, id| Self::move_row(conn, id, folder, now))
+    }
+
+    /// Run `apply` once for each distinct id that has a message, counting the
+    /// rows it changes, and commit only if every one succeeds
+    fn bulk_apply(
+        &self,
+        ids: &[String],
+        apply: impl Fn(&Connection, &str) -> SqlResult<usize>,
+    ) -> Result<BulkResult> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let mut result = BulkResult::default();
+        let mut seen = std::collections::HashSet::new();
+        for id in ids.iter().filter(|id| seen.insert(id.as_str())) {
+            let exists = tx.query_row("SELECT 1 FROM messages WHERE id = ?1", params![id], |_| Ok(()))
+                .optional()?
+                .is_some();
+            if exists {
+                result.affected += apply(&tx, id)?;
+            } else {
+                result.not_found.push(id.clone());
+            }
+        }
+        tx.commit()?;
+        Ok(result)
+    }
+
     /// Mark a message as read
-    pub fn mark_read(&self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
+    pub fn mark_read(&self, id: &str) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute("UPDATE messages SET is_read = 1 WHERE id = ?1", params![id])?;
         Ok(())
     }
 
+    /// Mark a message read or unread
+    pub fn set_read(&self, id: &str, read: bool) -> Result<bool> {
+        let conn = self.conn()?;
+        let affected = conn.execute(
+            "UPDATE messages SET is_read = ?2 WHERE id = ?1",
+            params![id, read as i32],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Star or unstar a message; the flag stays with it across folder moves
+    pub fn set_starred(&self, id: &str, starred: bool) -> Result<bool> {
+        let conn = self.conn()?;
+        let affected = conn.execute(
+            "UPDATE messages SET is_starred = ?2 WHERE id = ?1",
+            params![id, starred as i32],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Mark every message in a folder as read, returning how many changed
+    pub fn mark_all_read(&self, folder: &Folder) -> Result<usize> {
+        let conn = self.conn()?;
+        let affected = conn.execute(
+            "UPDATE messages SET is_read = 1 WHERE folder = ?1 AND is_read = 0",
+            params![folder.to_string()],
+        )?;
+        Ok(affected)
+    }
+
+    /// Number of unread messages in each folder that has any
+    pub fn unread_counts(&self) -> Result<std::collections::HashMap<String, i64>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT folder, COUNT(*) FROM messages WHERE is_read = 0 GROUP BY folder",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut counts = std::collections::HashMap::new();
+        for row in rows {
+            let (folder, count) = row?;
+            counts.insert(folder, count);
+        }
+        Ok(counts)
+    }
+
+    /// Record the recipient's acknowledgement of an outgoing message
+    pub fn set_delivery_status(&self, id: &str, status: &DeliveryStatus) -> Result<bool> {
+        let conn = self.conn()?;
+        let affected = conn.execute(
+            "UPDATE messages SET delivery_status = ?1 WHERE id = ?2",
+            params![status.to_string(), id],
+        )?;
+        Ok(affected > 0)
+    }
+
     fn row_to_message(row: &rusqlite::Row<'_>) -> SqlResult<Message> {
         Ok(Message {
             id: row.get(0)?,
@@ -171,59 +583,211 @@ impl Database {
             is_read: row.get::<_, i32>(7)? != 0,
             folder: Folder::from_str(&row.get::<_, String>(8)?),
             signature: row.get(9)?,
+            signature_status: SignatureStatus::from_str(&row.get::<_, String>(16)?),
             encrypted: row.get::<_, i32>(10)? != 0,
+            delivery_status: DeliveryStatus::from_str(&row.get::<_, String>(11)?),
+            email_message_id: row.get(12)?,
+            imap_uid: row.get(17)?,
+            content_type: ContentType::from_str(&row.get::<_, String>(13)?),
+            is_starred: row.get::<_, i32>(14)? != 0,
+            attachments: vec![],
+            read_receipt_requested: row.get::<_, i32>(15)? != 0,
+            read_receipts: vec![],
+            spam_score: row.get(18)?,
+            deliveries: vec![],
         })
     }
 
+    /// Whether the email with this INBOX UID has already been imported
+    pub fn has_imap_uid(&self, uid: u32) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(conn.query_row("SELECT EXISTS(SELECT 1 FROM messages WHERE imap_uid = ?1)", params![uid], |row| row.get(0))?)
+    }
+
+    /// Whether a message with this RFC 822 `Message-ID` is already stored
+    pub fn has_email_message_id(&self, email_message_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM messages WHERE email_message_id = ?1)",
+            params![email_message_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    // ── Attachments ──
+
+    /// Attachment metadata for a message (without the file bytes)
+    pub fn get_attachments(&self, message_id: &str) -> Result<Vec<Attachment>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, message_id, filename, mime_type, size, sha256, length(data) < size FROM attachments
+             WHERE message_id = ?1 ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map(params![message_id], |row| {
+            Ok(Attachment {
+                id: row.get(0)?,
+                message_id: row.get(1)?,
+                filename: row.get(2)?,
+                mime_type: row.get(3)?,
+                size: row.get(4)?,
+                sha256: row.get(5)?,
+                pending: row.get(6)?,
+                ..Default::default()
+            })
+        })?;
+        let mut attachments = Vec::new();
+        for row in rows {
+            attachments.push(row?);
+        }
+        Ok(attachments)
+    }
+
+    /// A single attachment including its bytes, which are empty while it is pending
+    pub fn get_attachment(&self, message_id: &str, id: &str) -> Result<Option<Attachment>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, message_id, filename, mime_type, size, data, sha256, transfer_key, length(data) < size
+             FROM attachments WHERE message_id = ?1 AND id = ?2",
+        )?;
+        let mut rows = stmt.query_map(params![message_id, id], |row| {
+            Ok(Attachment {
+                id: row.get(0)?,
+                message_id: row.get(1)?,
+                filename: row.get(2)?,
+                mime_type: row.get(3)?,
+                size: row.get(4)?,
+                data: row.get(5)?,
+                sha256: row.get(6)?,
+                transfer_key: row.get(7)?,
+                pending: row.get(8)?,
+            })
+        })?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Store the fetched contents of a pending attachment
+    pub fn store_attachment_data(&self, message_id: &str, id: &str, data: &[u8]) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE attachments SET data = ?3 WHERE message_id = ?1 AND id = ?2",
+            params![message_id, id, data],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Up to `len` bytes from `offset` of a complete attachment hashing to
+    /// `sha256` held under the transfer key with id `key_id`. Reads only the
+    /// bytes asked for.
+    pub fn attachment_chunk(&self, sha256: &str, key_id: &str, offset: u64, len: usize) -> Result<Option<AttachmentChunk>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT rowid, transfer_key FROM attachments
+             WHERE sha256 = ?1 AND transfer_key IS NOT NULL AND length(data) = size",
+        )?;
+        let copies = stmt.query_map(params![sha256], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+            .collect::<SqlResult<Vec<_>>>()?;
+        let Some((rowid, _)) = copies.into_iter().find(|(_, key)| attachment::key_id(key) == key_id) else {
+            return Ok(None);
+        };
+        Ok(conn.query_row(
+            "SELECT transfer_key, size, substr(data, ?2, ?3) FROM attachments WHERE rowid = ?1",
+            // substr counts from 1
+            params![rowid, offset as i64 + 1, len as i64],
+            |row| Ok(AttachmentChunk { transfer_key: row.get(0)?, size: row.get(1)?, data: row.get(2)? }),
+        ).optional()?)
+    }
+
     // ── Contacts ──
 
-    /// Upsert a contact
-    pub fn upsert_contact(&self, contact: &Contact) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
+    /// Upsert a contact. Fails if another contact already has its alias.
+    pub fn upsert_contact(&self, contact: &Contact) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT OR REPLACE INTO contacts (ledger_id, public_key, display_name, gmail_address)
-             VALUES (?1, ?2, ?3, ?4)",
+            "INSERT INTO contacts (ledger_id, public_key, encryption_public_key, display_name, gmail_address, verified, alias, preferred_delivery)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT(ledger_id) DO UPDATE SET
+                public_key = excluded.public_key,
+                encryption_public_key = excluded.encryption_public_key,
+                display_name = excluded.display_name,
+                gmail_address = excluded.gmail_address,
+                verified = excluded.verified,
+                alias = excluded.alias,
+                preferred_delivery = excluded.preferred_delivery",
             params![
                 contact.ledger_id,
                 contact.public_key,
+                contact.encryption_public_key,
                 contact.display_name,
                 contact.gmail_address,
+                contact.verified,
+                contact.alias,
+                contact.preferred_delivery.map(|mode| mode.to_string()),
             ],
-        )?;
+        ).map_err(|e| alias_conflict(e, contact.alias.as_deref()))?;
         Ok(())
     }
 
     /// Get a contact by Ledger ID
-    pub fn get_contact(&self, ledger_id: &str) -> Result<Option<Contact>, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
+    pub fn get_contact(&self, ledger_id: &str) -> Result<Option<Contact>> {
+        let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT ledger_id, public_key, display_name, gmail_address FROM contacts WHERE ledger_id = ?1"
+            &format!("SELECT {} FROM contacts WHERE ledger_id = ?1", CONTACT_COLUMNS)
         )?;
-        let mut rows = stmt.query_map(params![ledger_id], |row| {
-            Ok(Contact {
-                ledger_id: row.get(0)?,
-                public_key: row.get(1)?,
-                display_name: row.get(2)?,
-                gmail_address: row.get(3)?,
-            })
-        })?;
+        let mut rows = stmt.query_map(params![ledger_id], Self::row_to_contact)?;
         Ok(rows.next().transpose()?)
     }
 
+    /// Update a contact's display name, Gmail address, verified flag, alias
+    /// and/or preferred delivery mode; `None` leaves a field as is and an
+    /// empty string clears it. Returns false if not found.
+    pub fn update_contact(
+        &self,
+        ledger_id: &str,
+        display_name: Option<&str>,
+        gmail_address: Option<&str>,
+        verified: Option<bool>,
+        alias: Option<&str>,
+        preferred_delivery: Option<&str>,
+    ) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE contacts SET
+                display_name = CASE WHEN ?2 IS NULL THEN display_name ELSE NULLIF(?2, '') END,
+                gmail_address = CASE WHEN ?3 IS NULL THEN gmail_address ELSE NULLIF(?3, '') END,
+                verified = COALESCE(?4, verified),
+                alias = CASE WHEN ?5 IS NULL THEN alias ELSE NULLIF(?5, '') END,
+                preferred_delivery = CASE WHEN ?6 IS NULL THEN preferred_delivery ELSE NULLIF(?6, '') END
+             WHERE ledger_id = ?1",
+            params![ledger_id, display_name, gmail_address, verified, alias, preferred_delivery],
+        ).map_err(|e| alias_conflict(e, alias))?;
+        Ok(updated > 0)
+    }
+
+    /// The Ledger ID of the contact with this alias, with or without its `@`
+    pub fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        let Some(alias) = Contact::normalize_alias(alias) else { return Ok(None) };
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "SELECT ledger_id FROM contacts WHERE alias = ?1",
+            params![alias],
+            |row| row.get(0),
+        ).optional()?)
+    }
+
+    /// Remove a contact. Messages to and from them are kept.
+    pub fn delete_contact(&self, ledger_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let deleted = conn.execute("DELETE FROM contacts WHERE ledger_id = ?1", params![ledger_id])?;
+        Ok(deleted > 0)
+    }
+
     /// Get all contacts
-    pub fn get_contacts(&self) -> Result<Vec<Contact>, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
+    pub fn get_contacts(&self) -> Result<Vec<Contact>> {
+        let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT ledger_id, public_key, display_name, gmail_address FROM contacts ORDER BY display_name"
+            &format!("SELECT {} FROM contacts ORDER BY display_name", CONTACT_COLUMNS)
         )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(Contact {
-                ledger_id: row.get(0)?,
-                public_key: row.get(1)?,
-                display_name: row.get(2)?,
-                gmail_address: row.get(3)?,
-            })
-        })?;
+        let rows = stmt.query_map([], Self::row_to_contact)?;
         let mut contacts = Vec::new();
         for row in rows {
             contacts.push(row?);
@@ -231,38 +795,1205 @@ impl Database {
         Ok(contacts)
     }
 
-    // ── Settings ──
+    // ── Blocklist ──
 
-    /// Get a setting
-    pub fn get_setting(&self, key: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
-        let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
-        let mut rows = stmt.query_map(params![key], |row| row.get::<_, String>(0))?;
-        Ok(rows.next().transpose()?)
+    /// Block a Ledger ID and the PeerId derived from it
+    pub fn block(&self, ledger_id: &str, peer_id: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR IGNORE INTO blocklist (ledger_id, peer_id, blocked_at) VALUES (?1, ?2, ?3)",
+            params![ledger_id, peer_id, chrono::Utc::now().timestamp()],
+        )?;
+        Ok(())
     }
 
-    /// Set a setting
-    pub fn set_setting(&self, key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
+    /// Remove a Ledger ID from the blocklist. Returns false if it wasn't blocked.
+    pub fn unblock(&self, ledger_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let removed = conn.execute("DELETE FROM blocklist WHERE ledger_id = ?1", params![ledger_id])?;
+        Ok(removed > 0)
+    }
+
+    pub fn is_blocked(&self, ledger_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM blocklist WHERE ledger_id = ?1",
+            params![ledger_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Why mail from `from_ledger_id` is turned away, if it is: the sender is
+    /// blocked, or the `receive_policy` setting takes contacts only. P2P, DHT,
+    /// Gmail fallback and bulletin deliveries all check this before storing.
+    /// A sender claiming a contact's Ledger ID still has to pass signature
+    /// verification.
+    pub fn sender_rejection(&self, from_ledger_id: &str) -> Option<&'static str> {
+        match self.is_blocked(from_ledger_id) {
+            Ok(false) => {}
+            Ok(true) => return Some("blocked"),
+            Err(e) => tracing::error!("Failed to check blocklist: {}", e),
+        }
+
+        let policy = self.get_setting("receive_policy").ok().flatten()
+            .and_then(|p| ReceivePolicy::parse(&p))
+            .unwrap_or_default();
+        match policy {
+            ReceivePolicy::Open => None,
+            ReceivePolicy::ContactsOnly => match self.get_contact(from_ledger_id) {
+                Ok(Some(_)) => None,
+                Ok(None) => Some("not in contacts"),
+                Err(e) => {
+                    tracing::error!("Contact check failed: {}", e);
+                    Some("contact check failed")
+                }
+            },
+        }
+    }
+
+    pub fn is_peer_blocked(&self, peer_id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM blocklist WHERE peer_id = ?1",
+            params![peer_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn get_blocklist(&self) -> Result<Vec<BlockedPeer>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT ledger_id, peer_id, blocked_at FROM blocklist ORDER BY blocked_at DESC"
+        )?;
+        let blocked = stmt.query_map([], |row| {
+            Ok(BlockedPeer {
+                ledger_id: row.get(0)?,
+                peer_id: row.get(1)?,
+                blocked_at: row.get(2)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(blocked)
+    }
+
+    fn row_to_contact(row: &rusqlite::Row<'_>) -> SqlResult<Contact> {
+        Ok(Contact {
+            ledger_id: row.get(0)?,
+            public_key: row.get(1)?,
+            encryption_public_key: row.get(2)?,
+            display_name: row.get(3)?,
+            gmail_address: row.get(4)?,
+            verified: row.get(5)?,
+            alias: row.get(6)?,
+            // An unknown stored mode is treated as no preference
+            preferred_delivery: row.get::<_, Option<String>>(7)?.as_deref().and_then(DeliveryMode::parse),
+        })
+    }
+
+    // ── Peer directory ──
+
+    /// Record which peer serves a Ledger ID, and where it was last seen
+    pub fn upsert_peer_mapping(&self, ledger_id: &str, peer_id: &str, multiaddr: &str) -> Result<()> {
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
-            params![key, value],
+            "INSERT OR REPLACE INTO peer_directory (ledger_id, peer_id, multiaddr, last_seen)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![ledger_id, peer_id, multiaddr, chrono::Utc::now().timestamp()],
         )?;
         Ok(())
     }
 
-    /// Get all settings as key-value pairs
-    pub fn get_all_settings(&self) -> Result<std::collections::HashMap<String, String>, Box<dyn std::error::Error>> {
-        let conn = self.conn.lock().map_err(|e| format!("Lock error: {}", e))?;
-        let mut stmt = conn.prepare("SELECT key, value FROM settings")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-        })?;
-        let mut settings = std::collections::HashMap::new();
-        for row in rows {
-            let (k, v) = row?;
-            settings.insert(k, v);
-        }
-        Ok(settings)
+    /// Remember an address a peer was reachable at, refreshing its last-seen time
+    pub fn upsert_known_peer(&self, peer_id: &str, multiaddr: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO known_peers (peer_id, multiaddr, last_seen) VALUES (?1, ?2, ?3)",
+            params![peer_id, multiaddr, chrono::Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// All remembered `(peer_id, multiaddr)` pairs, most recently seen first
+    pub fn get_known_peers(&self) -> Result<Vec<(String, String)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT peer_id, multiaddr FROM known_peers ORDER BY last_seen DESC"
+        )?;
+        let peers = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(peers)
+    }
+
+    /// Remembered addresses of one peer, most recently seen first
+    pub fn get_known_peer_addresses(&self, peer_id: &str) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT multiaddr FROM known_peers WHERE peer_id = ?1 ORDER BY last_seen DESC"
+        )?;
+        let addrs = stmt.query_map(params![peer_id], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(addrs)
+    }
+
+    /// Forget every address of one peer
+    pub fn forget_known_peer(&self, peer_id: &str) -> Result<usize> {
+        let conn = self.conn()?;
+        let removed = conn.execute("DELETE FROM known_peers WHERE peer_id = ?1", params![peer_id])?;
+        Ok(removed)
+    }
+
+    /// Forget peer addresses not seen since `cutoff`
+    pub fn prune_known_peers(&self, cutoff: i64) -> Result<usize> {
+        let conn = self.conn()?;
+        let pruned = conn.execute("DELETE FROM known_peers WHERE last_seen < ?1", params![cutoff])?;
+        Ok(pruned)
+    }
+
+    /// Look up the peer last seen serving a Ledger ID
+    pub fn get_peer_for_ledger_id(&self, ledger_id: &str) -> Result<Option<PeerMapping>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT ledger_id, peer_id, multiaddr, last_seen FROM peer_directory WHERE ledger_id = ?1"
+        )?;
+        let mut rows = stmt.query_map(params![ledger_id], |row| {
+            Ok(PeerMapping {
+                ledger_id: row.get(0)?,
+                peer_id: row.get(1)?,
+                multiaddr: row.get(2)?,
+                last_seen: row.get(3)?,
+            })
+        })?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// The Ledger ID a peer was most recently seen serving
+    pub fn get_ledger_id_for_peer(&self, peer_id: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let ledger_id = conn.query_row(
+            "SELECT ledger_id FROM peer_directory WHERE peer_id = ?1 ORDER BY last_seen DESC LIMIT 1",
+            params![peer_id],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(ledger_id)
+    }
+
+    // ── Outbox ──
+
+    /// Queue an envelope for retry
+    pub fn enqueue_outbox(&self, entry: &OutboxEntry) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO outbox
+             (id, message_id, recipient, envelope_json, attempts, next_attempt_at, last_error, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.id,
+                entry.message_id,
+                entry.recipient,
+                entry.envelope_json,
+                entry.attempts,
+                entry.next_attempt_at,
+                entry.last_error,
+                entry.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Every queued envelope, soonest retry first
+    pub fn get_outbox(&self) -> Result<Vec<OutboxEntry>> {
+        self.query_outbox(i64::MAX)
+    }
+
+    /// Queued envelopes whose next retry is at or before `now`
+    pub fn due_outbox(&self, now: i64) -> Result<Vec<OutboxEntry>> {
+        self.query_outbox(now)
+    }
+
+    fn query_outbox(&self, due_by: i64) -> Result<Vec<OutboxEntry>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, message_id, recipient, envelope_json, attempts, next_attempt_at, last_error, created_at
+             FROM outbox WHERE next_attempt_at <= ?1 ORDER BY next_attempt_at"
+        )?;
+        let entries = stmt.query_map(params![due_by], |row| {
+            Ok(OutboxEntry {
+                id: row.get(0)?,
+                message_id: row.get(1)?,
+                recipient: row.get(2)?,
+                envelope_json: row.get(3)?,
+                attempts: row.get(4)?,
+                next_attempt_at: row.get(5)?,
+                last_error: row.get(6)?,
+                created_at: row.get(7)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Record a failed retry and when to try next
+    pub fn reschedule_outbox(
+        &self,
+        id: &str,
+        attempts: u32,
+        next_attempt_at: i64,
+        error: &str,
+    ) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE outbox SET attempts = ?2, next_attempt_at = ?3, last_error = ?4 WHERE id = ?1",
+            params![id, attempts, next_attempt_at, error],
+        )?;
+        Ok(())
+    }
+
+    /// Drop an envelope from the queue once delivered or abandoned
+    pub fn remove_outbox(&self, id: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let affected = conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+        Ok(affected > 0)
+    }
+
+    // ── DHT republishing ──
+
+    /// Remember an envelope we stored in the DHT, or note that it was just stored again
+    pub fn track_dht_envelope(
+        &self,
+        message_id: &str,
+        recipient: &str,
+        envelope_id: &str,
+        envelope_json: &str,
+        now: i64,
+    ) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO dht_envelopes (envelope_id, message_id, recipient, envelope_json, stored_at, published_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+             ON CONFLICT(envelope_id, recipient) DO UPDATE SET published_at = excluded.published_at",
+            params![envelope_id, message_id, recipient, envelope_json, now],
+        )?;
+        Ok(())
+    }
+
+    /// Tracked envelopes last published at or before `published_by`
+    pub fn due_dht_envelopes(&self, published_by: i64) -> Result<Vec<DhtEnvelope>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT envelope_id, message_id, recipient, envelope_json, stored_at, published_at
+             FROM dht_envelopes WHERE published_at <= ?1 ORDER BY recipient, stored_at"
+        )?;
+        let envelopes = stmt.query_map(params![published_by], |row| {
+            Ok(DhtEnvelope {
+                envelope_id: row.get(0)?,
+                message_id: row.get(1)?,
+                recipient: row.get(2)?,
+                envelope_json: row.get(3)?,
+                stored_at: row.get(4)?,
+                published_at: row.get(5)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(envelopes)
+    }
+
+    /// Record that envelopes tracked for `recipient` were republished at `now`
+    pub fn mark_dht_published(&self, recipient: &str, envelope_ids: &[String], now: i64) -> Result<()> {
+        let conn = self.conn()?;
+        for id in envelope_ids {
+            conn.execute(
+                "UPDATE dht_envelopes SET published_at = ?3 WHERE envelope_id = ?1 AND recipient = ?2",
+                params![id, recipient, now],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Stop republishing an envelope `recipient` acknowledged. Returns the
+    /// Sent copy's message id, if the envelope was still tracked.
+    pub fn untrack_dht_envelope(&self, envelope_id: &str, recipient: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "DELETE FROM dht_envelopes WHERE envelope_id = ?1 AND recipient = ?2 RETURNING message_id"
+        )?;
+        let mut rows = stmt.query_map(params![envelope_id, recipient], |row| row.get(0))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Stop republishing envelopes stored before `cutoff`. Returns how many were dropped.
+    pub fn expire_dht_envelopes(&self, cutoff: i64) -> Result<usize> {
+        let conn = self.conn()?;
+        let affected = conn.execute("DELETE FROM dht_envelopes WHERE stored_at < ?1", params![cutoff])?;
+        Ok(affected)
+    }
+
+    /// Make envelopes for the Ledger ID(s) `peer_id` serves due now, e.g. because
+    /// the peer just connected. Returns how many were brought forward.
+    pub fn retry_outbox_for_peer(&self, peer_id: &str) -> Result<usize> {
+        let conn = self.conn()?;
+        let affected = conn.execute(
+            "UPDATE outbox SET next_attempt_at = ?2
+             WHERE next_attempt_at > ?2
+               AND recipient IN (SELECT ledger_id FROM peer_directory WHERE peer_id = ?1)",
+            params![peer_id, chrono::Utc::now().timestamp()],
+        )?;
+        Ok(affected)
+    }
+
+    // ── Read receipts ──
+
+    /// Remember that the envelope `envelope_id` sent to `recipient` as part of
+    /// `message_id` asked for a read receipt
+    pub fn expect_read_receipt(&self, envelope_id: &str, message_id: &str, recipient: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR IGNORE INTO read_receipts (envelope_id, message_id, recipient) VALUES (?1, ?2, ?3)",
+            params![envelope_id, message_id, recipient],
+        )?;
+        Ok(())
+    }
+
+    /// Record `reader`'s receipt for `envelope_id`, returning the sent message
+    /// it belongs to. Only the first receipt from the envelope's recipient counts.
+    pub fn record_read_receipt(&self, envelope_id: &str, reader: &str, read_at: i64) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "UPDATE read_receipts SET read_at = ?3
+             WHERE envelope_id = ?1 AND recipient = ?2 AND read_at IS NULL
+             RETURNING message_id",
+        )?;
+        let mut rows = stmt.query_map(params![envelope_id, reader, read_at], |row| row.get(0))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Read receipt state of every recipient asked for one on a sent message.
+    /// A recipient may have been sent several envelopes (P2P, then the DHT or
+    /// outbox); a receipt for any of them counts.
+    pub fn get_read_receipts(&self, message_id: &str) -> Result<Vec<ReadReceiptStatus>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT recipient, MIN(read_at) FROM read_receipts WHERE message_id = ?1
+             GROUP BY recipient ORDER BY recipient",
+        )?;
+        let rows = stmt.query_map(params![message_id], |row| {
+            Ok(ReadReceiptStatus { recipient: row.get(0)?, read_at: row.get(1)? })
+        })?;
+
+        let mut receipts = Vec::new();
+        for row in rows {
+            receipts.push(row?);
+        }
+        Ok(receipts)
+    }
+
+    // ── Spam ──
+
+    /// Never file mail from `address` as spam again
+    pub fn allow_sender(&self, address: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR IGNORE INTO spam_allowlist (address, added_at) VALUES (?1, ?2)",
+            params![address.to_lowercase(), chrono::Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Whether `address` belongs to a contact or was marked not-spam
+    pub fn is_known_sender(&self, address: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let known = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM spam_allowlist WHERE address = ?1)
+                 OR EXISTS(SELECT 1 FROM contacts WHERE lower(gmail_address) = ?1)",
+            params![address.to_lowercase()],
+            |row| row.get(0),
+        )?;
+        Ok(known)
+    }
+
+    // ── Retired keys ──
+
+    /// Keep a rotated-away identity's encryption key until `key.expires_at`,
+    /// sealed under `passphrase` if the identity has one. Retiring the same
+    /// identity again moves its expiry.
+    pub fn retire_key(&self, key: &RetiredKey, retired_at: i64, passphrase: Option<&str>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO retired_keys (ledger_id, encryption_secret, retired_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![key.ledger_id, key.sealed_secret(passphrase)?, retired_at, key.expires_at],
+        )?;
+        Ok(())
+    }
+
+    /// Retired keys still in their grace period at `now`, opened with `passphrase`
+    pub fn get_retired_keys(&self, now: i64, passphrase: Option<&str>) -> Result<Vec<RetiredKey>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT ledger_id, encryption_secret, expires_at FROM retired_keys
+             WHERE expires_at > ?1 ORDER BY retired_at DESC"
+        )?;
+        let rows = stmt.query_map(params![now], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, i64>(2)?))
+        })?;
+
+        let mut keys = Vec::new();
+        for row in rows {
+            let (ledger_id, secret, expires_at) = row?;
+            keys.push(RetiredKey::open(ledger_id, &secret, expires_at, passphrase)?);
+        }
+        Ok(keys)
+    }
+
+    /// Seal retired keys stored raw under `passphrase`, as happens to an
+    /// unencrypted `identity.key` once the node gets one. Returns how many.
+    pub fn seal_retired_keys(&self, passphrase: &str) -> Result<usize> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT ledger_id, encryption_secret, expires_at FROM retired_keys")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, i64>(2)?))
+        })?.collect::<SqlResult<Vec<_>>>()?;
+
+        let mut sealed = 0;
+        for (ledger_id, secret, expires_at) in rows.into_iter().filter(|(_, secret, _)| RetiredKey::is_plaintext(secret)) {
+            let key = RetiredKey::open(ledger_id, &secret, expires_at, None)?;
+            conn.execute(
+                "UPDATE retired_keys SET encryption_secret = ?2 WHERE ledger_id = ?1",
+                params![key.ledger_id, key.sealed_secret(Some(passphrase))?],
+            )?;
+            sealed += 1;
+        }
+        Ok(sealed)
+    }
+
+    /// Forget retired keys whose grace period ended before `now`
+    pub fn purge_retired_keys(&self, now: i64) -> Result<usize> {
+        let conn = self.conn()?;
+        let purged = conn.execute("DELETE FROM retired_keys WHERE expires_at <= ?1", params![now])?;
+        Ok(purged)
+    }
+
+    // ── Settings ──
+
+    /// Get a setting
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+        let mut rows = stmt.query_map(params![key], |row| row.get::<_, String>(0))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Set a setting
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Set several settings in one transaction, so either all of them change or none do
+    pub fn set_settings(&self, settings: &[(&str, String)]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        for (key, value) in settings {
+            tx.execute(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// SOCKS5 proxy to route outbound traffic through, when Tor is enabled
+    pub fn tor_proxy(&self) -> Result<Option<String>> {
+        if self.get_setting("tor_enabled")?.as_deref() != Some("true") {
+            return Ok(None);
+        }
+        Ok(Some(self.get_setting("tor_socks_addr")?
+            .unwrap_or_else(|| crate::tor::DEFAULT_SOCKS_ADDR.to_string())))
+    }
+
+    /// Stored Gmail credentials, if Gmail has been configured with either an
+    /// app password or OAuth2 tokens
+    pub fn gmail_config(&self) -> Result<Option<GmailConfig>> {
+        let Some(email) = self.get_setting("gmail_email")? else {
+            return Ok(None);
+        };
+        let app_password = self.get_setting("gmail_app_password")?.unwrap_or_default();
+        let access_token = self.get_setting("gmail_access_token")?;
+        let refresh_token = self.get_setting("gmail_refresh_token")?;
+        if app_password.is_empty() && access_token.is_none() && refresh_token.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(GmailConfig {
+            email,
+            app_password,
+            imap_host: self.get_setting("gmail_imap_host")?,
+            smtp_host: self.get_setting("gmail_smtp_host")?,
+            oauth_client_id: self.get_setting("gmail_oauth_client_id")?,
+            oauth_client_secret: self.get_setting("gmail_oauth_client_secret")?,
+            access_token,
+            refresh_token,
+            token_expiry: self.get_setting("gmail_token_expiry")?.and_then(|v| v.parse().ok()),
+            socks_proxy: self.tor_proxy()?,
+            send_rate: self.get_setting("gmail_send_rate")?
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(crate::gmail::smtp_client::DEFAULT_GMAIL_SEND_RATE),
+        }))
+    }
+
+    /// Where importing from `account` stopped; a fresh cursor if it was
+    /// last used for a different account
+    pub fn imap_cursor(&self, account: &str) -> Result<ImapCursor> {
+        let saved = self.get_setting("gmail_imap_cursor")?
+            .and_then(|json| serde_json::from_str::<ImapCursor>(&json).ok())
+            .filter(|cursor| cursor.account == account);
+        Ok(saved.unwrap_or_else(|| ImapCursor { account: account.to_string(), ..Default::default() }))
+    }
+
+    /// Save import progress. Moving to another account or `UIDVALIDITY`
+    /// forgets the UIDs on stored mail, since the new ones can repeat them.
+    pub fn set_imap_cursor(&self, cursor: &ImapCursor) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let previous: Option<ImapCursor> = tx
+            .query_row("SELECT value FROM settings WHERE key = 'gmail_imap_cursor'", [], |row| row.get::<_, String>(0))
+            .optional()?
+            .and_then(|json| serde_json::from_str(&json).ok());
+        if previous.is_some_and(|p| p.account != cursor.account || p.uid_validity != cursor.uid_validity) {
+            tx.execute("UPDATE messages SET imap_uid = NULL WHERE imap_uid IS NOT NULL", [])?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('gmail_imap_cursor', ?1)",
+            params![serde_json::to_string(cursor)?],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Get all settings as key-value pairs
+    pub fn get_all_settings(&self) -> Result<std::collections::HashMap<String, String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT key, value FROM settings")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut settings = std::collections::HashMap::new();
+        for row in rows {
+            let (k, v) = row?;
+            settings.insert(k, v);
+        }
+        Ok(settings)
+    }
+}
+
+/// A write that broke `idx_contacts_alias` means the alias belongs to someone else
+fn alias_conflict(e: rusqlite::Error, alias: Option<&str>) -> LedgerError {
+    match (e.sqlite_error_code(), alias) {
+        (Some(rusqlite::ErrorCode::ConstraintViolation), Some(alias)) =>
+            LedgerError::invalid(format!("Alias {} is already taken", alias)),
+        _ => e.into(),
+    }
+}
+
+/// `WHERE` conditions over `messages` and the values for their `?`s, in
+/// order. Each filter adds its own condition, so they combine freely.
+struct MessageQuery {
+    conditions: Vec<&'static str>,
+    values: Vec<Box<dyn ToSql>>,
+}
+
+impl MessageQuery {
+    fn new(filter: &MessageFilter) -> Self {
+        let mut query = Self { conditions: Vec::new(), values: Vec::new() };
+        match filter.folder {
+            Some(ref folder) => query.push("folder = ?", vec![Box::new(folder.clone())]),
+            None => query.push("folder != 'trash'", vec![]),
+        }
+        if filter.starred_only {
+            query.push("is_starred = 1", vec![]);
+        }
+        if filter.unread_only {
+            query.push("is_read = 0", vec![]);
+        }
+        if let Some(ref from) = filter.from {
+            query.push(
+                "(lower(from_id) = lower(?) OR instr(lower(from_id), '<' || lower(?) || '>') > 0)",
+                vec![Box::new(from.clone()), Box::new(from.clone())],
+            );
+        }
+        if let Some(after) = filter.after {
+            query.push("timestamp >= ?", vec![Box::new(after)]);
+        }
+        if let Some(before) = filter.before {
+            query.push("timestamp < ?", vec![Box::new(before)]);
+        }
+        query
+    }
+
+    /// Add a condition with one value per `?` in it
+    fn push(&mut self, condition: &'static str, values: Vec<Box<dyn ToSql>>) {
+        debug_assert_eq!(condition.matches('?').count(), values.len(), "{}", condition);
+        self.conditions.push(condition);
+        self.values.extend(values);
+    }
+
+    fn where_clause(&self) -> String {
+        self.conditions.join(" AND ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db(name: &str) -> (Database, PathBuf) {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        (Database::open(&dir).unwrap(), dir)
+    }
+
+    #[test]
+    fn test_query_messages_pages_newest_first() {
+        let (db, dir) = temp_db("ledger_test_db_pagination");
+        for i in 0..5 {
+            let mut msg = Message::new("a".into(), "b".into(), format!("m{}", i), String::new());
+            msg.timestamp = i;
+            db.insert_message(&msg).unwrap();
+        }
+
+        let subjects = |page: Vec<Message>| page.into_iter().map(|m| m.subject).collect::<Vec<_>>();
+        assert_eq!(subjects(db.query_messages(&MessageFilter::folder("inbox"), 2, 0).unwrap()), vec!["m4", "m3"]);
+        assert_eq!(subjects(db.query_messages(&MessageFilter::folder("inbox"), 2, 4).unwrap()), vec!["m0"]);
+        assert!(db.query_messages(&MessageFilter::folder("sent"), 2, 0).unwrap().is_empty());
+        assert_eq!(db.query_messages(&MessageFilter::default(), 10, 0).unwrap().len(), 5);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_query_messages_by_sender_and_date() {
+        let (db, dir) = temp_db("ledger_test_db_query_messages");
+        for (i, from) in ["ledger:alice", "Bob Example <Bob@Example.com>", "ledger:alice", "bob@example.com"].iter().enumerate() {
+            let mut msg = Message::new(from.to_string(), "me".into(), format!("m{}", i), String::new());
+            msg.timestamp = 100 * i as i64;
+            db.insert_message(&msg).unwrap();
+        }
+        let subjects = |filter: MessageFilter| {
+            db.query_messages(&filter, 10, 0).unwrap().into_iter().map(|m| m.subject).collect::<Vec<_>>()
+        };
+
+        let from = |from: &str| MessageFilter { from: Some(from.into()), ..Default::default() };
+        assert_eq!(subjects(from("ledger:alice")), vec!["m2", "m0"]);
+        assert_eq!(subjects(from("BOB@example.com")), vec!["m3", "m1"]);
+        assert!(subjects(from("example.com")).is_empty());
+
+        // `after` is inclusive and `before` exclusive, and both combine with the rest
+        assert_eq!(subjects(MessageFilter { after: Some(100), before: Some(300), ..Default::default() }), vec!["m2", "m1"]);
+        assert_eq!(subjects(MessageFilter { after: Some(100), ..from("ledger:alice") }), vec!["m2"]);
+        assert!(subjects(MessageFilter { folder: Some("sent".into()), ..from("ledger:alice") }).is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_starred_filter() {
+        let (db, dir) = temp_db("ledger_test_db_starred");
+        let messages: Vec<Message> = (0..3)
+            .map(|i| Message::new("a".into(), "b".into(), format!("m{}", i), String::new()))
+            .collect();
+        for msg in &messages {
+            db.insert_message(msg).unwrap();
+        }
+
+        assert!(db.set_starred(&messages[1].id, true).unwrap());
+        assert!(!db.set_starred("missing", true).unwrap());
+        let starred = db.query_messages(&MessageFilter { starred_only: true, ..Default::default() }, 10, 0).unwrap();
+        assert_eq!(starred.len(), 1);
+        assert_eq!(starred[0].subject, "m1");
+        assert!(starred[0].is_starred);
+
+        // Filters combine: unread and starred
+        db.set_read(&messages[0].id, true).unwrap();
+        let unread = MessageFilter { unread_only: true, ..Default::default() };
+        assert_eq!(db.query_messages(&unread, 10, 0).unwrap().len(), 2);
+        assert_eq!(db.query_messages(&MessageFilter { starred_only: true, ..unread }, 10, 0).unwrap().len(), 1);
+
+        // Starring survives a move
+        db.move_message(&messages[1].id, &Folder::Archive).unwrap();
+        assert_eq!(db.query_messages(&MessageFilter { starred_only: true, ..MessageFilter::folder("archive") }, 10, 0).unwrap().len(), 1);
+        assert!(db.query_messages(&MessageFilter { starred_only: true, ..MessageFilter::folder("inbox") }, 10, 0).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_flags_and_unread_counts() {
+        let (db, dir) = temp_db("ledger_test_db_unread");
+        let inbox: Vec<Message> = (0..3)
+            .map(|i| Message::new("a".into(), "b".into(), format!("m{}", i), String::new()))
+            .collect();
+        for msg in &inbox {
+            db.insert_message(msg).unwrap();
+        }
+        let mut sent = Message::new("b".into(), "a".into(), "s".into(), String::new());
+        sent.folder = Folder::Sent;
+        db.insert_message(&sent).unwrap();
+
+        let counts = db.unread_counts().unwrap();
+        assert_eq!(counts.get("inbox"), Some(&3));
+        assert_eq!(counts.get("sent"), Some(&1));
+
+        assert!(db.set_read(&inbox[0].id, true).unwrap());
+        assert_eq!(db.unread_counts().unwrap().get("inbox"), Some(&2));
+        assert!(db.set_read(&inbox[0].id, false).unwrap());
+        assert!(!db.set_read("missing", true).unwrap());
+
+        assert_eq!(db.mark_all_read(&Folder::Inbox).unwrap(), 3);
+        let counts = db.unread_counts().unwrap();
+        assert_eq!(counts.get("inbox"), None);
+        assert_eq!(counts.get("sent"), Some(&1));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_trash_restore_and_purge() {
+        let (db, dir) = temp_db("ledger_test_db_trash");
+        let mut msg = Message::new("a".into(), "b".into(), "s".into(), String::new());
+        msg.folder = Folder::Sent;
+        db.insert_message(&msg).unwrap();
+
+        assert!(db.trash_message(&msg.id).unwrap());
+        assert!(!db.trash_message(&msg.id).unwrap());
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().folder, Folder::Trash);
+
+        assert!(db.restore_message(&msg.id).unwrap());
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().folder, Folder::Sent);
+
+        db.trash_message(&msg.id).unwrap();
+        assert_eq!(db.purge_trash(chrono::Utc::now().timestamp() - 60).unwrap(), 0);
+        assert_eq!(db.purge_trash(chrono::Utc::now().timestamp() + 60).unwrap(), 1);
+        assert!(db.get_message(&msg.id).unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_move_message() {
+        let (db, dir) = temp_db("ledger_test_db_move");
+        let msg = Message::new("a".into(), "b".into(), "s".into(), String::new());
+        db.insert_message(&msg).unwrap();
+
+        assert!(db.move_message(&msg.id, &Folder::Archive).unwrap());
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().folder, Folder::Archive);
+
+        // Trash remembers the folder it was filed from
+        assert!(db.move_message(&msg.id, &Folder::Trash).unwrap());
+        assert!(db.restore_message(&msg.id).unwrap());
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().folder, Folder::Archive);
+
+        assert!(!db.move_message("missing", &Folder::Inbox).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bulk_delete_and_move() {
+        let (db, dir) = temp_db("ledger_test_db_bulk");
+        let ids: Vec<String> = (0..3).map(|i| {
+            let msg = Message::new("a".into(), "b".into(), format!("m{}", i), String::new());
+            db.insert_message(&msg).unwrap();
+            msg.id
+        }).collect();
+        let folder = |id: &str| db.get_message(id).unwrap().unwrap().folder;
+
+        let moved = db.bulk_move(&[ids[0].clone(), "missing".into(), ids[1].clone()], &Folder::Archive).unwrap();
+        assert_eq!(moved, BulkResult { affected: 2, not_found: vec!["missing".into()] });
+        assert_eq!(folder(&ids[1]), Folder::Archive);
+
+        // Soft delete goes through Trash, skipping what is already there
+        db.trash_message(&ids[2]).unwrap();
+        let trashed = db.bulk_delete(&ids, false).unwrap();
+        assert_eq!(trashed, BulkResult { affected: 2, not_found: vec![] });
+        assert!(db.restore_message(&ids[0]).unwrap());
+        assert_eq!(folder(&ids[0]), Folder::Archive);
+
+        let deleted = db.bulk_delete(&[ids[0].clone(), ids[0].clone(), ids[1].clone()], true).unwrap();
+        assert_eq!(deleted, BulkResult { affected: 2, not_found: vec![] });
+        assert!(db.get_message(&ids[0]).unwrap().is_none());
+        assert_eq!(folder(&ids[2]), Folder::Trash);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_known_peers_upsert_and_prune() {
+        let (db, dir) = temp_db("ledger_test_db_known_peers");
+        db.upsert_known_peer("peer-a", "/ip4/10.0.0.1/tcp/4001").unwrap();
+        db.upsert_known_peer("peer-a", "/ip4/10.0.0.1/tcp/4001").unwrap();
+        db.upsert_known_peer("peer-b", "/ip4/10.0.0.2/tcp/4001").unwrap();
+        assert_eq!(db.get_known_peers().unwrap().len(), 2);
+        assert_eq!(db.get_known_peer_addresses("peer-a").unwrap(), vec!["/ip4/10.0.0.1/tcp/4001".to_string()]);
+
+        assert_eq!(db.prune_known_peers(chrono::Utc::now().timestamp() - 60).unwrap(), 0);
+        assert_eq!(db.prune_known_peers(chrono::Utc::now().timestamp() + 60).unwrap(), 2);
+        assert!(db.get_known_peers().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_ledger_id_for_peer() {
+        let (db, dir) = temp_db("ledger_test_db_ledger_id_for_peer");
+        assert_eq!(db.get_ledger_id_for_peer("peer-a").unwrap(), None);
+
+        db.upsert_peer_mapping("ledger:alice", "peer-a", "/ip4/10.0.0.1/tcp/4001").unwrap();
+        assert_eq!(db.get_ledger_id_for_peer("peer-a").unwrap().as_deref(), Some("ledger:alice"));
+        assert_eq!(db.get_ledger_id_for_peer("peer-b").unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_encrypt_existing_database() {
+        let (db, dir) = temp_db("ledger_test_encrypt_db");
+        let msg = Message::new("ledger:a".into(), "ledger:b".into(), "Hi".into(), "Secret body".into());
+        db.insert_message(&msg).unwrap();
+        drop(db);
+        assert!(!Database::is_encrypted(&dir));
+
+        let db = Database::open_with_key(&dir, Some("correct horse")).unwrap();
+        assert!(Database::is_encrypted(&dir));
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().body, "Secret body");
+        drop(db);
+
+        assert!(Database::open(&dir).is_err());
+        assert!(Database::open_with_key(&dir, Some("wrong")).is_err());
+        let db = Database::open_with_key(&dir, Some("correct horse")).unwrap();
+        assert_eq!(db.get_message(&msg.id).unwrap().unwrap().subject, "Hi");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_connections_checked_out_concurrently() {
+        let (db, dir) = temp_db("ledger_test_pool");
+        // A long-lived checkout no longer shuts everyone else out
+        let held = db.conn().unwrap();
+        assert!(db.ping(std::time::Duration::from_millis(100)));
+        assert!(db.query_messages(&MessageFilter::default(), 10, 0).unwrap().is_empty());
+        drop(held);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_vacuum_reclaims_deleted_space() {
+        let (db, dir) = temp_db("ledger_test_vacuum");
+        let body = "x".repeat(4096);
+        for _ in 0..200 {
+            db.insert_message(&Message::new("a".into(), "b".into(), "s".into(), body.clone())).unwrap();
+        }
+        db.checkpoint().unwrap();
+        for msg in db.query_messages(&MessageFilter::default(), 500, 0).unwrap() {
+            db.delete_message(&msg.id).unwrap();
+        }
+
+        assert!(db.vacuum().unwrap() > 0);
+        // The pool is usable again afterwards
+        assert!(db.query_messages(&MessageFilter::default(), 10, 0).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dht_envelope_tracking() {
+        let (db, dir) = temp_db("ledger_test_dht_tracking");
+        db.track_dht_envelope("msg-1", "ledger:bob", "env-1", "{}", 100).unwrap();
+        db.track_dht_envelope("msg-2", "ledger:bob", "env-2", "{}", 500).unwrap();
+        // Storing again only moves the publish time
+        db.track_dht_envelope("msg-1", "ledger:bob", "env-1", "{}", 200).unwrap();
+
+        let due = db.due_dht_envelopes(300).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].stored_at, due[0].published_at), (100, 200));
+
+        db.mark_dht_published("ledger:bob", &["env-1".into()], 600).unwrap();
+        assert!(db.due_dht_envelopes(300).unwrap().is_empty());
+
+        // A group envelope is tracked once per recipient
+        db.track_dht_envelope("msg-2", "ledger:carol", "env-2", "{}", 500).unwrap();
+        assert_eq!(db.untrack_dht_envelope("env-2", "ledger:carol").unwrap().as_deref(), Some("msg-2"));
+        assert_eq!(db.due_dht_envelopes(i64::MAX).unwrap().len(), 2);
+
+        assert_eq!(db.untrack_dht_envelope("env-1", "ledger:bob").unwrap().as_deref(), Some("msg-1"));
+        assert_eq!(db.untrack_dht_envelope("env-1", "ledger:bob").unwrap(), None);
+        assert_eq!(db.expire_dht_envelopes(1000).unwrap(), 1);
+        assert!(db.due_dht_envelopes(i64::MAX).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_imap_cursor_and_uids() {
+        let (db, dir) = temp_db("ledger_test_imap_cursor");
+        let mut cursor = db.imap_cursor("a@gmail.com").unwrap();
+        assert_eq!(cursor.last_uid, 0);
+
+        cursor.sync("a@gmail.com", 7);
+        cursor.last_uid = 42;
+        db.set_imap_cursor(&cursor).unwrap();
+        let mut msg = Message::new("x@example.com".into(), "a@gmail.com".into(), "s".into(), "b".into());
+        msg.imap_uid = Some(42);
+        db.insert_message(&msg).unwrap();
+
+        assert_eq!(db.imap_cursor("a@gmail.com").unwrap(), cursor);
+        assert_eq!(db.imap_cursor("b@gmail.com").unwrap().last_uid, 0);
+        assert!(db.has_imap_uid(42).unwrap());
+
+        // A new UIDVALIDITY starts the count again and forgets old UIDs
+        cursor.sync("a@gmail.com", 8);
+        assert_eq!(cursor.last_uid, 0);
+        db.set_imap_cursor(&cursor).unwrap();
+        assert!(!db.has_imap_uid(42).unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_checkpoint_empties_wal() {
+        let (db, dir) = temp_db("ledger_test_checkpoint");
+        db.set_setting("theme", "dark").unwrap();
+        db.checkpoint().unwrap();
+
+        let wal = std::fs::metadata(dir.join("ledger.db-wal")).map(|m| m.len()).unwrap_or(0);
+        assert_eq!(wal, 0);
+        assert_eq!(db.get_setting("theme").unwrap().as_deref(), Some("dark"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_block_and_unblock() {
+        let (db, dir) = temp_db("ledger_test_db_blocklist");
+        assert!(!db.is_blocked("ledger:spam").unwrap());
+
+        db.block("ledger:spam", "peer-spam").unwrap();
+        db.block("ledger:spam", "peer-spam").unwrap();
+        assert!(db.is_blocked("ledger:spam").unwrap());
+        assert!(db.is_peer_blocked("peer-spam").unwrap());
+        assert_eq!(db.get_blocklist().unwrap().len(), 1);
+
+        assert!(db.unblock("ledger:spam").unwrap());
+        assert!(!db.unblock("ledger:spam").unwrap());
+        assert!(!db.is_peer_blocked("peer-spam").unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_outbox_schedule() {
+        let (db, dir) = temp_db("ledger_test_db_outbox");
+        let now = chrono::Utc::now().timestamp();
+        let entry = OutboxEntry {
+            id: "env-1".into(),
+            message_id: "msg-1".into(),
+            recipient: "ledger:bob".into(),
+            envelope_json: "{}".into(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+        };
+        db.enqueue_outbox(&entry).unwrap();
+        assert_eq!(db.due_outbox(now).unwrap().len(), 1);
+
+        db.reschedule_outbox("env-1", 1, now + 600, "offline").unwrap();
+        assert!(db.due_outbox(now).unwrap().is_empty());
+        assert_eq!(db.get_outbox().unwrap()[0].last_error.as_deref(), Some("offline"));
+
+        // The recipient's peer coming online makes it due again
+        db.upsert_peer_mapping("ledger:bob", "peer-bob", "").unwrap();
+        assert_eq!(db.retry_outbox_for_peer("peer-bob").unwrap(), 1);
+        assert_eq!(db.due_outbox(now + 1).unwrap().len(), 1);
+
+        assert!(db.remove_outbox("env-1").unwrap());
+        assert!(db.get_outbox().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_update_and_delete_contact() {
+        let (db, dir) = temp_db("ledger_test_db_contacts");
+        db.upsert_contact(&Contact {
+            ledger_id: "ledger:alice".into(),
+            public_key: "pk".into(),
+            encryption_public_key: None,
+            display_name: Some("Alice".into()),
+            gmail_address: Some("alice@gmail.com".into()),
+            verified: false,
+            alias: None,
+            preferred_delivery: None,
+        }).unwrap();
+
+        assert!(db.update_contact("ledger:alice", Some("Alice B"), None, None, None, None).unwrap());
+        let contact = db.get_contact("ledger:alice").unwrap().unwrap();
+        assert_eq!(contact.display_name.as_deref(), Some("Alice B"));
+        assert_eq!(contact.gmail_address.as_deref(), Some("alice@gmail.com"));
+        assert!(!contact.verified);
+
+        assert!(db.update_contact("ledger:alice", None, Some(""), Some(true), None, None).unwrap());
+        let contact = db.get_contact("ledger:alice").unwrap().unwrap();
+        assert_eq!(contact.gmail_address, None);
+        assert!(contact.verified);
+        assert!(!db.update_contact("ledger:bob", Some("Bob"), None, None, None, None).unwrap());
+
+        assert!(db.update_contact("ledger:alice", None, None, None, None, Some("gmail_only")).unwrap());
+        let contact = db.get_contact("ledger:alice").unwrap().unwrap();
+        assert_eq!(contact.preferred_delivery, Some(DeliveryMode::GmailOnly));
+        assert!(db.update_contact("ledger:alice", None, None, None, None, Some("")).unwrap());
+        assert_eq!(db.get_contact("ledger:alice").unwrap().unwrap().preferred_delivery, None);
+
+        assert!(db.delete_contact("ledger:alice").unwrap());
+        assert!(!db.delete_contact("ledger:alice").unwrap());
+        assert!(db.get_contact("ledger:alice").unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_contact_aliases_are_unique() {
+        let (db, dir) = temp_db("ledger_test_db_contact_alias");
+        let contact = |ledger_id: &str, alias: Option<&str>| Contact {
+            ledger_id: ledger_id.into(),
+            public_key: "pk".into(),
+            encryption_public_key: None,
+            display_name: None,
+            gmail_address: None,
+            verified: false,
+            alias: alias.map(Into::into),
+            preferred_delivery: None,
+        };
+        db.upsert_contact(&contact("ledger:alice", Some("@alice"))).unwrap();
+        db.upsert_contact(&contact("ledger:bob", None)).unwrap();
+        db.upsert_contact(&contact("ledger:carol", None)).unwrap();
+
+        assert_eq!(db.resolve_alias("@Alice").unwrap().as_deref(), Some("ledger:alice"));
+        assert_eq!(db.resolve_alias("alice").unwrap().as_deref(), Some("ledger:alice"));
+        assert_eq!(db.resolve_alias("@nobody").unwrap(), None);
+
+        // Neither an upsert nor an update may take someone else's alias
+        let taken = db.upsert_contact(&contact("ledger:bob", Some("@alice"))).unwrap_err();
+        assert!(matches!(taken, LedgerError::InvalidInput(_)));
+        assert!(db.update_contact("ledger:carol", None, None, None, Some("@alice"), None).is_err());
+        assert_eq!(db.get_contact("ledger:alice").unwrap().unwrap().alias.as_deref(), Some("@alice"));
+
+        // Re-upserting keeps it, and clearing frees it up
+        db.upsert_contact(&contact("ledger:alice", Some("@alice"))).unwrap();
+        assert!(db.update_contact("ledger:alice", None, None, None, Some(""), None).unwrap());
+        assert!(db.update_contact("ledger:bob", None, None, None, Some("@alice"), None).unwrap());
+        assert_eq!(db.resolve_alias("@alice").unwrap().as_deref(), Some("ledger:bob"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_retired_keys_expire() {
+        let (db, dir) = temp_db("ledger_test_db_retired_keys");
+        let old = crate::crypto::keys::LedgerIdentity::generate().unwrap();
+        db.retire_key(&old.retire(200), 100, None).unwrap();
+
+        let keys = db.get_retired_keys(150, None).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].ledger_id, old.ledger_id);
+        assert_eq!(keys[0].encryption_secret.to_bytes(), old.encryption_secret.to_bytes());
+
+        // Retiring again moves the expiry rather than adding a second key
+        db.retire_key(&old.retire(300), 250, None).unwrap();
+        assert_eq!(db.get_retired_keys(250, None).unwrap().len(), 1);
+
+        assert!(db.get_retired_keys(300, None).unwrap().is_empty());
+        assert_eq!(db.purge_retired_keys(300).unwrap(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_retired_keys_sealed_under_passphrase() {
+        let (db, dir) = temp_db("ledger_test_db_retired_keys_sealed");
+        let old = crate::crypto::keys::LedgerIdentity::generate().unwrap();
+        let secret = old.encryption_secret.to_bytes();
+        let stored = |db: &Database| -> Vec<u8> {
+            db.conn().unwrap().query_row("SELECT encryption_secret FROM retired_keys", [], |row| row.get(0)).unwrap()
+        };
+
+        db.retire_key(&old.retire(200), 100, Some("hunter2")).unwrap();
+        assert!(!stored(&db).windows(32).any(|w| w == secret));
+        assert!(db.get_retired_keys(150, None).is_err());
+        assert!(db.get_retired_keys(150, Some("wrong")).is_err());
+        assert_eq!(db.get_retired_keys(150, Some("hunter2")).unwrap()[0].encryption_secret.to_bytes(), secret);
+
+        // Keys retired before the node had a passphrase get sealed once it does
+        db.retire_key(&old.retire(200), 100, None).unwrap();
+        assert_eq!(stored(&db), secret);
+        assert_eq!(db.seal_retired_keys("hunter2").unwrap(), 1);
+        assert_eq!(db.seal_retired_keys("hunter2").unwrap(), 0);
+        assert_eq!(db.get_retired_keys(150, Some("hunter2")).unwrap()[0].encryption_secret.to_bytes(), secret);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backfill_contact_encryption_keys() {
+        use base64::Engine;
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let (db, dir) = temp_db("ledger_test_db_contact_backfill");
+        let bob = crate::crypto::keys::LedgerIdentity::generate().unwrap();
+        let legacy_key = b64.encode([7u8; 32]);
+        {
+            let conn = db.conn().unwrap();
+            conn.execute(
+                "INSERT INTO contacts (ledger_id, public_key) VALUES (?1, ?2), (?3, ?4)",
+                params![
+                    "ledger:legacy", legacy_key,
+                    bob.ledger_id, bs58::encode(bob.public_key_bytes()).into_string(),
+                ],
+            ).unwrap();
+        }
+        drop(db);
+
+        let db = Database::open(&dir).unwrap();
+        let legacy = db.get_contact("ledger:legacy").unwrap().unwrap();
+        assert_eq!(legacy.encryption_public_key, Some(legacy_key));
+        let derived = db.get_contact(&bob.ledger_id).unwrap().unwrap();
+        let expected = crate::crypto::keys::LedgerIdentity::x25519_public_from_ed25519(&bob.public_key_bytes()).unwrap();
+        assert_eq!(derived.encryption_public_key, Some(b64.encode(expected)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_contact_keys_rederived() {
+        use base64::Engine;
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let (db, dir) = temp_db("ledger_test_db_contact_rederive");
+        let bob = crate::crypto::keys::LedgerIdentity::generate().unwrap();
+        {
+            // A key copied from Bob's node before it switched derivations, or substituted
+            let conn = db.conn().unwrap();
+            conn.execute(
+                "INSERT INTO contacts (ledger_id, public_key, encryption_public_key) VALUES (?1, '', ?2)",
+                params![bob.ledger_id, b64.encode([9u8; 32])],
+            ).unwrap();
+        }
+        drop(db);
+
+        let db = Database::open(&dir).unwrap();
+        let contact = db.get_contact(&bob.ledger_id).unwrap().unwrap();
+        assert_eq!(contact.encryption_public_key, Some(b64.encode(bob.encryption_public_bytes())));
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
@@ -0,0 +1,212 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+use crate::models::message::{ContentType, Message};
+
+/// Domain given to Ledger IDs so they read as email addresses. `.invalid` is
+/// reserved, so nothing can ever be delivered there by mistake.
+const LEDGER_MAIL_DOMAIN: &str = "ledger.invalid";
+
+/// RFC 5322 caps lines at 998 bytes; a body with longer lines goes out as base64
+const MAX_LINE_BYTES: usize = 998;
+
+/// Render a stored message as an RFC 822 email with CRLF line endings.
+/// Mail that never was email gets headers made up from its Ledger IDs.
+pub fn to_eml(msg: &Message) -> String {
+    let mut out = String::new();
+    let mut header = |name: &str, value: &str| {
+        out.push_str(name);
+        out.push_str(": ");
+        out.push_str(value);
+        out.push_str("\r\n");
+    };
+
+    header("Message-ID", &message_id(msg));
+    header("Date", &date(msg.timestamp).to_rfc2822());
+    header("From", &address(&msg.from_id));
+    let to: Vec<String> = msg.to_id.split(',')
+        .map(str::trim)
+        .filter(|to| !to.is_empty())
+        .map(address)
+        .collect();
+    if !to.is_empty() {
+        header("To", &to.join(", "));
+    }
+    header("Subject", &encode_header(&msg.subject));
+    header("MIME-Version", "1.0");
+    let mime = match msg.content_type {
+        ContentType::Text => "text/plain",
+        ContentType::Html => "text/html",
+    };
+    header("Content-Type", &format!("{}; charset=utf-8", mime));
+
+    let body = msg.body.replace("\r\n", "\n");
+    if body.lines().any(|line| line.len() > MAX_LINE_BYTES) {
+        header("Content-Transfer-Encoding", "base64");
+        out.push_str("\r\n");
+        let encoded = BASE64.encode(body.as_bytes());
+        for line in encoded.as_bytes().chunks(76) {
+            out.push_str(&String::from_utf8_lossy(line));
+            out.push_str("\r\n");
+        }
+    } else {
+        header("Content-Transfer-Encoding", "8bit");
+        out.push_str("\r\n");
+        for line in body.lines() {
+            out.push_str(line);
+            out.push_str("\r\n");
+        }
+    }
+    out
+}
+
+/// One mboxrd entry: a `From ` separator line, then the message with LF
+/// endings and any line starting `From ` (after `>`s) quoted with another `>`
+pub fn to_mbox_entry(msg: &Message) -> String {
+    let sender = mailbox_addr(&msg.from_id);
+    let mut out = format!("From {} {}\n", sender, date(msg.timestamp).format("%a %b %e %H:%M:%S %Y"));
+    for line in to_eml(msg).split("\r\n") {
+        if line.trim_start_matches('>').starts_with("From ") {
+            out.push('>');
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Whether an upload is an mbox file rather than a single message
+pub fn is_mbox(data: &[u8]) -> bool {
+    data.starts_with(b"From ")
+}
+
+/// Split an mbox file into its raw messages, dropping each `From ` separator
+/// and removing one `>` from quoted `From ` lines (mboxrd)
+pub fn split_mbox(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(b"From ") {
+            messages.extend(current.take());
+            current = Some(Vec::new());
+            continue;
+        }
+        let Some(ref mut message) = current else { continue };
+        let unquoted = line.iter().position(|&b| b != b'>')
+            .filter(|&n| n > 0 && line[n..].starts_with(b"From "))
+            .map_or(line, |_| &line[1..]);
+        message.extend_from_slice(unquoted);
+    }
+    messages.extend(current);
+    messages
+}
+
+/// The message id behind a `Message-ID` that `to_eml` made up, so a
+/// re-imported export is recognised as the message it came from
+pub fn exported_message_id(email_message_id: &str) -> Option<&str> {
+    email_message_id.trim()
+        .strip_prefix('<')?
+        .strip_suffix('>')?
+        .strip_suffix(LEDGER_MAIL_DOMAIN)?
+        .strip_suffix('@')
+}
+
+fn date(timestamp: i64) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default()
+}
+
+/// The original `Message-ID` for imported email, else one made from the message id
+fn message_id(msg: &Message) -> String {
+    match msg.email_message_id {
+        Some(ref id) => single_line(id),
+        None => format!("<{}@{}>", msg.id, LEDGER_MAIL_DOMAIN),
+    }
+}
+
+/// A From/To address: a Ledger ID becomes `"ledger:…" <…@ledger.invalid>`,
+/// anything else is kept as it was stored
+fn address(id: &str) -> String {
+    match id.strip_prefix("ledger:") {
+        Some(key) => format!("\"{}\" <{}@{}>", id, key, LEDGER_MAIL_DOMAIN),
+        None => single_line(id),
+    }
+}
+
+/// Bare addr-spec for the mbox `From ` line
+fn mailbox_addr(id: &str) -> String {
+    if let Some(key) = id.strip_prefix("ledger:") {
+        return format!("{}@{}", key, LEDGER_MAIL_DOMAIN);
+    }
+    match (id.rfind('<'), id.rfind('>')) {
+        (Some(start), Some(end)) if start < end => id[start + 1..end].to_string(),
+        _ if id.trim().is_empty() => "MAILER-DAEMON".to_string(),
+        _ => id.split_whitespace().collect(),
+    }
+}
+
+/// A header value as RFC 2047 base64 words if it isn't plain ASCII
+fn encode_header(value: &str) -> String {
+    let value = single_line(value);
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return value;
+    }
+    format!("=?UTF-8?B?{}?=", BASE64.encode(value.as_bytes()))
+}
+
+/// Line breaks in a stored value would start a new header
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eml_headers_for_ledger_message() {
+        let mut msg = Message::new("ledger:Abc".into(), "ledger:Def, bob@example.com".into(), "Grüße".into(), "Hi\nFrom me".into());
+        msg.timestamp = 0;
+        let eml = to_eml(&msg);
+
+        assert!(eml.contains(&format!("Message-ID: <{}@ledger.invalid>\r\n", msg.id)));
+        assert!(eml.contains("Date: Thu, 1 Jan 1970 00:00:00 +0000\r\n"));
+        assert!(eml.contains("From: \"ledger:Abc\" <Abc@ledger.invalid>\r\n"));
+        assert!(eml.contains("To: \"ledger:Def\" <Def@ledger.invalid>, bob@example.com\r\n"));
+        assert!(eml.contains("Subject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n"));
+        assert!(eml.ends_with("\r\n\r\nHi\r\nFrom me\r\n"));
+
+        // A subject can't smuggle in extra headers
+        msg.subject = "Hi\r\nBcc: eve@example.com".into();
+        assert!(!to_eml(&msg).contains("\r\nBcc:"));
+    }
+
+    #[test]
+    fn test_mbox_entry_quotes_from_lines() {
+        let mut msg = Message::new("Alice <alice@example.com>".into(), "bob@example.com".into(), "s".into(), "From here\n>From there".into());
+        msg.timestamp = 0;
+        let entry = to_mbox_entry(&msg);
+
+        assert!(entry.starts_with("From alice@example.com Thu Jan  1 00:00:00 1970\n"));
+        assert!(entry.contains("\n>From here\n>>From there\n"));
+        assert!(!entry.contains('\r'));
+    }
+
+    #[test]
+    fn test_split_mbox_roundtrip() {
+        let first = Message::new("a@example.com".into(), "b@example.com".into(), "One".into(), "From here\n>From there".into());
+        let second = Message::new("ledger:Abc".into(), "b@example.com".into(), "Two".into(), "Hi".into());
+        let mbox = [&first, &second].iter().map(|msg| to_mbox_entry(msg)).collect::<String>();
+        assert!(is_mbox(mbox.as_bytes()));
+
+        let split = split_mbox(mbox.as_bytes());
+        assert_eq!(split.len(), 2);
+        let parsed = mailparse::parse_mail(&split[0]).unwrap();
+        assert_eq!(parsed.get_body().unwrap().trim_end(), "From here\n>From there");
+
+        let id = mailparse::parse_mail(&split[1]).unwrap().headers.iter()
+            .find(|h| h.get_key() == "Message-ID")
+            .map(|h| h.get_value())
+            .unwrap();
+        assert_eq!(exported_message_id(&id), Some(second.id.as_str()));
+        assert_eq!(exported_message_id("<abc@mail.gmail.com>"), None);
+    }
+}
@@ -0,0 +1,289 @@
+use rusqlite::{Connection, Transaction};
+
+use crate::error::Result;
+
+/// Schema changes in order. `PRAGMA user_version` counts how many have been
+/// applied; a new step goes at the end and is never edited once released.
+const MIGRATIONS: &[&str] = &[
+    // 1: the schema as it stood when migrations were introduced
+    "
+    CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        from_id TEXT NOT NULL,
+        to_id TEXT NOT NULL,
+        subject TEXT DEFAULT '',
+        body TEXT DEFAULT '',
+        timestamp INTEGER NOT NULL,
+        delivery_method TEXT DEFAULT 'p2p',
+        is_read INTEGER DEFAULT 0,
+        folder TEXT DEFAULT 'inbox',
+        signature TEXT,
+        encrypted INTEGER DEFAULT 0,
+        delivery_status TEXT DEFAULT 'delivered',
+        deleted_at INTEGER,
+        previous_folder TEXT,
+        email_message_id TEXT,
+        content_type TEXT DEFAULT 'text',
+        is_starred INTEGER DEFAULT 0,
+        read_receipt_requested INTEGER DEFAULT 0
+    );
+
+    CREATE TABLE IF NOT EXISTS attachments (
+        id TEXT PRIMARY KEY,
+        message_id TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        mime_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS contacts (
+        ledger_id TEXT PRIMARY KEY,
+        public_key TEXT NOT NULL,
+        encryption_public_key TEXT,
+        display_name TEXT,
+        gmail_address TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS peer_directory (
+        ledger_id TEXT PRIMARY KEY,
+        peer_id TEXT NOT NULL,
+        multiaddr TEXT DEFAULT '',
+        last_seen INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS known_peers (
+        peer_id TEXT NOT NULL,
+        multiaddr TEXT NOT NULL,
+        last_seen INTEGER NOT NULL,
+        PRIMARY KEY (peer_id, multiaddr)
+    );
+
+    CREATE TABLE IF NOT EXISTS blocklist (
+        ledger_id TEXT PRIMARY KEY,
+        peer_id TEXT NOT NULL,
+        blocked_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS outbox (
+        id TEXT PRIMARY KEY,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        envelope_json TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS read_receipts (
+        envelope_id TEXT PRIMARY KEY,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        read_at INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_messages_folder ON messages(folder);
+    CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
+    CREATE INDEX IF NOT EXISTS idx_messages_to_id ON messages(to_id);
+    CREATE INDEX IF NOT EXISTS idx_attachments_message_id ON attachments(message_id);
+    CREATE INDEX IF NOT EXISTS idx_read_receipts_message_id ON read_receipts(message_id);
+    ",
+    // 2: envelopes we stored in the DHT and republish until the recipient acknowledges them
+    "
+    CREATE TABLE dht_envelopes (
+        envelope_id TEXT PRIMARY KEY,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        envelope_json TEXT NOT NULL,
+        stored_at INTEGER NOT NULL,
+        published_at INTEGER NOT NULL
+    );
+
+    CREATE INDEX idx_dht_envelopes_published_at ON dht_envelopes(published_at);
+    ",
+    // 3: signature check outcome; stored signatures were verified before insert
+    "
+    ALTER TABLE messages ADD COLUMN signature_status TEXT NOT NULL DEFAULT 'unsigned';
+    UPDATE messages SET signature_status = 'valid' WHERE signature IS NOT NULL;
+    ",
+    // 4: UID of imported Gmail mail, so a re-fetch doesn't store it twice
+    "
+    ALTER TABLE messages ADD COLUMN imap_uid INTEGER;
+    CREATE UNIQUE INDEX idx_messages_imap_uid ON messages(imap_uid) WHERE imap_uid IS NOT NULL;
+    ",
+    // 5: whether the user has confirmed a contact's safety number
+    "
+    ALTER TABLE contacts ADD COLUMN verified INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 6: a group envelope goes to several recipients, so track it per recipient
+    "
+    CREATE TABLE read_receipts_new (
+        envelope_id TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        read_at INTEGER,
+        PRIMARY KEY (envelope_id, recipient)
+    );
+    INSERT INTO read_receipts_new SELECT envelope_id, message_id, recipient, read_at FROM read_receipts;
+    DROP TABLE read_receipts;
+    ALTER TABLE read_receipts_new RENAME TO read_receipts;
+    CREATE INDEX idx_read_receipts_message_id ON read_receipts(message_id);
+
+    CREATE TABLE dht_envelopes_new (
+        envelope_id TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        envelope_json TEXT NOT NULL,
+        stored_at INTEGER NOT NULL,
+        published_at INTEGER NOT NULL,
+        PRIMARY KEY (envelope_id, recipient)
+    );
+    INSERT INTO dht_envelopes_new
+        SELECT envelope_id, message_id, recipient, envelope_json, stored_at, published_at FROM dht_envelopes;
+    DROP TABLE dht_envelopes;
+    ALTER TABLE dht_envelopes_new RENAME TO dht_envelopes;
+    CREATE INDEX idx_dht_envelopes_published_at ON dht_envelopes(published_at);
+    ",
+    // 7: a short name to send to instead of the Ledger ID, unique when set
+    "
+    ALTER TABLE contacts ADD COLUMN alias TEXT;
+    CREATE UNIQUE INDEX idx_contacts_alias ON contacts(alias) WHERE alias IS NOT NULL;
+    ",
+    // 8: encryption keys of rotated-away identities, usable until they expire
+    "
+    CREATE TABLE retired_keys (
+        ledger_id TEXT PRIMARY KEY,
+        encryption_secret BLOB NOT NULL,
+        retired_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    ",
+    // 9: spam scoring of fetched Gmail, and senders marked not-spam
+    "
+    ALTER TABLE messages ADD COLUMN spam_score INTEGER;
+    CREATE TABLE spam_allowlist (
+        address TEXT PRIMARY KEY,
+        added_at INTEGER NOT NULL
+    );
+    ",
+    // 10: attachments sent over P2P are fetched by hash, in chunks encrypted under a per-file key
+    "
+    ALTER TABLE attachments ADD COLUMN sha256 TEXT;
+    ALTER TABLE attachments ADD COLUMN transfer_key BLOB;
+    CREATE INDEX idx_attachments_sha256 ON attachments(sha256);
+    ",
+    // 11: per-contact delivery mode, overriding the `delivery_mode` setting
+    "
+    ALTER TABLE contacts ADD COLUMN preferred_delivery TEXT;
+    ",
+    // 12: contact keys are now checked at every open, so the one-off marker goes
+    "
+    DELETE FROM settings WHERE key = 'x25519_scheme';
+    ",
+];
+
+/// Apply every migration the database hasn't had yet, each in its own
+/// transaction together with the `user_version` bump
+pub(super) fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        if index == 0 {
+            upgrade_unversioned_schema(&tx)?;
+        }
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        tracing::debug!("Applied schema migration {}", index + 1);
+    }
+    Ok(())
+}
+
+/// Databases from before migrations already have their tables, which
+/// `CREATE TABLE IF NOT EXISTS` leaves alone; add the columns they may lack
+/// so they match version 1
+fn upgrade_unversioned_schema(tx: &Transaction) -> Result<()> {
+    for (column, decl) in [
+        ("delivery_status", "TEXT DEFAULT 'delivered'"),
+        ("deleted_at", "INTEGER"),
+        ("previous_folder", "TEXT"),
+        ("email_message_id", "TEXT"),
+        ("content_type", "TEXT DEFAULT 'text'"),
+        ("is_starred", "INTEGER DEFAULT 0"),
+        ("read_receipt_requested", "INTEGER DEFAULT 0"),
+    ] {
+        add_column_if_missing(tx, "messages", column, decl)?;
+    }
+    Ok(())
+}
+
+/// Add a column to a table created by an older version of the schema
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_fresh_database_migrates_to_latest() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        // Running again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_unversioned_database_upgraded() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (
+                id TEXT PRIMARY KEY, from_id TEXT NOT NULL, to_id TEXT NOT NULL,
+                subject TEXT DEFAULT '', body TEXT DEFAULT '', timestamp INTEGER NOT NULL,
+                delivery_method TEXT DEFAULT 'p2p', is_read INTEGER DEFAULT 0,
+                folder TEXT DEFAULT 'inbox', signature TEXT, encrypted INTEGER DEFAULT 0
+            );
+            INSERT INTO messages (id, from_id, to_id, timestamp) VALUES ('m1', 'a', 'b', 1);
+            INSERT INTO messages (id, from_id, to_id, timestamp, signature) VALUES ('m2', 'a', 'b', 2, 'sig');",
+        ).unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        let (status, starred): (String, i64) = conn.query_row(
+            "SELECT delivery_status, is_starred FROM messages WHERE id = 'm1'", [], |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!((status.as_str(), starred), ("delivered", 0));
+
+        let signature_status = |id: &str| -> String {
+            conn.query_row("SELECT signature_status FROM messages WHERE id = ?1", [id], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(signature_status("m1"), "unsigned");
+        assert_eq!(signature_status("m2"), "valid");
+    }
+}
@@ -1 +1,3 @@
 pub mod db;
+pub mod mbox;
+mod migrations;
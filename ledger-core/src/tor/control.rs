@@ -0,0 +1,231 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// Where Tor's control port listens unless `tor_control_addr` says otherwise
+pub const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:9051";
+
+/// Prefix of the onion service keys Tor hands out and takes back
+const ONION_KEY_PREFIX: &str = "ED25519-V3:";
+
+/// Just enough of Tor's control protocol to authenticate and run an onion
+/// service, which lives for as long as this connection stays open.
+pub struct TorControl {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+/// An onion service Tor is running for us
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnionService {
+    /// The 56-character v3 address, without `.onion`
+    pub service_id: String,
+    /// `ED25519-V3:<base64>` key that brings back the same address; only
+    /// returned when Tor generated a new one
+    pub private_key: Option<String>,
+}
+
+impl TorControl {
+    /// Connect to the control port and authenticate with `password`, or else
+    /// whichever of no authentication or the cookie file Tor offers
+    pub async fn connect(addr: &str, password: Option<&str>) -> io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut control = Self { reader: BufReader::new(reader), writer };
+        control.authenticate(password).await?;
+        Ok(control)
+    }
+
+    async fn authenticate(&mut self, password: Option<&str>) -> io::Result<()> {
+        let info = self.command("PROTOCOLINFO 1").await?;
+        let auth = info.iter().find_map(|line| line.strip_prefix("AUTH ")).unwrap_or_default();
+        let methods = field(auth, "METHODS").unwrap_or_default();
+        let methods: Vec<&str> = methods.split(',').collect();
+
+        let credential = match password {
+            Some(password) => quote(password),
+            None if methods.contains(&"NULL") => String::new(),
+            None if methods.contains(&"COOKIE") => {
+                let path = field(auth, "COOKIEFILE")
+                    .ok_or_else(|| io::Error::other("Tor didn't say where its auth cookie is"))?;
+                hex::encode(tokio::fs::read(path).await?)
+            }
+            None => {
+                return Err(io::Error::other(format!(
+                    "Tor's control port wants {}; set tor_control_password", methods.join(" or "),
+                )));
+            }
+        };
+        self.command(format!("AUTHENTICATE {}", credential).trim_end()).await?;
+        Ok(())
+    }
+
+    /// Publish an onion service forwarding its `port` to `target`. Passing the
+    /// `private_key` from an earlier call keeps the same address.
+    pub async fn add_onion(&mut self, key: Option<&str>, port: u16, target: SocketAddr) -> io::Result<OnionService> {
+        let key = match key {
+            Some(key) if is_onion_key(key) => key,
+            Some(_) => return Err(io::Error::other("Stored onion service key is malformed")),
+            None => "NEW:ED25519-V3",
+        };
+        let reply = self.command(&format!("ADD_ONION {} Port={},{}", key, port, target)).await?;
+        let service_id = reply.iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .ok_or_else(|| io::Error::other("Tor didn't return the onion service's address"))?;
+        Ok(OnionService {
+            service_id: service_id.to_string(),
+            private_key: reply.iter().find_map(|line| line.strip_prefix("PrivateKey=")).map(String::from),
+        })
+    }
+
+    /// Wait for Tor to close the connection, which takes the onion service down
+    pub async fn closed(mut self) -> io::Result<()> {
+        let mut line = String::new();
+        while self.reader.read_line(&mut line).await? > 0 {
+            line.clear();
+        }
+        Ok(())
+    }
+
+    /// Send one command and collect the text of its `250` reply lines
+    async fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.writer.write_all(format!("{}\r\n", command).as_bytes()).await?;
+        // Only the verb, so a password never ends up in an error
+        let verb = command.split(' ').next().unwrap_or_default();
+
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            let (code, separator, text) = match (line.get(..3), line.get(3..4), line.get(4..)) {
+                (Some(code), Some(separator), Some(text)) => (code, separator, text),
+                _ => return Err(io::Error::other(format!("Malformed reply from Tor: {:?}", line))),
+            };
+            if code != "250" {
+                return Err(io::Error::other(format!("Tor refused {}: {} {}", verb, code, text)));
+            }
+            lines.push(text.to_string());
+            match separator {
+                " " => return Ok(lines),
+                // A data block, ended by a lone "."; nothing we send needs it
+                "+" => while self.read_line().await? != "." {},
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Tor closed the control connection"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Whether `key` looks like what Tor returned, so it can't smuggle in other
+/// arguments or commands
+fn is_onion_key(key: &str) -> bool {
+    key.strip_prefix(ONION_KEY_PREFIX).is_some_and(|encoded| {
+        !encoded.is_empty() && encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"+/=".contains(&b))
+    })
+}
+
+/// The value of `KEY=value` or `KEY="quoted value"` in a reply line
+fn field(line: &str, key: &str) -> Option<String> {
+    let pattern = format!("{}=", key);
+    let (start, _) = line.match_indices(&pattern).find(|(i, _)| *i == 0 || line[..*i].ends_with(' '))?;
+    let value = &line[start + pattern.len()..];
+    let Some(quoted) = value.strip_prefix('"') else {
+        return Some(value.split(' ').next().unwrap_or_default().to_string());
+    };
+    let mut out = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(out),
+            '\\' => out.push(chars.next()?),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// A control protocol quoted string, which can't end the command early
+fn quote(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A control port that checks each command it gets and answers with the next reply
+    async fn fake_tor(script: Vec<(&'static str, String)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            for (expected, reply) in script {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                assert_eq!(line.trim_end(), expected);
+                writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_cookie_auth_and_add_onion() {
+        let cookie = std::env::temp_dir().join("ledger_test_tor_cookie");
+        std::fs::write(&cookie, [0xab, 0xcd]).unwrap();
+        let protocolinfo = format!(
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"{}\"\r\n250-VERSION Tor=\"0.4.8.9\"\r\n250 OK\r\n",
+            cookie.display(),
+        );
+        let addr = fake_tor(vec![
+            ("PROTOCOLINFO 1", protocolinfo),
+            ("AUTHENTICATE abcd", "250 OK\r\n".into()),
+            (
+                "ADD_ONION NEW:ED25519-V3 Port=9420,127.0.0.1:9420",
+                "250-ServiceID=abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx\r\n250-PrivateKey=ED25519-V3:c2VjcmV0\r\n250 OK\r\n".into(),
+            ),
+            ("ADD_ONION ED25519-V3:c2VjcmV0 Port=9420,127.0.0.1:9420", "550 Onion address collision\r\n".into()),
+        ]).await;
+
+        let mut control = TorControl::connect(&addr, None).await.unwrap();
+        let target = "127.0.0.1:9420".parse().unwrap();
+        let service = control.add_onion(None, 9420, target).await.unwrap();
+        assert_eq!(service.service_id, "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx");
+        assert_eq!(service.private_key.as_deref(), Some("ED25519-V3:c2VjcmV0"));
+
+        let refused = control.add_onion(service.private_key.as_deref(), 9420, target).await.unwrap_err();
+        assert!(refused.to_string().contains("550 Onion address collision"));
+        assert!(control.add_onion(Some("ED25519-V3:x Port=1"), 9420, target).await.is_err());
+        let _ = std::fs::remove_file(&cookie);
+    }
+
+    #[tokio::test]
+    async fn test_password_is_quoted_and_never_echoed() {
+        let addr = fake_tor(vec![
+            ("PROTOCOLINFO 1", "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n250 OK\r\n".into()),
+            ("AUTHENTICATE \"pa\\\"ss\"", "515 Authentication failed\r\n".into()),
+        ]).await;
+        let error = TorControl::connect(&addr, Some("pa\"ss")).await.err().unwrap().to_string();
+        assert_eq!(error, "Tor refused AUTHENTICATE: 515 Authentication failed");
+
+        let addr = fake_tor(vec![
+            ("PROTOCOLINFO 1", "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n250 OK\r\n".into()),
+        ]).await;
+        assert!(TorControl::connect(&addr, None).await.is_err());
+    }
+}
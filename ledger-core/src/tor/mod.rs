@@ -0,0 +1,95 @@
+pub mod control;
+pub mod transport;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+
+use crate::store::db::Database;
+use control::TorControl;
+
+/// Where Tor's SOCKS5 proxy listens unless `tor_socks_addr` says otherwise
+pub const DEFAULT_SOCKS_ADDR: &str = "127.0.0.1:9050";
+const PROXY_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fail unless something is accepting connections on the proxy address
+pub async fn check_proxy(proxy: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match tokio::time::timeout(PROXY_CHECK_TIMEOUT, tokio::net::TcpStream::connect(proxy)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("Tor is enabled but the SOCKS5 proxy at {} is unreachable: {}", proxy, e).into()),
+        Err(_) => Err(format!("Tor is enabled but the SOCKS5 proxy at {} did not respond", proxy).into()),
+    }
+}
+
+/// Open a TCP connection to `host:port` through the SOCKS5 proxy. Hostnames
+/// are resolved by the proxy, so no DNS lookups happen outside Tor.
+pub async fn connect(proxy: &str, host: &str, port: u16) -> std::io::Result<tokio::net::TcpStream> {
+    let stream = tokio_socks::tcp::Socks5Stream::connect(proxy, (host, port))
+        .await
+        .map_err(std::io::Error::other)?;
+    Ok(stream.into_inner())
+}
+
+/// How the P2P node uses Tor
+#[derive(Debug, Clone)]
+pub struct TorConfig {
+    /// SOCKS5 proxy every outbound dial goes through
+    pub socks_addr: String,
+    /// Our onion service, when the node is reachable as one. The swarm then
+    /// only listens on loopback, where Tor forwards it.
+    pub onion_addr: Option<Multiaddr>,
+}
+
+/// Publish the P2P port as an onion service through the control port at
+/// `tor_control_addr`. The key Tor generates the first time is saved as
+/// `tor_onion_key`, so the address survives restarts. Tor keeps the service
+/// up for as long as the control connection, which is held open in the background.
+pub async fn start_onion_service(db: &Database, p2p_port: u16) -> Result<Multiaddr, Box<dyn std::error::Error>> {
+    let control_addr = db.get_setting("tor_control_addr")?
+        .unwrap_or_else(|| control::DEFAULT_CONTROL_ADDR.to_string());
+    let password = db.get_setting("tor_control_password")?.filter(|p| !p.is_empty());
+    let mut control = TorControl::connect(&control_addr, password.as_deref()).await
+        .map_err(|e| format!("Onion service enabled but Tor's control port at {} failed: {}", control_addr, e))?;
+
+    let saved_key = db.get_setting("tor_onion_key")?.filter(|k| !k.is_empty());
+    let target = SocketAddr::from((Ipv4Addr::LOCALHOST, p2p_port));
+    let service = control.add_onion(saved_key.as_deref(), p2p_port, target).await
+        .map_err(|e| format!("Failed to create onion service: {}", e))?;
+    if let Some(ref key) = service.private_key {
+        db.set_setting("tor_onion_key", key)?;
+    }
+
+    tokio::spawn(async move {
+        match control.closed().await {
+            Ok(()) => tracing::warn!("Tor closed the control connection; the onion service is down"),
+            Err(e) => tracing::warn!("Lost Tor's control connection ({}); the onion service is down", e),
+        }
+    });
+    Ok(format!("/onion3/{}:{}", service.service_id, p2p_port).parse()?)
+}
+
+/// Whether `addr` is an onion service
+pub fn is_onion(addr: &Multiaddr) -> bool {
+    matches!(addr.iter().next(), Some(Protocol::Onion3(_)))
+}
+
+/// Move onion addresses to the front, keeping the order otherwise
+pub fn prefer_onion(addrs: &mut [Multiaddr]) {
+    addrs.sort_by_key(|addr| !is_onion(addr));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefer_onion() {
+        let onion: Multiaddr = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:9420".parse().unwrap();
+        let ip: Multiaddr = "/ip4/192.0.2.1/tcp/9420".parse().unwrap();
+        let dns: Multiaddr = "/dns4/example.com/tcp/9420".parse().unwrap();
+        let mut addrs = vec![ip.clone(), onion.clone(), dns.clone()];
+        prefer_onion(&mut addrs);
+        assert_eq!(addrs, vec![onion, ip, dns]);
+    }
+}
@@ -0,0 +1,119 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use libp2p::core::transport::{ListenerId, TransportError, TransportEvent};
+use libp2p::futures::future::BoxFuture;
+use libp2p::multiaddr::Protocol;
+use libp2p::{tcp, Multiaddr, Transport};
+
+/// libp2p TCP transport that dials through a SOCKS5 proxy.
+///
+/// Listening is delegated to the ordinary TCP transport; only outbound
+/// connections are proxied.
+pub struct Socks5Transport {
+    proxy: String,
+    listener: tcp::tokio::Transport,
+}
+
+impl Socks5Transport {
+    pub fn new(proxy: String) -> Self {
+        Self {
+            proxy,
+            listener: tcp::tokio::Transport::new(tcp::Config::default()),
+        }
+    }
+}
+
+/// Host and port of a `/ip4|ip6|dns*/…/tcp/…` or `/onion3/…` address, with
+/// an optional `/p2p` suffix
+fn dial_target(addr: &Multiaddr) -> Option<(String, u16)> {
+    let mut iter = addr.iter();
+    let (host, port) = match iter.next()? {
+        // Displays as `/onion3/<address>:<port>`; the proxy resolves `<address>.onion`
+        Protocol::Onion3(onion) => {
+            let text = Protocol::Onion3(onion.clone()).to_string();
+            let (host, _) = text.strip_prefix("/onion3/")?.split_once(':')?;
+            (format!("{}.onion", host), onion.port())
+        }
+        host => {
+            let host = match host {
+                Protocol::Ip4(ip) => ip.to_string(),
+                Protocol::Ip6(ip) => ip.to_string(),
+                Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => host.to_string(),
+                _ => return None,
+            };
+            let Protocol::Tcp(port) = iter.next()? else {
+                return None;
+            };
+            (host, port)
+        }
+    };
+    match iter.next() {
+        None | Some(Protocol::P2p(_)) => Some((host, port)),
+        _ => None,
+    }
+}
+
+impl Transport for Socks5Transport {
+    type Output = tcp::tokio::TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = <tcp::tokio::Transport as Transport>::ListenerUpgrade;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(&mut self, id: ListenerId, addr: Multiaddr) -> Result<(), TransportError<Self::Error>> {
+        self.listener.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.listener.remove_listener(id)
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let Some((host, port)) = dial_target(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+        let proxy = self.proxy.clone();
+        Ok(Box::pin(async move {
+            crate::tor::connect(&proxy, &host, port).await.map(tcp::tokio::TcpStream)
+        }))
+    }
+
+    fn dial_as_listener(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        // Simultaneous-open is meaningless through a proxy; dial normally
+        self.dial(addr)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.listener).poll(cx)
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.listener.address_translation(listen, observed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dial_target() {
+        let addr: Multiaddr = "/dns4/example.com/tcp/9420".parse().unwrap();
+        assert_eq!(dial_target(&addr), Some(("example.com".into(), 9420)));
+
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/9420".parse::<Multiaddr>().unwrap()
+            .with(Protocol::P2p(libp2p::PeerId::random()));
+        assert_eq!(dial_target(&addr), Some(("10.0.0.1".into(), 9420)));
+
+        let addr: Multiaddr = "/ip4/10.0.0.1/udp/9420/quic-v1".parse().unwrap();
+        assert_eq!(dial_target(&addr), None);
+
+        let onion = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+        let addr: Multiaddr = format!("/onion3/{}:9420", onion).parse().unwrap();
+        assert_eq!(dial_target(&addr), Some((format!("{}.onion", onion), 9420)));
+    }
+}
@@ -43,8 +43,10 @@ public class LedgerApiClient {
             String json = response.body().string();
             JsonObject wrapper = gson.fromJson(json, JsonObject.class);
             if (wrapper.has("data") && !wrapper.get("data").isJsonNull()) {
+                // data is a page: {messages, has_more, next_offset}
+                JsonObject page = wrapper.getAsJsonObject("data");
                 Type listType = new TypeToken<List<Message>>() {}.getType();
-                return gson.fromJson(wrapper.get("data"), listType);
+                return gson.fromJson(page.get("messages"), listType);
             }
             return new ArrayList<>();
         }
@@ -14,6 +14,7 @@ public class Message {
     public boolean isRead;
     public String folder;
     public boolean encrypted;
+    public String signatureStatus;
 
     @Override
     public String toString() {
@@ -39,8 +39,8 @@ public class LedgerApiClient : IDisposable
     public async Task<List<MessageDto>?> GetMessagesAsync(string? folder = null)
     {
         var url = folder != null ? $"/api/messages?folder={folder}" : "/api/messages";
-        var resp = await _http.GetFromJsonAsync<ApiResponse<List<MessageDto>>>(url, JsonOpts);
-        return resp?.Data;
+        var resp = await _http.GetFromJsonAsync<ApiResponse<MessagePage>>(url, JsonOpts);
+        return resp?.Data?.Messages;
     }
 
     public async Task<MessageDto?> GetMessageAsync(string id)
@@ -49,9 +49,14 @@ public class LedgerApiClient : IDisposable
         return resp?.Data;
     }
 
-    public async Task<MessageDto?> SendMessageAsync(string to, string subject, string body, string? mode = null)
+    public async Task<MessageDto?> SendMessageAsync(string to, string subject, string body, string? mode = null,
+        IEnumerable<string>? cc = null, IEnumerable<string>? bcc = null, string contentType = "text", bool readReceipt = false)
     {
-        var payload = new { to, subject, body, mode = mode ?? "auto" };
+        var payload = new
+        {
+            to, subject, body, mode, content_type = contentType,
+            cc = cc ?? Array.Empty<string>(), bcc = bcc ?? Array.Empty<string>(), read_receipt = readReceipt,
+        };
         var response = await _http.PostAsJsonAsync("/api/messages", payload, JsonOpts);
         var resp = await response.Content.ReadFromJsonAsync<ApiResponse<MessageDto>>(JsonOpts);
         return resp?.Data;
@@ -115,7 +120,7 @@ public class LedgerApiClient : IDisposable
 
     // ── Contacts ──
 
-    public async Task<bool> AddContactAsync(string ledgerId, string publicKey, string? displayName = null, string? gmailAddress = null)
+    public async Task<bool> AddContactAsync(string ledgerId, string? publicKey = null, string? displayName = null, string? gmailAddress = null)
     {
         var response = await _http.PostAsJsonAsync("/api/contacts",
             new { ledger_id = ledgerId, public_key = publicKey, display_name = displayName, gmail_address = gmailAddress },
@@ -142,6 +147,13 @@ public class IdentityInfo
     public string PeerId { get; set; } = "";
 }
 
+public class MessagePage
+{
+    public List<MessageDto> Messages { get; set; } = new();
+    public bool HasMore { get; set; }
+    public int? NextOffset { get; set; }
+}
+
 public class MessageDto
 {
     public string Id { get; set; } = "";
@@ -152,8 +164,14 @@ public class MessageDto
     public long Timestamp { get; set; }
     public string DeliveryMethod { get; set; } = "";
     public bool IsRead { get; set; }
+    public bool IsStarred { get; set; }
     public string Folder { get; set; } = "";
     public bool Encrypted { get; set; }
+    public string SignatureStatus { get; set; } = "unsigned";
+    public string? EmailMessageId { get; set; }
+    public string ContentType { get; set; } = "text";
+    public bool ReadReceiptRequested { get; set; }
+    public List<ReadReceiptDto> ReadReceipts { get; set; } = new();
 
     public string DeliveryIcon => DeliveryMethod switch
     {
@@ -163,11 +181,24 @@ public class MessageDto
         _ => "❓"
     };
 
+    public string SignatureBadge => SignatureStatus switch
+    {
+        "valid" => "✅",
+        "invalid" => "❌",
+        _ => ""
+    };
+
     public string FormattedDate => DateTimeOffset.FromUnixTimeSeconds(Timestamp).LocalDateTime.ToString("MMM dd, HH:mm");
 
     public string ShortFrom => FromId.Length > 20 ? FromId[..20] + "..." : FromId;
 }
 
+public class ReadReceiptDto
+{
+    public string Recipient { get; set; } = "";
+    public long? ReadAt { get; set; }
+}
+
 public class PeerDto
 {
     public string PeerId { get; set; } = "";
@@ -18,6 +18,15 @@ if (-not (Get-Command rustc -ErrorAction SilentlyContinue)) {
     Write-Host "Rust already installed: $(rustc --version)"
 }
 
+# OpenSSL (SQLCipher links against libcrypto)
+if (-not $env:OPENSSL_DIR) {
+    Write-Host "Installing OpenSSL..."
+    winget install -e --id ShiningLight.OpenSSL.Dev
+    [Environment]::SetEnvironmentVariable("OPENSSL_DIR", "C:\Program Files\OpenSSL-Win64", "User")
+} else {
+    Write-Host "OpenSSL already configured: $env:OPENSSL_DIR"
+}
+
 # .NET 8 SDK
 if (-not (Get-Command dotnet -ErrorAction SilentlyContinue)) {
     Write-Host "Installing .NET 8 SDK..."